@@ -0,0 +1,519 @@
+use crate::config::AnomalyDetectionConfig;
+use crate::prom::ANOMALIES_DETECTED;
+use crate::Result;
+use failure::{err_msg, ResultExt};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use ipfs_monitoring_plugin_client::monitoring::{BlockPresenceType, EventType, PushedEvent};
+use ipfs_resolver_common::wantlist::JSONWantType;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The lower bound for the standard deviation of count baselines.
+/// This avoids huge z-scores for baselines that were (almost) constant so far.
+const MIN_STDDEV_COUNT: f64 = 1.0;
+
+/// The lower bound for the standard deviation of ratio baselines.
+const MIN_STDDEV_RATIO: f64 = 0.05;
+
+/// Types of anomalies we detect.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AnomalyType {
+    /// The number of WANTs for a single CID spiked.
+    WantSpike,
+
+    /// A single peer sent an unusual number of WANT_HAVE entries.
+    WantHaveFlood,
+
+    /// The fraction of DONT_HAVE block presences jumped.
+    DontHaveRatio,
+
+    /// The number of events received from a monitor dropped.
+    MonitorQuiet,
+}
+
+impl AnomalyType {
+    /// Returns the name of the anomaly type, as used for the metric label.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            AnomalyType::WantSpike => "want_spike",
+            AnomalyType::WantHaveFlood => "want_have_flood",
+            AnomalyType::DontHaveRatio => "dont_have_ratio",
+            AnomalyType::MonitorQuiet => "monitor_quiet",
+        }
+    }
+}
+
+/// A detected anomaly, as logged and sent to the sink.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Anomaly {
+    /// The end of the bucket in which the anomaly was detected.
+    pub(crate) timestamp: chrono::DateTime<chrono::Utc>,
+
+    /// The monitor on which the anomaly was detected.
+    pub(crate) monitor: String,
+
+    pub(crate) anomaly_type: AnomalyType,
+
+    /// The CID or peer responsible for the anomaly, if any.
+    pub(crate) subject: Option<String>,
+
+    /// The observed value.
+    pub(crate) value: f64,
+
+    /// The expected value, i.e., the mean of the baseline.
+    pub(crate) baseline: f64,
+
+    /// How many standard deviations the observed value deviates from the baseline.
+    pub(crate) z_score: f64,
+}
+
+/// An exponentially weighted moving mean and variance.
+#[derive(Clone, Debug, Default)]
+struct Baseline {
+    mean: f64,
+    variance: f64,
+    samples: u64,
+}
+
+impl Baseline {
+    fn update(&mut self, x: f64, alpha: f64) {
+        if self.samples == 0 {
+            self.mean = x;
+        } else {
+            let diff = x - self.mean;
+            let incr = alpha * diff;
+            self.mean += incr;
+            self.variance = (1.0 - alpha) * (self.variance + diff * incr);
+        }
+        self.samples += 1;
+    }
+
+    /// Computes by how many standard deviations x lies above the mean.
+    fn z_score(&self, x: f64, min_stddev: f64) -> f64 {
+        (x - self.mean) / self.variance.sqrt().max(min_stddev)
+    }
+}
+
+/// The baselines of a single monitor.
+#[derive(Clone, Debug, Default)]
+struct Baselines {
+    /// Of the number of WANTs for the most-wanted CID per bucket.
+    max_wants_per_cid: Baseline,
+
+    /// Of the number of WANT_HAVEs sent by the most active peer per bucket.
+    max_want_haves_per_peer: Baseline,
+
+    /// Of the fraction of DONT_HAVEs among block presences per bucket.
+    dont_have_ratio: Baseline,
+
+    /// Of the number of events per bucket.
+    events: Baseline,
+}
+
+/// Counts of events received within one bucket.
+#[derive(Clone, Debug, Default)]
+struct Bucket {
+    events: u64,
+    wants_by_cid: HashMap<String, u64>,
+    want_haves_by_peer: HashMap<String, u64>,
+    haves: u64,
+    dont_haves: u64,
+}
+
+impl Bucket {
+    fn record(&mut self, event: &PushedEvent) {
+        self.events += 1;
+
+        if let EventType::BitswapMessage(msg) = &event.inner {
+            for entry in msg.wantlist_entries.iter().filter(|e| !e.cancel) {
+                *self.wants_by_cid.entry(entry.cid.path.clone()).or_default() += 1;
+                if entry.want_type == JSONWantType::Have {
+                    *self
+                        .want_haves_by_peer
+                        .entry(event.peer.clone())
+                        .or_default() += 1;
+                }
+            }
+            for presence in msg.block_presences.iter() {
+                match presence.block_presence_type {
+                    BlockPresenceType::Have => self.haves += 1,
+                    BlockPresenceType::DontHave => self.dont_haves += 1,
+                }
+            }
+        }
+    }
+
+    /// Returns the key with the highest count and that count, or `None` if the map is empty.
+    fn max_entry(counts: &HashMap<String, u64>) -> Option<(&String, u64)> {
+        counts
+            .iter()
+            .max_by_key(|(_, count)| **count)
+            .map(|(key, count)| (key, *count))
+    }
+}
+
+/// Detects anomalies in the stream of events of one monitor.
+///
+/// Events are counted in buckets of fixed length.
+/// At the end of each bucket, the counts are compared to EWMA baselines of previous buckets, and
+/// anomalies are reported if they deviate by more than the configured z-score.
+/// Afterwards, the baselines are updated with the counts of the bucket.
+#[derive(Debug)]
+pub(crate) struct AnomalyDetector {
+    monitor_name: String,
+    cfg: AnomalyDetectionConfig,
+    current_bucket: Mutex<Bucket>,
+    baselines: Mutex<Baselines>,
+}
+
+impl AnomalyDetector {
+    pub(crate) fn new(monitor_name: &str, cfg: &AnomalyDetectionConfig) -> AnomalyDetector {
+        AnomalyDetector {
+            monitor_name: monitor_name.to_string(),
+            cfg: cfg.clone(),
+            current_bucket: Default::default(),
+            baselines: Default::default(),
+        }
+    }
+
+    /// Records a batch of events into the current bucket.
+    pub(crate) fn record_events(&self, events: &[PushedEvent]) {
+        let mut bucket = self.current_bucket.lock().unwrap();
+        for event in events {
+            bucket.record(event)
+        }
+    }
+
+    /// Closes the current bucket, evaluates it against the baselines, and updates the baselines.
+    fn evaluate_bucket(&self) -> Vec<Anomaly> {
+        let bucket = std::mem::take(&mut *self.current_bucket.lock().unwrap());
+        let mut baselines = self.baselines.lock().unwrap();
+        let ts = chrono::Utc::now();
+        let mut anomalies = Vec::new();
+
+        let (cid, wants) = match Bucket::max_entry(&bucket.wants_by_cid) {
+            Some((cid, wants)) => (Some(cid.clone()), wants),
+            None => (None, 0),
+        };
+        if let Some(anomaly) = self.check_spike(
+            &mut baselines.max_wants_per_cid,
+            wants as f64,
+            MIN_STDDEV_COUNT,
+            wants >= self.cfg.min_count,
+        ) {
+            anomalies.push(self.anomaly(ts, AnomalyType::WantSpike, cid, anomaly));
+        }
+
+        let (peer, want_haves) = match Bucket::max_entry(&bucket.want_haves_by_peer) {
+            Some((peer, want_haves)) => (Some(peer.clone()), want_haves),
+            None => (None, 0),
+        };
+        if let Some(anomaly) = self.check_spike(
+            &mut baselines.max_want_haves_per_peer,
+            want_haves as f64,
+            MIN_STDDEV_COUNT,
+            want_haves >= self.cfg.min_count,
+        ) {
+            anomalies.push(self.anomaly(ts, AnomalyType::WantHaveFlood, peer, anomaly));
+        }
+
+        // The ratio is meaningless for few presences, so we neither check nor update the baseline
+        // in that case.
+        let presences = bucket.haves + bucket.dont_haves;
+        if presences >= self.cfg.min_count {
+            let ratio = bucket.dont_haves as f64 / presences as f64;
+            if let Some(anomaly) = self.check_spike(
+                &mut baselines.dont_have_ratio,
+                ratio,
+                MIN_STDDEV_RATIO,
+                true,
+            ) {
+                anomalies.push(self.anomaly(ts, AnomalyType::DontHaveRatio, None, anomaly));
+            }
+        }
+
+        // For the event rate we are interested in drops, not spikes.
+        let events = bucket.events as f64;
+        let events_baseline = &mut baselines.events;
+        if events_baseline.samples >= self.cfg.warmup_buckets
+            && events_baseline.mean >= self.cfg.min_count as f64
+        {
+            let z_score = -events_baseline.z_score(events, MIN_STDDEV_COUNT);
+            if z_score >= self.cfg.z_score_threshold {
+                anomalies.push(self.anomaly(
+                    ts,
+                    AnomalyType::MonitorQuiet,
+                    None,
+                    (events, events_baseline.mean, z_score),
+                ));
+            }
+        }
+        events_baseline.update(events, self.cfg.ewma_alpha);
+
+        anomalies
+    }
+
+    /// Checks whether x exceeds the baseline by more than the configured z-score, and then updates
+    /// the baseline.
+    /// Returns the observed value, the mean of the baseline, and the z-score if so.
+    fn check_spike(
+        &self,
+        baseline: &mut Baseline,
+        x: f64,
+        min_stddev: f64,
+        significant: bool,
+    ) -> Option<(f64, f64, f64)> {
+        let res = if baseline.samples >= self.cfg.warmup_buckets && significant {
+            let z_score = baseline.z_score(x, min_stddev);
+            if z_score >= self.cfg.z_score_threshold {
+                Some((x, baseline.mean, z_score))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        baseline.update(x, self.cfg.ewma_alpha);
+
+        res
+    }
+
+    fn anomaly(
+        &self,
+        timestamp: chrono::DateTime<chrono::Utc>,
+        anomaly_type: AnomalyType,
+        subject: Option<String>,
+        (value, baseline, z_score): (f64, f64, f64),
+    ) -> Anomaly {
+        Anomaly {
+            timestamp,
+            monitor: self.monitor_name.clone(),
+            anomaly_type,
+            subject,
+            value,
+            baseline,
+            z_score,
+        }
+    }
+}
+
+/// A sink that receives detected anomalies as JSON via HTTP POST requests.
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+pub(crate) struct AnomalySink {
+    client: Client<HttpConnector>,
+    url: Uri,
+}
+
+impl AnomalySink {
+    pub(crate) fn new(url: &str) -> Result<AnomalySink> {
+        let url = url.parse::<Uri>().context("invalid URL")?;
+        if url.scheme_str() != Some("http") {
+            return Err(err_msg("only http:// URLs are supported"));
+        }
+
+        Ok(AnomalySink {
+            client: Client::new(),
+            url,
+        })
+    }
+
+    /// Sends an anomaly to the sink in the background.
+    /// Failures are logged, but not retried.
+    fn send(&self, anomaly: &Anomaly) {
+        let body = match serde_json::to_vec(anomaly) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("unable to encode anomaly {:?}: {:?}", anomaly, err);
+                return;
+            }
+        };
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            match client.request(req).await {
+                Ok(resp) if !resp.status().is_success() => {
+                    warn!("anomaly sink responded with status {}", resp.status())
+                }
+                Ok(_) => {}
+                Err(err) => warn!("unable to send anomaly to sink: {}", err),
+            }
+        });
+    }
+}
+
+/// Periodically evaluates the buckets of the given detector, until the program exits.
+/// Detected anomalies are logged, counted, and sent to the sink, if any.
+pub(crate) async fn run_detection(detector: Arc<AnomalyDetector>, sink: Option<AnomalySink>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(detector.cfg.bucket_seconds));
+    // The first tick completes immediately.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        for anomaly in detector.evaluate_bucket() {
+            match serde_json::to_string(&anomaly) {
+                Ok(json) => warn!("anomaly detected: {}", json),
+                Err(err) => error!("unable to encode anomaly {:?}: {:?}", anomaly, err),
+            }
+            ANOMALIES_DETECTED
+                .with_label_values(&[&anomaly.monitor, anomaly.anomaly_type.name()])
+                .inc();
+            if let Some(sink) = &sink {
+                sink.send(&anomaly)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipfs_monitoring_plugin_client::monitoring::BitswapMessage;
+    use ipfs_resolver_common::wantlist::{JSONWantlistEntry, JsonCID};
+
+    fn config() -> AnomalyDetectionConfig {
+        AnomalyDetectionConfig {
+            bucket_seconds: 10,
+            ewma_alpha: 0.1,
+            z_score_threshold: 4.0,
+            warmup_buckets: 5,
+            min_count: 10,
+            webhook_url: None,
+        }
+    }
+
+    /// Creates an event with `n` WANT_BLOCKs for the given CID.
+    fn wants(cid: &str, n: usize) -> PushedEvent {
+        PushedEvent {
+            timestamp: chrono::Utc::now(),
+            peer: "peer1".to_string(),
+            inner: EventType::BitswapMessage(BitswapMessage {
+                wantlist_entries: (0..n)
+                    .map(|_| JSONWantlistEntry {
+                        priority: 1,
+                        cancel: false,
+                        send_dont_have: false,
+                        cid: JsonCID {
+                            path: cid.to_string(),
+                        },
+                        want_type: JSONWantType::Block,
+                    })
+                    .collect(),
+                full_wantlist: false,
+                blocks: vec![],
+                block_presences: vec![],
+                connected_addresses: vec![],
+            }),
+        }
+    }
+
+    /// Records a bucket with `n` WANTs for the given CID, split into events of 10 WANTs, and
+    /// evaluates it.
+    fn evaluate(detector: &AnomalyDetector, cid: &str, n: usize) -> Vec<Anomaly> {
+        let events: Vec<_> = (0..n / 10).map(|_| wants(cid, 10)).collect();
+        detector.record_events(&events);
+        detector.evaluate_bucket()
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(config().validate().is_ok());
+
+        for z_score_threshold in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let cfg = AnomalyDetectionConfig {
+                z_score_threshold,
+                ..config()
+            };
+            assert!(cfg.validate().is_err(), "{}", z_score_threshold);
+        }
+        for ewma_alpha in [0.0, 1.5, f64::NAN] {
+            let cfg = AnomalyDetectionConfig {
+                ewma_alpha,
+                ..config()
+            };
+            assert!(cfg.validate().is_err(), "{}", ewma_alpha);
+        }
+        let cfg = AnomalyDetectionConfig {
+            bucket_seconds: 0,
+            ..config()
+        };
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn baseline_ewma() {
+        let mut b = Baseline::default();
+        b.update(10.0, 0.5);
+        assert_eq!(b.mean, 10.0);
+        assert_eq!(b.variance, 0.0);
+
+        b.update(20.0, 0.5);
+        assert_eq!(b.mean, 15.0);
+        assert_eq!(b.variance, 25.0);
+        assert_eq!(b.samples, 2);
+
+        assert_eq!(b.z_score(25.0, 1.0), 2.0);
+        // The standard deviation is bounded from below.
+        assert_eq!(b.z_score(25.0, 10.0), 1.0);
+    }
+
+    #[test]
+    fn no_anomalies_during_warmup() {
+        let detector = AnomalyDetector::new("de1", &config());
+        for _ in 0..4 {
+            assert!(evaluate(&detector, "QmA", 100).is_empty());
+        }
+        assert!(evaluate(&detector, "QmA", 10_000).is_empty());
+    }
+
+    #[test]
+    fn steady_state_is_not_anomalous() {
+        let detector = AnomalyDetector::new("de1", &config());
+        for i in 0..50 {
+            let n = if i % 2 == 0 { 90 } else { 110 };
+            let anomalies = evaluate(&detector, "QmA", n);
+            assert!(anomalies.is_empty(), "bucket {}: {:?}", i, anomalies);
+        }
+    }
+
+    #[test]
+    fn spike_is_anomalous() {
+        let detector = AnomalyDetector::new("de1", &config());
+        for i in 0..20 {
+            let n = if i % 2 == 0 { 90 } else { 110 };
+            assert!(evaluate(&detector, "QmA", n).is_empty());
+        }
+
+        let anomalies = evaluate(&detector, "QmB", 1000);
+        assert_eq!(anomalies.len(), 1);
+        let anomaly = &anomalies[0];
+        assert_eq!(anomaly.anomaly_type, AnomalyType::WantSpike);
+        assert_eq!(anomaly.subject.as_deref(), Some("QmB"));
+        assert_eq!(anomaly.value, 1000.0);
+        assert!(anomaly.z_score >= 4.0);
+    }
+
+    #[test]
+    fn quiet_monitor_is_anomalous() {
+        let detector = AnomalyDetector::new("de1", &config());
+        for _ in 0..20 {
+            assert!(evaluate(&detector, "QmA", 1000).is_empty());
+        }
+
+        let anomalies = evaluate(&detector, "QmA", 0);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].anomaly_type, AnomalyType::MonitorQuiet);
+    }
+}
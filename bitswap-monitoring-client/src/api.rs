@@ -0,0 +1,84 @@
+use crate::health::{self, HealthState};
+use crate::tail::{self, EventTail};
+use crate::Result;
+use failure::ResultExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+const API_PATH_EVENTS: &str = "/events";
+const API_PATH_HEALTHZ: &str = "/healthz";
+const API_PATH_READYZ: &str = "/readyz";
+
+/// The state shared by all handlers of the HTTP API.
+#[derive(Clone, Debug)]
+pub(crate) struct ApiState {
+    pub(crate) tail: EventTail,
+    pub(crate) health: HealthState,
+}
+
+/// Starts a task to serve the HTTP API, i.e., the live event tail and health checks.
+pub(crate) fn run_api_server(addr: SocketAddr, state: ApiState) -> Result<()> {
+    let builder = Server::try_bind(&addr).context("unable to bind")?;
+
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle_request(req, &state)) }
+            }))
+        }
+    });
+
+    tokio::spawn(async move {
+        if let Err(err) = builder.serve(make_service).await {
+            error!("API server failed: {:?}", err)
+        }
+    });
+
+    Ok(())
+}
+
+pub(crate) fn handle_request(req: Request<Body>, state: &ApiState) -> Response<Body> {
+    if req.method() != Method::GET {
+        return plain_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method not allowed\n".to_string(),
+        );
+    }
+
+    match req.uri().path() {
+        API_PATH_EVENTS => tail::handle_subscription(&req, &state.tail),
+        API_PATH_HEALTHZ => health::handle_liveness(&state.health),
+        API_PATH_READYZ => health::handle_readiness(&state.health),
+        _ => plain_response(StatusCode::NOT_FOUND, "not found\n".to_string()),
+    }
+}
+
+pub(crate) fn plain_response(status: StatusCode, msg: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .body(Body::from(msg))
+        .unwrap()
+}
+
+pub(crate) fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    match serde_json::to_string(body) {
+        Ok(json) => Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(err) => {
+            error!("unable to encode response: {:?}", err);
+            plain_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to encode response\n".to_string(),
+            )
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use crate::config::LabelLimitConfig;
+use crate::prom::LABEL_VALUES_FOLDED;
+use prometheus::IntGauge;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The number of values counted per admitted value, see `LabelGuard`.
+const COUNTERS_PER_VALUE: usize = 10;
+
+/// Bounds the number of distinct values of one metric label dimension, and thus the number of
+/// time series created for it.
+///
+/// Values on the allow list are always tracked.
+/// Additionally, the `max_values` other values seen most often so far are tracked.
+/// Values not admitted are supposed to be folded into a catch-all bucket by the caller.
+/// The guard is shared between monitors, so that all monitors track the same label values.
+///
+/// Occurrences are counted using the Space-Saving algorithm, with a fixed number of counters
+/// proportional to `max_values`:
+/// If all counters are in use, a new value takes over the counter of the least frequent value
+/// that is not admitted, including its count.
+/// This bounds memory usage, at the cost of overestimating the counts of rare values.
+/// The inherited count is remembered as the error of the counter.
+///
+/// If a value is guaranteed to be seen more often than the least frequent tracked value, i.e.,
+/// its count minus its error is larger, it replaces that value.
+/// Time series of replaced values keep their last values, and further occurrences are folded.
+/// Callers caching the outcome of `admit` need to invalidate their caches whenever the
+/// `generation` changes.
+#[derive(Debug)]
+pub(crate) struct LabelGuard {
+    dimension: String,
+    allow_list: HashSet<String>,
+    max_values: Option<usize>,
+    state: Mutex<LabelGuardState>,
+    /// Incremented whenever a value is replaced.
+    generation: AtomicU64,
+    folded_gauge: IntGauge,
+}
+
+#[derive(Debug, Default)]
+struct LabelGuardState {
+    /// The counters of values not on the allow list.
+    counters: HashMap<String, Counter>,
+
+    /// Values admitted in addition to the allow list, ordered by count.
+    admitted: BTreeSet<(u64, String)>,
+
+    /// Counted values which are not admitted, ordered by count.
+    folded: BTreeSet<(u64, String)>,
+}
+
+/// The approximate number of occurrences of a value.
+#[derive(Clone, Copy, Debug)]
+struct Counter {
+    /// An upper bound of the number of occurrences.
+    count: u64,
+    /// The maximum overestimation of `count`, inherited from an evicted value.
+    error: u64,
+}
+
+impl LabelGuard {
+    pub(crate) fn new(dimension: &str, cfg: &LabelLimitConfig) -> LabelGuard {
+        let folded_gauge = LABEL_VALUES_FOLDED
+            .get_metric_with_label_values(&[dimension])
+            .unwrap();
+        folded_gauge.set(0);
+
+        LabelGuard {
+            dimension: dimension.to_string(),
+            allow_list: cfg.allow_list.iter().cloned().collect(),
+            max_values: cfg.max_values,
+            state: Default::default(),
+            generation: AtomicU64::new(0),
+            folded_gauge,
+        }
+    }
+
+    /// Returns the values that are always tracked.
+    pub(crate) fn allow_list(&self) -> impl Iterator<Item = &String> {
+        self.allow_list.iter()
+    }
+
+    /// Checks whether the given value is on the allow list.
+    pub(crate) fn is_allow_listed(&self, value: &str) -> bool {
+        self.allow_list.contains(value)
+    }
+
+    /// Returns the generation of the set of admitted values.
+    /// This changes whenever a value that was admitted before is not admitted anymore, or vice
+    /// versa.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Returns the maximum number of values counted at the same time.
+    fn num_counters(max_values: usize) -> usize {
+        max_values
+            .saturating_add(1)
+            .saturating_mul(COUNTERS_PER_VALUE)
+    }
+
+    /// Records an occurrence of the given value.
+    /// This is meant to be called for every event, and admits values as needed.
+    pub(crate) fn record(&self, value: &str) {
+        if self.allow_list.contains(value) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        // Without a limit, every value is admitted right away.
+        let max_values = self.max_values.unwrap_or(usize::MAX);
+
+        let counter = match state.counters.get(value).cloned() {
+            Some(old) => {
+                let key = (old.count, value.to_string());
+                let counter = Counter {
+                    count: old.count + 1,
+                    error: old.error,
+                };
+                state.counters.insert(key.1.clone(), counter);
+                if state.admitted.remove(&key) {
+                    state.admitted.insert((counter.count, key.1));
+                    return;
+                }
+                state.folded.remove(&key);
+                counter
+            }
+            None if state.admitted.len() < max_values => {
+                debug!("admitting value {} for label {}", value, self.dimension);
+                let counter = Counter { count: 1, error: 0 };
+                state.counters.insert(value.to_string(), counter);
+                state.admitted.insert((counter.count, value.to_string()));
+                return;
+            }
+            None if state.counters.len() < Self::num_counters(max_values) => {
+                let counter = Counter { count: 1, error: 0 };
+                state.counters.insert(value.to_string(), counter);
+                counter
+            }
+            None => {
+                // Take over the counter of the least frequent folded value.
+                // There is always one, because there are more counters than admitted values.
+                let (min_count, evicted) = state.folded.pop_first().unwrap();
+                state.counters.remove(&evicted);
+                let counter = Counter {
+                    count: min_count + 1,
+                    error: min_count,
+                };
+                state.counters.insert(value.to_string(), counter);
+                counter
+            }
+        };
+
+        // Replace the least frequent admitted value, if this one is guaranteed to be seen more
+        // often.
+        let replaced = match state.admitted.first() {
+            Some((min_count, _)) if counter.count - counter.error > *min_count => {
+                state.admitted.pop_first()
+            }
+            _ => None,
+        };
+        match replaced {
+            Some(replaced) => {
+                debug!(
+                    "label {}: value {} replaces value {} among the top {} values",
+                    self.dimension, value, replaced.1, max_values
+                );
+                state.admitted.insert((counter.count, value.to_string()));
+                state.folded.insert(replaced);
+                self.generation.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                state.folded.insert((counter.count, value.to_string()));
+            }
+        }
+
+        self.folded_gauge.set(state.folded.len() as i64);
+    }
+
+    /// Decides whether the given value gets its own time series.
+    /// If this returns false, the value should be folded into the catch-all bucket.
+    ///
+    /// Values need to be recorded before they can be admitted.
+    pub(crate) fn admit(&self, value: &str) -> bool {
+        if self.allow_list.contains(value) {
+            return true;
+        }
+        let state = self.state.lock().unwrap();
+        state
+            .counters
+            .get(value)
+            .is_some_and(|c| state.admitted.contains(&(c.count, value.to_string())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(max_values: Option<usize>) -> LabelGuard {
+        LabelGuard::new(
+            "test",
+            &LabelLimitConfig {
+                allow_list: vec!["DE".to_string()],
+                max_values,
+            },
+        )
+    }
+
+    fn record_n(guard: &LabelGuard, value: &str, n: usize) {
+        for _ in 0..n {
+            guard.record(value);
+        }
+    }
+
+    #[test]
+    fn unlimited_admits_everything() {
+        let g = guard(None);
+        for v in ["US", "NL", "FR"] {
+            g.record(v);
+            assert!(g.admit(v));
+        }
+        assert_eq!(g.generation(), 0);
+    }
+
+    #[test]
+    fn allow_list_is_always_admitted() {
+        let g = guard(Some(0));
+        assert!(g.admit("DE"));
+        g.record("US");
+        assert!(!g.admit("US"));
+    }
+
+    #[test]
+    fn keeps_most_frequent_values() {
+        let g = guard(Some(2));
+        record_n(&g, "US", 3);
+        record_n(&g, "NL", 2);
+        record_n(&g, "FR", 2);
+        assert!(g.admit("US"));
+        assert!(g.admit("NL"));
+        // Seen as often as NL, but not more often.
+        assert!(!g.admit("FR"));
+        assert_eq!(g.generation(), 0);
+
+        g.record("FR");
+        assert!(g.admit("FR"));
+        assert!(!g.admit("NL"));
+        assert!(g.admit("US"));
+        assert_eq!(g.generation(), 1);
+
+        // Allow-listed values do not take up any of the slots.
+        record_n(&g, "DE", 10);
+        assert!(g.admit("US"));
+        assert!(g.admit("FR"));
+    }
+
+    #[test]
+    fn bounds_counted_values() {
+        let g = guard(Some(2));
+        record_n(&g, "US", 5);
+        record_n(&g, "NL", 3);
+        for i in 0..1000 {
+            g.record(&format!("rare{}", i));
+        }
+        {
+            let state = g.state.lock().unwrap();
+            assert_eq!(state.counters.len(), LabelGuard::num_counters(2));
+            assert_eq!(
+                state.admitted.len() + state.folded.len(),
+                state.counters.len()
+            );
+        }
+        // Rare values inherit large counts, but are not guaranteed to be seen more often.
+        assert!(g.admit("US"));
+        assert!(g.admit("NL"));
+        assert_eq!(g.generation(), 0);
+
+        // A frequent value still makes it, even if it was evicted before.
+        record_n(&g, "rare0", 10);
+        assert!(g.admit("rare0"));
+        assert!(g.admit("US"));
+        assert!(!g.admit("NL"));
+        assert_eq!(g.generation(), 1);
+    }
+}
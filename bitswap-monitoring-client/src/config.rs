@@ -1,4 +1,4 @@
-use failure::ResultExt;
+use failure::{err_msg, ResultExt};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::path::Path;
@@ -14,6 +14,19 @@ pub(crate) struct Config {
     /// Specifies on what address a prometheus endpoint will be created.
     pub(crate) prometheus_address: String,
 
+    /// Specifies on what address an HTTP API will be created.
+    /// The API provides live event streaming and health checks.
+    /// If not provided, the API is disabled.
+    /// This was previously called `tail_address`, which is still accepted.
+    #[serde(alias = "tail_address")]
+    pub(crate) api_address: Option<String>,
+
+    /// The number of seconds without events after which a monitor is considered stalled.
+    /// Stalled monitors are reported as unhealthy.
+    /// Defaults to 300 if unspecified.
+    #[serde(default = "default_stall_timeout_seconds")]
+    pub(crate) stall_timeout_seconds: u64,
+
     /// Specifies where MaxMind GeoLite databases are located.
     /// Defaults to /usr/local/share/GeoIP if unspecified.
     #[serde(default = "default_geoip_database_path")]
@@ -21,8 +34,129 @@ pub(crate) struct Config {
 
     /// Specifies the location of the public gateway ID file.
     /// Each line in the file should contain one peer ID.
-    /// If not provided, all traffic will be logged as non-gateway traffic.
+    /// This is a shorthand for a `gateway_source` in plain format without periodic refresh, and
+    /// cannot be combined with `gateway_source`.
+    /// If neither is provided, all traffic will be logged as non-gateway traffic.
     pub(crate) gateway_file_path: Option<String>,
+
+    /// Specifies where to load the list of public gateways from.
+    /// If neither this nor `gateway_file_path` is provided, all traffic will be logged as
+    /// non-gateway traffic.
+    pub(crate) gateway_source: Option<GatewaySourceConfig>,
+
+    /// Limits the number of distinct values per label, to bound the number of time series.
+    /// Defaults to tracking all values.
+    #[serde(default)]
+    pub(crate) label_limits: LabelLimitsConfig,
+
+    /// Configures detection of anomalies in the event streams of the monitors.
+    /// If not provided, anomaly detection is disabled.
+    pub(crate) anomaly_detection: Option<AnomalyDetectionConfig>,
+}
+
+/// Configuration for anomaly detection.
+/// Anomalies are detected per monitor, by comparing counts of events within fixed-length buckets
+/// to exponentially weighted moving baselines of previous buckets.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct AnomalyDetectionConfig {
+    /// The length of a bucket, in seconds.
+    /// Defaults to 10 if unspecified.
+    #[serde(default = "default_anomaly_bucket_seconds")]
+    pub(crate) bucket_seconds: u64,
+
+    /// The smoothing factor of the baselines, between 0 and 1.
+    /// Higher values adapt to changes more quickly.
+    /// Defaults to 0.1 if unspecified.
+    #[serde(default = "default_anomaly_ewma_alpha")]
+    pub(crate) ewma_alpha: f64,
+
+    /// The number of standard deviations a bucket must deviate from the baseline to be reported
+    /// as an anomaly.
+    /// Defaults to 4 if unspecified.
+    #[serde(default = "default_anomaly_z_score_threshold")]
+    pub(crate) z_score_threshold: f64,
+
+    /// The number of buckets to observe before reporting anomalies.
+    /// Defaults to 30 if unspecified.
+    #[serde(default = "default_anomaly_warmup_buckets")]
+    pub(crate) warmup_buckets: u64,
+
+    /// The minimum count per bucket for spikes to be reported.
+    /// This applies to WANTs per CID, WANT_HAVEs per peer, and the number of block presences
+    /// considered for the DONT_HAVE ratio.
+    /// Monitors going quiet are only reported if their baseline is at least this many events
+    /// per bucket.
+    /// Defaults to 100 if unspecified.
+    #[serde(default = "default_anomaly_min_count")]
+    pub(crate) min_count: u64,
+
+    /// An http:// URL to which detected anomalies are sent as JSON via POST requests.
+    /// If not provided, anomalies are only logged and counted.
+    pub(crate) webhook_url: Option<String>,
+}
+
+/// Configuration for loading the list of public gateways.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct GatewaySourceConfig {
+    /// An http:// or https:// URL, or the path to a local file.
+    pub(crate) location: String,
+
+    /// The format of the gateway list.
+    /// Defaults to `plain` if unspecified.
+    #[serde(default)]
+    pub(crate) format: GatewayListFormat,
+
+    /// The interval in which to reload the list, in seconds.
+    /// If unspecified, the list is only reloaded on SIGUSR1.
+    pub(crate) refresh_interval_seconds: Option<u64>,
+}
+
+/// Formats of gateway lists.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum GatewayListFormat {
+    /// One peer ID per line.
+    #[default]
+    Plain,
+
+    /// The JSON output of `ipfs-gateway-finder`.
+    FinderJson,
+
+    /// The CSV output of `ipfs-gateway-finder`.
+    FinderCsv,
+}
+
+/// Cardinality limits for the dynamically created metric labels.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct LabelLimitsConfig {
+    /// Limits for the `origin_country` label.
+    /// Allow-listed values must be ISO 3166-1 two-letter codes.
+    /// Defaults to an allow list of a few countries with high traffic and no limit.
+    #[serde(default = "default_origin_country_limits")]
+    pub(crate) origin_country: LabelLimitConfig,
+}
+
+impl Default for LabelLimitsConfig {
+    fn default() -> Self {
+        LabelLimitsConfig {
+            origin_country: default_origin_country_limits(),
+        }
+    }
+}
+
+/// Cardinality limits for a single label.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct LabelLimitConfig {
+    /// Values that are always tracked individually.
+    /// Metrics for these are created ahead of time.
+    #[serde(default)]
+    pub(crate) allow_list: Vec<String>,
+
+    /// The maximum number of values tracked individually in addition to the allow list.
+    /// The values seen most often so far are tracked, all others are folded into the catch-all
+    /// value `Other`.
+    /// If unspecified, all values are tracked.
+    pub(crate) max_values: Option<usize>,
 }
 
 /// Configuration for a single data source.
@@ -39,6 +173,54 @@ fn default_geoip_database_path() -> String {
     "/usr/local/share/GeoIP".to_string()
 }
 
+fn default_stall_timeout_seconds() -> u64 {
+    300
+}
+
+fn default_anomaly_bucket_seconds() -> u64 {
+    10
+}
+
+fn default_anomaly_ewma_alpha() -> f64 {
+    0.1
+}
+
+fn default_anomaly_z_score_threshold() -> f64 {
+    4.0
+}
+
+fn default_anomaly_warmup_buckets() -> u64 {
+    30
+}
+
+fn default_anomaly_min_count() -> u64 {
+    100
+}
+
+fn default_origin_country_limits() -> LabelLimitConfig {
+    LabelLimitConfig {
+        // These are countries with high traffic, so we initialize them beforehand.
+        allow_list: vec!["DE".to_string(), "US".to_string(), "NL".to_string()],
+        max_values: None,
+    }
+}
+
+impl AnomalyDetectionConfig {
+    /// Checks that the values are in range, before anything is set up with them.
+    pub(crate) fn validate(&self) -> Result<()> {
+        // These are written such that NaN is rejected as well.
+        if self.bucket_seconds == 0
+            || !(self.ewma_alpha > 0.0 && self.ewma_alpha <= 1.0)
+            || !(self.z_score_threshold > 0.0 && self.z_score_threshold.is_finite())
+        {
+            return Err(err_msg(
+                "invalid anomaly detection config, need bucket_seconds > 0, 0 < ewma_alpha <= 1, and a finite z_score_threshold > 0",
+            ));
+        }
+        Ok(())
+    }
+}
+
 impl Config {
     /// Reads a Config from a given path.
     pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Config> {
@@ -48,4 +230,19 @@ impl Config {
 
         Ok(config)
     }
+
+    /// Returns the configured source of the list of public gateways, if any.
+    pub(crate) fn gateway_source(&self) -> Result<Option<GatewaySourceConfig>> {
+        match (&self.gateway_file_path, &self.gateway_source) {
+            (Some(_), Some(_)) => Err(err_msg(
+                "only one of gateway_file_path and gateway_source may be provided",
+            )),
+            (Some(path), None) => Ok(Some(GatewaySourceConfig {
+                location: path.clone(),
+                format: GatewayListFormat::Plain,
+                refresh_interval_seconds: None,
+            })),
+            (None, source) => Ok(source.clone()),
+        }
+    }
 }
@@ -1,22 +1,48 @@
+use crate::config::{GatewayListFormat, GatewaySourceConfig};
 use failure::{err_msg, ResultExt};
-use std::collections::HashSet;
+use libp2p_identity::PeerId;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::sync::RwLock;
 
 use crate::Result;
 
+/// Known public gateways, mapping peer IDs to the hostnames of their gateways, if known.
+pub(crate) type KnownGateways = Arc<RwLock<HashMap<String, Option<String>>>>;
+
+/// A single result of `ipfs-gateway-finder`, in JSON format.
+/// This only contains the fields we need.
+#[derive(Clone, Debug, Deserialize)]
+struct GatewayFinderJSONResult {
+    gateway: String,
+    bitswap_message: Option<GatewayFinderJSONBitswapMessage>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct GatewayFinderJSONBitswapMessage {
+    peer: String,
+}
+
+/// A single result of `ipfs-gateway-finder`, in CSV format.
+/// This only contains the fields we need.
+#[derive(Clone, Debug, Deserialize)]
+struct GatewayFinderCSVResult {
+    gateway: String,
+    first_bs_peer: String,
+}
+
 pub(crate) fn set_up_signal_handling(
-    path: String,
-    known_gateways: Arc<RwLock<HashSet<String>>>,
+    source: GatewaySourceConfig,
+    known_gateways: KnownGateways,
 ) -> Result<()> {
     let mut stream =
         signal(SignalKind::user_defined1()).context("failed to set up handler for SIGUSR1")?;
     tokio::spawn(async move {
-        signal_handler_update_gateways(&mut stream, &path, &known_gateways).await
+        signal_handler_update_gateways(&mut stream, &source, &known_gateways).await
     });
 
     Ok(())
@@ -24,20 +50,20 @@ pub(crate) fn set_up_signal_handling(
 
 async fn signal_handler_update_gateways(
     signal_stream: &mut Signal,
-    gateway_data_path: &String,
-    known_gateways: &Arc<RwLock<HashSet<String>>>,
+    source: &GatewaySourceConfig,
+    known_gateways: &KnownGateways,
 ) {
-    while let Some(_) = signal_stream.recv().await {
+    while signal_stream.recv().await.is_some() {
         info!("received SIGUSR1, reloading gateway IDs");
-        match update_known_gateways(gateway_data_path, known_gateways).await {
+        match update_known_gateways(source, known_gateways).await {
             Ok(_) => {
                 info!("updated known gateways successfully");
             }
             Err(err) => {
                 // We should let the user know this failed, but we will try again next time.
                 error!(
-                    "unable to update known gateways from file {}: {:?}",
-                    gateway_data_path, err
+                    "unable to update known gateways from {}: {:?}",
+                    source.location, err
                 );
             }
         }
@@ -47,27 +73,201 @@ async fn signal_handler_update_gateways(
     info!("SIGUSR1 stream closed, exiting signal handler");
 }
 
+/// Starts a task to reload the list of gateways periodically.
+pub(crate) fn set_up_periodic_refresh(
+    source: GatewaySourceConfig,
+    interval: Duration,
+    known_gateways: KnownGateways,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // The first tick completes immediately, but we just loaded the list.
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            debug!("periodically reloading gateway IDs");
+            match update_known_gateways(&source, &known_gateways).await {
+                Ok(_) => {
+                    info!(
+                        "updated known gateways successfully, now know {} gateway IDs",
+                        known_gateways.read().await.len()
+                    );
+                }
+                Err(err) => {
+                    // We keep the old list and try again next time.
+                    error!(
+                        "unable to update known gateways from {}: {:?}",
+                        source.location, err
+                    );
+                }
+            }
+        }
+    });
+}
+
+/// Loads the list of gateways from the given source and replaces the known gateways with it.
+/// If loading fails, the known gateways are not modified.
 pub(crate) async fn update_known_gateways(
-    gateway_data_path: &String,
-    known_gateways: &Arc<RwLock<HashSet<String>>>,
+    source: &GatewaySourceConfig,
+    known_gateways: &KnownGateways,
 ) -> Result<()> {
-    let file = File::open(gateway_data_path)
+    let data = fetch_gateway_list(&source.location)
         .await
-        .context("unable to open gateway ID file")?;
+        .context("unable to fetch gateway list")?;
+    let new_gateways =
+        parse_gateway_list(&data, source.format).context("unable to parse gateway list")?;
 
-    let mut new_gateways = HashSet::new();
-    let mut lines = BufReader::new(file).lines();
+    let mut known_gateways = known_gateways.write().await;
+    *known_gateways = new_gateways;
 
-    while let Some(line) = lines.next_line().await.context("unable to read")? {
-        if !line.starts_with("Qm") && !line.starts_with("12D3KooW") && line.len() < 46 {
-            return Err(err_msg(format!("invalid gateway ID {}", line)));
-        }
+    Ok(())
+}
 
-        new_gateways.insert(line);
+/// Reads the gateway list from an http:// or https:// URL, or a local file otherwise.
+async fn fetch_gateway_list(location: &str) -> Result<Vec<u8>> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let resp = reqwest::get(location)
+            .await
+            .context("unable to make request")?
+            .error_for_status()
+            .context("request failed")?;
+        let data = resp.bytes().await.context("unable to read response")?;
+        Ok(data.to_vec())
+    } else {
+        let data = tokio::fs::read(location)
+            .await
+            .context("unable to read gateway file")?;
+        Ok(data)
     }
+}
 
-    let mut known_gateways = known_gateways.write().await;
-    known_gateways.extend(new_gateways);
+fn parse_gateway_list(
+    data: &[u8],
+    format: GatewayListFormat,
+) -> Result<HashMap<String, Option<String>>> {
+    let mut gateways = HashMap::new();
+
+    match format {
+        GatewayListFormat::Plain => {
+            let data = std::str::from_utf8(data).context("gateway list is not valid UTF-8")?;
+            for line in data.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+                insert_gateway(&mut gateways, line, None)?;
+            }
+        }
+        GatewayListFormat::FinderJson => {
+            let results =
+                serde_json::Deserializer::from_slice(data).into_iter::<GatewayFinderJSONResult>();
+            for res in results {
+                let res = res.context("unable to decode gateway finder result")?;
+                // Gateways for which no Bitswap message was received have no peer ID.
+                if let Some(msg) = res.bitswap_message {
+                    insert_gateway(&mut gateways, &msg.peer, Some(res.gateway))?;
+                }
+            }
+        }
+        GatewayListFormat::FinderCsv => {
+            let mut reader = csv::Reader::from_reader(data);
+            for res in reader.deserialize() {
+                let res: GatewayFinderCSVResult =
+                    res.context("unable to decode gateway finder result")?;
+                if !res.first_bs_peer.is_empty() {
+                    insert_gateway(&mut gateways, &res.first_bs_peer, Some(res.gateway))?;
+                }
+            }
+        }
+    }
+
+    Ok(gateways)
+}
+
+fn insert_gateway(
+    gateways: &mut HashMap<String, Option<String>>,
+    peer_id: &str,
+    hostname: Option<String>,
+) -> Result<()> {
+    PeerId::from_str(peer_id)
+        .map_err(|e| err_msg(format!("{}", e)))
+        .context(format!("invalid gateway ID {}", peer_id))?;
+
+    match gateways.get(peer_id) {
+        // Multiple gateways may be operated by the same node, we keep the first one.
+        Some(Some(existing)) => {
+            debug!(
+                "gateway ID {} is used by gateways {} and {:?}, keeping {}",
+                peer_id, existing, hostname, existing
+            )
+        }
+        _ => {
+            gateways.insert(peer_id.to_string(), hostname);
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_1: &str = "QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN";
+    const PEER_2: &str = "QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa";
+
+    #[test]
+    fn parse_plain_list() {
+        let data = format!("{}\n\n  {}  \n{}\n", PEER_1, PEER_2, PEER_1);
+        let gateways = parse_gateway_list(data.as_bytes(), GatewayListFormat::Plain).unwrap();
+        assert_eq!(gateways.len(), 2);
+        assert_eq!(gateways.get(PEER_1), Some(&None));
+        assert_eq!(gateways.get(PEER_2), Some(&None));
+    }
+
+    #[test]
+    fn parse_finder_json() {
+        let data = format!(
+            r#"{{"gateway":"gw1.example.com","gateway_url":"https://gw1.example.com/ipfs/:hash","bitswap_message":{{"peer":"{}","timestamp":"2021-01-01T00:00:00Z"}}}}
+{{"gateway":"gw2.example.com","gateway_url":"https://gw2.example.com/ipfs/:hash","bitswap_message":null}}
+{{"gateway":"gw3.example.com","gateway_url":"https://gw3.example.com/ipfs/:hash","bitswap_message":{{"peer":"{}"}}}}
+"#,
+            PEER_1, PEER_1
+        );
+        let gateways = parse_gateway_list(data.as_bytes(), GatewayListFormat::FinderJson).unwrap();
+        // Gateways without a Bitswap message are skipped, the first gateway per peer is kept.
+        assert_eq!(gateways.len(), 1);
+        assert_eq!(
+            gateways.get(PEER_1),
+            Some(&Some("gw1.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_finder_csv() {
+        let data = format!(
+            "gateway,gateway_url,first_bs_peer,first_bs_address
+gw1.example.com,https://gw1.example.com/ipfs/:hash,{},/ip4/1.2.3.4/tcp/4001
+gw2.example.com,https://gw2.example.com/ipfs/:hash,,
+gw3.example.com,https://gw3.example.com/ipfs/:hash,{},/ip4/5.6.7.8/tcp/4001
+",
+            PEER_1, PEER_2
+        );
+        let gateways = parse_gateway_list(data.as_bytes(), GatewayListFormat::FinderCsv).unwrap();
+        assert_eq!(gateways.len(), 2);
+        assert_eq!(
+            gateways.get(PEER_1),
+            Some(&Some("gw1.example.com".to_string()))
+        );
+        assert_eq!(
+            gateways.get(PEER_2),
+            Some(&Some("gw3.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_peer_id() {
+        let data = format!("{}\nnot-a-peer-id\n", PEER_1);
+        assert!(parse_gateway_list(data.as_bytes(), GatewayListFormat::Plain).is_err());
+
+        let data = "gateway,first_bs_peer\ngw1.example.com,QmInvalid\n";
+        assert!(parse_gateway_list(data.as_bytes(), GatewayListFormat::FinderCsv).is_err());
+    }
+}
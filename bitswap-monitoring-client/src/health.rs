@@ -0,0 +1,284 @@
+use crate::api;
+use crate::prom::{MONITOR_CONNECTED, MONITOR_LAST_EVENT_TIMESTAMP, MONITOR_RECONNECTS};
+use chrono::TimeZone;
+use hyper::{Body, Response, StatusCode};
+use prometheus::{IntCounter, IntGauge};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Connection state of a single monitor.
+/// This is updated by the connection loop of that monitor and read by the health checks.
+#[derive(Debug)]
+pub(crate) struct MonitorHealth {
+    monitor_name: String,
+
+    /// Whether we are currently subscribed to events from the monitor.
+    connected: AtomicBool,
+
+    /// The number of times we tried to reconnect to the monitor.
+    reconnects: AtomicU64,
+
+    /// When we last received events from the monitor, as milliseconds since the Unix epoch.
+    /// Zero if we never received any events.
+    last_event_ts_millis: AtomicI64,
+
+    connected_gauge: IntGauge,
+    last_event_gauge: IntGauge,
+    reconnects_counter: IntCounter,
+}
+
+impl MonitorHealth {
+    fn new(monitor_name: &str) -> MonitorHealth {
+        let connected_gauge = MONITOR_CONNECTED
+            .get_metric_with_label_values(&[monitor_name])
+            .unwrap();
+        connected_gauge.set(0);
+
+        MonitorHealth {
+            monitor_name: monitor_name.to_string(),
+            connected: AtomicBool::new(false),
+            reconnects: Default::default(),
+            last_event_ts_millis: Default::default(),
+            connected_gauge,
+            last_event_gauge: MONITOR_LAST_EVENT_TIMESTAMP
+                .get_metric_with_label_values(&[monitor_name])
+                .unwrap(),
+            reconnects_counter: MONITOR_RECONNECTS
+                .get_metric_with_label_values(&[monitor_name])
+                .unwrap(),
+        }
+    }
+
+    pub(crate) fn set_connected(&self) {
+        self.connected.store(true, Ordering::Relaxed);
+        self.connected_gauge.set(1);
+    }
+
+    pub(crate) fn set_disconnected(&self) {
+        self.connected.store(false, Ordering::Relaxed);
+        self.connected_gauge.set(0);
+    }
+
+    pub(crate) fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+        self.reconnects_counter.inc();
+    }
+
+    /// Records that events were received just now.
+    pub(crate) fn record_events(&self) {
+        let now = chrono::Utc::now();
+        self.last_event_ts_millis
+            .store(now.timestamp_millis(), Ordering::Relaxed);
+        self.last_event_gauge.set(now.timestamp());
+    }
+
+    fn last_event_ts(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self.last_event_ts_millis.load(Ordering::Relaxed) {
+            0 => None,
+            millis => chrono::Utc.timestamp_millis_opt(millis).single(),
+        }
+    }
+}
+
+/// The connection states of all monitors, plus the configuration to judge them.
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+pub(crate) struct HealthState {
+    monitors: Arc<Mutex<Vec<Arc<MonitorHealth>>>>,
+    stall_timeout: chrono::Duration,
+    started_ts: chrono::DateTime<chrono::Utc>,
+}
+
+/// The state of one monitor, as reported by the health check endpoints.
+#[derive(Clone, Debug, Serialize)]
+struct MonitorHealthReport {
+    monitor: String,
+    connected: bool,
+    last_event: Option<chrono::DateTime<chrono::Utc>>,
+    reconnects: u64,
+    stalled: bool,
+}
+
+/// The response of the health check endpoints.
+#[derive(Clone, Debug, Serialize)]
+struct HealthReport {
+    healthy: bool,
+    ready: bool,
+    monitors: Vec<MonitorHealthReport>,
+}
+
+impl HealthState {
+    /// Creates a new health state.
+    /// A monitor is considered stalled if we did not receive any events from it for longer than
+    /// `stall_timeout_seconds`, or since startup.
+    pub(crate) fn new(stall_timeout_seconds: u64) -> HealthState {
+        HealthState {
+            monitors: Default::default(),
+            stall_timeout: chrono::Duration::seconds(stall_timeout_seconds as i64),
+            started_ts: chrono::Utc::now(),
+        }
+    }
+
+    /// Registers a monitor and returns a handle to update its state.
+    pub(crate) fn register(&self, monitor_name: &str) -> Arc<MonitorHealth> {
+        let monitor = Arc::new(MonitorHealth::new(monitor_name));
+        self.monitors.lock().unwrap().push(monitor.clone());
+        monitor
+    }
+
+    fn report(&self) -> HealthReport {
+        let now = chrono::Utc::now();
+        let monitors: Vec<_> = self
+            .monitors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| {
+                let last_event = m.last_event_ts();
+                MonitorHealthReport {
+                    monitor: m.monitor_name.clone(),
+                    connected: m.connected.load(Ordering::Relaxed),
+                    last_event,
+                    reconnects: m.reconnects.load(Ordering::Relaxed),
+                    stalled: now - last_event.unwrap_or(self.started_ts) > self.stall_timeout,
+                }
+            })
+            .collect();
+
+        HealthReport {
+            healthy: monitors.iter().all(|m| !m.stalled),
+            ready: !monitors.is_empty() && monitors.iter().all(|m| m.connected),
+            monitors,
+        }
+    }
+}
+
+/// Handles a liveness check.
+/// This succeeds if no monitor is stalled.
+pub(crate) fn handle_liveness(health: &HealthState) -> Response<Body> {
+    let report = health.report();
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    api::json_response(status, &report)
+}
+
+/// Handles a readiness check.
+/// This succeeds if we are connected to all monitors.
+pub(crate) fn handle_readiness(health: &HealthState) -> Response<Body> {
+    let report = health.report();
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    api::json_response(status, &report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{handle_request, ApiState};
+    use crate::tail::EventTail;
+    use hyper::Request;
+
+    async fn get(state: &ApiState, path: &str) -> (StatusCode, serde_json::Value) {
+        let resp = handle_request(Request::get(path).body(Body::empty()).unwrap(), state);
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn api_state(health: &HealthState) -> ApiState {
+        ApiState {
+            tail: EventTail::new(),
+            health: health.clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn ready_once_all_monitors_connected() {
+        let health = HealthState::new(60);
+        let state = api_state(&health);
+
+        // Not ready before any monitor is registered.
+        let (status, _) = get(&state, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let a = health.register("health-test-ready-a");
+        let b = health.register("health-test-ready-b");
+        let (status, report) = get(&state, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["ready"], false);
+
+        a.set_connected();
+        let (status, _) = get(&state, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        b.set_connected();
+        let (status, report) = get(&state, "/readyz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["ready"], true);
+        assert_eq!(report["monitors"][0]["monitor"], "health-test-ready-a");
+        assert_eq!(report["monitors"][1]["connected"], true);
+
+        b.set_disconnected();
+        b.record_reconnect();
+        let (status, report) = get(&state, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["monitors"][1]["reconnects"], 1);
+
+        // Liveness does not depend on connections.
+        let (status, _) = get(&state, "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn stalled_monitor_fails_liveness() {
+        let health = HealthState::new(60);
+        let state = api_state(&health);
+        let a = health.register("health-test-stalled-a");
+        let b = health.register("health-test-stalled-b");
+        for m in [&a, &b] {
+            m.set_connected();
+            m.record_events();
+        }
+        let (status, report) = get(&state, "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["healthy"], true);
+
+        // One monitor stalls past the threshold, the other one is fine.
+        let stalled_ts = chrono::Utc::now() - chrono::Duration::seconds(61);
+        a.last_event_ts_millis
+            .store(stalled_ts.timestamp_millis(), Ordering::Relaxed);
+        let (status, report) = get(&state, "/healthz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["healthy"], false);
+        assert_eq!(report["monitors"][0]["stalled"], true);
+        assert_eq!(report["monitors"][1]["stalled"], false);
+
+        // Readiness only depends on connections.
+        let (status, _) = get(&state, "/readyz").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn monitor_without_events_stalls_after_startup() {
+        let health = HealthState {
+            started_ts: chrono::Utc::now() - chrono::Duration::seconds(61),
+            ..HealthState::new(60)
+        };
+        let state = api_state(&health);
+        health.register("health-test-startup").set_connected();
+
+        let (status, report) = get(&state, "/healthz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["monitors"][0]["last_event"], serde_json::Value::Null);
+        assert_eq!(report["monitors"][0]["stalled"], true);
+    }
+}
@@ -5,8 +5,14 @@ extern crate lazy_static;
 #[macro_use]
 extern crate prometheus;
 
+use crate::anomaly::{AnomalyDetector, AnomalySink};
+use crate::api::ApiState;
+use crate::cardinality::LabelGuard;
 use crate::config::Config;
+use crate::gateways::KnownGateways;
+use crate::health::{HealthState, MonitorHealth};
 use crate::prom::{MetricsKey, PublicGatewayStatus};
+use crate::tail::EventTail;
 use clap::{App, Arg};
 use failure::{err_msg, ResultExt};
 use futures_util::StreamExt;
@@ -16,16 +22,20 @@ use ipfs_monitoring_plugin_client::monitoring::{
 use ipfs_resolver_common::wantlist::JSONWantType;
 use ipfs_resolver_common::{logging, Result};
 use prom::{Geolocation, Metrics};
-use std::collections::HashSet;
 use std::env;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::RwLock;
 
+mod anomaly;
+mod api;
+mod cardinality;
 mod config;
 mod gateways;
 mod geolocation;
+mod health;
 mod prom;
+mod tail;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -70,23 +80,32 @@ async fn run_with_config(cfg: Config) -> Result<()> {
     info!("successfully read MaxMind database");
 
     // Read list of public gateway IDs.
-    let known_gateways = Arc::new(RwLock::new(HashSet::new()));
-    match cfg.gateway_file_path {
-        Some(path) => {
-            debug!("loading gateway IDs from {}", path);
-            gateways::update_known_gateways(&path, &known_gateways)
+    let known_gateways: KnownGateways = Default::default();
+    match cfg.gateway_source().context("invalid gateway config")? {
+        Some(source) => {
+            debug!("loading gateway IDs from {}", source.location);
+            gateways::update_known_gateways(&source, &known_gateways)
                 .await
                 .context("unable to load gateway IDs")?;
             info!("loaded {} gateway IDs", known_gateways.read().await.len());
 
+            if let Some(secs) = source.refresh_interval_seconds {
+                debug!("starting loop to reload gateway IDs every {} seconds", secs);
+                gateways::set_up_periodic_refresh(
+                    source.clone(),
+                    Duration::from_secs(secs),
+                    known_gateways.clone(),
+                );
+            }
+
             debug!("starting loop to handle SIGUSR1");
             let known_gateways = known_gateways.clone();
-            gateways::set_up_signal_handling(path.clone(), known_gateways)
+            gateways::set_up_signal_handling(source, known_gateways)
                 .context("unable to set up signal handling to reload gateway IDs")?;
             info!("started signal handler. Send SIGUSR1 to reload list of gateways.");
         }
         None => {
-            info!("no gateway file provided, all traffic will be logged as non-gateway")
+            info!("no gateway source provided, all traffic will be logged as non-gateway")
         }
     }
 
@@ -100,6 +119,36 @@ async fn run_with_config(cfg: Config) -> Result<()> {
     prom::run_prometheus(prometheus_address)?;
     info!("started prometheus server");
 
+    // Set up cardinality limits.
+    // We create metrics for allow-listed countries ahead of time, so they need to be valid.
+    let country_guard = Arc::new(LabelGuard::new(
+        "origin_country",
+        &cfg.label_limits.origin_country,
+    ));
+    for country_code in country_guard.allow_list() {
+        celes::Country::from_alpha2(country_code)
+            .map_err(|e| err_msg(format!("{}", e)))
+            .context(format!(
+                "invalid country code {} in origin_country allow list",
+                country_code
+            ))?;
+    }
+
+    // Set up anomaly detection.
+    let anomaly_cfg = cfg.anomaly_detection.clone();
+    match &anomaly_cfg {
+        Some(c) => c.validate()?,
+        None => info!("no anomaly detection config provided, anomaly detection is disabled"),
+    }
+    let anomaly_sink = match anomaly_cfg.as_ref().and_then(|c| c.webhook_url.as_ref()) {
+        Some(url) => Some(AnomalySink::new(url).context("unable to set up anomaly sink")?),
+        None => None,
+    };
+
+    // Set up shared state for the API
+    let event_tail = EventTail::new();
+    let health = HealthState::new(cfg.stall_timeout_seconds);
+
     // Connect to monitors
     info!("starting infinite connection loop, try Ctrl+C to exit");
     let handles = cfg
@@ -110,13 +159,29 @@ async fn run_with_config(cfg: Config) -> Result<()> {
                 .into_iter()
                 .map(|name| {
                     let name = name.clone();
-                    let country_db = country_db.clone();
-                    let known_gateways = known_gateways.clone();
+                    let shared_state = SharedState {
+                        country_db: country_db.clone(),
+                        known_gateways: known_gateways.clone(),
+                        event_tail: event_tail.clone(),
+                        country_guard: country_guard.clone(),
+                    };
                     let amqp_server_address = c.amqp_server_address.clone();
+                    let monitor_health = health.register(&name);
+                    let anomaly_detector = anomaly_cfg.as_ref().map(|c| {
+                        let detector = Arc::new(AnomalyDetector::new(&name, c));
+                        tokio::spawn(anomaly::run_detection(
+                            detector.clone(),
+                            anomaly_sink.clone(),
+                        ));
+                        detector
+                    });
 
                     tokio::spawn(async move {
-                        // Create metrics for a few popular countries ahead of time.
-                        let mut metrics_by_country = Metrics::create_basic_set(&name);
+                        // Create metrics for allow-listed countries ahead of time.
+                        let mut metrics_by_country = Metrics::create_basic_set(
+                            &name,
+                            shared_state.country_guard.allow_list(),
+                        );
                         let routing_keys = vec![
                             RoutingKeyInformation::BitswapMessages {
                                 monitor_name: name.clone(),
@@ -126,17 +191,24 @@ async fn run_with_config(cfg: Config) -> Result<()> {
                             },
                         ];
 
+                        let mut first_attempt = true;
                         loop {
-                            let country_db = country_db.clone();
+                            if !first_attempt {
+                                monitor_health.record_reconnect();
+                            }
+                            first_attempt = false;
+
                             let res = connect_and_receive(
                                 &mut metrics_by_country,
                                 &name,
                                 &amqp_server_address,
                                 &routing_keys,
-                                country_db,
-                                &known_gateways,
+                                &shared_state,
+                                &monitor_health,
+                                anomaly_detector.as_deref(),
                             )
                             .await;
+                            monitor_health.set_disconnected();
 
                             info!(
                                 "server {}, monitor {}: result: {:?}",
@@ -156,6 +228,29 @@ async fn run_with_config(cfg: Config) -> Result<()> {
         .flatten()
         .collect::<Vec<_>>();
 
+    // Set up API
+    match cfg.api_address {
+        Some(addr) => {
+            let api_address = addr
+                .parse::<SocketAddr>()
+                .context("invalid api_address")?;
+
+            debug!("starting API server");
+            api::run_api_server(
+                api_address,
+                ApiState {
+                    tail: event_tail,
+                    health,
+                },
+            )
+            .context("unable to start API server")?;
+            info!("started API server on {}", api_address);
+        }
+        None => {
+            info!("no API address provided, live event streaming and health checks are disabled")
+        }
+    }
+
     // Sleep forever (probably)
     for handle in handles {
         handle.await.context("connection loop failed")?;
@@ -164,14 +259,31 @@ async fn run_with_config(cfg: Config) -> Result<()> {
     Ok(())
 }
 
+/// Handles shared between the connection loops of all monitors.
+#[derive(Clone)]
+struct SharedState {
+    country_db: Arc<maxminddb::Reader<Vec<u8>>>,
+    known_gateways: KnownGateways,
+    event_tail: EventTail,
+    country_guard: Arc<LabelGuard>,
+}
+
 async fn connect_and_receive(
     metrics_by_country: &mut prom::MetricsMap,
     monitor_name: &str,
     amqp_server_address: &str,
     routing_keys: &[RoutingKeyInformation],
-    country_db: Arc<maxminddb::Reader<Vec<u8>>>,
-    known_gateways: &Arc<RwLock<HashSet<String>>>,
+    shared_state: &SharedState,
+    monitor_health: &MonitorHealth,
+    anomaly_detector: Option<&AnomalyDetector>,
 ) -> Result<()> {
+    let SharedState {
+        country_db,
+        known_gateways,
+        event_tail,
+        country_guard,
+    } = shared_state;
+
     debug!(
         "connecting to AMQP server {} at {} and subscribing to events for monitor {}...",
         monitor_name, amqp_server_address, monitor_name
@@ -181,8 +293,11 @@ async fn connect_and_receive(
         "connected for monitor {} at {}",
         monitor_name, amqp_server_address
     );
+    monitor_health.set_connected();
 
     let mut first = true;
+    // The generation of admitted countries our cached metrics are based on.
+    let mut country_generation = None;
 
     while let Some(events) = client.next().await {
         match events {
@@ -191,36 +306,67 @@ async fn connect_and_receive(
                 break;
             }
             Ok((_, events)) => {
+                monitor_health.record_events();
+                if let Some(detector) = anomaly_detector {
+                    detector.record_events(&events);
+                }
                 if first {
                     first = false;
                     info!("receiving messages for monitor {}...", monitor_name)
                 }
 
                 for event in events {
-                    let geolocation = geolocation::geolocate_event(&country_db, &event);
+                    let geolocation = geolocation::geolocate_event(country_db, &event);
                     debug!(
                         "{}: determined origin of event {:?} to be {:?}",
                         monitor_name, event, geolocation
                     );
 
-                    let origin_type = if known_gateways.read().await.contains(&event.peer) {
+                    let gateway = known_gateways.read().await.get(&event.peer).cloned();
+                    let origin_type = if gateway.is_some() {
                         PublicGatewayStatus::Gateway
                     } else {
                         PublicGatewayStatus::NonGateway
                     };
 
+                    event_tail.publish(monitor_name, &event, &geolocation, origin_type);
+
                     let metrics_key = MetricsKey {
                         geo_origin: geolocation,
                         overlay_origin: origin_type,
                     };
 
+                    // Count the country towards the most frequent ones, and forget which
+                    // countries we folded if that changed.
+                    if let Geolocation::Alpha2(country_code) = &metrics_key.geo_origin {
+                        country_guard.record(country_code);
+                    }
+                    if country_generation != Some(country_guard.generation()) {
+                        country_generation = Some(country_guard.generation());
+                        metrics_by_country.retain(|k, _| match &k.geo_origin {
+                            Geolocation::Alpha2(country_code) => {
+                                country_guard.is_allow_listed(country_code)
+                            }
+                            _ => true,
+                        });
+                    }
+
                     let metrics = match metrics_by_country.get(&metrics_key) {
                         None => {
                             debug!(
                                 "{}: metrics for {:?} missing, creating on the fly...",
                                 monitor_name, metrics_key
                             );
-                            match Metrics::new_for_key(monitor_name, &metrics_key) {
+                            // Fold the country into Other if it's not among the most frequent
+                            // countries.
+                            // We cache the resulting metrics under the original key, so we only
+                            // do this once per country, until the most frequent countries change.
+                            let bounded_key = metrics_key.with_bounded_country(country_guard);
+                            let new_metrics = match metrics_by_country.get(&bounded_key) {
+                                Some(m) => Ok(m.clone()),
+                                None => Metrics::new_for_key(monitor_name, &bounded_key),
+                            };
+                            match new_metrics {
                                 Ok(new_metrics) => {
                                     // We know that the metrics_key value is safe, since we were able to create metrics with it.
                                     metrics_by_country.insert(metrics_key.clone(), new_metrics);
@@ -270,6 +416,11 @@ async fn connect_and_receive(
                         }
                         EventType::BitswapMessage(msg) => {
                             metrics.num_messages.inc();
+                            if let Some(Some(hostname)) = &gateway {
+                                prom::GATEWAY_MESSAGES_RECEIVED
+                                    .with_label_values(&[monitor_name, hostname])
+                                    .inc();
+                            }
 
                             if !msg.wantlist_entries.is_empty() {
                                 if msg.full_wantlist {
@@ -369,7 +520,7 @@ async fn connect_and_receive(
         }
     }
 
-    info!("monitor {}: disconnected?", monitor_name);
+    warn!("monitor {}: disconnected", monitor_name);
 
     Ok(()) // I guess?
 }
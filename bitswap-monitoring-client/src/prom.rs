@@ -1,7 +1,8 @@
+use crate::cardinality::LabelGuard;
 use failure::{err_msg, ResultExt};
 use ipfs_resolver_common::Result;
 use prometheus::core::{AtomicU64, GenericCounter};
-use prometheus::IntCounterVec;
+use prometheus::{IntCounterVec, IntGaugeVec};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 
@@ -54,13 +55,57 @@ lazy_static! {
         &["monitor","origin_country","origin_is_gateway"]
     )
     .unwrap();
+
+    pub static ref MONITOR_CONNECTED: IntGaugeVec = register_int_gauge_vec!(
+        "monitor_connected",
+        "whether we are currently connected to and subscribed to events of a monitor, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref MONITOR_LAST_EVENT_TIMESTAMP: IntGaugeVec = register_int_gauge_vec!(
+        "monitor_last_event_timestamp_seconds",
+        "the time events were last received from a monitor, in seconds since the Unix epoch, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref LABEL_VALUES_FOLDED: IntGaugeVec = register_int_gauge_vec!(
+        "label_values_folded",
+        "number of distinct label values currently counted and folded into the catch-all value due to cardinality limits, by label",
+        &["label"]
+    )
+    .unwrap();
+
+    pub static ref MONITOR_RECONNECTS: IntCounterVec = register_int_counter_vec!(
+        "monitor_reconnects",
+        "number of attempts to reconnect to a monitor, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref GATEWAY_MESSAGES_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "gateway_bitswap_messages_received",
+        "number of Bitswap messages received from known public gateways, by monitor and gateway hostname",
+        &["monitor", "gateway"]
+    )
+    .unwrap();
+
+    pub static ref ANOMALIES_DETECTED: IntCounterVec = register_int_counter_vec!(
+        "anomalies_detected",
+        "number of anomalies detected, by monitor and anomaly type",
+        &["monitor", "anomaly_type"]
+    )
+    .unwrap();
 }
 
 /// Country constants for various error conditions.
 pub(crate) static COUNTRY_NAME_UNKNOWN: &'static str = "Unknown";
 pub(crate) static COUNTRY_NAME_ERROR: &'static str = "Error";
+pub(crate) static COUNTRY_NAME_OTHER: &'static str = "Other";
 
 /// A set of metrics instantiated by monitor name and country.
+#[derive(Clone)]
 pub(crate) struct Metrics {
     /// Counter for Bitswap messages.
     pub(crate) num_messages: GenericCounter<AtomicU64>,
@@ -117,10 +162,26 @@ pub(crate) enum Geolocation {
     /// The location could not be determined because an error occurred.
     Error,
 
+    /// The location is one of many countries not tracked individually, to limit the number of
+    /// time series.
+    Other,
+
     /// ISO 3166-1 two-letter code, e.g. "US", "DE", "NL".
     Alpha2(String),
 }
 
+impl Geolocation {
+    /// Returns the ISO 3166-1 two-letter code, or the name of one of the special countries.
+    pub(crate) fn short_name(&self) -> &str {
+        match self {
+            Geolocation::Unknown => COUNTRY_NAME_UNKNOWN,
+            Geolocation::Error => COUNTRY_NAME_ERROR,
+            Geolocation::Other => COUNTRY_NAME_OTHER,
+            Geolocation::Alpha2(country_code) => country_code,
+        }
+    }
+}
+
 /// The key type for metrics.
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub(crate) struct MetricsKey {
@@ -128,43 +189,63 @@ pub(crate) struct MetricsKey {
     pub overlay_origin: PublicGatewayStatus,
 }
 
+impl MetricsKey {
+    /// Returns a copy of this key with the country folded into [`Geolocation::Other`], if the
+    /// guard does not admit it.
+    pub(crate) fn with_bounded_country(&self, country_guard: &LabelGuard) -> MetricsKey {
+        let geo_origin = match &self.geo_origin {
+            Geolocation::Alpha2(country_code) if !country_guard.admit(country_code) => {
+                Geolocation::Other
+            }
+            g => g.clone(),
+        };
+
+        MetricsKey {
+            geo_origin,
+            overlay_origin: self.overlay_origin,
+        }
+    }
+}
+
 pub(crate) type MetricsMap = HashMap<MetricsKey, Metrics>;
 
 impl Metrics {
-    /// Creates a set of metrics consisting of a few popular countries and the special
+    /// Creates a set of metrics consisting of the given countries and the special
     /// error-condition countries, for the given monitor.
-    pub(crate) fn create_basic_set(monitor_name: &str) -> HashMap<MetricsKey, Metrics> {
-        [
-            // These are countries with high traffic, so we initialize them beforehand.
-            celes::Country::germany(),
-            celes::Country::the_united_states_of_america(),
-            celes::Country::the_netherlands(),
-        ]
-        .into_iter()
-        .map(|c| Geolocation::Alpha2(c.alpha2.to_string()))
-        // Add the Unknown and Error geolocations.
-        .chain([Geolocation::Unknown, Geolocation::Error].into_iter())
-        // Map to MetricsKeys for both overlay origin types.
-        .map(|g| {
-            [
-                MetricsKey {
-                    geo_origin: g.clone(),
-                    overlay_origin: PublicGatewayStatus::NonGateway,
-                },
-                MetricsKey {
-                    geo_origin: g,
-                    overlay_origin: PublicGatewayStatus::Gateway,
-                },
-            ]
-        })
-        .flatten()
-        // Create metrics.
-        .map(|k| {
-            let metrics =
-                Self::new_for_key(monitor_name, &k).expect("invalid metric initialization");
-            (k, metrics)
-        })
-        .collect()
+    /// The countries must be valid ISO 3166-1 two-letter codes.
+    pub(crate) fn create_basic_set<'a, I>(
+        monitor_name: &str,
+        countries: I,
+    ) -> HashMap<MetricsKey, Metrics>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        countries
+            .into_iter()
+            .map(|c| Geolocation::Alpha2(c.clone()))
+            // Add the Unknown, Error, and Other geolocations.
+            .chain([Geolocation::Unknown, Geolocation::Error, Geolocation::Other])
+            // Map to MetricsKeys for both overlay origin types.
+            .map(|g| {
+                [
+                    MetricsKey {
+                        geo_origin: g.clone(),
+                        overlay_origin: PublicGatewayStatus::NonGateway,
+                    },
+                    MetricsKey {
+                        geo_origin: g,
+                        overlay_origin: PublicGatewayStatus::Gateway,
+                    },
+                ]
+            })
+            .flatten()
+            // Create metrics.
+            .map(|k| {
+                let metrics =
+                    Self::new_for_key(monitor_name, &k).expect("invalid metric initialization");
+                (k, metrics)
+            })
+            .collect()
     }
 
     /// Creates a new set of metrics for the given metrics key, encoding geolocation and gateway
@@ -193,6 +274,11 @@ impl Metrics {
                 COUNTRY_NAME_ERROR,
                 key.overlay_origin,
             )),
+            Geolocation::Other => Ok(Self::new_for_country_name_and_gateway_status(
+                monitor_name,
+                COUNTRY_NAME_OTHER,
+                key.overlay_origin,
+            )),
         }
     }
 
@@ -0,0 +1,520 @@
+use crate::api;
+use crate::prom::{Geolocation, PublicGatewayStatus};
+use crate::Result;
+use failure::{err_msg, ResultExt};
+use hyper::body::Bytes;
+use hyper::{Body, Request, Response, StatusCode};
+use ipfs_monitoring_plugin_client::monitoring::{
+    BitswapMessage, BlockPresenceType, ConnectionEventType, EventType, PushedEvent,
+};
+use ipfs_resolver_common::wantlist::{JSONWantType, JSONWantlistEntry};
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// The number of events buffered per subscriber.
+/// Subscribers that fall further behind than this miss events.
+const TAIL_CHANNEL_CAPACITY: usize = 4096;
+
+/// The interval in which keepalive comments are sent to subscribers.
+/// This also serves to detect subscribers that went away while no events matched their filter.
+const TAIL_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// An event as published to live tail subscribers, annotated with its origin.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct TailEvent {
+    /// The name of the monitor this event was received from.
+    pub(crate) monitor: String,
+
+    /// ISO 3166-1 two-letter code of the origin country, or one of the special
+    /// `Unknown` or `Error` countries.
+    pub(crate) origin_country: String,
+
+    /// Whether the origin peer is a known public gateway.
+    pub(crate) origin_is_gateway: bool,
+
+    #[serde(flatten)]
+    pub(crate) event: PushedEvent,
+}
+
+/// A handle to publish events to live tail subscribers.
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+pub(crate) struct EventTail {
+    sender: broadcast::Sender<Arc<TailEvent>>,
+}
+
+impl EventTail {
+    pub(crate) fn new() -> EventTail {
+        let (sender, _) = broadcast::channel(TAIL_CHANNEL_CAPACITY);
+        EventTail { sender }
+    }
+
+    /// Publishes an event to all current subscribers.
+    /// If there are no subscribers, this does nothing, in particular it does not clone the event.
+    pub(crate) fn publish(
+        &self,
+        monitor_name: &str,
+        event: &PushedEvent,
+        geolocation: &Geolocation,
+        gateway_status: PublicGatewayStatus,
+    ) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+
+        let tail_event = TailEvent {
+            monitor: monitor_name.to_string(),
+            origin_country: geolocation.short_name().to_string(),
+            origin_is_gateway: gateway_status == PublicGatewayStatus::Gateway,
+            event: event.clone(),
+        };
+
+        // This only fails if all subscribers went away in the meantime, which is fine.
+        let _ = self.sender.send(Arc::new(tail_event));
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<TailEvent>> {
+        self.sender.subscribe()
+    }
+}
+
+/// Types of entries that can be selected via the `entry_type` filter.
+/// The names match the label values used for metrics.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TailEntryType {
+    WantBlock,
+    WantHave,
+    Cancel,
+    Block,
+    Have,
+    DontHave,
+    Connected,
+    Disconnected,
+}
+
+impl FromStr for TailEntryType {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "want_block" => Ok(TailEntryType::WantBlock),
+            "want_have" => Ok(TailEntryType::WantHave),
+            "cancel" => Ok(TailEntryType::Cancel),
+            "block" => Ok(TailEntryType::Block),
+            "have" => Ok(TailEntryType::Have),
+            "dont_have" => Ok(TailEntryType::DontHave),
+            "connected" => Ok(TailEntryType::Connected),
+            "disconnected" => Ok(TailEntryType::Disconnected),
+            _ => Err(err_msg(format!("invalid entry type {}", s))),
+        }
+    }
+}
+
+impl TailEntryType {
+    fn from_wantlist_entry(entry: &JSONWantlistEntry) -> TailEntryType {
+        if entry.cancel {
+            TailEntryType::Cancel
+        } else {
+            match entry.want_type {
+                JSONWantType::Block => TailEntryType::WantBlock,
+                JSONWantType::Have => TailEntryType::WantHave,
+            }
+        }
+    }
+}
+
+/// A filter for live tail events, built from the query parameters of a subscription request.
+///
+/// Every parameter may be given multiple times or as a comma-separated list, in which case
+/// events matching any of the values are selected.
+/// Different parameters are combined, i.e., an event must match all of them.
+#[derive(Clone, Debug, Default)]
+struct TailFilter {
+    monitors: Option<Vec<String>>,
+    peers: Option<Vec<String>>,
+    cid_prefixes: Option<Vec<String>>,
+    entry_types: Option<Vec<TailEntryType>>,
+    countries: Option<Vec<String>>,
+    gateway: Option<bool>,
+}
+
+impl TailFilter {
+    fn from_query(query: Option<&str>) -> Result<TailFilter> {
+        let mut filter = TailFilter::default();
+        let query = match query {
+            Some(query) => query,
+            None => return Ok(filter),
+        };
+
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            let values = value
+                .split(',')
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string());
+            match key.as_ref() {
+                "monitor" => filter.monitors.get_or_insert_with(Vec::new).extend(values),
+                "peer" => filter.peers.get_or_insert_with(Vec::new).extend(values),
+                "cid_prefix" => filter
+                    .cid_prefixes
+                    .get_or_insert_with(Vec::new)
+                    .extend(values),
+                "country" => filter.countries.get_or_insert_with(Vec::new).extend(values),
+                "entry_type" => {
+                    let entry_types = values
+                        .map(|v| v.parse())
+                        .collect::<Result<Vec<TailEntryType>>>()?;
+                    filter
+                        .entry_types
+                        .get_or_insert_with(Vec::new)
+                        .extend(entry_types)
+                }
+                "gateway" => {
+                    filter.gateway = Some(
+                        value
+                            .parse::<bool>()
+                            .context(format!("invalid gateway flag {}", value))?,
+                    )
+                }
+                _ => return Err(err_msg(format!("unknown filter parameter {}", key))),
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// Applies the filter to an event.
+    /// Returns `None` if the event does not match, or a copy of the event reduced to the
+    /// matching entries otherwise.
+    fn apply(&self, event: &TailEvent) -> Option<TailEvent> {
+        if !Self::matches_any(&self.monitors, |m| *m == event.monitor)
+            || !Self::matches_any(&self.peers, |p| *p == event.event.peer)
+            || !Self::matches_any(&self.countries, |c| {
+                c.eq_ignore_ascii_case(&event.origin_country)
+            })
+            || self.gateway.is_some_and(|g| g != event.origin_is_gateway)
+        {
+            return None;
+        }
+
+        match &event.event.inner {
+            EventType::ConnectionEvent(conn_event) => {
+                // Connection events don't carry CIDs, so they never match a CID filter.
+                if self.cid_prefixes.is_some() {
+                    return None;
+                }
+                let entry_type = match conn_event.connection_event_type {
+                    ConnectionEventType::Connected => TailEntryType::Connected,
+                    ConnectionEventType::Disconnected => TailEntryType::Disconnected,
+                };
+                if !Self::matches_any(&self.entry_types, |t| *t == entry_type) {
+                    return None;
+                }
+
+                Some(event.clone())
+            }
+            EventType::BitswapMessage(msg) => {
+                if self.cid_prefixes.is_none() && self.entry_types.is_none() {
+                    return Some(event.clone());
+                }
+
+                let wantlist_entries: Vec<_> = msg
+                    .wantlist_entries
+                    .iter()
+                    .filter(|e| {
+                        self.matches_entry(TailEntryType::from_wantlist_entry(e), &e.cid.path)
+                    })
+                    .cloned()
+                    .collect();
+                let blocks: Vec<_> = msg
+                    .blocks
+                    .iter()
+                    .filter(|c| self.matches_entry(TailEntryType::Block, &c.path))
+                    .cloned()
+                    .collect();
+                let block_presences: Vec<_> = msg
+                    .block_presences
+                    .iter()
+                    .filter(|p| {
+                        let entry_type = match p.block_presence_type {
+                            BlockPresenceType::Have => TailEntryType::Have,
+                            BlockPresenceType::DontHave => TailEntryType::DontHave,
+                        };
+                        self.matches_entry(entry_type, &p.cid.path)
+                    })
+                    .cloned()
+                    .collect();
+
+                if wantlist_entries.is_empty() && blocks.is_empty() && block_presences.is_empty() {
+                    return None;
+                }
+
+                let mut filtered = event.clone();
+                filtered.event.inner = EventType::BitswapMessage(BitswapMessage {
+                    wantlist_entries,
+                    full_wantlist: msg.full_wantlist,
+                    blocks,
+                    block_presences,
+                    connected_addresses: msg.connected_addresses.clone(),
+                });
+                Some(filtered)
+            }
+        }
+    }
+
+    fn matches_entry(&self, entry_type: TailEntryType, cid: &str) -> bool {
+        Self::matches_any(&self.entry_types, |t| *t == entry_type)
+            && Self::matches_any(&self.cid_prefixes, |p| cid.starts_with(p.as_str()))
+    }
+
+    /// Checks whether any of the given filter values matches, or the filter is not set.
+    fn matches_any<T, F>(values: &Option<Vec<T>>, f: F) -> bool
+    where
+        F: Fn(&T) -> bool,
+    {
+        values.as_ref().is_none_or(|values| values.iter().any(f))
+    }
+}
+
+/// Handles a request to subscribe to the live event tail.
+///
+/// Events are streamed as server-sent events, with one JSON-encoded event per message.
+pub(crate) fn handle_subscription(req: &Request<Body>, tail: &EventTail) -> Response<Body> {
+    let filter = match TailFilter::from_query(req.uri().query()) {
+        Ok(filter) => filter,
+        Err(err) => {
+            debug!("rejecting live tail subscription: {}", err);
+            return api::plain_response(StatusCode::BAD_REQUEST, format!("{}\n", err));
+        }
+    };
+    debug!("new live tail subscription with filter {:?}", filter);
+
+    let (sender, body) = Body::channel();
+    tokio::spawn(stream_events(tail.subscribe(), sender, filter));
+
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+/// Forwards matching events to a subscriber until the subscriber goes away.
+async fn stream_events(
+    mut receiver: broadcast::Receiver<Arc<TailEvent>>,
+    mut sender: hyper::body::Sender,
+    filter: TailFilter,
+) {
+    let mut keepalive = tokio::time::interval(TAIL_KEEPALIVE_INTERVAL);
+
+    loop {
+        let chunk = tokio::select! {
+            res = receiver.recv() => match res {
+                Ok(event) => match filter.apply(&event) {
+                    Some(event) => match serde_json::to_string(&event) {
+                        Ok(json) => format!("data: {}\n\n", json),
+                        Err(err) => {
+                            error!("unable to encode event {:?}: {:?}", event, err);
+                            continue;
+                        }
+                    },
+                    None => continue,
+                },
+                Err(RecvError::Lagged(n)) => {
+                    warn!("live tail subscriber lagging behind, skipped {} events", n);
+                    format!(": skipped {} events\n\n", n)
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+        };
+
+        if sender.send_data(Bytes::from(chunk)).await.is_err() {
+            break;
+        }
+    }
+
+    debug!("live tail subscriber went away");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipfs_monitoring_plugin_client::monitoring::{BlockPresence, ConnectionEvent};
+    use ipfs_resolver_common::wantlist::JsonCID;
+
+    fn cid(path: &str) -> JsonCID {
+        JsonCID {
+            path: path.to_string(),
+        }
+    }
+
+    fn want(path: &str, want_type: JSONWantType, cancel: bool) -> JSONWantlistEntry {
+        JSONWantlistEntry {
+            priority: 1,
+            cancel,
+            send_dont_have: false,
+            cid: cid(path),
+            want_type,
+        }
+    }
+
+    fn event(peer: &str, inner: EventType) -> TailEvent {
+        TailEvent {
+            monitor: "de1".to_string(),
+            origin_country: "DE".to_string(),
+            origin_is_gateway: false,
+            event: PushedEvent {
+                timestamp: chrono::Utc::now(),
+                peer: peer.to_string(),
+                inner,
+            },
+        }
+    }
+
+    fn bitswap_message(peer: &str) -> TailEvent {
+        event(
+            peer,
+            EventType::BitswapMessage(BitswapMessage {
+                wantlist_entries: vec![
+                    want("QmA", JSONWantType::Block, false),
+                    want("QmB", JSONWantType::Have, false),
+                    want("bafyC", JSONWantType::Block, true),
+                ],
+                full_wantlist: false,
+                blocks: vec![cid("QmD")],
+                block_presences: vec![BlockPresence {
+                    cid: cid("bafyE"),
+                    block_presence_type: BlockPresenceType::DontHave,
+                }],
+                connected_addresses: vec![],
+            }),
+        )
+    }
+
+    fn connection_event(peer: &str) -> TailEvent {
+        event(
+            peer,
+            EventType::ConnectionEvent(ConnectionEvent {
+                remote: "/ip4/1.2.3.4/tcp/4001".to_string(),
+                connection_event_type: ConnectionEventType::Connected,
+            }),
+        )
+    }
+
+    fn wantlist_cids(event: &TailEvent) -> Vec<String> {
+        match &event.event.inner {
+            EventType::BitswapMessage(msg) => msg
+                .wantlist_entries
+                .iter()
+                .map(|e| e.cid.path.clone())
+                .collect(),
+            EventType::ConnectionEvent(_) => panic!("expected bitswap message"),
+        }
+    }
+
+    #[test]
+    fn parse_empty_query() {
+        let filter = TailFilter::from_query(None).unwrap();
+        assert!(filter.monitors.is_none());
+        assert!(filter.entry_types.is_none());
+        assert!(filter.gateway.is_none());
+    }
+
+    #[test]
+    fn parse_valid_query() {
+        let filter = TailFilter::from_query(Some(
+            "monitor=de1,us1&monitor=%20nl1&entry_type=want_block,cancel&gateway=true&cid_prefix=Qm",
+        ))
+        .unwrap();
+        assert_eq!(
+            filter.monitors,
+            Some(vec![
+                "de1".to_string(),
+                "us1".to_string(),
+                "nl1".to_string()
+            ])
+        );
+        assert_eq!(
+            filter.entry_types,
+            Some(vec![TailEntryType::WantBlock, TailEntryType::Cancel])
+        );
+        assert_eq!(filter.cid_prefixes, Some(vec!["Qm".to_string()]));
+        assert_eq!(filter.gateway, Some(true));
+    }
+
+    #[test]
+    fn parse_invalid_query() {
+        assert!(TailFilter::from_query(Some("entry_type=want_everything")).is_err());
+        assert!(TailFilter::from_query(Some("gateway=maybe")).is_err());
+        assert!(TailFilter::from_query(Some("color=red")).is_err());
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = TailFilter::default();
+        let event = bitswap_message("peer1");
+        let filtered = filter.apply(&event).unwrap();
+        assert_eq!(wantlist_cids(&filtered), vec!["QmA", "QmB", "bafyC"]);
+        assert!(filter.apply(&connection_event("peer1")).is_some());
+    }
+
+    #[test]
+    fn filter_by_event_attributes() {
+        let event = bitswap_message("peer1");
+        let filter = TailFilter::from_query(Some("peer=peer2,peer1&country=de")).unwrap();
+        assert!(filter.apply(&event).is_some());
+
+        let filter = TailFilter::from_query(Some("peer=peer2")).unwrap();
+        assert!(filter.apply(&event).is_none());
+
+        let filter = TailFilter::from_query(Some("monitor=us1")).unwrap();
+        assert!(filter.apply(&event).is_none());
+
+        let filter = TailFilter::from_query(Some("gateway=true")).unwrap();
+        assert!(filter.apply(&event).is_none());
+    }
+
+    #[test]
+    fn filter_reduces_entries() {
+        let event = bitswap_message("peer1");
+
+        let filter = TailFilter::from_query(Some("cid_prefix=Qm")).unwrap();
+        let filtered = filter.apply(&event).unwrap();
+        assert_eq!(wantlist_cids(&filtered), vec!["QmA", "QmB"]);
+        match &filtered.event.inner {
+            EventType::BitswapMessage(msg) => {
+                assert_eq!(msg.blocks.len(), 1);
+                assert!(msg.block_presences.is_empty());
+            }
+            EventType::ConnectionEvent(_) => panic!("expected bitswap message"),
+        }
+
+        let filter = TailFilter::from_query(Some("entry_type=cancel,dont_have")).unwrap();
+        let filtered = filter.apply(&event).unwrap();
+        assert_eq!(wantlist_cids(&filtered), vec!["bafyC"]);
+
+        // Nothing left after filtering.
+        let filter = TailFilter::from_query(Some("entry_type=want_have&cid_prefix=bafy")).unwrap();
+        assert!(filter.apply(&event).is_none());
+    }
+
+    #[test]
+    fn filter_connection_events() {
+        let event = connection_event("peer1");
+
+        let filter = TailFilter::from_query(Some("entry_type=connected")).unwrap();
+        assert!(filter.apply(&event).is_some());
+
+        let filter = TailFilter::from_query(Some("entry_type=disconnected,want_block")).unwrap();
+        assert!(filter.apply(&event).is_none());
+
+        // Connection events carry no CIDs.
+        let filter = TailFilter::from_query(Some("cid_prefix=Qm")).unwrap();
+        assert!(filter.apply(&event).is_none());
+    }
+}
@@ -0,0 +1,285 @@
+//! Pseudonymization of peer IDs and anonymization of addresses, for publishing datasets.
+//!
+//! Peer IDs are replaced by a keyed hash (HMAC-SHA256), which is consistent across files, monitors,
+//! and runs, as long as the same key is used.
+//! The key is read from a file, so that it can be managed separately from configs and datasets.
+
+use crate::Result;
+use failure::{err_msg, ResultExt};
+use hmac::{Hmac, Mac};
+use parity_multiaddr::{Multiaddr, Protocol};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// The minimum length of the key, in bytes.
+const MIN_KEY_LENGTH: usize = 16;
+
+/// The number of bytes of the hash used for pseudonyms.
+const PSEUDONYM_LENGTH: usize = 16;
+
+/// Configuration for anonymization.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnonymizationConfig {
+    /// Path to a file containing the key for pseudonymizing peer IDs.
+    /// The key is used as raw bytes, except for leading and trailing ASCII whitespace, which is
+    /// ignored.
+    /// The key must be at least 16 bytes long.
+    pub key_file: String,
+
+    /// How to anonymize addresses.
+    #[serde(default)]
+    pub address_mode: AddressMode,
+
+    /// The directory containing the MaxMind GeoLite2 Country and ASN databases.
+    /// Only required for the `country_asn` address mode.
+    #[serde(default)]
+    pub geoip_database_path: Option<String>,
+}
+
+/// Ways to anonymize addresses.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressMode {
+    /// Truncate IP addresses to their /24 (IPv4) or /48 (IPv6) prefix.
+    /// Everything except the IP is removed from the multiaddress, e.g., `/ip4/1.2.3.4/tcp/4001`
+    /// becomes `/ip4/1.2.3.0`.
+    /// Addresses without an IP are removed.
+    #[default]
+    Truncate,
+
+    /// Replace addresses with the country and autonomous system of their IP, e.g., `DE/AS3320`.
+    /// Unknown parts are replaced with `unknown`.
+    CountryAsn,
+}
+
+/// Records that contain peer IDs or addresses.
+pub trait Anonymize {
+    /// Replaces all peer IDs and addresses in the record.
+    fn anonymize(&mut self, anonymizer: &Anonymizer);
+}
+
+/// Returns an anonymized copy of the record if an anonymizer is given, or the record itself
+/// otherwise.
+pub fn anonymized<'a, T: Anonymize + Clone>(
+    record: &'a T,
+    anonymizer: Option<&Anonymizer>,
+) -> Cow<'a, T> {
+    match anonymizer {
+        Some(anonymizer) => {
+            let mut record = record.clone();
+            record.anonymize(anonymizer);
+            Cow::Owned(record)
+        }
+        None => Cow::Borrowed(record),
+    }
+}
+
+/// Anonymizes peer IDs and addresses.
+pub struct Anonymizer {
+    key: Vec<u8>,
+    address_mode: AddressMode,
+    geoip: Option<GeoIpDatabases>,
+}
+
+struct GeoIpDatabases {
+    country: maxminddb::Reader<Vec<u8>>,
+    asn: maxminddb::Reader<Vec<u8>>,
+}
+
+impl Anonymizer {
+    pub fn new(cfg: &AnonymizationConfig) -> Result<Anonymizer> {
+        let key = std::fs::read(&cfg.key_file).context("unable to read key file")?;
+        let key = key.trim_ascii().to_vec();
+        if key.len() < MIN_KEY_LENGTH {
+            return Err(err_msg(format!(
+                "key must be at least {} bytes long",
+                MIN_KEY_LENGTH
+            )));
+        }
+
+        let geoip = match cfg.address_mode {
+            AddressMode::Truncate => None,
+            AddressMode::CountryAsn => {
+                let path = Path::new(cfg.geoip_database_path.as_ref().ok_or_else(|| {
+                    err_msg("geoip_database_path is required for address mode country_asn")
+                })?);
+                Some(GeoIpDatabases {
+                    country: maxminddb::Reader::open_readfile(path.join("GeoLite2-Country.mmdb"))
+                        .context("unable to open GeoLite2 Country database")?,
+                    asn: maxminddb::Reader::open_readfile(path.join("GeoLite2-ASN.mmdb"))
+                        .context("unable to open GeoLite2 ASN database")?,
+                })
+            }
+        };
+
+        Ok(Anonymizer {
+            key,
+            address_mode: cfg.address_mode,
+            geoip,
+        })
+    }
+
+    /// Computes the pseudonym for a peer ID.
+    pub fn pseudonymize_peer(&self, peer_id: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key size");
+        mac.update(peer_id.as_bytes());
+        let hash = mac.finalize().into_bytes();
+        hex::encode(&hash[..PSEUDONYM_LENGTH])
+    }
+
+    /// Anonymizes an address, which is usually a multiaddress.
+    /// Empty addresses stay empty.
+    pub fn anonymize_address(&self, address: &str) -> String {
+        if address.is_empty() {
+            return String::new();
+        }
+        let ip = address
+            .parse::<Multiaddr>()
+            .ok()
+            .and_then(|ma| match ma.iter().next() {
+                Some(Protocol::Ip4(ip)) => Some(IpAddr::V4(ip)),
+                Some(Protocol::Ip6(ip)) => Some(IpAddr::V6(ip)),
+                _ => None,
+            });
+
+        match self.address_mode {
+            AddressMode::Truncate => match ip {
+                Some(IpAddr::V4(ip)) => {
+                    let o = ip.octets();
+                    Multiaddr::from(Protocol::Ip4(Ipv4Addr::new(o[0], o[1], o[2], 0))).to_string()
+                }
+                Some(IpAddr::V6(ip)) => {
+                    let s = ip.segments();
+                    Multiaddr::from(Protocol::Ip6(Ipv6Addr::new(
+                        s[0], s[1], s[2], 0, 0, 0, 0, 0,
+                    )))
+                    .to_string()
+                }
+                None => String::new(),
+            },
+            AddressMode::CountryAsn => {
+                let (country, asn) = match (ip, self.geoip.as_ref()) {
+                    (Some(ip), Some(geoip)) => (
+                        geoip
+                            .country
+                            .lookup::<maxminddb::geoip2::Country>(ip)
+                            .ok()
+                            .and_then(|c| c.country)
+                            .and_then(|c| c.iso_code)
+                            .map(|c| c.to_string()),
+                        geoip
+                            .asn
+                            .lookup::<maxminddb::geoip2::Asn>(ip)
+                            .ok()
+                            .and_then(|a| a.autonomous_system_number)
+                            .map(|a| format!("AS{}", a)),
+                    ),
+                    _ => (None, None),
+                };
+                format!(
+                    "{}/{}",
+                    country.as_deref().unwrap_or("unknown"),
+                    asn.as_deref().unwrap_or("unknown")
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anonymizer(key: &str) -> Anonymizer {
+        Anonymizer {
+            key: key.as_bytes().to_vec(),
+            address_mode: AddressMode::Truncate,
+            geoip: None,
+        }
+    }
+
+    #[test]
+    fn pseudonyms_are_deterministic() {
+        let a = anonymizer("0123456789abcdef");
+        let p = a.pseudonymize_peer("QmPeer1");
+        assert_eq!(p.len(), 2 * PSEUDONYM_LENGTH);
+        assert!(p.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(p, "QmPeer1");
+
+        // Same key, same pseudonym.
+        assert_eq!(p, a.pseudonymize_peer("QmPeer1"));
+        assert_eq!(
+            p,
+            anonymizer("0123456789abcdef").pseudonymize_peer("QmPeer1")
+        );
+
+        assert_ne!(p, a.pseudonymize_peer("QmPeer2"));
+        assert_ne!(
+            p,
+            anonymizer("fedcba9876543210").pseudonymize_peer("QmPeer1")
+        );
+    }
+
+    #[test]
+    fn key_file() {
+        let path = std::env::temp_dir().join(format!("anonymize-key-{}", std::process::id()));
+        let cfg = AnonymizationConfig {
+            key_file: path.display().to_string(),
+            address_mode: AddressMode::Truncate,
+            geoip_database_path: None,
+        };
+
+        // Surrounding whitespace is not part of the key.
+        std::fs::write(&path, "  0123456789abcdef\n").unwrap();
+        let a = Anonymizer::new(&cfg).unwrap();
+        assert_eq!(
+            a.pseudonymize_peer("QmPeer1"),
+            anonymizer("0123456789abcdef").pseudonymize_peer("QmPeer1")
+        );
+
+        std::fs::write(&path, "0123456789abcde\n").unwrap();
+        assert!(Anonymizer::new(&cfg).is_err());
+
+        // Keys do not need to be valid UTF-8, and all of their bytes count.
+        std::fs::write(&path, [0xff; 16]).unwrap();
+        let p = Anonymizer::new(&cfg).unwrap().pseudonymize_peer("QmPeer1");
+        std::fs::write(&path, [0xfe; 16]).unwrap();
+        assert_ne!(
+            p,
+            Anonymizer::new(&cfg).unwrap().pseudonymize_peer("QmPeer1")
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncate_addresses() {
+        let a = anonymizer("0123456789abcdef");
+        assert_eq!(a.anonymize_address("/ip4/1.2.3.4/tcp/4001"), "/ip4/1.2.3.0");
+        assert_eq!(a.anonymize_address("/ip4/1.2.3.255"), "/ip4/1.2.3.0");
+        assert_eq!(
+            a.anonymize_address("/ip6/2001:db8:1234:5678:9abc::1/udp/4001/quic"),
+            "/ip6/2001:db8:1234::"
+        );
+        assert_eq!(a.anonymize_address(""), "");
+        // Addresses without an IP are removed.
+        assert_eq!(a.anonymize_address("/dns4/example.com/tcp/4001"), "");
+        assert_eq!(a.anonymize_address("not an address"), "");
+    }
+
+    #[test]
+    fn country_asn_without_databases() {
+        let a = Anonymizer {
+            address_mode: AddressMode::CountryAsn,
+            ..anonymizer("0123456789abcdef")
+        };
+        assert_eq!(
+            a.anonymize_address("/ip4/1.2.3.4/tcp/4001"),
+            "unknown/unknown"
+        );
+        assert_eq!(a.anonymize_address(""), "");
+    }
+}
@@ -6,7 +6,10 @@ extern crate failure;
 use failure::{Error, ResultExt};
 use std::path::PathBuf;
 
+pub mod anonymize;
 pub mod logging;
+pub mod output;
+pub mod rotation;
 pub mod wantlist;
 
 pub type Result<T> = std::result::Result<T, Error>;
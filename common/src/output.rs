@@ -0,0 +1,484 @@
+//! Output sinks for records produced by the trace processing tools.
+//!
+//! All sinks implement [`RecordSink`], so tools can write records without caring about the
+//! format.
+//! Sinks must be [finished](RecordSink::finish) explicitly, dropping them may lose data.
+
+use crate::Result;
+use failure::ResultExt;
+use flate2::write::GzEncoder;
+use parquet::basic::Compression;
+use parquet::data_type::{
+    BoolType, ByteArray, ByteArrayType, DataType, DoubleType, Int32Type, Int64Type,
+};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use serde::de::{Error as _, SeqAccess};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of records buffered per Parquet row group.
+pub const PARQUET_ROW_GROUP_SIZE: usize = 100_000;
+
+/// Supported output formats.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// Gzipped CSV with a header row.
+    #[default]
+    Csv,
+
+    /// Parquet with typed columns and Snappy compression.
+    /// Strings such as peer IDs and CIDs are dictionary-encoded.
+    Parquet,
+}
+
+impl OutputFormat {
+    /// The file extension of the format, including the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => ".csv.gz",
+            OutputFormat::Parquet => ".parquet",
+        }
+    }
+
+    /// Adjusts the extension of the given path to the format.
+    /// Extensions of the other format (and `.csv`) are replaced, other paths are returned as-is.
+    /// This allows switching formats without changing all output paths.
+    pub fn output_path(&self, path: &str) -> String {
+        for ext in [".csv.gz", ".csv", ".parquet"] {
+            if let Some(stem) = path.strip_suffix(ext) {
+                if ext == ".csv" && *self == OutputFormat::Csv {
+                    // CSV output is always gzipped, but we keep paths that don't say so.
+                    return path.to_string();
+                }
+                return format!("{}{}", stem, self.extension());
+            }
+        }
+        path.to_string()
+    }
+}
+
+/// A sink for records of one type.
+pub trait RecordSink<T> {
+    /// Writes a single record.
+    fn write(&mut self, record: &T) -> Result<()>;
+
+    /// Returns the number of bytes written to the file so far.
+    /// Records buffered in memory or in the compressor are not included.
+    fn bytes_written(&self) -> u64;
+
+    /// Flushes all buffered records and closes the sink.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Creates a sink for the given format, writing to a new file at the given path.
+/// The extension of the path is adjusted to the format, see [`OutputFormat::output_path`].
+pub fn create_sink<T>(path: &str, format: OutputFormat) -> Result<Box<dyn RecordSink<T>>>
+where
+    T: Serialize + ParquetRecord + Clone + 'static,
+{
+    let path = format.output_path(path);
+    let sink: Box<dyn RecordSink<T>> = match format {
+        OutputFormat::Csv => Box::new(CsvSink::create(&path)?),
+        OutputFormat::Parquet => Box::new(ParquetSink::create(&path)?),
+    };
+
+    Ok(sink)
+}
+
+/// A writer that counts the bytes written through it.
+/// The counter is shared, so that it can be read while the writer is owned by an encoder.
+struct CountingWriter<W> {
+    inner: W,
+    bytes_written: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A sink producing gzipped CSV.
+pub struct CsvSink<T> {
+    writer: csv::Writer<GzEncoder<CountingWriter<BufWriter<File>>>>,
+    bytes_written: Arc<AtomicU64>,
+    _record: PhantomData<T>,
+}
+
+impl<T> CsvSink<T> {
+    pub fn create(path: &str) -> Result<CsvSink<T>> {
+        let f = File::create(path).context(format!("unable to create file {}", path))?;
+        let bytes_written = Arc::new(AtomicU64::new(0));
+        Ok(CsvSink {
+            writer: csv::Writer::from_writer(GzEncoder::new(
+                CountingWriter {
+                    inner: BufWriter::new(f),
+                    bytes_written: bytes_written.clone(),
+                },
+                flate2::Compression::default(),
+            )),
+            bytes_written,
+            _record: PhantomData,
+        })
+    }
+}
+
+impl<T: Serialize> RecordSink<T> for CsvSink<T> {
+    fn write(&mut self, record: &T) -> Result<()> {
+        self.writer.serialize(record)?;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let encoder = self
+            .writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .context("unable to flush CSV writer")?;
+        let mut w = encoder.finish().context("unable to finish gzip stream")?;
+        w.flush().context("unable to flush file")?;
+        Ok(())
+    }
+}
+
+/// A record type that can be written to Parquet.
+pub trait ParquetRecord: Sized {
+    /// The schema of the records, in Parquet message type syntax.
+    const PARQUET_SCHEMA: &'static str;
+
+    /// Writes the columns of the given records to a row group.
+    /// Columns must be written in the order in which they appear in the schema.
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()>;
+}
+
+/// A group of columns that is part of a flat record, such as the columns of a
+/// [`CSVWantlistEntry`](crate::wantlist::CSVWantlistEntry).
+///
+/// The `csv` crate does not support `#[serde(flatten)]`, so records composed of multiple groups
+/// (de)serialize their groups one after another, using these methods.
+pub trait ColumnGroup: Sized {
+    /// The number of columns in the group.
+    const NUM_COLUMNS: usize;
+
+    /// Serializes the columns as fields of a struct.
+    fn serialize_columns<S: SerializeStruct>(&self, s: &mut S)
+        -> std::result::Result<(), S::Error>;
+
+    /// Deserializes the columns, in order, from a sequence of fields.
+    fn deserialize_columns<'de, A: SeqAccess<'de>>(
+        seq: &mut A,
+    ) -> std::result::Result<Self, A::Error>;
+
+    /// Writes the columns of the given records to a row group, in order.
+    fn write_parquet_column_group<'a, I>(records: I, w: &mut ParquetColumnWriter) -> Result<()>
+    where
+        I: Iterator<Item = &'a Self> + Clone,
+        Self: 'a;
+}
+
+/// Deserializes the next column of a [`ColumnGroup`] from a sequence of fields.
+pub fn next_column<'de, A, T>(seq: &mut A, name: &'static str) -> std::result::Result<T, A::Error>
+where
+    A: SeqAccess<'de>,
+    T: Deserialize<'de>,
+{
+    seq.next_element()?
+        .ok_or_else(|| A::Error::custom(format!("missing column {}", name)))
+}
+
+/// Writes the columns of one row group, in order.
+pub struct ParquetColumnWriter<'a, 'b> {
+    row_group: &'a mut SerializedRowGroupWriter<'b, BufWriter<File>>,
+}
+
+impl<'a, 'b> ParquetColumnWriter<'a, 'b> {
+    fn write_column<D: DataType>(
+        &mut self,
+        values: &[D::T],
+        def_levels: Option<&[i16]>,
+    ) -> Result<()> {
+        let mut col = self
+            .row_group
+            .next_column()?
+            .ok_or_else(|| failure::err_msg("more columns written than defined in schema"))?;
+        col.typed::<D>().write_batch(values, def_levels, None)?;
+        col.close()?;
+        Ok(())
+    }
+
+    fn write_optional_column<D: DataType, I: IntoIterator<Item = Option<D::T>>>(
+        &mut self,
+        values: I,
+    ) -> Result<()> {
+        let mut present = Vec::new();
+        let mut def_levels = Vec::new();
+        for v in values {
+            match v {
+                Some(v) => {
+                    present.push(v);
+                    def_levels.push(1)
+                }
+                None => def_levels.push(0),
+            }
+        }
+        self.write_column::<D>(&present, Some(&def_levels))
+    }
+
+    /// Writes a required `INT32` column.
+    pub fn write_i32<I: IntoIterator<Item = i32>>(&mut self, values: I) -> Result<()> {
+        let values: Vec<_> = values.into_iter().collect();
+        self.write_column::<Int32Type>(&values, None)
+    }
+
+    /// Writes a required `INT32 (UINT_32)` column.
+    pub fn write_u32<I: IntoIterator<Item = u32>>(&mut self, values: I) -> Result<()> {
+        // Parquet stores unsigned integers in signed physical types, reinterpreting the bits.
+        self.write_i32(values.into_iter().map(|v| v as i32))
+    }
+
+    /// Writes a required `INT64` column.
+    pub fn write_i64<I: IntoIterator<Item = i64>>(&mut self, values: I) -> Result<()> {
+        let values: Vec<_> = values.into_iter().collect();
+        self.write_column::<Int64Type>(&values, None)
+    }
+
+    /// Writes an optional `INT64` column.
+    pub fn write_optional_i64<I: IntoIterator<Item = Option<i64>>>(
+        &mut self,
+        values: I,
+    ) -> Result<()> {
+        self.write_optional_column::<Int64Type, _>(values)
+    }
+
+    /// Writes a required `INT64 (UINT_64)` column.
+    pub fn write_u64<I: IntoIterator<Item = u64>>(&mut self, values: I) -> Result<()> {
+        self.write_i64(values.into_iter().map(|v| v as i64))
+    }
+
+    /// Writes an optional `INT64 (UINT_64)` column.
+    pub fn write_optional_u64<I: IntoIterator<Item = Option<u64>>>(
+        &mut self,
+        values: I,
+    ) -> Result<()> {
+        self.write_optional_i64(values.into_iter().map(|v| v.map(|v| v as i64)))
+    }
+
+    /// Writes a required `DOUBLE` column.
+    pub fn write_f64<I: IntoIterator<Item = f64>>(&mut self, values: I) -> Result<()> {
+        let values: Vec<_> = values.into_iter().collect();
+        self.write_column::<DoubleType>(&values, None)
+    }
+
+    /// Writes a required `BOOLEAN` column.
+    pub fn write_bool<I: IntoIterator<Item = bool>>(&mut self, values: I) -> Result<()> {
+        let values: Vec<_> = values.into_iter().collect();
+        self.write_column::<BoolType>(&values, None)
+    }
+
+    /// Writes a required `BINARY (STRING)` column.
+    pub fn write_str<'s, I: IntoIterator<Item = &'s str>>(&mut self, values: I) -> Result<()> {
+        let values: Vec<_> = values.into_iter().map(ByteArray::from).collect();
+        self.write_column::<ByteArrayType>(&values, None)
+    }
+
+    /// Writes an optional `BINARY (STRING)` column.
+    pub fn write_optional_str<'s, I: IntoIterator<Item = Option<&'s str>>>(
+        &mut self,
+        values: I,
+    ) -> Result<()> {
+        self.write_optional_column::<ByteArrayType, _>(
+            values.into_iter().map(|v| v.map(ByteArray::from)),
+        )
+    }
+}
+
+/// A sink producing Parquet files.
+/// Records are buffered and written in row groups of [`PARQUET_ROW_GROUP_SIZE`] records.
+pub struct ParquetSink<T> {
+    writer: SerializedFileWriter<BufWriter<File>>,
+    buffer: Vec<T>,
+}
+
+impl<T: ParquetRecord> ParquetSink<T> {
+    pub fn create(path: &str) -> Result<ParquetSink<T>> {
+        let schema = Arc::new(parse_message_type(T::PARQUET_SCHEMA).context("invalid schema")?);
+        let props = Arc::new(
+            WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .set_dictionary_enabled(true)
+                .build(),
+        );
+        let f = File::create(path).context(format!("unable to create file {}", path))?;
+        let writer = SerializedFileWriter::new(BufWriter::new(f), schema, props)
+            .context("unable to create Parquet writer")?;
+
+        Ok(ParquetSink {
+            writer,
+            buffer: Vec::with_capacity(PARQUET_ROW_GROUP_SIZE),
+        })
+    }
+
+    fn flush_row_group(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let mut row_group = self.writer.next_row_group()?;
+        T::write_parquet_columns(
+            &self.buffer,
+            &mut ParquetColumnWriter {
+                row_group: &mut row_group,
+            },
+        )
+        .context("unable to write columns")?;
+        row_group.close()?;
+        self.buffer.clear();
+
+        Ok(())
+    }
+}
+
+impl<T: ParquetRecord + Clone> RecordSink<T> for ParquetSink<T> {
+    fn write(&mut self, record: &T) -> Result<()> {
+        self.buffer.push(record.clone());
+        if self.buffer.len() >= PARQUET_ROW_GROUP_SIZE {
+            self.flush_row_group()
+                .context("unable to write row group")?;
+        }
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.writer.bytes_written() as u64
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.flush_row_group()
+            .context("unable to write row group")?;
+        self.writer
+            .close()
+            .context("unable to close Parquet writer")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::{Field, RowAccessor};
+
+    #[derive(Clone, Debug, PartialEq, Serialize)]
+    struct TestRecord {
+        id: i64,
+        peer_id: String,
+        diff_ms: Option<u64>,
+        matched: bool,
+    }
+
+    impl ParquetRecord for TestRecord {
+        const PARQUET_SCHEMA: &'static str = "
+            message test_record {
+                REQUIRED INT64 id;
+                REQUIRED BINARY peer_id (UTF8);
+                OPTIONAL INT64 diff_ms (UINT_64);
+                REQUIRED BOOLEAN matched;
+            }";
+
+        fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+            w.write_i64(records.iter().map(|r| r.id))?;
+            w.write_str(records.iter().map(|r| r.peer_id.as_str()))?;
+            w.write_optional_u64(records.iter().map(|r| r.diff_ms))?;
+            w.write_bool(records.iter().map(|r| r.matched))?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_path_matches_format() {
+        assert_eq!(
+            OutputFormat::Parquet.output_path("tmp/wl-$id$.csv.gz"),
+            "tmp/wl-$id$.parquet"
+        );
+        assert_eq!(
+            OutputFormat::Parquet.output_path("conn.csv"),
+            "conn.parquet"
+        );
+        assert_eq!(OutputFormat::Csv.output_path("conn.parquet"), "conn.csv.gz");
+        assert_eq!(OutputFormat::Csv.output_path("conn.csv"), "conn.csv");
+        assert_eq!(OutputFormat::Csv.output_path("conn.csv.gz"), "conn.csv.gz");
+        assert_eq!(OutputFormat::Parquet.output_path("conn"), "conn");
+    }
+
+    #[test]
+    fn parquet_round_trip() {
+        let dir = std::env::temp_dir().join(format!("parquet-round-trip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("records.csv.gz");
+
+        // More than a row group, to check that row groups are split correctly.
+        let records: Vec<_> = (0..PARQUET_ROW_GROUP_SIZE as i64 + 10)
+            .map(|id| TestRecord {
+                id,
+                peer_id: format!("peer{}", id % 3),
+                diff_ms: if id % 2 == 0 {
+                    Some(id as u64 * 10)
+                } else {
+                    None
+                },
+                matched: id % 5 == 0,
+            })
+            .collect();
+
+        let mut sink =
+            create_sink::<TestRecord>(path.to_str().unwrap(), OutputFormat::Parquet).unwrap();
+        for r in records.iter() {
+            sink.write(r).unwrap();
+        }
+        sink.finish().unwrap();
+
+        // The extension is adjusted to the format.
+        assert!(!path.exists());
+        let reader = SerializedFileReader::try_from(dir.join("records.parquet").as_path()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+
+        let read: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap();
+                TestRecord {
+                    id: row.get_long(0).unwrap(),
+                    peer_id: row.get_string(1).unwrap().clone(),
+                    diff_ms: match row.get_column_iter().nth(2).unwrap().1 {
+                        Field::Null => None,
+                        Field::ULong(v) => Some(*v),
+                        f => panic!("unexpected field {:?}", f),
+                    },
+                    matched: row.get_bool(3).unwrap(),
+                }
+            })
+            .collect();
+        assert_eq!(read, records);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
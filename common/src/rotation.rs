@@ -0,0 +1,573 @@
+//! Rotation of output files, and a manifest describing what each file contains.
+//!
+//! Output file paths are produced from a pattern containing `$id$`, which is replaced by the ID of
+//! the last message before the file, i.e., the first file is numbered 0.
+//! Callers mark the end of each message with [`RotatingWriter::end_message`].
+//! Files are only rotated between messages, i.e., all records of one message end up in the same
+//! file.
+
+use crate::output::{create_sink, OutputFormat, ParquetRecord, RecordSink};
+use crate::Result;
+use failure::ResultExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+
+/// Configuration for rotating output files.
+/// A new file is started as soon as any of the limits is reached.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RotationConfig {
+    /// The maximum number of rows per file.
+    #[serde(default)]
+    pub max_rows: Option<u64>,
+
+    /// The maximum number of messages per file, including messages which did not produce any
+    /// rows.
+    #[serde(default)]
+    pub max_messages: Option<u64>,
+
+    /// The maximum size of a file, in bytes.
+    /// This is approximate, as compressors and Parquet row groups buffer data before it is
+    /// written.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+
+    /// The period of trace time, in seconds, covered by each file.
+    /// Periods are aligned to the Unix epoch and based on the timestamps of the rows, not on wall
+    /// clock time, e.g., a period of 3600 produces one file per hour of the trace.
+    #[serde(default)]
+    pub trace_period_secs: Option<u64>,
+
+    /// The path of a CSV file listing all output files, together with the range of message IDs
+    /// and timestamps they contain, their number of rows, and their SHA-256 checksum.
+    #[serde(default)]
+    pub manifest_file: Option<String>,
+}
+
+impl RotationConfig {
+    /// Returns whether none of the limits are set.
+    pub fn is_unbounded(&self) -> bool {
+        self.max_rows.is_none()
+            && self.max_messages.is_none()
+            && self.max_bytes.is_none()
+            && self.trace_period_secs.is_none()
+    }
+}
+
+/// Records that can be written to rotated outputs.
+pub trait ManifestRecord {
+    /// The ID of the message this record originates from.
+    fn message_id(&self) -> i64;
+
+    /// The timestamp of the record, as seconds and sub-second milliseconds since the Unix epoch.
+    fn timestamp(&self) -> (i64, u32);
+}
+
+/// An entry of the manifest, describing one output file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub file: String,
+    /// The smallest message ID in the file.
+    pub first_message_id: i64,
+    /// The largest message ID in the file.
+    pub last_message_id: i64,
+    /// The earliest timestamp in the file.
+    pub first_timestamp_seconds: i64,
+    pub first_timestamp_subsec_milliseconds: u32,
+    /// The latest timestamp in the file.
+    pub last_timestamp_seconds: i64,
+    pub last_timestamp_subsec_milliseconds: u32,
+    pub rows: u64,
+    /// The hex-encoded SHA-256 checksum of the file.
+    pub sha256: String,
+}
+
+/// The file currently being written to.
+struct CurrentFile<T> {
+    sink: Box<dyn RecordSink<T>>,
+    period: Option<i64>,
+    entry: ManifestEntry,
+}
+
+/// Writes records to a series of files, rotating them according to a [`RotationConfig`].
+/// Files are created lazily, so no empty files are produced.
+pub struct RotatingWriter<T> {
+    pattern: String,
+    format: OutputFormat,
+    cfg: RotationConfig,
+    current: Option<CurrentFile<T>>,
+    /// The ID of the last message ended, see `end_message`.
+    last_message_id: i64,
+    /// The ID of the last message before the next file, i.e., as of the last rotation.
+    next_file_id: i64,
+    /// The number of messages ended since the last rotation.
+    messages_since_rotation: u64,
+    /// Whether no record was written since the last message ended.
+    at_message_boundary: bool,
+    manifest: Option<csv::Writer<File>>,
+}
+
+impl<T> RotatingWriter<T>
+where
+    T: ManifestRecord + Serialize + ParquetRecord + Clone + 'static,
+{
+    pub fn new(pattern: &str, format: OutputFormat, cfg: RotationConfig) -> Result<Self> {
+        let manifest = cfg
+            .manifest_file
+            .as_ref()
+            .map(|path| {
+                File::create(path)
+                    .context(format!("unable to create manifest file {}", path))
+                    .map(csv::Writer::from_writer)
+            })
+            .transpose()?;
+
+        Ok(RotatingWriter {
+            pattern: pattern.to_string(),
+            format,
+            cfg,
+            current: None,
+            last_message_id: 0,
+            next_file_id: 0,
+            messages_since_rotation: 0,
+            at_message_boundary: true,
+            manifest,
+        })
+    }
+
+    fn period_of(&self, ts_secs: i64) -> Option<i64> {
+        self.cfg
+            .trace_period_secs
+            .map(|period| ts_secs.div_euclid(period as i64))
+    }
+
+    fn limit_reached(&self) -> bool {
+        self.cfg
+            .max_messages
+            .is_some_and(|max| self.messages_since_rotation >= max)
+            || self.current.as_ref().is_some_and(|current| {
+                self.cfg
+                    .max_rows
+                    .is_some_and(|max| current.entry.rows >= max)
+                    || self
+                        .cfg
+                        .max_bytes
+                        .is_some_and(|max| current.sink.bytes_written() >= max)
+            })
+    }
+
+    /// Checks whether the record belongs to a later period than the current file.
+    /// Records of earlier periods, which can occur if timestamps are not perfectly ordered, are
+    /// written to the current file.
+    fn period_moved_forward(&self, current: &CurrentFile<T>, record: &T) -> bool {
+        match (current.period, self.period_of(record.timestamp().0)) {
+            (Some(current), Some(period)) => period > current,
+            _ => false,
+        }
+    }
+
+    /// Writes a record, rotating the output file beforehand if necessary.
+    pub fn write(&mut self, record: &T) -> Result<()> {
+        if self.at_message_boundary {
+            if let Some(current) = self.current.as_ref() {
+                if self.period_moved_forward(current, record) {
+                    self.rotate()?;
+                }
+            }
+        }
+        self.at_message_boundary = false;
+
+        let (ts_secs, ts_subsec_millis) = record.timestamp();
+        let current = match self.current.as_mut() {
+            Some(current) => current,
+            None => {
+                let path = self.format.output_path(
+                    &self
+                        .pattern
+                        .replace("$id$", &format!("{:09}", self.next_file_id)),
+                );
+                debug!("starting new output file {}", path);
+                let sink = create_sink(&path, self.format)
+                    .context(format!("unable to create output file {}", path))?;
+                let period = self.period_of(ts_secs);
+                self.current.insert(CurrentFile {
+                    sink,
+                    period,
+                    entry: ManifestEntry {
+                        file: path,
+                        first_message_id: record.message_id(),
+                        last_message_id: record.message_id(),
+                        first_timestamp_seconds: ts_secs,
+                        first_timestamp_subsec_milliseconds: ts_subsec_millis,
+                        last_timestamp_seconds: ts_secs,
+                        last_timestamp_subsec_milliseconds: ts_subsec_millis,
+                        rows: 0,
+                        sha256: String::new(),
+                    },
+                })
+            }
+        };
+
+        current.sink.write(record)?;
+
+        let entry = &mut current.entry;
+        entry.first_message_id = entry.first_message_id.min(record.message_id());
+        entry.last_message_id = entry.last_message_id.max(record.message_id());
+        entry.rows += 1;
+        if (ts_secs, ts_subsec_millis)
+            < (
+                entry.first_timestamp_seconds,
+                entry.first_timestamp_subsec_milliseconds,
+            )
+        {
+            entry.first_timestamp_seconds = ts_secs;
+            entry.first_timestamp_subsec_milliseconds = ts_subsec_millis;
+        }
+        if (ts_secs, ts_subsec_millis)
+            > (
+                entry.last_timestamp_seconds,
+                entry.last_timestamp_subsec_milliseconds,
+            )
+        {
+            entry.last_timestamp_seconds = ts_secs;
+            entry.last_timestamp_subsec_milliseconds = ts_subsec_millis;
+        }
+
+        Ok(())
+    }
+
+    /// Marks the end of a message, i.e., all records of the message have been written.
+    /// This rotates the output file if any of the row, message, or size limits is reached.
+    pub fn end_message(&mut self, message_id: i64) -> Result<()> {
+        self.last_message_id = self.last_message_id.max(message_id);
+        self.messages_since_rotation += 1;
+        self.at_message_boundary = true;
+        if self.limit_reached() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the current file, if any, and records it in the manifest.
+    /// The next record will be written to a new file.
+    pub fn rotate(&mut self) -> Result<()> {
+        self.messages_since_rotation = 0;
+        self.next_file_id = self.last_message_id;
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let mut entry = current.entry;
+        current
+            .sink
+            .finish()
+            .context(format!("unable to finish output file {}", entry.file))?;
+
+        if let Some(manifest) = self.manifest.as_mut() {
+            entry.sha256 = sha256_of_file(&entry.file)
+                .context(format!("unable to compute checksum of {}", entry.file))?;
+            manifest
+                .serialize(&entry)
+                .context("unable to write manifest entry")?;
+            // Flush, so that the manifest is usable even if we crash later.
+            manifest.flush().context("unable to flush manifest")?;
+        }
+
+        Ok(())
+    }
+
+    /// Finishes the current file and the manifest.
+    pub fn finish(mut self) -> Result<()> {
+        self.rotate()?;
+        if let Some(mut manifest) = self.manifest.take() {
+            manifest.flush().context("unable to flush manifest")?;
+        }
+        Ok(())
+    }
+}
+
+fn sha256_of_file(path: &str) -> Result<String> {
+    let mut f = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut f, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::ParquetColumnWriter;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+
+    #[derive(Clone, Debug, Serialize)]
+    struct TestRecord {
+        message_id: i64,
+        ts_secs: i64,
+        payload: String,
+    }
+
+    impl ParquetRecord for TestRecord {
+        const PARQUET_SCHEMA: &'static str = "
+            message test_record {
+                REQUIRED INT64 message_id;
+                REQUIRED INT64 ts_secs;
+                REQUIRED BINARY payload (UTF8);
+            }";
+
+        fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+            w.write_i64(records.iter().map(|r| r.message_id))?;
+            w.write_i64(records.iter().map(|r| r.ts_secs))?;
+            w.write_str(records.iter().map(|r| r.payload.as_str()))?;
+            Ok(())
+        }
+    }
+
+    impl ManifestRecord for TestRecord {
+        fn message_id(&self) -> i64 {
+            self.message_id
+        }
+
+        fn timestamp(&self) -> (i64, u32) {
+            (self.ts_secs, 0)
+        }
+    }
+
+    fn record(message_id: i64, ts_secs: i64) -> TestRecord {
+        TestRecord {
+            message_id,
+            ts_secs,
+            payload: String::new(),
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rotation-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes the messages, each given as a list of records, and returns the manifest.
+    fn write_messages(
+        dir: &Path,
+        cfg: RotationConfig,
+        messages: &[(i64, Vec<TestRecord>)],
+    ) -> Vec<ManifestEntry> {
+        let manifest_path = dir.join("manifest.csv");
+        let cfg = RotationConfig {
+            manifest_file: Some(manifest_path.display().to_string()),
+            ..cfg
+        };
+        let pattern = dir.join("wl-$id$.csv.gz").display().to_string();
+        let mut w = RotatingWriter::new(&pattern, OutputFormat::Csv, cfg).unwrap();
+        for (message_id, records) in messages {
+            records.iter().for_each(|r| w.write(r).unwrap());
+            w.end_message(*message_id).unwrap();
+        }
+        w.finish().unwrap();
+
+        csv::Reader::from_path(manifest_path)
+            .unwrap()
+            .deserialize()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap()
+    }
+
+    fn file_name(entry: &ManifestEntry) -> String {
+        Path::new(&entry.file)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn rotate_by_rows() {
+        let dir = test_dir("rows");
+        // Messages 1 and 4 produce no rows.
+        let messages = vec![
+            (1, vec![]),
+            (2, vec![record(2, 10), record(2, 10)]),
+            (3, vec![record(3, 11)]),
+            (4, vec![]),
+            (5, vec![record(5, 12), record(5, 12), record(5, 13)]),
+            (6, vec![record(6, 14)]),
+        ];
+        let manifest = write_messages(
+            &dir,
+            RotationConfig {
+                max_rows: Some(2),
+                ..Default::default()
+            },
+            &messages,
+        );
+
+        // Messages are never split, and files are named after the message before them.
+        let files: Vec<_> = manifest
+            .iter()
+            .map(|e| (file_name(e), e.first_message_id, e.last_message_id, e.rows))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("wl-000000000.csv.gz".to_string(), 2, 2, 2),
+                ("wl-000000002.csv.gz".to_string(), 3, 5, 4),
+                ("wl-000000005.csv.gz".to_string(), 6, 6, 1),
+            ]
+        );
+        assert_eq!(manifest[1].first_timestamp_seconds, 11);
+        assert_eq!(manifest[1].last_timestamp_seconds, 13);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_messages() {
+        let dir = test_dir("messages");
+        let messages: Vec<_> = (1..=7)
+            .map(|id| {
+                // Every other message produces no rows, but counts nonetheless.
+                let records = if id % 2 == 0 {
+                    vec![record(id, id)]
+                } else {
+                    vec![]
+                };
+                (id, records)
+            })
+            .collect();
+        let manifest = write_messages(
+            &dir,
+            RotationConfig {
+                max_messages: Some(3),
+                ..Default::default()
+            },
+            &messages,
+        );
+
+        let files: Vec<_> = manifest
+            .iter()
+            .map(|e| (file_name(e), e.first_message_id, e.rows))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("wl-000000000.csv.gz".to_string(), 2, 1),
+                ("wl-000000003.csv.gz".to_string(), 4, 2),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_bytes() {
+        let dir = test_dir("bytes");
+        // Pseudo-random payloads, so that the compressor produces output.
+        let mut state = 1u64;
+        let messages: Vec<_> = (1..=200)
+            .map(|id| {
+                let payload = (0..1000)
+                    .map(|_| {
+                        state = state
+                            .wrapping_mul(6364136223846793005)
+                            .wrapping_add(1442695040888963407);
+                        char::from(b'a' + (state >> 59) as u8)
+                    })
+                    .collect();
+                (
+                    id,
+                    vec![TestRecord {
+                        message_id: id,
+                        ts_secs: id,
+                        payload,
+                    }],
+                )
+            })
+            .collect();
+        let max_bytes = 50_000;
+        let manifest = write_messages(
+            &dir,
+            RotationConfig {
+                max_bytes: Some(max_bytes),
+                ..Default::default()
+            },
+            &messages,
+        );
+
+        assert!(manifest.len() > 1);
+        assert_eq!(manifest.iter().map(|e| e.rows).sum::<u64>(), 200);
+        for entry in &manifest[..manifest.len() - 1] {
+            assert!(std::fs::metadata(&entry.file).unwrap().len() >= max_bytes);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_period_only_forward() {
+        let dir = test_dir("period");
+        let messages = vec![
+            (1, vec![record(1, 3598)]),
+            (2, vec![record(2, 3601)]),
+            // Slightly out of order, stays in the current file.
+            (3, vec![record(3, 3599)]),
+            (4, vec![record(4, 3602)]),
+            (5, vec![record(5, 7200)]),
+            // Records of one message are never split, even across periods.
+            (6, vec![record(6, 7300), record(6, 10800)]),
+        ];
+        let manifest = write_messages(
+            &dir,
+            RotationConfig {
+                trace_period_secs: Some(3600),
+                ..Default::default()
+            },
+            &messages,
+        );
+
+        let files: Vec<_> = manifest
+            .iter()
+            .map(|e| {
+                (
+                    file_name(e),
+                    e.rows,
+                    e.first_timestamp_seconds,
+                    e.last_timestamp_seconds,
+                )
+            })
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("wl-000000000.csv.gz".to_string(), 1, 3598, 3598),
+                ("wl-000000001.csv.gz".to_string(), 3, 3599, 3602),
+                ("wl-000000004.csv.gz".to_string(), 3, 7200, 10800),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_checksums() {
+        let dir = test_dir("manifest");
+        let messages: Vec<_> = (1..=5).map(|id| (id, vec![record(id, id)])).collect();
+        let manifest = write_messages(
+            &dir,
+            RotationConfig {
+                max_rows: Some(2),
+                ..Default::default()
+            },
+            &messages,
+        );
+
+        assert_eq!(manifest.len(), 3);
+        for entry in &manifest {
+            let mut data = Vec::new();
+            File::open(&entry.file)
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            assert_eq!(entry.sha256, hex::encode(Sha256::digest(&data)));
+        }
+        assert_ne!(manifest[0].sha256, manifest[1].sha256);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
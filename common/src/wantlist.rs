@@ -1,10 +1,16 @@
+use crate::anonymize::{Anonymize, Anonymizer};
+use crate::output::{next_column, ColumnGroup, ParquetColumnWriter, ParquetRecord};
+use crate::rotation::ManifestRecord;
 use crate::Result;
 use failure::{err_msg, ResultExt};
 use parity_multiaddr::Multiaddr;
-use serde::{Deserialize, Serialize};
+use serde::de::{SeqAccess, Visitor};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_repr::*;
 use std::collections::HashMap;
-use std::mem;
+use std::marker::PhantomData;
+use std::{fmt, mem};
 
 /// Constants for the `want_type` field of a `JSONWantlistEntry`.
 #[derive(Serialize_repr, Deserialize_repr, Debug, Copy, Clone, Eq, PartialEq)]
@@ -92,9 +98,9 @@ pub const CSV_DUPLICATE_STATUS_DUP_FULL_WANTLIST: u32 = 1;
 pub const CSV_DUPLICATE_STATUS_DUP_RECONNECT: u32 = 2;
 pub const CSV_DUPLICATE_STATUS_DUP_SLIDING_WINDOW: u32 = 4;
 
-// TODO keep this in sync with `unification::matcher::OutputCSVWantlistEntry`.
 /// A wantlist entry, to be serialized as CSV.
-#[derive(Clone, Serialize, Deserialize, Debug)]
+/// Records with additional columns can be built with [`ExtendedCSVWantlistEntry`].
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct CSVWantlistEntry {
     /// A synthetic counter which is incremented for every object in the stream of logged
     /// `JSONMessage`s.
@@ -139,6 +145,217 @@ pub struct CSVWantlistEntry {
     pub upgrades_earlier_request: bool,
 }
 
+/// Expands to the Parquet column definitions of a [`CSVWantlistEntry`], for use in [`concat!`] to
+/// build the schemas of records containing wantlist entries.
+#[macro_export]
+macro_rules! csv_wantlist_entry_parquet_columns {
+    () => {
+        "
+            REQUIRED INT64 message_id;
+            REQUIRED INT32 message_type;
+            REQUIRED INT64 timestamp_seconds;
+            REQUIRED INT32 timestamp_subsec_milliseconds (UINT_32);
+            REQUIRED BINARY peer_id (UTF8);
+            REQUIRED BINARY address (UTF8);
+            REQUIRED INT32 priority;
+            REQUIRED INT32 entry_type;
+            REQUIRED BINARY cid (UTF8);
+            REQUIRED INT32 duplicate_status (UINT_32);
+            REQUIRED INT32 sliding_window_smallest_match (UINT_32);
+            REQUIRED INT32 secs_since_earlier_message (UINT_32);
+            REQUIRED BOOLEAN upgrades_earlier_request;
+        "
+    };
+}
+
+impl ColumnGroup for CSVWantlistEntry {
+    const NUM_COLUMNS: usize = 13;
+
+    fn serialize_columns<S: SerializeStruct>(
+        &self,
+        s: &mut S,
+    ) -> std::result::Result<(), S::Error> {
+        s.serialize_field("message_id", &self.message_id)?;
+        s.serialize_field("message_type", &self.message_type)?;
+        s.serialize_field("timestamp_seconds", &self.timestamp_seconds)?;
+        s.serialize_field(
+            "timestamp_subsec_milliseconds",
+            &self.timestamp_subsec_milliseconds,
+        )?;
+        s.serialize_field("peer_id", &self.peer_id)?;
+        s.serialize_field("address", &self.address)?;
+        s.serialize_field("priority", &self.priority)?;
+        s.serialize_field("entry_type", &self.entry_type)?;
+        s.serialize_field("cid", &self.cid)?;
+        s.serialize_field("duplicate_status", &self.duplicate_status)?;
+        s.serialize_field(
+            "sliding_window_smallest_match",
+            &self.sliding_window_smallest_match,
+        )?;
+        s.serialize_field(
+            "secs_since_earlier_message",
+            &self.secs_since_earlier_message,
+        )?;
+        s.serialize_field("upgrades_earlier_request", &self.upgrades_earlier_request)
+    }
+
+    fn deserialize_columns<'de, A: SeqAccess<'de>>(
+        seq: &mut A,
+    ) -> std::result::Result<Self, A::Error> {
+        Ok(CSVWantlistEntry {
+            message_id: next_column(seq, "message_id")?,
+            message_type: next_column(seq, "message_type")?,
+            timestamp_seconds: next_column(seq, "timestamp_seconds")?,
+            timestamp_subsec_milliseconds: next_column(seq, "timestamp_subsec_milliseconds")?,
+            peer_id: next_column(seq, "peer_id")?,
+            address: next_column(seq, "address")?,
+            priority: next_column(seq, "priority")?,
+            entry_type: next_column(seq, "entry_type")?,
+            cid: next_column(seq, "cid")?,
+            duplicate_status: next_column(seq, "duplicate_status")?,
+            sliding_window_smallest_match: next_column(seq, "sliding_window_smallest_match")?,
+            secs_since_earlier_message: next_column(seq, "secs_since_earlier_message")?,
+            upgrades_earlier_request: next_column(seq, "upgrades_earlier_request")?,
+        })
+    }
+
+    fn write_parquet_column_group<'a, I>(records: I, w: &mut ParquetColumnWriter) -> Result<()>
+    where
+        I: Iterator<Item = &'a Self> + Clone,
+    {
+        w.write_i64(records.clone().map(|r| r.message_id))?;
+        w.write_i32(records.clone().map(|r| r.message_type))?;
+        w.write_i64(records.clone().map(|r| r.timestamp_seconds))?;
+        w.write_u32(records.clone().map(|r| r.timestamp_subsec_milliseconds))?;
+        w.write_str(records.clone().map(|r| r.peer_id.as_str()))?;
+        w.write_str(records.clone().map(|r| r.address.as_str()))?;
+        w.write_i32(records.clone().map(|r| r.priority))?;
+        w.write_i32(records.clone().map(|r| r.entry_type))?;
+        w.write_str(records.clone().map(|r| r.cid.as_str()))?;
+        w.write_u32(records.clone().map(|r| r.duplicate_status))?;
+        w.write_u32(records.clone().map(|r| r.sliding_window_smallest_match))?;
+        w.write_u32(records.clone().map(|r| r.secs_since_earlier_message))?;
+        w.write_bool(records.map(|r| r.upgrades_earlier_request))?;
+        Ok(())
+    }
+}
+
+impl ParquetRecord for CSVWantlistEntry {
+    const PARQUET_SCHEMA: &'static str = concat!(
+        "message wantlist_entry {",
+        csv_wantlist_entry_parquet_columns!(),
+        "}"
+    );
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        Self::write_parquet_column_group(records.iter(), w)
+    }
+}
+
+/// A [`CSVWantlistEntry`] with additional columns, such as the results of matching entries between
+/// monitors.
+/// This serializes to a flat record, with the extension columns before those of the entry.
+#[derive(Clone, Debug)]
+pub struct ExtendedCSVWantlistEntry<E> {
+    pub extension: E,
+    pub entry: CSVWantlistEntry,
+}
+
+/// Columns extending [`CSVWantlistEntry`]s, see [`ExtendedCSVWantlistEntry`].
+pub trait WantlistEntryExtension: ColumnGroup {
+    /// The Parquet schema of the extended record, which lists the extension columns followed by
+    /// [`csv_wantlist_entry_parquet_columns`].
+    const PARQUET_SCHEMA: &'static str;
+}
+
+impl<E: WantlistEntryExtension> Serialize for ExtendedCSVWantlistEntry<E> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct(
+            "ExtendedCSVWantlistEntry",
+            E::NUM_COLUMNS + CSVWantlistEntry::NUM_COLUMNS,
+        )?;
+        self.extension.serialize_columns(&mut s)?;
+        self.entry.serialize_columns(&mut s)?;
+        s.end()
+    }
+}
+
+impl<'de, E: WantlistEntryExtension> Deserialize<'de> for ExtendedCSVWantlistEntry<E> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct ExtendedVisitor<E>(PhantomData<E>);
+
+        impl<'de, E: WantlistEntryExtension> Visitor<'de> for ExtendedVisitor<E> {
+            type Value = ExtendedCSVWantlistEntry<E>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(
+                    f,
+                    "a sequence of {} columns",
+                    E::NUM_COLUMNS + CSVWantlistEntry::NUM_COLUMNS
+                )
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> std::result::Result<Self::Value, A::Error> {
+                Ok(ExtendedCSVWantlistEntry {
+                    extension: E::deserialize_columns(&mut seq)?,
+                    entry: CSVWantlistEntry::deserialize_columns(&mut seq)?,
+                })
+            }
+        }
+
+        // Columns are read by position, which the `csv` crate supports with and without headers.
+        deserializer.deserialize_tuple(
+            E::NUM_COLUMNS + CSVWantlistEntry::NUM_COLUMNS,
+            ExtendedVisitor(PhantomData),
+        )
+    }
+}
+
+impl<E: WantlistEntryExtension> ParquetRecord for ExtendedCSVWantlistEntry<E> {
+    const PARQUET_SCHEMA: &'static str = E::PARQUET_SCHEMA;
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        E::write_parquet_column_group(records.iter().map(|r| &r.extension), w)?;
+        CSVWantlistEntry::write_parquet_column_group(records.iter().map(|r| &r.entry), w)
+    }
+}
+
+impl<E> Anonymize for ExtendedCSVWantlistEntry<E> {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.entry.anonymize(anonymizer)
+    }
+}
+
+impl<E> ManifestRecord for ExtendedCSVWantlistEntry<E> {
+    fn message_id(&self) -> i64 {
+        self.entry.message_id()
+    }
+
+    fn timestamp(&self) -> (i64, u32) {
+        self.entry.timestamp()
+    }
+}
+
+impl Anonymize for CSVWantlistEntry {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.peer_id = anonymizer.pseudonymize_peer(&self.peer_id);
+        self.address = anonymizer.anonymize_address(&self.address);
+    }
+}
+
+impl ManifestRecord for CSVWantlistEntry {
+    fn message_id(&self) -> i64 {
+        self.message_id
+    }
+
+    fn timestamp(&self) -> (i64, u32) {
+        (self.timestamp_seconds, self.timestamp_subsec_milliseconds)
+    }
+}
+
 impl CSVWantlistEntry {
     pub fn from_wantlist_entries(
         entries: Vec<WantlistEntry>,
@@ -312,6 +529,35 @@ impl CSVConnectionEvent {
     }
 }
 
+impl Anonymize for CSVConnectionEvent {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.peer_id = anonymizer.pseudonymize_peer(&self.peer_id);
+        self.address = anonymizer.anonymize_address(&self.address);
+    }
+}
+
+impl ParquetRecord for CSVConnectionEvent {
+    const PARQUET_SCHEMA: &'static str = "
+        message connection_event {
+            REQUIRED INT64 message_id;
+            REQUIRED INT64 timestamp_seconds;
+            REQUIRED INT32 timestamp_subsec_millis (UINT_32);
+            REQUIRED BINARY peer_id (UTF8);
+            REQUIRED BINARY address (UTF8);
+            REQUIRED INT32 event_type;
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_i64(records.iter().map(|r| r.message_id))?;
+        w.write_i64(records.iter().map(|r| r.timestamp_seconds))?;
+        w.write_u32(records.iter().map(|r| r.timestamp_subsec_millis))?;
+        w.write_str(records.iter().map(|r| r.peer_id.as_str()))?;
+        w.write_str(records.iter().map(|r| r.address.as_str()))?;
+        w.write_i32(records.iter().map(|r| r.event_type))?;
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Ord, PartialOrd, PartialEq, Eq)]
 enum WantType {
     Block,
@@ -405,6 +651,11 @@ impl EngineSimulation {
     pub fn num_ledgers(&self) -> usize {
         self.peers.len()
     }
+
+    /// Returns the effective configuration of the simulation.
+    pub fn config(&self) -> &EngineSimulationConfig {
+        &self.cfg
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -1104,3 +1355,134 @@ impl EngineSimulation {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::schema::parser::parse_message_type;
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct TestColumns {
+        monitor_id: u64,
+        note: Option<String>,
+    }
+
+    impl ColumnGroup for TestColumns {
+        const NUM_COLUMNS: usize = 2;
+
+        fn serialize_columns<S: SerializeStruct>(
+            &self,
+            s: &mut S,
+        ) -> std::result::Result<(), S::Error> {
+            s.serialize_field("monitor_id", &self.monitor_id)?;
+            s.serialize_field("note", &self.note)
+        }
+
+        fn deserialize_columns<'de, A: SeqAccess<'de>>(
+            seq: &mut A,
+        ) -> std::result::Result<Self, A::Error> {
+            Ok(TestColumns {
+                monitor_id: next_column(seq, "monitor_id")?,
+                note: next_column(seq, "note")?,
+            })
+        }
+
+        fn write_parquet_column_group<'a, I>(records: I, w: &mut ParquetColumnWriter) -> Result<()>
+        where
+            I: Iterator<Item = &'a Self> + Clone,
+        {
+            w.write_u64(records.clone().map(|r| r.monitor_id))?;
+            w.write_optional_str(records.map(|r| r.note.as_deref()))
+        }
+    }
+
+    impl WantlistEntryExtension for TestColumns {
+        const PARQUET_SCHEMA: &'static str = concat!(
+            "message test_entry {
+                REQUIRED INT64 monitor_id (UINT_64);
+                OPTIONAL BINARY note (UTF8);",
+            csv_wantlist_entry_parquet_columns!(),
+            "}"
+        );
+    }
+
+    fn entry(message_id: i64, upgrades_earlier_request: bool) -> CSVWantlistEntry {
+        CSVWantlistEntry {
+            message_id,
+            message_type: CSV_MESSAGE_TYPE_INCREMENTAL,
+            timestamp_seconds: 1_600_000_000,
+            timestamp_subsec_milliseconds: 123,
+            peer_id: "QmPeer".to_string(),
+            address: "/ip4/1.2.3.4/tcp/4001".to_string(),
+            priority: 42,
+            entry_type: CSV_ENTRY_TYPE_WANT_BLOCK,
+            cid: "QmCid".to_string(),
+            duplicate_status: CSV_DUPLICATE_STATUS_DUP_FULL_WANTLIST
+                | CSV_DUPLICATE_STATUS_DUP_SLIDING_WINDOW,
+            sliding_window_smallest_match: 5,
+            secs_since_earlier_message: 7,
+            upgrades_earlier_request,
+        }
+    }
+
+    #[test]
+    fn extended_entry_csv_round_trip() {
+        let records = [
+            ExtendedCSVWantlistEntry {
+                extension: TestColumns {
+                    monitor_id: 1,
+                    note: Some("a".to_string()),
+                },
+                entry: entry(1, true),
+            },
+            ExtendedCSVWantlistEntry {
+                extension: TestColumns {
+                    monitor_id: 2,
+                    note: None,
+                },
+                entry: entry(2, false),
+            },
+        ];
+
+        let mut w = csv::Writer::from_writer(Vec::new());
+        records.iter().for_each(|r| w.serialize(r).unwrap());
+        let data = w.into_inner().unwrap();
+
+        // The base columns follow the extension columns, exactly as if the entry was serialized
+        // on its own.
+        let mut base = csv::Writer::from_writer(Vec::new());
+        base.serialize(&records[0].entry).unwrap();
+        let base = String::from_utf8(base.into_inner().unwrap()).unwrap();
+        let data_str = String::from_utf8(data.clone()).unwrap();
+        assert_eq!(
+            data_str.lines().next().unwrap(),
+            format!("monitor_id,note,{}", base.lines().next().unwrap())
+        );
+
+        let read: Vec<ExtendedCSVWantlistEntry<TestColumns>> = csv::Reader::from_reader(&data[..])
+            .deserialize()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(read.len(), records.len());
+        for (read, written) in read.iter().zip(records.iter()) {
+            assert_eq!(read.extension, written.extension);
+            assert_eq!(read.entry, written.entry);
+        }
+    }
+
+    #[test]
+    fn extended_entry_parquet_schema() {
+        let schema =
+            parse_message_type(ExtendedCSVWantlistEntry::<TestColumns>::PARQUET_SCHEMA).unwrap();
+        let base = parse_message_type(CSVWantlistEntry::PARQUET_SCHEMA).unwrap();
+        assert_eq!(
+            schema.get_fields().len(),
+            TestColumns::NUM_COLUMNS + CSVWantlistEntry::NUM_COLUMNS
+        );
+        assert_eq!(base.get_fields().len(), CSVWantlistEntry::NUM_COLUMNS);
+        assert_eq!(
+            &schema.get_fields()[TestColumns::NUM_COLUMNS..],
+            base.get_fields()
+        );
+    }
+}
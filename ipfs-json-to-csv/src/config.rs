@@ -1,4 +1,9 @@
+use crate::filter::FilterConfig;
+use crate::validate::ValidationConfig;
 use failure::ResultExt;
+use ipfs_resolver_common::anonymize::AnonymizationConfig;
+use ipfs_resolver_common::output::OutputFormat;
+use ipfs_resolver_common::rotation::RotationConfig;
 use ipfs_resolver_common::{wantlist, Result};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
@@ -7,13 +12,58 @@ use std::path::{Path, PathBuf};
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Config {
     pub(crate) input_globs: Vec<String>,
+    #[serde(default)]
+    pub(crate) input_format: InputFormat,
+    #[serde(default)]
+    pub(crate) output_format: OutputFormat,
     pub(crate) wantlist_output_file_pattern: String,
+    #[serde(default)]
+    pub(crate) wantlist_output_rotation: RotationConfig,
     pub(crate) connection_events_output_file: String,
     pub(crate) connection_duration_output_file: String,
+    /// Per-peer sessions, i.e., overlapping connections merged.
+    #[serde(default)]
+    pub(crate) session_output_file: Option<String>,
     pub(crate) ledger_count_output_file: String,
+    pub(crate) block_output_file: Option<String>,
+    pub(crate) block_presence_output_file: Option<String>,
+    pub(crate) sqlite_output_file: Option<String>,
+    /// Where to write the JSON summary of the run, or `-` for stdout, which is the default.
+    #[serde(default = "default_summary_output_file")]
+    pub(crate) summary_output_file: String,
+    #[serde(default)]
+    pub(crate) filter: FilterConfig,
+    #[serde(default)]
+    pub(crate) validation: ValidationConfig,
+    #[serde(default)]
+    pub(crate) anonymization: Option<AnonymizationConfig>,
+    #[serde(default = "default_simulation_threads")]
+    pub(crate) simulation_threads: usize,
     pub(crate) simulation_config: wantlist::EngineSimulationConfig,
 }
 
+fn default_simulation_threads() -> usize {
+    1
+}
+
+fn default_summary_output_file() -> String {
+    "-".to_string()
+}
+
+/// The format of the input files.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum InputFormat {
+    /// One `JSONMessage` per line, as produced by the modified Go implementation of IPFS.
+    #[default]
+    JsonMessages,
+
+    /// A stream of `PushedEvent`s, either as batches or individually, as produced by the
+    /// monitoring plugin.
+    /// Blocks and block presences are only present in this format.
+    PushedEvents,
+}
+
 impl Config {
     pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Config> {
         let f = File::open(path).context("unable to open file")?;
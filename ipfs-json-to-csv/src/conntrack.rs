@@ -1,29 +1,60 @@
 use failure::{err_msg, ResultExt};
+use ipfs_resolver_common::anonymize::{Anonymize, Anonymizer};
+use ipfs_resolver_common::output::{ParquetColumnWriter, ParquetRecord};
 use ipfs_resolver_common::{wantlist, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+/// Tracks connections to peers, from CONNECTED and DISCONNECTED events.
+///
+/// A peer can be connected over multiple transports at once, so connections are tracked per peer
+/// and address.
 #[derive(Clone, Debug)]
 pub(crate) struct ConnectionDurationTracker {
     beginning_ts: Option<chrono::DateTime<chrono::Utc>>,
     connections: HashMap<String, PeerConnectionBuffer>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 struct PeerConnectionBuffer {
-    connection_start: Option<chrono::DateTime<chrono::Utc>>,
-    connected_address: Option<String>,
+    /// Currently open connections, in the order they were opened.
+    open_connections: Vec<OpenConnection>,
     past_connections: Vec<ConnectionMetadata>,
 }
 
+#[derive(Clone, Debug)]
+struct OpenConnection {
+    start: chrono::DateTime<chrono::Utc>,
+    address: Option<String>,
+}
+
+impl OpenConnection {
+    fn close(self, end: chrono::DateTime<chrono::Utc>, synthetic: bool) -> ConnectionMetadata {
+        ConnectionMetadata {
+            start: self.start,
+            end,
+            address: self.address,
+            synthetic_close: synthetic,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct ConnectionMetadata {
     start: chrono::DateTime<chrono::Utc>,
     end: chrono::DateTime<chrono::Utc>,
     address: Option<String>,
+    /// Whether the connection was closed without a matching DISCONNECTED event, i.e., it was
+    /// still open at the end of the trace, or a later CONNECTED event showed that we missed the
+    /// disconnect.
+    synthetic_close: bool,
 }
 
 impl ConnectionMetadata {
+    pub(crate) fn duration(&self) -> chrono::Duration {
+        self.end - self.start
+    }
+
     pub(crate) fn to_csv(&self, peer_id: String) -> CSVConnectionMetadata {
         CSVConnectionMetadata {
             peer_id,
@@ -32,6 +63,65 @@ impl ConnectionMetadata {
             end_ts_seconds: self.end.timestamp(),
             end_ts_subsec_millis: self.end.timestamp_subsec_millis(),
             address: self.address.clone(),
+            synthetic_close: self.synthetic_close,
+        }
+    }
+}
+
+/// A period of time during which a peer had at least one connection, i.e., the union of
+/// overlapping connections.
+#[derive(Clone, Debug)]
+pub(crate) struct SessionMetadata {
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    connections: u32,
+    /// Whether the connection ending the session was closed synthetically.
+    synthetic_close: bool,
+}
+
+impl SessionMetadata {
+    /// Merges the connections of one peer into sessions, ordered by start time.
+    pub(crate) fn from_connections(connections: &[ConnectionMetadata]) -> Vec<SessionMetadata> {
+        let mut connections: Vec<_> = connections.iter().collect();
+        connections.sort_by_key(|c| c.start);
+
+        let mut sessions: Vec<SessionMetadata> = Vec::new();
+        for c in connections {
+            match sessions.last_mut() {
+                Some(session) if c.start <= session.end => {
+                    session.connections += 1;
+                    if c.end > session.end {
+                        session.end = c.end;
+                        session.synthetic_close = c.synthetic_close;
+                    } else if c.end == session.end {
+                        session.synthetic_close |= c.synthetic_close;
+                    }
+                }
+                _ => sessions.push(SessionMetadata {
+                    start: c.start,
+                    end: c.end,
+                    connections: 1,
+                    synthetic_close: c.synthetic_close,
+                }),
+            }
+        }
+
+        sessions
+    }
+
+    pub(crate) fn duration(&self) -> chrono::Duration {
+        self.end - self.start
+    }
+
+    pub(crate) fn to_csv(&self, peer_id: String) -> CSVSessionMetadata {
+        CSVSessionMetadata {
+            peer_id,
+            start_ts_seconds: self.start.timestamp(),
+            start_ts_subsec_millis: self.start.timestamp_subsec_millis(),
+            end_ts_seconds: self.end.timestamp(),
+            end_ts_subsec_millis: self.end.timestamp_subsec_millis(),
+            connections: self.connections,
+            synthetic_close: self.synthetic_close,
         }
     }
 }
@@ -39,12 +129,89 @@ impl ConnectionMetadata {
 /// Metadata about an overlay session, to be serialized to CSV.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct CSVConnectionMetadata {
-    peer_id: String,
-    start_ts_seconds: i64,
-    start_ts_subsec_millis: u32,
-    end_ts_seconds: i64,
-    end_ts_subsec_millis: u32,
-    address: Option<String>,
+    pub(crate) peer_id: String,
+    pub(crate) start_ts_seconds: i64,
+    pub(crate) start_ts_subsec_millis: u32,
+    pub(crate) end_ts_seconds: i64,
+    pub(crate) end_ts_subsec_millis: u32,
+    pub(crate) address: Option<String>,
+    pub(crate) synthetic_close: bool,
+}
+
+impl ParquetRecord for CSVConnectionMetadata {
+    const PARQUET_SCHEMA: &'static str = "
+        message connection_metadata {
+            REQUIRED BINARY peer_id (UTF8);
+            REQUIRED INT64 start_ts_seconds;
+            REQUIRED INT32 start_ts_subsec_millis (UINT_32);
+            REQUIRED INT64 end_ts_seconds;
+            REQUIRED INT32 end_ts_subsec_millis (UINT_32);
+            OPTIONAL BINARY address (UTF8);
+            REQUIRED BOOLEAN synthetic_close;
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_str(records.iter().map(|r| r.peer_id.as_str()))?;
+        w.write_i64(records.iter().map(|r| r.start_ts_seconds))?;
+        w.write_u32(records.iter().map(|r| r.start_ts_subsec_millis))?;
+        w.write_i64(records.iter().map(|r| r.end_ts_seconds))?;
+        w.write_u32(records.iter().map(|r| r.end_ts_subsec_millis))?;
+        w.write_optional_str(records.iter().map(|r| r.address.as_deref()))?;
+        w.write_bool(records.iter().map(|r| r.synthetic_close))?;
+        Ok(())
+    }
+}
+
+impl Anonymize for CSVConnectionMetadata {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.peer_id = anonymizer.pseudonymize_peer(&self.peer_id);
+        self.address = self
+            .address
+            .as_ref()
+            .map(|a| anonymizer.anonymize_address(a));
+    }
+}
+
+/// A merged per-peer session, to be serialized to CSV.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CSVSessionMetadata {
+    pub(crate) peer_id: String,
+    pub(crate) start_ts_seconds: i64,
+    pub(crate) start_ts_subsec_millis: u32,
+    pub(crate) end_ts_seconds: i64,
+    pub(crate) end_ts_subsec_millis: u32,
+    pub(crate) connections: u32,
+    pub(crate) synthetic_close: bool,
+}
+
+impl ParquetRecord for CSVSessionMetadata {
+    const PARQUET_SCHEMA: &'static str = "
+        message session_metadata {
+            REQUIRED BINARY peer_id (UTF8);
+            REQUIRED INT64 start_ts_seconds;
+            REQUIRED INT32 start_ts_subsec_millis (UINT_32);
+            REQUIRED INT64 end_ts_seconds;
+            REQUIRED INT32 end_ts_subsec_millis (UINT_32);
+            REQUIRED INT32 connections (UINT_32);
+            REQUIRED BOOLEAN synthetic_close;
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_str(records.iter().map(|r| r.peer_id.as_str()))?;
+        w.write_i64(records.iter().map(|r| r.start_ts_seconds))?;
+        w.write_u32(records.iter().map(|r| r.start_ts_subsec_millis))?;
+        w.write_i64(records.iter().map(|r| r.end_ts_seconds))?;
+        w.write_u32(records.iter().map(|r| r.end_ts_subsec_millis))?;
+        w.write_u32(records.iter().map(|r| r.connections))?;
+        w.write_bool(records.iter().map(|r| r.synthetic_close))?;
+        Ok(())
+    }
+}
+
+impl Anonymize for CSVSessionMetadata {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.peer_id = anonymizer.pseudonymize_peer(&self.peer_id);
+    }
 }
 
 impl ConnectionDurationTracker {
@@ -55,22 +222,21 @@ impl ConnectionDurationTracker {
         }
     }
 
+    /// Closes all open connections at the given timestamp, marking them as closed
+    /// synthetically, and returns all connections per peer.
     pub fn finalize(
         mut self,
         final_ts: chrono::DateTime<chrono::Utc>,
     ) -> HashMap<String, Vec<ConnectionMetadata>> {
         for (k, v) in self.connections.iter_mut() {
-            if v.connection_start.is_some() {
+            if !v.open_connections.is_empty() {
                 debug!("finalizing currently connected peer {}", k);
-
-                let start = v.connection_start.take().unwrap();
-                let addr = v.connected_address.take();
-                v.past_connections.push(ConnectionMetadata {
-                    start,
-                    end: final_ts.clone(),
-                    address: addr,
-                })
             }
+            v.past_connections.extend(
+                v.open_connections
+                    .drain(..)
+                    .map(|c| c.close(final_ts, true)),
+            );
         }
 
         self.connections
@@ -122,62 +288,82 @@ impl ConnectionDurationTracker {
         peer_id: String,
         connected: bool,
         disconnected: bool,
-        _found: bool,
+        found: bool,
         addr: Option<String>,
         ts: chrono::DateTime<chrono::Utc>,
     ) -> Result<()> {
-        let mut fresh = false;
-        let mut entry = self.connections.entry(peer_id.clone()).or_insert_with(|| {
+        let entry = self.connections.entry(peer_id.clone()).or_insert_with(|| {
             debug!(
                 "peer connection buffer for peer {} not found, initializing new one",
                 peer_id
             );
-            fresh = true;
-            PeerConnectionBuffer {
-                connection_start: Some(ts.clone()),
-                connected_address: addr.clone(),
-                past_connections: Vec::new(),
-            }
+            PeerConnectionBuffer::default()
         });
 
-        if entry.connected_address.is_some() {
-            if addr != entry.connected_address {
-                warn!(
-                    "address mismatch, had {:?}, got {:?}",
-                    entry.connected_address, addr
-                )
-            }
-        }
-
         if connected {
-            if entry.connection_start.is_some() {
-                if !fresh {
-                    warn!(
-                        "got CONNECTED, but already have a connection for peer {}, ignoring",
-                        peer_id
-                    );
-                }
-                return Ok(());
+            if !found && !entry.open_connections.is_empty() {
+                // The peer was not connected according to the node, so we must have missed
+                // the DISCONNECTED events of the connections we still consider open.
+                warn!(
+                    "got CONNECTED with found=false, but have {} open connection(s) for peer {}, closing them",
+                    entry.open_connections.len(),
+                    peer_id
+                );
+                entry
+                    .past_connections
+                    .extend(entry.open_connections.drain(..).map(|c| c.close(ts, true)));
+            } else if found && entry.open_connections.is_empty() {
+                debug!(
+                    "got CONNECTED with found=true, but have no open connection for peer {}, probably connected before the trace started",
+                    peer_id
+                );
             }
 
-            entry.connection_start = Some(ts);
-            entry.connected_address = addr;
-        } else if disconnected {
-            if entry.connection_start.is_none() {
+            if entry.open_connections.iter().any(|c| c.address == addr) {
                 warn!(
-                    "got DISCONNECTED, but don't have a connection for peer {}, ignoring",
-                    peer_id
+                    "got CONNECTED, but already have a connection to {:?} for peer {}, ignoring",
+                    addr, peer_id
                 );
                 return Ok(());
             }
 
-            let start = entry.connection_start.take().unwrap();
-            let addr = entry.connected_address.take();
-            entry.past_connections.push(ConnectionMetadata {
-                start,
-                end: ts,
+            entry.open_connections.push(OpenConnection {
+                start: ts,
                 address: addr,
             });
+        } else if disconnected {
+            let i = match entry
+                .open_connections
+                .iter()
+                .position(|c| c.address == addr)
+            {
+                Some(i) => i,
+                None if entry.open_connections.is_empty() => {
+                    warn!(
+                        "got DISCONNECTED from {:?}, but have no open connection for peer {}, ignoring",
+                        addr, peer_id
+                    );
+                    return Ok(());
+                }
+                None => {
+                    // We don't know which connection was closed, so we close the oldest one.
+                    // Ignoring the event would keep a connection open until the end of the trace.
+                    warn!(
+                        "address mismatch for peer {}, had {:?}, got {:?}, closing oldest connection",
+                        peer_id,
+                        entry
+                            .open_connections
+                            .iter()
+                            .map(|c| &c.address)
+                            .collect::<Vec<_>>(),
+                        addr
+                    );
+                    0
+                }
+            };
+
+            let conn = entry.open_connections.remove(i);
+            entry.past_connections.push(conn.close(ts, false));
         } else {
             return Err(err_msg("got neither CONNECTED nor DISCONNECTED"));
         }
@@ -185,3 +371,180 @@ impl ConnectionDurationTracker {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(secs: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn addr(a: &str) -> Option<String> {
+        Some(a.to_string())
+    }
+
+    fn connect(t: &mut ConnectionDurationTracker, secs: i64, found: bool, a: &str) {
+        t.push_inner("peer".to_string(), true, false, found, addr(a), ts(secs))
+            .unwrap();
+    }
+
+    fn disconnect(t: &mut ConnectionDurationTracker, secs: i64, a: &str) {
+        t.push_inner("peer".to_string(), false, true, false, addr(a), ts(secs))
+            .unwrap();
+    }
+
+    /// Returns the connections of the peer as (start, end, address, synthetic_close).
+    fn connections(
+        t: ConnectionDurationTracker,
+        final_secs: i64,
+    ) -> Vec<(i64, i64, Option<String>, bool)> {
+        t.finalize(ts(final_secs))
+            .remove("peer")
+            .unwrap()
+            .into_iter()
+            .map(|c| {
+                (
+                    c.start.timestamp(),
+                    c.end.timestamp(),
+                    c.address,
+                    c.synthetic_close,
+                )
+            })
+            .collect()
+    }
+
+    fn connection(start: i64, end: i64, synthetic_close: bool) -> ConnectionMetadata {
+        ConnectionMetadata {
+            start: ts(start),
+            end: ts(end),
+            address: None,
+            synthetic_close,
+        }
+    }
+
+    #[test]
+    fn overlapping_connections() {
+        let mut t = ConnectionDurationTracker::new();
+        connect(&mut t, 0, false, "/ip4/1.2.3.4/tcp/4001");
+        connect(&mut t, 1, true, "/ip4/1.2.3.4/udp/4001/quic");
+        // Already connected on that address.
+        connect(&mut t, 2, true, "/ip4/1.2.3.4/tcp/4001");
+        disconnect(&mut t, 3, "/ip4/1.2.3.4/tcp/4001");
+        disconnect(&mut t, 4, "/ip4/1.2.3.4/udp/4001/quic");
+
+        assert_eq!(
+            connections(t, 10),
+            vec![
+                (0, 3, addr("/ip4/1.2.3.4/tcp/4001"), false),
+                (1, 4, addr("/ip4/1.2.3.4/udp/4001/quic"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn connect_not_found_closes_open_connections() {
+        let mut t = ConnectionDurationTracker::new();
+        connect(&mut t, 0, false, "/ip4/1.2.3.4/tcp/4001");
+        connect(&mut t, 1, true, "/ip4/1.2.3.4/udp/4001/quic");
+        // We missed the disconnects.
+        connect(&mut t, 5, false, "/ip4/1.2.3.4/tcp/4001");
+
+        assert_eq!(
+            connections(t, 10),
+            vec![
+                (0, 5, addr("/ip4/1.2.3.4/tcp/4001"), true),
+                (1, 5, addr("/ip4/1.2.3.4/udp/4001/quic"), true),
+                // Still open at the end of the trace.
+                (5, 10, addr("/ip4/1.2.3.4/tcp/4001"), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn disconnect_address_mismatch() {
+        // With a single open connection, that one is closed.
+        let mut t = ConnectionDurationTracker::new();
+        connect(&mut t, 0, false, "/ip4/1.2.3.4/tcp/4001");
+        disconnect(&mut t, 3, "/ip4/5.6.7.8/tcp/4001");
+        assert_eq!(
+            connections(t, 10),
+            vec![(0, 3, addr("/ip4/1.2.3.4/tcp/4001"), false)]
+        );
+
+        // With multiple open connections, the oldest one is closed.
+        let mut t = ConnectionDurationTracker::new();
+        connect(&mut t, 0, false, "/ip4/1.2.3.4/tcp/4001");
+        connect(&mut t, 1, true, "/ip4/1.2.3.4/udp/4001/quic");
+        disconnect(&mut t, 3, "/ip4/5.6.7.8/tcp/4001");
+        assert_eq!(
+            connections(t, 10),
+            vec![
+                (0, 3, addr("/ip4/1.2.3.4/tcp/4001"), false),
+                (1, 10, addr("/ip4/1.2.3.4/udp/4001/quic"), true),
+            ]
+        );
+
+        // A later DISCONNECTED with a mismatching address closes the remaining one.
+        let mut t = ConnectionDurationTracker::new();
+        connect(&mut t, 0, false, "/ip4/1.2.3.4/tcp/4001");
+        connect(&mut t, 1, true, "/ip4/1.2.3.4/udp/4001/quic");
+        disconnect(&mut t, 3, "/ip4/5.6.7.8/tcp/4001");
+        disconnect(&mut t, 4, "/ip4/5.6.7.8/tcp/4001");
+        assert_eq!(
+            connections(t, 10),
+            vec![
+                (0, 3, addr("/ip4/1.2.3.4/tcp/4001"), false),
+                (1, 4, addr("/ip4/1.2.3.4/udp/4001/quic"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn disconnect_without_connection() {
+        let mut t = ConnectionDurationTracker::new();
+        disconnect(&mut t, 3, "/ip4/1.2.3.4/tcp/4001");
+        assert_eq!(connections(t, 10), vec![]);
+    }
+
+    #[test]
+    fn merge_sessions() {
+        let sessions = SessionMetadata::from_connections(&[
+            connection(20, 25, false),
+            connection(3, 8, true),
+            // Starts exactly when the previous one ends.
+            connection(8, 9, false),
+            connection(0, 5, false),
+            // Ends at the same time as the other one, which was closed regularly.
+            connection(21, 25, true),
+            connection(30, 31, true),
+        ]);
+
+        let sessions: Vec<_> = sessions
+            .iter()
+            .map(|s| s.to_csv("peer".to_string()))
+            .map(|s| {
+                (
+                    s.start_ts_seconds,
+                    s.end_ts_seconds,
+                    s.connections,
+                    s.synthetic_close,
+                )
+            })
+            .collect();
+        assert_eq!(
+            sessions,
+            vec![(0, 9, 3, false), (20, 25, 2, true), (30, 31, 1, true)]
+        );
+    }
+
+    #[test]
+    fn session_closed_by_longest_connection() {
+        // The synthetic close of a connection that ends before the session does not matter.
+        let sessions =
+            SessionMetadata::from_connections(&[connection(0, 10, false), connection(2, 5, true)]);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].duration(), chrono::Duration::seconds(10));
+        assert!(!sessions[0].synthetic_close);
+    }
+}
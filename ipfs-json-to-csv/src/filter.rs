@@ -0,0 +1,306 @@
+use crate::conntrack::{CSVConnectionMetadata, CSVSessionMetadata};
+use crate::pushed::{CSVBlock, CSVBlockPresence};
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use failure::ResultExt;
+use ipfs_resolver_common::wantlist::{CSVConnectionEvent, CSVWantlistEntry};
+use ipfs_resolver_common::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Configuration for filtering output rows.
+/// Filters are only applied to the outputs, the engine simulation and connection tracker still see
+/// all messages.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct FilterConfig {
+    /// Only rows at or after this timestamp (RFC 3339) are written.
+    #[serde(default)]
+    pub(crate) start_timestamp: Option<DateTime<Utc>>,
+
+    /// Only rows before this timestamp (RFC 3339) are written.
+    #[serde(default)]
+    pub(crate) end_timestamp: Option<DateTime<Utc>>,
+
+    /// If set, only rows of these peers are written.
+    #[serde(default)]
+    pub(crate) peer_allow_list: Option<Vec<String>>,
+
+    /// Rows of these peers are not written.
+    #[serde(default)]
+    pub(crate) peer_deny_list: Vec<String>,
+
+    /// If set, only rows for these CIDs are written.
+    /// CIDs are compared as they appear in the trace, i.e., not normalized.
+    #[serde(default)]
+    pub(crate) cids: Option<Vec<String>>,
+
+    /// If non-empty, only rows for CIDs starting with any of these prefixes are written.
+    #[serde(default)]
+    pub(crate) cid_prefixes: Vec<String>,
+}
+
+impl FilterConfig {
+    /// Applies the filter options given on the command line.
+    /// Timestamps override the configured ones, lists are extended.
+    pub(crate) fn merge_cli_args(&mut self, matches: &ArgMatches) -> Result<()> {
+        if let Some(ts) = matches.value_of("start") {
+            self.start_timestamp = Some(Self::parse_timestamp(ts)?);
+        }
+        if let Some(ts) = matches.value_of("end") {
+            self.end_timestamp = Some(Self::parse_timestamp(ts)?);
+        }
+        if let Some(peers) = matches.values_of("allow_peer") {
+            self.peer_allow_list
+                .get_or_insert_with(Vec::new)
+                .extend(peers.map(String::from));
+        }
+        if let Some(peers) = matches.values_of("deny_peer") {
+            self.peer_deny_list.extend(peers.map(String::from));
+        }
+        if let Some(cids) = matches.values_of("cid") {
+            self.cids
+                .get_or_insert_with(Vec::new)
+                .extend(cids.map(String::from));
+        }
+        if let Some(prefixes) = matches.values_of("cid_prefix") {
+            self.cid_prefixes.extend(prefixes.map(String::from));
+        }
+
+        Ok(())
+    }
+
+    fn parse_timestamp(ts: &str) -> Result<DateTime<Utc>> {
+        let ts = DateTime::parse_from_rfc3339(ts)
+            .context(format!("invalid timestamp {}", ts))?
+            .with_timezone(&Utc);
+        Ok(ts)
+    }
+}
+
+/// Decides which rows are written to the outputs.
+///
+/// Time ranges apply to all outputs.
+/// Connection durations and sessions are kept if they overlap with the time range.
+/// Peer filters apply to all outputs except ledger counts.
+/// CID filters apply only to outputs that carry a CID, i.e., wantlist entries, blocks, and block
+/// presences.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OutputFilter {
+    start_millis: Option<i64>,
+    end_millis: Option<i64>,
+    peer_allow_list: Option<HashSet<String>>,
+    peer_deny_list: HashSet<String>,
+    cids: Option<HashSet<String>>,
+    cid_prefixes: Vec<String>,
+}
+
+impl OutputFilter {
+    pub(crate) fn new(cfg: &FilterConfig) -> OutputFilter {
+        OutputFilter {
+            start_millis: cfg.start_timestamp.map(|ts| ts.timestamp_millis()),
+            end_millis: cfg.end_timestamp.map(|ts| ts.timestamp_millis()),
+            peer_allow_list: cfg
+                .peer_allow_list
+                .as_ref()
+                .map(|peers| peers.iter().cloned().collect()),
+            peer_deny_list: cfg.peer_deny_list.iter().cloned().collect(),
+            cids: cfg.cids.as_ref().map(|cids| cids.iter().cloned().collect()),
+            cid_prefixes: cfg.cid_prefixes.clone(),
+        }
+    }
+
+    fn millis(seconds: i64, subsec_millis: u32) -> i64 {
+        seconds * 1000 + subsec_millis as i64
+    }
+
+    fn time_matches(&self, seconds: i64, subsec_millis: u32) -> bool {
+        self.time_range_overlaps(seconds, subsec_millis, seconds, subsec_millis)
+    }
+
+    fn time_range_overlaps(
+        &self,
+        start_seconds: i64,
+        start_subsec_millis: u32,
+        end_seconds: i64,
+        end_subsec_millis: u32,
+    ) -> bool {
+        let start = Self::millis(start_seconds, start_subsec_millis);
+        let end = Self::millis(end_seconds, end_subsec_millis);
+        self.start_millis.is_none_or(|s| end >= s) && self.end_millis.is_none_or(|e| start < e)
+    }
+
+    fn peer_matches(&self, peer_id: &str) -> bool {
+        !self.peer_deny_list.contains(peer_id)
+            && self
+                .peer_allow_list
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(peer_id))
+    }
+
+    fn cid_matches(&self, cid: &str) -> bool {
+        self.cids.as_ref().is_none_or(|cids| cids.contains(cid))
+            && (self.cid_prefixes.is_empty()
+                || self.cid_prefixes.iter().any(|p| cid.starts_with(p)))
+    }
+
+    pub(crate) fn wantlist_entry(&self, e: &CSVWantlistEntry) -> bool {
+        self.time_matches(e.timestamp_seconds, e.timestamp_subsec_milliseconds)
+            && self.peer_matches(&e.peer_id)
+            && self.cid_matches(&e.cid)
+    }
+
+    pub(crate) fn connection_event(&self, e: &CSVConnectionEvent) -> bool {
+        self.time_matches(e.timestamp_seconds, e.timestamp_subsec_millis)
+            && self.peer_matches(&e.peer_id)
+    }
+
+    pub(crate) fn connection_duration(&self, c: &CSVConnectionMetadata) -> bool {
+        self.time_range_overlaps(
+            c.start_ts_seconds,
+            c.start_ts_subsec_millis,
+            c.end_ts_seconds,
+            c.end_ts_subsec_millis,
+        ) && self.peer_matches(&c.peer_id)
+    }
+
+    pub(crate) fn session(&self, s: &CSVSessionMetadata) -> bool {
+        self.time_range_overlaps(
+            s.start_ts_seconds,
+            s.start_ts_subsec_millis,
+            s.end_ts_seconds,
+            s.end_ts_subsec_millis,
+        ) && self.peer_matches(&s.peer_id)
+    }
+
+    pub(crate) fn ledger_count(&self, ts_secs: i64) -> bool {
+        self.time_matches(ts_secs, 0)
+    }
+
+    pub(crate) fn block(&self, b: &CSVBlock) -> bool {
+        self.time_matches(b.timestamp_seconds, b.timestamp_subsec_milliseconds)
+            && self.peer_matches(&b.peer_id)
+            && self.cid_matches(&b.cid)
+    }
+
+    pub(crate) fn block_presence(&self, p: &CSVBlockPresence) -> bool {
+        self.time_matches(p.timestamp_seconds, p.timestamp_subsec_milliseconds)
+            && self.peer_matches(&p.peer_id)
+            && self.cid_matches(&p.cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(s: &str) -> Option<DateTime<Utc>> {
+        Some(FilterConfig::parse_timestamp(s).unwrap())
+    }
+
+    fn time_filter() -> OutputFilter {
+        OutputFilter::new(&FilterConfig {
+            start_timestamp: ts("2021-01-01T00:00:10Z"),
+            end_timestamp: ts("2021-01-01T00:00:20Z"),
+            ..Default::default()
+        })
+    }
+
+    /// Seconds since the Unix epoch of 2021-01-01T00:00:00Z.
+    const BASE: i64 = 1609459200;
+
+    #[test]
+    fn time_range_start_inclusive_end_exclusive() {
+        let f = time_filter();
+        assert!(!f.time_matches(BASE + 9, 999));
+        assert!(f.time_matches(BASE + 10, 0));
+        assert!(f.time_matches(BASE + 19, 999));
+        assert!(!f.time_matches(BASE + 20, 0));
+
+        // Open-ended ranges.
+        let f = OutputFilter::new(&FilterConfig {
+            start_timestamp: ts("2021-01-01T00:00:10Z"),
+            ..Default::default()
+        });
+        assert!(!f.time_matches(BASE + 9, 999));
+        assert!(f.time_matches(BASE + 1_000_000, 0));
+        assert!(OutputFilter::default().time_matches(0, 0));
+    }
+
+    #[test]
+    fn time_range_overlap() {
+        let f = time_filter();
+        // Ends exactly at the start.
+        assert!(f.time_range_overlaps(BASE, 0, BASE + 10, 0));
+        // Ends right before the start.
+        assert!(!f.time_range_overlaps(BASE, 0, BASE + 9, 999));
+        // Starts exactly at the end.
+        assert!(!f.time_range_overlaps(BASE + 20, 0, BASE + 30, 0));
+        // Starts right before the end.
+        assert!(f.time_range_overlaps(BASE + 19, 999, BASE + 30, 0));
+        // Spans the whole range.
+        assert!(f.time_range_overlaps(BASE, 0, BASE + 30, 0));
+        // Within the range.
+        assert!(f.time_range_overlaps(BASE + 12, 0, BASE + 13, 0));
+    }
+
+    #[test]
+    fn peer_allow_and_deny_lists() {
+        let f = OutputFilter::new(&FilterConfig {
+            peer_deny_list: vec!["peer2".to_string()],
+            ..Default::default()
+        });
+        assert!(f.peer_matches("peer1"));
+        assert!(!f.peer_matches("peer2"));
+
+        let f = OutputFilter::new(&FilterConfig {
+            peer_allow_list: Some(vec!["peer1".to_string(), "peer2".to_string()]),
+            peer_deny_list: vec!["peer2".to_string()],
+            ..Default::default()
+        });
+        assert!(f.peer_matches("peer1"));
+        // The deny list takes precedence.
+        assert!(!f.peer_matches("peer2"));
+        assert!(!f.peer_matches("peer3"));
+
+        // An empty allow list allows nothing.
+        let f = OutputFilter::new(&FilterConfig {
+            peer_allow_list: Some(vec![]),
+            ..Default::default()
+        });
+        assert!(!f.peer_matches("peer1"));
+    }
+
+    #[test]
+    fn cids_and_cid_prefixes() {
+        let f = OutputFilter::new(&FilterConfig {
+            cid_prefixes: vec!["bafy".to_string(), "Qm".to_string()],
+            ..Default::default()
+        });
+        assert!(f.cid_matches("bafyabc"));
+        assert!(f.cid_matches("QmAbc"));
+        assert!(!f.cid_matches("zb2rh"));
+        // Prefixes are case-sensitive, as CIDs are not normalized.
+        assert!(!f.cid_matches("BAFYABC"));
+
+        // Both exact CIDs and prefixes need to match.
+        let f = OutputFilter::new(&FilterConfig {
+            cids: Some(vec!["QmAbc".to_string(), "zb2rh".to_string()]),
+            cid_prefixes: vec!["Qm".to_string()],
+            ..Default::default()
+        });
+        assert!(f.cid_matches("QmAbc"));
+        assert!(!f.cid_matches("zb2rh"));
+        assert!(!f.cid_matches("QmDef"));
+    }
+
+    #[test]
+    fn ledger_counts_ignore_peers() {
+        let f = OutputFilter::new(&FilterConfig {
+            start_timestamp: ts("2021-01-01T00:00:10Z"),
+            peer_allow_list: Some(vec![]),
+            ..Default::default()
+        });
+        assert!(f.ledger_count(BASE + 10));
+        assert!(!f.ledger_count(BASE + 9));
+    }
+}
@@ -3,19 +3,32 @@ extern crate log;
 
 mod config;
 mod conntrack;
-
-use crate::conntrack::ConnectionDurationTracker;
+mod filter;
+mod pushed;
+mod simulation;
+mod sqlite;
+mod summary;
+mod validate;
+
+use crate::config::InputFormat;
+use crate::conntrack::{CSVConnectionMetadata, CSVSessionMetadata, SessionMetadata};
+use crate::filter::OutputFilter;
+use crate::pushed::{CSVBlock, CSVBlockPresence};
+use crate::simulation::Simulation;
+use crate::sqlite::SqliteOutput;
+use crate::summary::RunSummary;
 use clap::{App, Arg};
-use csv::Writer;
 use failure::{err_msg, ResultExt};
 use flate2::read::GzDecoder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use ipfs_monitoring_plugin_client::conversion::PushedEventConverter;
+use ipfs_resolver_common::anonymize::{anonymized, Anonymizer};
+use ipfs_resolver_common::output::{create_sink, ParquetColumnWriter, ParquetRecord, RecordSink};
+use ipfs_resolver_common::rotation::RotatingWriter;
+use ipfs_resolver_common::wantlist::{CSVConnectionEvent, CSVWantlistEntry};
 use ipfs_resolver_common::{logging, wantlist, Result};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::io;
-use std::io::{BufRead, BufReader, BufWriter};
+use std::io::{BufRead, BufReader};
 
 fn main() -> Result<()> {
     logging::set_up_logging()?;
@@ -24,8 +37,8 @@ fn main() -> Result<()> {
         .about("converts JSON files to CSV files and some other stuff")
         .help(
             "This will transform a JSON file into a CSV file.\n\
-             Additionally, one line of CSV-formatted data will be written to Stdout,\
-             containing timestamps of the first and last entry of the file.",
+             Additionally, a JSON summary of the run will be written to Stdout, \
+             or to the summary_output_file given in the config.",
         )
         .arg(
             Arg::with_name("cfg")
@@ -34,6 +47,68 @@ fn main() -> Result<()> {
                 .default_value("config.yaml")
                 .help("the config file to load"),
         )
+        .arg(
+            Arg::with_name("start")
+                .long("start")
+                .value_name("TIMESTAMP")
+                .help("only output rows at or after this RFC 3339 timestamp, overrides the config"),
+        )
+        .arg(
+            Arg::with_name("end")
+                .long("end")
+                .value_name("TIMESTAMP")
+                .help("only output rows before this RFC 3339 timestamp, overrides the config"),
+        )
+        .arg(
+            Arg::with_name("allow_peer")
+                .long("allow-peer")
+                .value_name("PEER_ID")
+                .multiple(true)
+                .number_of_values(1)
+                .help("only output rows of this peer, can be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("deny_peer")
+                .long("deny-peer")
+                .value_name("PEER_ID")
+                .multiple(true)
+                .number_of_values(1)
+                .help("do not output rows of this peer, can be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("cid")
+                .long("cid")
+                .value_name("CID")
+                .multiple(true)
+                .number_of_values(1)
+                .help("only output rows for this CID, can be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("cid_prefix")
+                .long("cid-prefix")
+                .value_name("PREFIX")
+                .multiple(true)
+                .number_of_values(1)
+                .help("only output rows for CIDs with this prefix, can be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .help("the number of threads to run the simulation on, 0 for one per core, overrides the config"),
+        )
+        .arg(
+            Arg::with_name("validate")
+                .long("validate")
+                .help("only scan the input files for anomalies and produce a report, exits with code 2 if thresholds are exceeded"),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .value_name("PATH")
+                .requires("validate")
+                .help("the file to write the validation report to, defaults to stdout"),
+        )
         .get_matches();
 
     if !matches.is_present("cfg") {
@@ -43,8 +118,48 @@ fn main() -> Result<()> {
     let config = matches.value_of("cfg").unwrap();
 
     info!("attempting to load config from file '{}'", config);
-    let config = config::Config::open(config).context("unable to load config")?;
+    let mut config = config::Config::open(config).context("unable to load config")?;
+    config
+        .filter
+        .merge_cli_args(&matches)
+        .context("invalid filter arguments")?;
+    if let Some(threads) = matches.value_of("threads") {
+        config.simulation_threads = threads
+            .parse::<usize>()
+            .context("invalid number of simulation threads")?;
+    }
+    if config.simulation_threads == 0 {
+        config.simulation_threads = std::thread::available_parallelism()
+            .context("unable to determine number of cores")?
+            .get();
+    }
+
+    info!("input format is {:?}", config.input_format);
+
+    if matches.is_present("validate") {
+        debug!("validation config is {:?}", config.validation);
+        let report = validate::validate(&config).context("unable to validate traces")?;
+        match matches.value_of("report") {
+            Some(path) => serde_json::to_writer_pretty(
+                File::create(path).context("unable to create report file")?,
+                &report,
+            )
+            .context("unable to write report")?,
+            None => println!(
+                "{}",
+                serde_json::to_string_pretty(&report).context("unable to serialize report")?
+            ),
+        }
+
+        if !report.passed() {
+            error!("validation thresholds exceeded");
+            std::process::exit(2);
+        }
+        info!("validation passed");
+        return Ok(());
+    }
 
+    info!("output format is {:?}", config.output_format);
     info!(
         "output file for wantlist entries is {}",
         config.wantlist_output_file_pattern
@@ -57,10 +172,24 @@ fn main() -> Result<()> {
         "output file for connection durations is {}",
         config.connection_duration_output_file
     );
+    info!(
+        "output file for sessions is {:?}",
+        config.session_output_file
+    );
     info!(
         "output file for ledger counts is {}",
         config.ledger_count_output_file
     );
+    info!("output file for blocks is {:?}", config.block_output_file);
+    info!(
+        "output file for block presences is {:?}",
+        config.block_presence_output_file
+    );
+    info!("output database is {:?}", config.sqlite_output_file);
+    info!("summary file is {}", config.summary_output_file);
+    info!("output filter is {:?}", config.filter);
+    info!("anonymization is {:?}", config.anonymization);
+    info!("simulation threads: {}", config.simulation_threads);
     debug!("simulation config is {:?}", config.simulation_config);
 
     do_transform(config).context("unable to do transformation")?;
@@ -73,20 +202,56 @@ struct SingleFileTransformResult {
     num_missing_ledgers: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct CSVLedgerCount {
     ts_secs: i64,
     missing_ledgers: usize,
     total_ledgers: usize,
 }
 
+impl ParquetRecord for CSVLedgerCount {
+    const PARQUET_SCHEMA: &'static str = "
+        message ledger_count {
+            REQUIRED INT64 ts_secs;
+            REQUIRED INT64 missing_ledgers (UINT_64);
+            REQUIRED INT64 total_ledgers (UINT_64);
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_i64(records.iter().map(|r| r.ts_secs))?;
+        w.write_u64(records.iter().map(|r| r.missing_ledgers as u64))?;
+        w.write_u64(records.iter().map(|r| r.total_ledgers as u64))?;
+        Ok(())
+    }
+}
+
+type Sink<T> = Box<dyn RecordSink<T>>;
+
+/// Outputs written to for every input file.
+struct SharedOutputs {
+    conn_writer: Sink<CSVConnectionEvent>,
+    sqlite: Option<SqliteOutput>,
+    /// Decides which rows are written to the outputs.
+    filter: OutputFilter,
+    /// Anonymizes rows before they are written, if configured.
+    anonymizer: Option<Anonymizer>,
+    /// Statistics about the run, written as a summary at the end.
+    summary: RunSummary,
+}
+
+/// Outputs and state specific to recordings of `PushedEvent`s.
+struct PushedEventOutputs {
+    converter: PushedEventConverter,
+    block_writer: Option<Sink<CSVBlock>>,
+    block_presence_writer: Option<Sink<CSVBlockPresence>>,
+}
+
 fn do_transform_single_file(
     mut infile: BufReader<GzDecoder<File>>,
-    wl_writer: &mut csv::Writer<GzEncoder<BufWriter<File>>>,
-    conn_writer: &mut csv::Writer<GzEncoder<BufWriter<File>>>,
-    engine: &mut wantlist::EngineSimulation,
+    wl_writer: &mut RotatingWriter<CSVWantlistEntry>,
+    outputs: &mut SharedOutputs,
+    sim: &mut Simulation,
     current_message_id: &mut i64,
-    conn_tracker: &mut ConnectionDurationTracker,
 ) -> Result<SingleFileTransformResult> {
     let mut buf = String::new();
     let mut first_message_ts = None;
@@ -105,32 +270,11 @@ fn do_transform_single_file(
         }
         last_message_ts.replace(message.timestamp);
 
-        // Add to connection tracker.
-        conn_tracker
-            .push(&message)
-            .context("unable to track connection duration")?;
-
-        // Update simulated wantlists.
-        let ingest_result = engine.ingest(&message, *current_message_id)?;
-        debug!("ingest result: {:?}", ingest_result);
-
-        if ingest_result.missing_ledger {
-            missing_ledgers += 1;
-        }
-        if let Some(entries) = ingest_result.wantlist_entries.as_ref() {
-            entries
-                .iter()
-                .try_for_each(|e| wl_writer.serialize(e))
-                .context("unable to serialize wantlist entries")?;
-        }
-        if let Some(conn_event) = ingest_result.connection_event.as_ref() {
-            conn_writer
-                .serialize(conn_event)
-                .context("unable to serialize connection event")?;
-        }
+        missing_ledgers += sim.ingest(message, *current_message_id, wl_writer, outputs)?;
 
         buf.clear();
     }
+    missing_ledgers += sim.sync(wl_writer, outputs)?;
 
     Ok(SingleFileTransformResult {
         timestamps: if let Some(first) = first_message_ts {
@@ -142,34 +286,175 @@ fn do_transform_single_file(
     })
 }
 
+fn do_transform_single_pushed_events_file(
+    infile: BufReader<GzDecoder<File>>,
+    wl_writer: &mut RotatingWriter<CSVWantlistEntry>,
+    outputs: &mut SharedOutputs,
+    sim: &mut Simulation,
+    current_message_id: &mut i64,
+    pushed_outputs: &mut PushedEventOutputs,
+) -> Result<SingleFileTransformResult> {
+    let mut first_message_ts = None;
+    let mut last_message_ts = None;
+    let mut missing_ledgers = 0;
+    for event in pushed::read_events(infile) {
+        let event = event.context("unable to decode event")?;
+        debug!("decoded event {:?}", event);
+        *current_message_id += 1;
+
+        if first_message_ts.is_none() {
+            first_message_ts = Some(event.timestamp);
+        }
+        last_message_ts.replace(event.timestamp);
+
+        // Write blocks and block presences.
+        let (blocks, presences) = pushed::blocks_and_presences(&event, *current_message_id);
+        let anonymizer = outputs.anonymizer.as_ref();
+        if let Some(w) = pushed_outputs.block_writer.as_mut() {
+            blocks
+                .iter()
+                .filter(|b| outputs.filter.block(b))
+                .try_for_each(|b| w.write(&anonymized(b, anonymizer)))
+                .context("unable to serialize blocks")?;
+        }
+        if let Some(w) = pushed_outputs.block_presence_writer.as_mut() {
+            presences
+                .iter()
+                .filter(|p| outputs.filter.block_presence(p))
+                .try_for_each(|p| w.write(&anonymized(p, anonymizer)))
+                .context("unable to serialize block presences")?;
+        }
+
+        // Convert to a message the simulation understands, if relevant to the simulation.
+        if let Some(message) = pushed_outputs.converter.convert(&event) {
+            missing_ledgers += sim.ingest(message, *current_message_id, wl_writer, outputs)?;
+        }
+    }
+    missing_ledgers += sim.sync(wl_writer, outputs)?;
+
+    Ok(SingleFileTransformResult {
+        timestamps: first_message_ts.map(|first| (first, last_message_ts.unwrap())),
+        num_missing_ledgers: missing_ledgers,
+    })
+}
+
+/// Writes the results of simulating a message to the outputs.
+/// Returns whether the ledger for the peer was missing.
+fn write_ingest_result(
+    message_id: i64,
+    peer: &str,
+    ingest_result: &wantlist::IngestResult,
+    wl_writer: &mut RotatingWriter<CSVWantlistEntry>,
+    outputs: &mut SharedOutputs,
+) -> Result<bool> {
+    outputs.summary.record_message(peer, ingest_result);
+
+    if let Some(entries) = ingest_result.wantlist_entries.as_ref() {
+        let filter = &outputs.filter;
+        let anonymizer = outputs.anonymizer.as_ref();
+        let entries: Vec<_> = entries
+            .iter()
+            .filter(|e| filter.wantlist_entry(e))
+            .map(|e| anonymized(e, anonymizer))
+            .collect();
+        entries
+            .iter()
+            .try_for_each(|e| wl_writer.write(e))
+            .context("unable to serialize wantlist entries")?;
+        if let Some(db) = outputs.sqlite.as_mut() {
+            entries
+                .iter()
+                .try_for_each(|e| db.insert_wantlist_entry(e))?;
+        }
+    }
+    wl_writer
+        .end_message(message_id)
+        .context("unable to rotate wantlist output")?;
+    if let Some(conn_event) = ingest_result
+        .connection_event
+        .as_ref()
+        .filter(|e| outputs.filter.connection_event(e))
+    {
+        let conn_event = anonymized(conn_event, outputs.anonymizer.as_ref());
+        outputs
+            .conn_writer
+            .write(&conn_event)
+            .context("unable to serialize connection event")?;
+        if let Some(db) = outputs.sqlite.as_mut() {
+            db.insert_connection_event(&conn_event)?;
+        }
+    }
+
+    Ok(ingest_result.missing_ledger)
+}
+
 fn do_transform(cfg: config::Config) -> Result<()> {
-    let mut engine = wantlist::EngineSimulation::new(cfg.simulation_config.clone())
+    let mut sim = Simulation::new(&cfg.simulation_config, cfg.simulation_threads)
         .context("unable to set up engine simulation")?;
     let mut current_message_id: i64 = 0;
-    let mut conn_tracker = ConnectionDurationTracker::new();
     let mut final_ts = None;
-
-    let mut conn_events_output_writer = csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(cfg.connection_events_output_file.clone())
-                .context("unable to open connection events output file for writing")?,
-        ),
-        Compression::default(),
-    ));
-    let mut connection_durations_output_writer = csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(cfg.connection_duration_output_file.clone())
-                .context("unable to open connection duration output file for writing")?,
-        ),
-        Compression::default(),
-    ));
-    let mut ledger_count_output_writer = csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(cfg.ledger_count_output_file.clone())
-                .context("unable to open missing ledgers output file for writing")?,
-        ),
-        Compression::default(),
-    ));
+    let run_before = std::time::Instant::now();
+
+    let mut shared_outputs = SharedOutputs {
+        conn_writer: create_sink(&cfg.connection_events_output_file, cfg.output_format)
+            .context("unable to open connection events output file for writing")?,
+        sqlite: cfg
+            .sqlite_output_file
+            .as_ref()
+            .map(|path| SqliteOutput::create(path))
+            .transpose()
+            .context("unable to create SQLite output database")?,
+        filter: OutputFilter::new(&cfg.filter),
+        anonymizer: cfg
+            .anonymization
+            .as_ref()
+            .map(Anonymizer::new)
+            .transpose()
+            .context("unable to set up anonymization")?,
+        summary: RunSummary::new(sim.config().clone()),
+    };
+    let mut connection_durations_output_writer: Sink<CSVConnectionMetadata> =
+        create_sink(&cfg.connection_duration_output_file, cfg.output_format)
+            .context("unable to open connection duration output file for writing")?;
+    let mut session_output_writer: Option<Sink<CSVSessionMetadata>> = cfg
+        .session_output_file
+        .as_ref()
+        .map(|path| create_sink(path, cfg.output_format))
+        .transpose()
+        .context("unable to open session output file for writing")?;
+    let mut ledger_count_output_writer =
+        create_sink(&cfg.ledger_count_output_file, cfg.output_format)
+            .context("unable to open missing ledgers output file for writing")?;
+
+    if cfg.input_format != InputFormat::PushedEvents
+        && (cfg.block_output_file.is_some() || cfg.block_presence_output_file.is_some())
+    {
+        warn!("block and block presence outputs are only produced for input format pushed_events")
+    }
+    let mut pushed_outputs = PushedEventOutputs {
+        converter: PushedEventConverter::new(),
+        block_writer: cfg
+            .block_output_file
+            .as_ref()
+            .map(|path| create_sink(path, cfg.output_format))
+            .transpose()
+            .context("unable to open block output file for writing")?,
+        block_presence_writer: cfg
+            .block_presence_output_file
+            .as_ref()
+            .map(|path| create_sink(path, cfg.output_format))
+            .transpose()
+            .context("unable to open block presence output file for writing")?,
+    };
+
+    // Without any rotation limits, we start a new wantlist output file for every input file.
+    let rotate_per_input_file = cfg.wantlist_output_rotation.is_unbounded();
+    let mut wl_output_writer = RotatingWriter::new(
+        &cfg.wantlist_output_file_pattern,
+        cfg.output_format,
+        cfg.wantlist_output_rotation.clone(),
+    )
+    .context("unable to set up wantlist output")?;
 
     let input_files = cfg.glob_results().context("unable to glob")?;
     debug!("paths: {:?}", input_files);
@@ -180,23 +465,43 @@ fn do_transform(cfg: config::Config) -> Result<()> {
             std::fs::File::open(&path).context("unable to open input file for reading")?,
         ));
 
-        let mut wl_output_writer =
-            create_wl_output_writer(cfg.wantlist_output_file_pattern.clone(), current_message_id)
-                .context("unable to create output file")?;
-
+        shared_outputs
+            .summary
+            .begin_file(path.display().to_string());
         let id_before = current_message_id;
         let before = std::time::Instant::now();
-        let transform_result = do_transform_single_file(
-            input_file,
-            &mut wl_output_writer,
-            &mut conn_events_output_writer,
-            &mut engine,
-            &mut current_message_id,
-            &mut conn_tracker,
-        )
+        let transform_result = match cfg.input_format {
+            InputFormat::JsonMessages => do_transform_single_file(
+                input_file,
+                &mut wl_output_writer,
+                &mut shared_outputs,
+                &mut sim,
+                &mut current_message_id,
+            ),
+            InputFormat::PushedEvents => do_transform_single_pushed_events_file(
+                input_file,
+                &mut wl_output_writer,
+                &mut shared_outputs,
+                &mut sim,
+                &mut current_message_id,
+                &mut pushed_outputs,
+            ),
+        }
         .context(format!("unable to process file {}", path.display()))?;
+        if rotate_per_input_file {
+            wl_output_writer
+                .rotate()
+                .context("unable to finish wantlist output file")?;
+        }
         let num_messages = current_message_id - id_before;
         let time_diff = before.elapsed();
+        shared_outputs.summary.finish_file(
+            num_messages as u64,
+            transform_result.timestamps,
+            transform_result.num_missing_ledgers,
+            sim.num_ledgers(),
+            time_diff,
+        );
 
         info!(
             "processed {} messages in {:.1}s => {:.1}msg/s",
@@ -209,13 +514,19 @@ fn do_transform(cfg: config::Config) -> Result<()> {
             Some((first, last)) => {
                 info!("first ts: {}, last ts: {}", first, last);
 
-                ledger_count_output_writer
-                    .serialize(CSVLedgerCount {
-                        ts_secs: last.timestamp(),
-                        missing_ledgers: transform_result.num_missing_ledgers,
-                        total_ledgers: engine.num_ledgers(),
-                    })
-                    .context("unable to serialize missing ledgers record")?;
+                let ledger_count = CSVLedgerCount {
+                    ts_secs: last.timestamp(),
+                    missing_ledgers: transform_result.num_missing_ledgers,
+                    total_ledgers: sim.num_ledgers(),
+                };
+                if shared_outputs.filter.ledger_count(ledger_count.ts_secs) {
+                    ledger_count_output_writer
+                        .write(&ledger_count)
+                        .context("unable to serialize missing ledgers record")?;
+                    if let Some(db) = shared_outputs.sqlite.as_mut() {
+                        db.insert_ledger_count(&ledger_count)?;
+                    }
+                }
 
                 final_ts.replace(last);
             }
@@ -224,21 +535,58 @@ fn do_transform(cfg: config::Config) -> Result<()> {
         info!(
             "{} missing ledgers, {} ledgers total",
             transform_result.num_missing_ledgers,
-            engine.num_ledgers()
+            sim.num_ledgers()
         );
     }
 
+    let (engines, conn_trackers): (Vec<_>, Vec<_>) = sim
+        .finish()
+        .context("unable to stop engine simulation")?
+        .into_iter()
+        .unzip();
+
     info!("finalizing connection tracker...");
     if let Some(ts) = final_ts {
-        let connections = conn_tracker.finalize(ts);
+        // Sort by peer, so that the output does not depend on hash map order or sharding.
+        let mut connections: Vec<_> = conn_trackers
+            .into_iter()
+            .flat_map(|t| t.finalize(ts))
+            .collect();
+        connections.sort_by(|(a, _), (b, _)| a.cmp(b));
 
         info!("writing connections CSV...");
-        for (peer_id, conns) in connections.into_iter() {
-            for c in conns.into_iter() {
+        for (peer_id, conns) in connections {
+            for c in conns.iter() {
                 let to_encode = c.to_csv(peer_id.clone());
+                shared_outputs
+                    .summary
+                    .record_connection(c.duration(), to_encode.synthetic_close);
+                if !shared_outputs.filter.connection_duration(&to_encode) {
+                    continue;
+                }
+                let to_encode = anonymized(&to_encode, shared_outputs.anonymizer.as_ref());
                 connection_durations_output_writer
-                    .serialize(to_encode)
+                    .write(&to_encode)
                     .context("unable to serialize connection metadata")?;
+                if let Some(db) = shared_outputs.sqlite.as_mut() {
+                    db.insert_connection_duration(&to_encode)?;
+                }
+            }
+
+            for s in SessionMetadata::from_connections(&conns) {
+                shared_outputs.summary.record_session(s.duration());
+                let to_encode = s.to_csv(peer_id.clone());
+                if !shared_outputs.filter.session(&to_encode) {
+                    continue;
+                }
+                let to_encode = anonymized(&to_encode, shared_outputs.anonymizer.as_ref());
+                if let Some(w) = session_output_writer.as_mut() {
+                    w.write(&to_encode)
+                        .context("unable to serialize session metadata")?;
+                }
+                if let Some(db) = shared_outputs.sqlite.as_mut() {
+                    db.insert_session(&to_encode)?;
+                }
             }
         }
     } else {
@@ -247,33 +595,69 @@ fn do_transform(cfg: config::Config) -> Result<()> {
 
     info!("finalizing engine simulation...");
     if let Some(ts) = final_ts {
-        let end_of_simulation_cancels =
-            engine.generate_end_of_simulation_entries(ts, current_message_id + 1);
-
-        let mut wl_output_writer =
-            create_wl_output_writer(cfg.wantlist_output_file_pattern.clone(), current_message_id)
-                .context("unable to create output file")?;
-
+        let mut end_of_simulation_cancels: Vec<_> = engines
+            .into_iter()
+            .flat_map(|e| e.generate_end_of_simulation_entries(ts, current_message_id + 1))
+            .collect();
+        // Sort, so that the output does not depend on hash map order or sharding.
+        end_of_simulation_cancels.sort_by(|a, b| (&a.peer_id, &a.cid).cmp(&(&b.peer_id, &b.cid)));
+        shared_outputs
+            .summary
+            .record_end_of_simulation_entries(&end_of_simulation_cancels);
+
+        let filter = &shared_outputs.filter;
+        let anonymizer = shared_outputs.anonymizer.as_ref();
+        let end_of_simulation_cancels: Vec<_> = end_of_simulation_cancels
+            .iter()
+            .filter(|e| filter.wantlist_entry(e))
+            .map(|e| anonymized(e, anonymizer))
+            .collect();
         end_of_simulation_cancels
             .iter()
-            .try_for_each(|e| wl_output_writer.serialize(e))
+            .try_for_each(|e| wl_output_writer.write(e))
             .context("unable to serialize end-of-simulation synthetic cancels")?;
+        if let Some(db) = shared_outputs.sqlite.as_mut() {
+            end_of_simulation_cancels
+                .iter()
+                .try_for_each(|e| db.insert_wantlist_entry(e))?;
+        }
     } else {
         warn!("missing final timestamp, unable to finalize")
     }
 
-    Ok(())
-}
+    wl_output_writer
+        .finish()
+        .context("unable to finish wantlist output")?;
+    shared_outputs
+        .conn_writer
+        .finish()
+        .context("unable to finish connection events output file")?;
+    connection_durations_output_writer
+        .finish()
+        .context("unable to finish connection duration output file")?;
+    if let Some(w) = session_output_writer {
+        w.finish().context("unable to finish session output file")?;
+    }
+    ledger_count_output_writer
+        .finish()
+        .context("unable to finish ledger count output file")?;
+    if let Some(w) = pushed_outputs.block_writer {
+        w.finish().context("unable to finish block output file")?;
+    }
+    if let Some(w) = pushed_outputs.block_presence_writer {
+        w.finish()
+            .context("unable to finish block presence output file")?;
+    }
+    if let Some(db) = shared_outputs.sqlite {
+        db.finish()
+            .context("unable to finish SQLite output database")?;
+    }
+
+    shared_outputs
+        .summary
+        .finish(run_before.elapsed())
+        .write_to(&cfg.summary_output_file)
+        .context("unable to write summary")?;
 
-fn create_wl_output_writer(
-    pattern: String,
-    current_message_id: i64,
-) -> Result<Writer<GzEncoder<BufWriter<std::fs::File>>>> {
-    Ok(csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(pattern.replace("$id$", &format!("{:09}", current_message_id)))
-                .context("unable to open wantlist output file for writing")?,
-        ),
-        Compression::default(),
-    )))
+    Ok(())
 }
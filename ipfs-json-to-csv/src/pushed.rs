@@ -0,0 +1,237 @@
+use ipfs_monitoring_plugin_client::monitoring::{BlockPresenceType, EventType, PushedEvent};
+use ipfs_resolver_common::anonymize::{Anonymize, Anonymizer};
+use ipfs_resolver_common::output::{ParquetColumnWriter, ParquetRecord};
+use ipfs_resolver_common::Result;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// Block presence type constants for CSV files.
+pub(crate) const CSV_BLOCK_PRESENCE_TYPE_HAVE: i32 = 1;
+pub(crate) const CSV_BLOCK_PRESENCE_TYPE_DONT_HAVE: i32 = 2;
+
+/// A recorded object, which is either a batch of events as pushed by the monitoring plugin, or a
+/// single event.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum RecordedEvents {
+    Batch(Vec<PushedEvent>),
+    Single(PushedEvent),
+}
+
+/// Reads all events from a recording of `PushedEvent`s.
+/// The recording is expected to contain a stream of JSON values, each of which is either a batch
+/// (i.e., an array) of events or a single event.
+pub(crate) fn read_events<R: Read>(reader: R) -> impl Iterator<Item = Result<PushedEvent>> {
+    serde_json::Deserializer::from_reader(reader)
+        .into_iter::<RecordedEvents>()
+        .flat_map(|res| {
+            let events: Vec<Result<PushedEvent>> = match res {
+                Ok(RecordedEvents::Batch(events)) => events.into_iter().map(Ok).collect(),
+                Ok(RecordedEvents::Single(event)) => vec![Ok(event)],
+                Err(err) => vec![Err(err.into())],
+            };
+            events
+        })
+}
+
+/// A block received via Bitswap, to be serialized as CSV.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CSVBlock {
+    /// The ID of the message this block was received in.
+    /// This is consistent with the `message_id` of wantlist entries.
+    pub(crate) message_id: i64,
+
+    /// Timestamp as seconds since the Unix epoch.
+    pub(crate) timestamp_seconds: i64,
+    /// Sub-second milliseconds of the timestamp.
+    pub(crate) timestamp_subsec_milliseconds: u32,
+
+    /// The ID of the sending peer.
+    pub(crate) peer_id: String,
+    /// The underlay multiaddress of the sending peer, if available.
+    pub(crate) address: String,
+
+    /// The human-readable CID of the block, not normalized.
+    pub(crate) cid: String,
+}
+
+impl ParquetRecord for CSVBlock {
+    const PARQUET_SCHEMA: &'static str = "
+        message block {
+            REQUIRED INT64 message_id;
+            REQUIRED INT64 timestamp_seconds;
+            REQUIRED INT32 timestamp_subsec_milliseconds (UINT_32);
+            REQUIRED BINARY peer_id (UTF8);
+            REQUIRED BINARY address (UTF8);
+            REQUIRED BINARY cid (UTF8);
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_i64(records.iter().map(|r| r.message_id))?;
+        w.write_i64(records.iter().map(|r| r.timestamp_seconds))?;
+        w.write_u32(records.iter().map(|r| r.timestamp_subsec_milliseconds))?;
+        w.write_str(records.iter().map(|r| r.peer_id.as_str()))?;
+        w.write_str(records.iter().map(|r| r.address.as_str()))?;
+        w.write_str(records.iter().map(|r| r.cid.as_str()))?;
+        Ok(())
+    }
+}
+
+impl Anonymize for CSVBlock {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.peer_id = anonymizer.pseudonymize_peer(&self.peer_id);
+        self.address = anonymizer.anonymize_address(&self.address);
+    }
+}
+
+/// A block presence received via Bitswap, to be serialized as CSV.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CSVBlockPresence {
+    /// The ID of the message this block presence was received in.
+    /// This is consistent with the `message_id` of wantlist entries.
+    pub(crate) message_id: i64,
+
+    /// Timestamp as seconds since the Unix epoch.
+    pub(crate) timestamp_seconds: i64,
+    /// Sub-second milliseconds of the timestamp.
+    pub(crate) timestamp_subsec_milliseconds: u32,
+
+    /// The ID of the sending peer.
+    pub(crate) peer_id: String,
+    /// The underlay multiaddress of the sending peer, if available.
+    pub(crate) address: String,
+
+    /// The human-readable CID, not normalized.
+    pub(crate) cid: String,
+    /// Block presence type, see `CSV_BLOCK_PRESENCE_TYPE_` constants.
+    pub(crate) presence_type: i32,
+}
+
+impl ParquetRecord for CSVBlockPresence {
+    const PARQUET_SCHEMA: &'static str = "
+        message block_presence {
+            REQUIRED INT64 message_id;
+            REQUIRED INT64 timestamp_seconds;
+            REQUIRED INT32 timestamp_subsec_milliseconds (UINT_32);
+            REQUIRED BINARY peer_id (UTF8);
+            REQUIRED BINARY address (UTF8);
+            REQUIRED BINARY cid (UTF8);
+            REQUIRED INT32 presence_type;
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_i64(records.iter().map(|r| r.message_id))?;
+        w.write_i64(records.iter().map(|r| r.timestamp_seconds))?;
+        w.write_u32(records.iter().map(|r| r.timestamp_subsec_milliseconds))?;
+        w.write_str(records.iter().map(|r| r.peer_id.as_str()))?;
+        w.write_str(records.iter().map(|r| r.address.as_str()))?;
+        w.write_str(records.iter().map(|r| r.cid.as_str()))?;
+        w.write_i32(records.iter().map(|r| r.presence_type))?;
+        Ok(())
+    }
+}
+
+impl Anonymize for CSVBlockPresence {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.peer_id = anonymizer.pseudonymize_peer(&self.peer_id);
+        self.address = anonymizer.anonymize_address(&self.address);
+    }
+}
+
+/// Creates CSV records for the blocks and block presences contained in an event.
+pub(crate) fn blocks_and_presences(
+    event: &PushedEvent,
+    message_id: i64,
+) -> (Vec<CSVBlock>, Vec<CSVBlockPresence>) {
+    let msg = match &event.inner {
+        EventType::BitswapMessage(msg) => msg,
+        EventType::ConnectionEvent(_) => return (Vec::new(), Vec::new()),
+    };
+    let timestamp_seconds = event.timestamp.timestamp();
+    let timestamp_subsec_milliseconds = event.timestamp.timestamp_subsec_millis();
+    let address = msg.connected_addresses.first().cloned().unwrap_or_default();
+
+    let blocks = msg
+        .blocks
+        .iter()
+        .map(|cid| CSVBlock {
+            message_id,
+            timestamp_seconds,
+            timestamp_subsec_milliseconds,
+            peer_id: event.peer.clone(),
+            address: address.clone(),
+            cid: cid.path.clone(),
+        })
+        .collect();
+    let presences = msg
+        .block_presences
+        .iter()
+        .map(|p| CSVBlockPresence {
+            message_id,
+            timestamp_seconds,
+            timestamp_subsec_milliseconds,
+            peer_id: event.peer.clone(),
+            address: address.clone(),
+            cid: p.cid.path.clone(),
+            presence_type: match p.block_presence_type {
+                BlockPresenceType::Have => CSV_BLOCK_PRESENCE_TYPE_HAVE,
+                BlockPresenceType::DontHave => CSV_BLOCK_PRESENCE_TYPE_DONT_HAVE,
+            },
+        })
+        .collect();
+
+    (blocks, presences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORDING: &str = r#"[{"timestamp":"2021-01-01T00:00:01.250Z","peer":"peer1","bitswap_message":{"wantlist_entries":[],"full_wantlist":false,"blocks":[{"/":"QmBlock"}],"block_presences":[{"cid":{"/":"QmHave"},"block_presence_type":0},{"cid":{"/":"QmDontHave"},"block_presence_type":1}],"connected_addresses":["/ip4/1.2.3.4/tcp/4001"]}}]
+{"timestamp":"2021-01-01T00:00:02Z","peer":"peer2","connection_event":{"remote":"/ip4/5.6.7.8/tcp/4001","connection_event_type":0}}
+"#;
+
+    #[test]
+    fn read_batches_and_single_events() {
+        let events: Vec<_> = read_events(RECORDING.as_bytes())
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].peer, "peer1");
+        assert_eq!(events[1].peer, "peer2");
+
+        assert!(read_events("{\"invalid\": true}".as_bytes())
+            .next()
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn convert_blocks_and_presences() {
+        let events: Vec<_> = read_events(RECORDING.as_bytes())
+            .collect::<Result<_>>()
+            .unwrap();
+
+        let (blocks, presences) = blocks_and_presences(&events[0], 7);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].message_id, 7);
+        assert_eq!(blocks[0].timestamp_seconds, 1609459201);
+        assert_eq!(blocks[0].timestamp_subsec_milliseconds, 250);
+        assert_eq!(blocks[0].address, "/ip4/1.2.3.4/tcp/4001");
+        assert_eq!(blocks[0].cid, "QmBlock");
+        assert_eq!(
+            presences
+                .iter()
+                .map(|p| (p.cid.as_str(), p.presence_type))
+                .collect::<Vec<_>>(),
+            vec![
+                ("QmHave", CSV_BLOCK_PRESENCE_TYPE_HAVE),
+                ("QmDontHave", CSV_BLOCK_PRESENCE_TYPE_DONT_HAVE)
+            ]
+        );
+
+        let (blocks, presences) = blocks_and_presences(&events[1], 8);
+        assert!(blocks.is_empty());
+        assert!(presences.is_empty());
+    }
+}
@@ -0,0 +1,547 @@
+use crate::conntrack::ConnectionDurationTracker;
+use crate::{write_ingest_result, SharedOutputs};
+use failure::{err_msg, ResultExt};
+use ipfs_resolver_common::rotation::RotatingWriter;
+use ipfs_resolver_common::wantlist::{
+    CSVWantlistEntry, EngineSimulation, EngineSimulationConfig, IngestResult, JSONMessage,
+};
+use ipfs_resolver_common::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TryRecvError};
+use std::thread::JoinHandle;
+
+/// The number of messages sent to a worker at once.
+const BATCH_SIZE: usize = 256;
+
+/// The number of batches queued per worker before the decoding stage blocks.
+const BATCHES_PER_WORKER_QUEUE: usize = 16;
+
+/// The number of messages that have been dispatched to workers, but whose results have not yet
+/// been written, before the decoding stage blocks.
+/// This bounds memory usage if one worker lags behind, e.g., because of a very active peer.
+const MAX_PENDING_MESSAGES: usize = 1 << 16;
+
+/// Feeds a message to the connection tracker and engine simulation.
+fn simulate(
+    engine: &mut EngineSimulation,
+    conn_tracker: &mut ConnectionDurationTracker,
+    message: &JSONMessage,
+    message_id: i64,
+) -> Result<IngestResult> {
+    // Add to connection tracker.
+    conn_tracker
+        .push(message)
+        .context("unable to track connection duration")?;
+
+    // Update simulated wantlists.
+    let ingest_result = engine.ingest(message, message_id)?;
+    debug!("ingest result: {:?}", ingest_result);
+
+    Ok(ingest_result)
+}
+
+/// The engine simulation and connection tracker.
+///
+/// Both of these only keep state per peer, so they can be sharded by peer over multiple worker
+/// threads.
+/// Results are always written in order of message ID, so the outputs do not depend on the number
+/// of threads.
+pub(crate) enum Simulation {
+    /// Everything runs on the current thread.
+    Sequential {
+        engine: EngineSimulation,
+        conn_tracker: ConnectionDurationTracker,
+    },
+
+    /// Messages are sharded by hash of the peer ID over worker threads.
+    Sharded(ShardedSimulation),
+}
+
+impl Simulation {
+    /// Sets up the simulation, using the given number of worker threads.
+    /// For one thread, everything runs on the current thread.
+    pub(crate) fn new(cfg: &EngineSimulationConfig, threads: usize) -> Result<Simulation> {
+        if threads <= 1 {
+            return Ok(Simulation::Sequential {
+                engine: EngineSimulation::new(cfg.clone())?,
+                conn_tracker: ConnectionDurationTracker::new(),
+            });
+        }
+
+        Ok(Simulation::Sharded(ShardedSimulation::new(cfg, threads)?))
+    }
+
+    /// Returns the effective configuration of the simulation.
+    pub(crate) fn config(&self) -> &EngineSimulationConfig {
+        match self {
+            Simulation::Sequential { engine, .. } => engine.config(),
+            Simulation::Sharded(s) => &s.cfg,
+        }
+    }
+
+    /// Returns the number of ledgers simulated up to the last written result.
+    pub(crate) fn num_ledgers(&self) -> usize {
+        match self {
+            Simulation::Sequential { engine, .. } => engine.num_ledgers(),
+            Simulation::Sharded(s) => s.shards.iter().map(|s| s.num_ledgers).sum(),
+        }
+    }
+
+    /// Feeds a message to the simulation.
+    /// The results of this and earlier messages are written to the outputs as they become
+    /// available.
+    /// Returns the number of missing ledgers among the written results.
+    pub(crate) fn ingest(
+        &mut self,
+        message: JSONMessage,
+        message_id: i64,
+        wl_writer: &mut RotatingWriter<CSVWantlistEntry>,
+        outputs: &mut SharedOutputs,
+    ) -> Result<usize> {
+        match self {
+            Simulation::Sequential {
+                engine,
+                conn_tracker,
+            } => {
+                let ingest_result = simulate(engine, conn_tracker, &message, message_id)?;
+                let missing_ledger = write_ingest_result(
+                    message_id,
+                    &message.peer,
+                    &ingest_result,
+                    wl_writer,
+                    outputs,
+                )?;
+                Ok(missing_ledger as usize)
+            }
+            Simulation::Sharded(s) => s.ingest(message, message_id, wl_writer, outputs),
+        }
+    }
+
+    /// Waits for all messages to be processed and writes their results.
+    /// Returns the number of missing ledgers among the written results.
+    pub(crate) fn sync(
+        &mut self,
+        wl_writer: &mut RotatingWriter<CSVWantlistEntry>,
+        outputs: &mut SharedOutputs,
+    ) -> Result<usize> {
+        match self {
+            Simulation::Sequential { .. } => Ok(0),
+            Simulation::Sharded(s) => s.write_results(0, wl_writer, outputs),
+        }
+    }
+
+    /// Stops the simulation and returns the engine simulations and connection trackers of all
+    /// shards.
+    /// All results must have been written, see `sync`.
+    pub(crate) fn finish(self) -> Result<Vec<(EngineSimulation, ConnectionDurationTracker)>> {
+        match self {
+            Simulation::Sequential {
+                engine,
+                conn_tracker,
+            } => Ok(vec![(engine, conn_tracker)]),
+            Simulation::Sharded(s) => s.finish(),
+        }
+    }
+}
+
+/// The result of ingesting one message in a worker.
+struct ShardResult {
+    message_id: i64,
+    peer: String,
+    ingest_result: IngestResult,
+    /// The number of ledgers of the shard after ingesting the message.
+    num_ledgers: usize,
+}
+
+type Batch = Vec<(i64, JSONMessage)>;
+
+/// A worker thread and its queues.
+struct Shard {
+    input: SyncSender<Batch>,
+    batch: Batch,
+    results: Receiver<Result<Vec<ShardResult>>>,
+    buffered: VecDeque<ShardResult>,
+    /// The number of ledgers of the shard, as of the last written result.
+    num_ledgers: usize,
+    handle: JoinHandle<(EngineSimulation, ConnectionDurationTracker)>,
+}
+
+impl Shard {
+    /// Sends the current batch of messages to the worker, if it is not empty.
+    fn flush(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+        if self.input.send(batch).is_err() {
+            // The worker stopped, most likely because of an error, which we'd like to report.
+            while let Ok(results) = self.results.try_recv() {
+                results?;
+            }
+            return Err(err_msg("simulation worker died"));
+        }
+        Ok(())
+    }
+}
+
+/// Runs the simulation on worker threads, one shard each.
+///
+/// Messages are decoded on the current thread and dispatched to the worker responsible for their
+/// peer.
+/// The order in which messages were dispatched is recorded, and results are written in that
+/// order, i.e., by message ID.
+pub(crate) struct ShardedSimulation {
+    cfg: EngineSimulationConfig,
+    shards: Vec<Shard>,
+    /// The shards of messages whose results have not been written yet, in order of message ID.
+    pending: VecDeque<usize>,
+}
+
+impl ShardedSimulation {
+    fn new(cfg: &EngineSimulationConfig, threads: usize) -> Result<ShardedSimulation> {
+        let effective_cfg = EngineSimulation::new(cfg.clone())?.config().clone();
+        let mut shards = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let engine = EngineSimulation::new(cfg.clone())?;
+            let (input_tx, input_rx) = std::sync::mpsc::sync_channel(BATCHES_PER_WORKER_QUEUE);
+            let (result_tx, result_rx) = std::sync::mpsc::channel();
+            let handle = std::thread::Builder::new()
+                .name(format!("simulation-{}", i))
+                .spawn(move || run_worker(engine, input_rx, result_tx))
+                .context("unable to spawn simulation worker")?;
+
+            shards.push(Shard {
+                input: input_tx,
+                batch: Vec::with_capacity(BATCH_SIZE),
+                results: result_rx,
+                buffered: VecDeque::new(),
+                num_ledgers: 0,
+                handle,
+            })
+        }
+
+        Ok(ShardedSimulation {
+            cfg: effective_cfg,
+            shards,
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn shard_of(&self, peer: &str) -> usize {
+        // DefaultHasher::new() uses fixed keys, so this is the same for every run.
+        let mut hasher = DefaultHasher::new();
+        peer.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn ingest(
+        &mut self,
+        message: JSONMessage,
+        message_id: i64,
+        wl_writer: &mut RotatingWriter<CSVWantlistEntry>,
+        outputs: &mut SharedOutputs,
+    ) -> Result<usize> {
+        let i = self.shard_of(&message.peer);
+        self.pending.push_back(i);
+        let shard = &mut self.shards[i];
+        shard.batch.push((message_id, message));
+        if shard.batch.len() >= BATCH_SIZE {
+            shard.flush()?;
+        }
+
+        self.write_results(MAX_PENDING_MESSAGES, wl_writer, outputs)
+    }
+
+    /// Writes results in order of message ID, as long as they are available.
+    /// Blocks until at most `max_pending` messages are pending.
+    /// Returns the number of missing ledgers among the written results.
+    fn write_results(
+        &mut self,
+        max_pending: usize,
+        wl_writer: &mut RotatingWriter<CSVWantlistEntry>,
+        outputs: &mut SharedOutputs,
+    ) -> Result<usize> {
+        let mut missing_ledgers = 0;
+        while let Some(&i) = self.pending.front() {
+            let block = self.pending.len() > max_pending;
+            let shard = &mut self.shards[i];
+
+            if shard.buffered.is_empty() {
+                let results = if block {
+                    // The result we're waiting for might still be in the local batch.
+                    shard.flush()?;
+                    shard
+                        .results
+                        .recv()
+                        .map_err(|_| err_msg("simulation worker died"))?
+                } else {
+                    match shard.results.try_recv() {
+                        Ok(results) => results,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            return Err(err_msg("simulation worker died"))
+                        }
+                    }
+                };
+                shard.buffered.extend(results?);
+                continue;
+            }
+
+            let result = shard.buffered.pop_front().unwrap();
+            self.pending.pop_front();
+            shard.num_ledgers = result.num_ledgers;
+            if write_ingest_result(
+                result.message_id,
+                &result.peer,
+                &result.ingest_result,
+                wl_writer,
+                outputs,
+            )? {
+                missing_ledgers += 1;
+            }
+        }
+
+        Ok(missing_ledgers)
+    }
+
+    fn finish(self) -> Result<Vec<(EngineSimulation, ConnectionDurationTracker)>> {
+        if !self.pending.is_empty() {
+            return Err(err_msg("simulation finished with pending results"));
+        }
+
+        self.shards
+            .into_iter()
+            .map(|shard| {
+                // Closing the input makes the worker return.
+                drop(shard.input);
+                shard
+                    .handle
+                    .join()
+                    .map_err(|_| err_msg("simulation worker panicked"))
+            })
+            .collect()
+    }
+}
+
+/// Runs the simulation for one shard, until the input is closed or an error occurs.
+fn run_worker(
+    mut engine: EngineSimulation,
+    input: Receiver<Batch>,
+    results: Sender<Result<Vec<ShardResult>>>,
+) -> (EngineSimulation, ConnectionDurationTracker) {
+    let mut conn_tracker = ConnectionDurationTracker::new();
+    for batch in input {
+        let batch_results: Result<Vec<_>> = batch
+            .into_iter()
+            .map(|(message_id, message)| {
+                let ingest_result = simulate(&mut engine, &mut conn_tracker, &message, message_id)
+                    .context(format!("unable to simulate message {}", message_id))?;
+                Ok(ShardResult {
+                    message_id,
+                    peer: message.peer,
+                    ingest_result,
+                    num_ledgers: engine.num_ledgers(),
+                })
+            })
+            .collect();
+
+        let failed = batch_results.is_err();
+        if results.send(batch_results).is_err() || failed {
+            break;
+        }
+    }
+
+    (engine, conn_tracker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::OutputFilter;
+    use crate::summary::RunSummary;
+    use flate2::read::GzDecoder;
+    use ipfs_resolver_common::output::{create_sink, OutputFormat};
+    use ipfs_resolver_common::rotation::RotationConfig;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::Path;
+
+    const NUM_PEERS: usize = 13;
+
+    fn config() -> EngineSimulationConfig {
+        EngineSimulationConfig {
+            allow_empty_full_wantlist: false,
+            allow_empty_connection_event: false,
+            insert_full_wantlist_synth_cancels: true,
+            insert_disconnect_synth_cancels: true,
+            reconnect_duplicate_duration_secs: 5,
+            sliding_window_lengths: vec![1, 9, 31],
+        }
+    }
+
+    /// A synthetic trace of peers connecting, exchanging wantlists, and disconnecting, split into
+    /// files.
+    fn trace() -> Vec<Vec<JSONMessage>> {
+        let mut connected = [false; NUM_PEERS];
+        let mut i = 0;
+        (0..3)
+            .map(|_| {
+                (0..1500)
+                    .map(|_| {
+                        i += 1;
+                        let p = (i * 7) % NUM_PEERS;
+                        let (connected_event, disconnected_event) = if !connected[p] {
+                            connected[p] = true;
+                            (true, false)
+                        } else if i % 37 == 0 {
+                            connected[p] = false;
+                            (false, true)
+                        } else {
+                            (false, false)
+                        };
+                        let entries: Vec<_> = if connected_event || disconnected_event {
+                            vec![]
+                        } else {
+                            (0..i % 4)
+                                .map(|j| {
+                                    serde_json::json!({
+                                        "priority": 1,
+                                        "cancel": (i + j) % 3 == 0,
+                                        "send_dont_have": j % 2 == 0,
+                                        "cid": {"/": format!("Qm{}", (i * 13 + j) % 50)},
+                                        "want_type": (i + j) % 2,
+                                    })
+                                })
+                                .collect()
+                        };
+                        let connection_event = connected_event || disconnected_event;
+                        serde_json::from_value(serde_json::json!({
+                            "timestamp": chrono::DateTime::from_timestamp_millis(
+                                1_600_000_000_000 + i as i64 * 10
+                            )
+                            .unwrap(),
+                            "peer": format!("peer{}", p),
+                            "address": format!("/ip4/10.0.0.{}/tcp/4001", p),
+                            "received_entries": if connection_event { None } else { Some(entries) },
+                            "full_want_list": if connection_event { None } else { Some(i % 11 == 0) },
+                            "peer_connected": connection_event.then_some(connected_event),
+                            "peer_disconnected": connection_event.then_some(disconnected_event),
+                            "connect_event_peer_found": connection_event.then_some(true),
+                        }))
+                        .unwrap()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn read_gzipped(path: &Path) -> String {
+        let mut s = String::new();
+        GzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut s)
+            .unwrap();
+        s
+    }
+
+    struct RunOutputs {
+        wantlist_files: Vec<String>,
+        connection_events: String,
+        end_of_simulation_cancels: Vec<CSVWantlistEntry>,
+        num_ledgers: Vec<usize>,
+    }
+
+    fn run(threads: usize) -> RunOutputs {
+        let dir =
+            std::env::temp_dir().join(format!("simulation-{}-{}", threads, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut sim = Simulation::new(&config(), threads).unwrap();
+        let mut wl_writer = RotatingWriter::new(
+            &dir.join("wl-$id$.csv.gz").display().to_string(),
+            OutputFormat::Csv,
+            RotationConfig::default(),
+        )
+        .unwrap();
+        let mut outputs = SharedOutputs {
+            conn_writer: create_sink(
+                &dir.join("conn.csv.gz").display().to_string(),
+                OutputFormat::Csv,
+            )
+            .unwrap(),
+            sqlite: None,
+            filter: OutputFilter::default(),
+            anonymizer: None,
+            summary: RunSummary::new(sim.config().clone()),
+        };
+
+        let mut message_id = 0;
+        let mut final_ts = None;
+        let mut num_ledgers = Vec::new();
+        for (i, file) in trace().into_iter().enumerate() {
+            outputs.summary.begin_file(format!("file{}", i));
+            for message in file {
+                message_id += 1;
+                final_ts = Some(message.timestamp);
+                sim.ingest(message, message_id, &mut wl_writer, &mut outputs)
+                    .unwrap();
+            }
+            sim.sync(&mut wl_writer, &mut outputs).unwrap();
+            wl_writer.rotate().unwrap();
+            num_ledgers.push(sim.num_ledgers());
+        }
+        wl_writer.finish().unwrap();
+        outputs.conn_writer.finish().unwrap();
+
+        let mut end_of_simulation_cancels: Vec<_> = sim
+            .finish()
+            .unwrap()
+            .into_iter()
+            .flat_map(|(e, _)| {
+                e.generate_end_of_simulation_entries(final_ts.unwrap(), message_id + 1)
+            })
+            .collect();
+        end_of_simulation_cancels.sort_by(|a, b| (&a.peer_id, &a.cid).cmp(&(&b.peer_id, &b.cid)));
+
+        let mut wl_paths: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with("wl-"))
+            .collect();
+        wl_paths.sort();
+        let outputs = RunOutputs {
+            wantlist_files: wl_paths.iter().map(|p| read_gzipped(p)).collect(),
+            connection_events: read_gzipped(&dir.join("conn.csv.gz")),
+            end_of_simulation_cancels,
+            num_ledgers,
+        };
+        std::fs::remove_dir_all(&dir).unwrap();
+        outputs
+    }
+
+    #[test]
+    fn sharded_matches_sequential() {
+        let sequential = run(1);
+        let sharded = run(4);
+
+        // Make sure the trace exercises everything.
+        assert_eq!(sequential.wantlist_files.len(), 3);
+        assert!(sequential
+            .wantlist_files
+            .iter()
+            .all(|f| f.lines().count() > 1000));
+        assert!(sequential.connection_events.lines().count() > 100);
+        assert!(!sequential.end_of_simulation_cancels.is_empty());
+        assert_eq!(*sequential.num_ledgers.last().unwrap(), NUM_PEERS);
+
+        assert_eq!(sequential.wantlist_files, sharded.wantlist_files);
+        assert_eq!(sequential.connection_events, sharded.connection_events);
+        assert_eq!(
+            sequential.end_of_simulation_cancels,
+            sharded.end_of_simulation_cancels
+        );
+        assert_eq!(sequential.num_ledgers, sharded.num_ledgers);
+    }
+}
@@ -0,0 +1,471 @@
+use crate::conntrack::{CSVConnectionMetadata, CSVSessionMetadata};
+use crate::CSVLedgerCount;
+use failure::ResultExt;
+use ipfs_resolver_common::wantlist::{CSVConnectionEvent, CSVWantlistEntry};
+use ipfs_resolver_common::Result;
+use rusqlite::{params, Connection};
+
+/// The number of rows written per transaction.
+const ROWS_PER_TRANSACTION: usize = 50_000;
+
+/// The schema of the database.
+/// The columns are the same as in the CSV outputs.
+/// Boolean columns are stored as integers (0 or 1).
+const SCHEMA: &str = "
+CREATE TABLE wantlist_entries (
+    message_id                    INTEGER NOT NULL,
+    message_type                  INTEGER NOT NULL,
+    timestamp_seconds             INTEGER NOT NULL,
+    timestamp_subsec_milliseconds INTEGER NOT NULL,
+    peer_id                       TEXT    NOT NULL,
+    address                       TEXT    NOT NULL,
+    priority                      INTEGER NOT NULL,
+    entry_type                    INTEGER NOT NULL,
+    cid                           TEXT    NOT NULL,
+    duplicate_status              INTEGER NOT NULL,
+    sliding_window_smallest_match INTEGER NOT NULL,
+    secs_since_earlier_message    INTEGER NOT NULL,
+    upgrades_earlier_request      INTEGER NOT NULL
+);
+
+CREATE TABLE connection_events (
+    message_id              INTEGER NOT NULL,
+    timestamp_seconds       INTEGER NOT NULL,
+    timestamp_subsec_millis INTEGER NOT NULL,
+    peer_id                 TEXT    NOT NULL,
+    address                 TEXT    NOT NULL,
+    event_type              INTEGER NOT NULL
+);
+
+CREATE TABLE connection_durations (
+    peer_id                TEXT    NOT NULL,
+    start_ts_seconds       INTEGER NOT NULL,
+    start_ts_subsec_millis INTEGER NOT NULL,
+    end_ts_seconds         INTEGER NOT NULL,
+    end_ts_subsec_millis   INTEGER NOT NULL,
+    address                TEXT,
+    synthetic_close        INTEGER NOT NULL
+);
+
+CREATE TABLE sessions (
+    peer_id                TEXT    NOT NULL,
+    start_ts_seconds       INTEGER NOT NULL,
+    start_ts_subsec_millis INTEGER NOT NULL,
+    end_ts_seconds         INTEGER NOT NULL,
+    end_ts_subsec_millis   INTEGER NOT NULL,
+    connections            INTEGER NOT NULL,
+    synthetic_close        INTEGER NOT NULL
+);
+
+CREATE TABLE ledger_counts (
+    ts_secs         INTEGER NOT NULL,
+    missing_ledgers INTEGER NOT NULL,
+    total_ledgers   INTEGER NOT NULL
+);
+";
+
+/// The indices of the database.
+/// These are created after all data has been inserted, which is a lot faster than maintaining
+/// them during the import.
+const INDICES: &str = "
+CREATE INDEX wantlist_entries_cid ON wantlist_entries (cid);
+CREATE INDEX wantlist_entries_peer_id_ts ON wantlist_entries (peer_id, timestamp_seconds);
+CREATE INDEX wantlist_entries_ts ON wantlist_entries (timestamp_seconds);
+CREATE INDEX wantlist_entries_message_id ON wantlist_entries (message_id);
+CREATE INDEX connection_events_peer_id_ts ON connection_events (peer_id, timestamp_seconds);
+CREATE INDEX connection_durations_peer_id_ts ON connection_durations (peer_id, start_ts_seconds);
+CREATE INDEX sessions_peer_id_ts ON sessions (peer_id, start_ts_seconds);
+";
+
+/// An SQLite database holding the outputs of the transformation.
+/// Rows are inserted in batches of `ROWS_PER_TRANSACTION`, each in one transaction.
+pub(crate) struct SqliteOutput {
+    conn: Connection,
+    rows_in_transaction: usize,
+}
+
+impl SqliteOutput {
+    /// Creates the database at the given path and sets up the schema.
+    /// This fails if the database already contains the tables.
+    pub(crate) fn create(path: &str) -> Result<SqliteOutput> {
+        let conn = Connection::open(path).context("unable to open database")?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+            .context("unable to configure database")?;
+        conn.execute_batch(SCHEMA)
+            .context("unable to create schema, does the database already exist?")?;
+        conn.execute_batch("BEGIN")
+            .context("unable to begin transaction")?;
+
+        Ok(SqliteOutput {
+            conn,
+            rows_in_transaction: 0,
+        })
+    }
+
+    /// Commits the current transaction if it is full.
+    fn row_inserted(&mut self) -> Result<()> {
+        self.rows_in_transaction += 1;
+        if self.rows_in_transaction >= ROWS_PER_TRANSACTION {
+            self.conn
+                .execute_batch("COMMIT; BEGIN")
+                .context("unable to commit transaction")?;
+            self.rows_in_transaction = 0;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn insert_wantlist_entry(&mut self, e: &CSVWantlistEntry) -> Result<()> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO wantlist_entries VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            )?
+            .execute(params![
+                e.message_id,
+                e.message_type,
+                e.timestamp_seconds,
+                e.timestamp_subsec_milliseconds,
+                e.peer_id,
+                e.address,
+                e.priority,
+                e.entry_type,
+                e.cid,
+                e.duplicate_status,
+                e.sliding_window_smallest_match,
+                e.secs_since_earlier_message,
+                e.upgrades_earlier_request,
+            ])
+            .context("unable to insert wantlist entry")?;
+        self.row_inserted()
+    }
+
+    pub(crate) fn insert_connection_event(&mut self, e: &CSVConnectionEvent) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT INTO connection_events VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?
+            .execute(params![
+                e.message_id,
+                e.timestamp_seconds,
+                e.timestamp_subsec_millis,
+                e.peer_id,
+                e.address,
+                e.event_type,
+            ])
+            .context("unable to insert connection event")?;
+        self.row_inserted()
+    }
+
+    pub(crate) fn insert_connection_duration(&mut self, c: &CSVConnectionMetadata) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT INTO connection_durations VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?
+            .execute(params![
+                c.peer_id,
+                c.start_ts_seconds,
+                c.start_ts_subsec_millis,
+                c.end_ts_seconds,
+                c.end_ts_subsec_millis,
+                c.address,
+                c.synthetic_close,
+            ])
+            .context("unable to insert connection duration")?;
+        self.row_inserted()
+    }
+
+    pub(crate) fn insert_session(&mut self, s: &CSVSessionMetadata) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT INTO sessions VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?
+            .execute(params![
+                s.peer_id,
+                s.start_ts_seconds,
+                s.start_ts_subsec_millis,
+                s.end_ts_seconds,
+                s.end_ts_subsec_millis,
+                s.connections,
+                s.synthetic_close,
+            ])
+            .context("unable to insert session")?;
+        self.row_inserted()
+    }
+
+    pub(crate) fn insert_ledger_count(&mut self, l: &CSVLedgerCount) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT INTO ledger_counts VALUES (?1, ?2, ?3)")?
+            .execute(params![l.ts_secs, l.missing_ledgers, l.total_ledgers])
+            .context("unable to insert ledger count")?;
+        self.row_inserted()
+    }
+
+    /// Commits the last transaction and creates the indices.
+    pub(crate) fn finish(self) -> Result<()> {
+        self.conn
+            .execute_batch("COMMIT")
+            .context("unable to commit transaction")?;
+        info!("creating database indices...");
+        self.conn
+            .execute_batch(INDICES)
+            .context("unable to create indices")?;
+        self.conn
+            .close()
+            .map_err(|(_, e)| e)
+            .context("unable to close database")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{FilterConfig, OutputFilter};
+    use crate::summary::RunSummary;
+    use crate::{write_ingest_result, SharedOutputs};
+    use ipfs_resolver_common::anonymize::{AddressMode, AnonymizationConfig, Anonymizer};
+    use ipfs_resolver_common::output::{create_sink, OutputFormat};
+    use ipfs_resolver_common::rotation::{RotatingWriter, RotationConfig};
+    use ipfs_resolver_common::wantlist::{
+        EngineSimulationConfig, IngestResult, CSV_CONNECTION_EVENT_CONNECTED_FOUND,
+        CSV_DUPLICATE_STATUS_NO_DUP, CSV_ENTRY_TYPE_WANT_HAVE, CSV_MESSAGE_TYPE_INCREMENTAL,
+    };
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn entry(message_id: i64, peer: &str, cid: &str) -> CSVWantlistEntry {
+        CSVWantlistEntry {
+            message_id,
+            message_type: CSV_MESSAGE_TYPE_INCREMENTAL,
+            timestamp_seconds: 1_600_000_000 + message_id,
+            timestamp_subsec_milliseconds: 0,
+            peer_id: peer.to_string(),
+            address: "/ip4/1.2.3.4/tcp/4001".to_string(),
+            priority: 1,
+            entry_type: CSV_ENTRY_TYPE_WANT_HAVE,
+            cid: cid.to_string(),
+            duplicate_status: CSV_DUPLICATE_STATUS_NO_DUP,
+            sliding_window_smallest_match: 0,
+            secs_since_earlier_message: 0,
+            upgrades_earlier_request: false,
+        }
+    }
+
+    fn connection_event(message_id: i64, peer: &str) -> CSVConnectionEvent {
+        CSVConnectionEvent {
+            message_id,
+            timestamp_seconds: 1_600_000_000 + message_id,
+            timestamp_subsec_millis: 0,
+            peer_id: peer.to_string(),
+            address: "/ip4/1.2.3.4/tcp/4001".to_string(),
+            event_type: CSV_CONNECTION_EVENT_CONNECTED_FOUND,
+        }
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        conn.prepare(&format!("PRAGMA table_info({})", table))
+            .unwrap()
+            .query_map([], |row| row.get(1))
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect()
+    }
+
+    fn count(conn: &Connection, query: &str) -> usize {
+        conn.query_row(query, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn writes_rows_across_transactions() {
+        let dir = std::env::temp_dir().join(format!("sqlite-rows-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db.sqlite").display().to_string();
+
+        // More rows than fit into one transaction, with the connection events spread over both.
+        let num_entries = ROWS_PER_TRANSACTION + 10;
+        let mut output = SqliteOutput::create(&db_path).unwrap();
+        for message_id in 0..num_entries as i64 {
+            output
+                .insert_wantlist_entry(&entry(message_id, "peer1", "QmA"))
+                .unwrap();
+            if message_id % 1000 == 0 {
+                output
+                    .insert_connection_event(&connection_event(message_id, "peer1"))
+                    .unwrap();
+            }
+        }
+        output.finish().unwrap();
+
+        let conn = Connection::open(&db_path).unwrap();
+        assert_eq!(
+            columns(&conn, "wantlist_entries"),
+            vec![
+                "message_id",
+                "message_type",
+                "timestamp_seconds",
+                "timestamp_subsec_milliseconds",
+                "peer_id",
+                "address",
+                "priority",
+                "entry_type",
+                "cid",
+                "duplicate_status",
+                "sliding_window_smallest_match",
+                "secs_since_earlier_message",
+                "upgrades_earlier_request",
+            ]
+        );
+        assert_eq!(
+            columns(&conn, "connection_events"),
+            vec![
+                "message_id",
+                "timestamp_seconds",
+                "timestamp_subsec_millis",
+                "peer_id",
+                "address",
+                "event_type",
+            ]
+        );
+
+        // Rows of both transactions are there.
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM wantlist_entries"),
+            num_entries
+        );
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM connection_events"),
+            num_entries / 1000 + 1
+        );
+        assert_eq!(
+            count(
+                &conn,
+                &format!(
+                    "SELECT COUNT(*) FROM wantlist_entries WHERE message_id >= {}",
+                    ROWS_PER_TRANSACTION
+                )
+            ),
+            10
+        );
+
+        // The indices were created.
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name LIKE 'wantlist_entries_%'"
+            ),
+            4
+        );
+
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_filtered_and_anonymized_rows() {
+        let dir = std::env::temp_dir().join(format!("sqlite-filtered-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_file = dir.join("key");
+        std::fs::write(&key_file, KEY).unwrap();
+        let db_path = dir.join("db.sqlite").display().to_string();
+
+        let anonymizer = Anonymizer::new(&AnonymizationConfig {
+            key_file: key_file.display().to_string(),
+            address_mode: AddressMode::Truncate,
+            geoip_database_path: None,
+        })
+        .unwrap();
+        let pseudonym = anonymizer.pseudonymize_peer("peer1");
+        let mut wl_writer = RotatingWriter::new(
+            &dir.join("wl-$id$.csv.gz").display().to_string(),
+            OutputFormat::Csv,
+            RotationConfig::default(),
+        )
+        .unwrap();
+        let mut outputs = SharedOutputs {
+            conn_writer: create_sink(
+                &dir.join("conn.csv.gz").display().to_string(),
+                OutputFormat::Csv,
+            )
+            .unwrap(),
+            sqlite: Some(SqliteOutput::create(&db_path).unwrap()),
+            filter: OutputFilter::new(&FilterConfig {
+                peer_deny_list: vec!["peer2".to_string()],
+                cid_prefixes: vec!["Qm".to_string()],
+                ..Default::default()
+            }),
+            anonymizer: Some(anonymizer),
+            summary: RunSummary::new(EngineSimulationConfig {
+                allow_empty_full_wantlist: false,
+                allow_empty_connection_event: false,
+                insert_full_wantlist_synth_cancels: true,
+                insert_disconnect_synth_cancels: true,
+                reconnect_duplicate_duration_secs: 5,
+                sliding_window_lengths: vec![],
+            }),
+        };
+
+        outputs.summary.begin_file("trace.json.gz".to_string());
+
+        // More entries than fit into one transaction, some of which are filtered out by CID or
+        // peer, and connection events of both peers.
+        let num_messages = ROWS_PER_TRANSACTION / 2 + 10;
+        for message_id in 0..num_messages as i64 {
+            for peer in ["peer1", "peer2"] {
+                let result = IngestResult {
+                    missing_ledger: false,
+                    wantlist_entries: Some(vec![
+                        entry(message_id, peer, "QmA"),
+                        entry(message_id, peer, "QmB"),
+                        entry(message_id, peer, "bafyC"),
+                    ]),
+                    connection_event: (message_id % 1000 == 0)
+                        .then(|| connection_event(message_id, peer)),
+                };
+                write_ingest_result(message_id, peer, &result, &mut wl_writer, &mut outputs)
+                    .unwrap();
+            }
+        }
+        wl_writer.finish().unwrap();
+        outputs.sqlite.take().unwrap().finish().unwrap();
+
+        let conn = Connection::open(&db_path).unwrap();
+        // Rows of both transactions are there, and only those that passed the filter.
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM wantlist_entries"),
+            2 * num_messages
+        );
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM connection_events"),
+            num_messages / 1000 + 1
+        );
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM wantlist_entries WHERE cid NOT LIKE 'Qm%'"
+            ),
+            0
+        );
+
+        // All rows are anonymized.
+        for table in ["wantlist_entries", "connection_events"] {
+            let (peers, addresses): (usize, usize) = conn
+                .query_row(
+                    &format!(
+                        "SELECT COUNT(DISTINCT peer_id), COUNT(DISTINCT address) FROM {}",
+                        table
+                    ),
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!((peers, addresses), (1, 1));
+            let (peer, address): (String, String) = conn
+                .query_row(
+                    &format!("SELECT peer_id, address FROM {} LIMIT 1", table),
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!(peer, pseudonym);
+            assert_eq!(address, "/ip4/1.2.3.0");
+        }
+
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
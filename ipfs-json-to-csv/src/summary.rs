@@ -0,0 +1,373 @@
+use chrono::{DateTime, Utc};
+use failure::ResultExt;
+use ipfs_resolver_common::wantlist::{
+    CSVWantlistEntry, EngineSimulationConfig, IngestResult, CSV_ENTRY_TYPE_CANCEL,
+    CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_DISCONNECT, CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_END_OF_SIMULATION,
+    CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_FULL_WANTLIST, CSV_ENTRY_TYPE_WANT_BLOCK,
+    CSV_ENTRY_TYPE_WANT_BLOCK_SEND_DONT_HAVE, CSV_ENTRY_TYPE_WANT_HAVE,
+    CSV_ENTRY_TYPE_WANT_HAVE_SEND_DONT_HAVE,
+};
+use ipfs_resolver_common::Result;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+
+/// Counts of wantlist entries, by entry type.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct EntryTypeCounts {
+    cancel: u64,
+    want_block: u64,
+    want_block_send_dont_have: u64,
+    want_have: u64,
+    want_have_send_dont_have: u64,
+    synthetic_cancel_full_wantlist: u64,
+    synthetic_cancel_disconnect: u64,
+    synthetic_cancel_end_of_simulation: u64,
+}
+
+impl EntryTypeCounts {
+    fn add(&mut self, entry_type: i32) {
+        let count = match entry_type {
+            CSV_ENTRY_TYPE_CANCEL => &mut self.cancel,
+            CSV_ENTRY_TYPE_WANT_BLOCK => &mut self.want_block,
+            CSV_ENTRY_TYPE_WANT_BLOCK_SEND_DONT_HAVE => &mut self.want_block_send_dont_have,
+            CSV_ENTRY_TYPE_WANT_HAVE => &mut self.want_have,
+            CSV_ENTRY_TYPE_WANT_HAVE_SEND_DONT_HAVE => &mut self.want_have_send_dont_have,
+            CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_FULL_WANTLIST => {
+                &mut self.synthetic_cancel_full_wantlist
+            }
+            CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_DISCONNECT => &mut self.synthetic_cancel_disconnect,
+            CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_END_OF_SIMULATION => {
+                &mut self.synthetic_cancel_end_of_simulation
+            }
+            _ => {
+                warn!("unknown entry type {}", entry_type);
+                return;
+            }
+        };
+        *count += 1;
+    }
+
+    fn merge(&mut self, other: &EntryTypeCounts) {
+        self.cancel += other.cancel;
+        self.want_block += other.want_block;
+        self.want_block_send_dont_have += other.want_block_send_dont_have;
+        self.want_have += other.want_have;
+        self.want_have_send_dont_have += other.want_have_send_dont_have;
+        self.synthetic_cancel_full_wantlist += other.synthetic_cancel_full_wantlist;
+        self.synthetic_cancel_disconnect += other.synthetic_cancel_disconnect;
+        self.synthetic_cancel_end_of_simulation += other.synthetic_cancel_end_of_simulation;
+    }
+
+    fn synthetic_cancels(&self) -> u64 {
+        self.synthetic_cancel_full_wantlist
+            + self.synthetic_cancel_disconnect
+            + self.synthetic_cancel_end_of_simulation
+    }
+}
+
+/// Statistics about one input file.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct FileSummary {
+    path: String,
+    messages: u64,
+    first_timestamp: Option<DateTime<Utc>>,
+    last_timestamp: Option<DateTime<Utc>>,
+    entries: EntryTypeCounts,
+    missing_ledgers: usize,
+    /// The number of ledgers after processing this file.
+    total_ledgers: usize,
+    processing_seconds: f64,
+    messages_per_second: f64,
+}
+
+/// A machine-readable summary of a run, written once all inputs have been processed.
+///
+/// All counts refer to the inputs and the simulation, i.e., they are not affected by output
+/// filters.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RunSummary {
+    files: Vec<FileSummary>,
+    messages: u64,
+    entries: EntryTypeCounts,
+    synthetic_cancels: u64,
+    missing_ledgers: usize,
+    peers_seen: usize,
+    connections: u64,
+    synthetically_closed_connections: u64,
+    total_connection_seconds: f64,
+    sessions: u64,
+    total_session_seconds: f64,
+    processing_seconds: f64,
+    messages_per_second: f64,
+    simulation_config: EngineSimulationConfig,
+
+    #[serde(skip)]
+    peers: HashSet<String>,
+    /// Summed up as integers, so that the result does not depend on the order of connections.
+    #[serde(skip)]
+    total_connection_millis: i64,
+    #[serde(skip)]
+    total_session_millis: i64,
+}
+
+impl RunSummary {
+    pub(crate) fn new(simulation_config: EngineSimulationConfig) -> RunSummary {
+        RunSummary {
+            files: Vec::new(),
+            messages: 0,
+            entries: Default::default(),
+            synthetic_cancels: 0,
+            missing_ledgers: 0,
+            peers_seen: 0,
+            connections: 0,
+            synthetically_closed_connections: 0,
+            total_connection_seconds: 0.0,
+            sessions: 0,
+            total_session_seconds: 0.0,
+            processing_seconds: 0.0,
+            messages_per_second: 0.0,
+            simulation_config,
+            peers: HashSet::new(),
+            total_connection_millis: 0,
+            total_session_millis: 0,
+        }
+    }
+
+    /// Starts recording statistics for a new input file.
+    pub(crate) fn begin_file(&mut self, path: String) {
+        self.files.push(FileSummary {
+            path,
+            messages: 0,
+            first_timestamp: None,
+            last_timestamp: None,
+            entries: Default::default(),
+            missing_ledgers: 0,
+            total_ledgers: 0,
+            processing_seconds: 0.0,
+            messages_per_second: 0.0,
+        })
+    }
+
+    /// Records the result of simulating a message from the given peer.
+    pub(crate) fn record_message(&mut self, peer: &str, result: &IngestResult) {
+        if !self.peers.contains(peer) {
+            self.peers.insert(peer.to_string());
+        }
+
+        if let Some(entries) = result.wantlist_entries.as_ref() {
+            let file = self.files.last_mut().expect("no file begun");
+            for e in entries {
+                file.entries.add(e.entry_type);
+            }
+        }
+    }
+
+    /// Finishes recording statistics for the current input file.
+    /// Messages are counted here, because `PushedEvent`s do not always result in messages for the
+    /// simulation.
+    pub(crate) fn finish_file(
+        &mut self,
+        messages: u64,
+        timestamps: Option<(DateTime<Utc>, DateTime<Utc>)>,
+        missing_ledgers: usize,
+        total_ledgers: usize,
+        elapsed: Duration,
+    ) {
+        let file = self.files.last_mut().expect("no file begun");
+        file.messages = messages;
+        file.first_timestamp = timestamps.map(|(first, _)| first);
+        file.last_timestamp = timestamps.map(|(_, last)| last);
+        file.missing_ledgers = missing_ledgers;
+        file.total_ledgers = total_ledgers;
+        file.processing_seconds = elapsed.as_secs_f64();
+        file.messages_per_second = messages as f64 / elapsed.as_secs_f64();
+    }
+
+    /// Records a finished connection.
+    pub(crate) fn record_connection(&mut self, duration: chrono::Duration, synthetic_close: bool) {
+        self.connections += 1;
+        if synthetic_close {
+            self.synthetically_closed_connections += 1;
+        }
+        self.total_connection_millis += duration.num_milliseconds();
+    }
+
+    /// Records a finished session, i.e., a period of overlapping connections.
+    pub(crate) fn record_session(&mut self, duration: chrono::Duration) {
+        self.sessions += 1;
+        self.total_session_millis += duration.num_milliseconds();
+    }
+
+    /// Records the synthetic cancels generated at the end of the simulation.
+    pub(crate) fn record_end_of_simulation_entries(&mut self, entries: &[CSVWantlistEntry]) {
+        for e in entries {
+            self.entries.add(e.entry_type);
+        }
+    }
+
+    /// Computes the totals, given the total processing time.
+    pub(crate) fn finish(mut self, elapsed: Duration) -> RunSummary {
+        for file in self.files.iter() {
+            self.messages += file.messages;
+            self.missing_ledgers += file.missing_ledgers;
+            self.entries.merge(&file.entries);
+        }
+        self.synthetic_cancels = self.entries.synthetic_cancels();
+        self.peers_seen = self.peers.len();
+        self.total_connection_seconds = self.total_connection_millis as f64 / 1000.0;
+        self.total_session_seconds = self.total_session_millis as f64 / 1000.0;
+        self.processing_seconds = elapsed.as_secs_f64();
+        self.messages_per_second = self.messages as f64 / elapsed.as_secs_f64();
+        self
+    }
+
+    /// Writes the summary as JSON to the given file, or to Stdout if the path is `-`.
+    pub(crate) fn write_to(&self, path: &str) -> Result<()> {
+        self.write_json(open_output(path)?)
+    }
+
+    fn write_json<W: Write>(&self, mut w: W) -> Result<()> {
+        serde_json::to_writer_pretty(&mut w, self).context("unable to serialize summary")?;
+        writeln!(w).context("unable to write summary")?;
+        Ok(())
+    }
+}
+
+/// Opens the file the summary is written to, or Stdout if the path is `-`.
+fn open_output(path: &str) -> Result<Box<dyn Write>> {
+    match path {
+        "-" => Ok(Box::new(std::io::stdout())),
+        path => Ok(Box::new(
+            File::create(path).context("unable to create summary file")?,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipfs_resolver_common::wantlist::CSV_MESSAGE_TYPE_INCREMENTAL;
+
+    fn entry(entry_type: i32) -> CSVWantlistEntry {
+        CSVWantlistEntry {
+            message_id: 1,
+            message_type: CSV_MESSAGE_TYPE_INCREMENTAL,
+            timestamp_seconds: 0,
+            timestamp_subsec_milliseconds: 0,
+            peer_id: "peer1".to_string(),
+            address: String::new(),
+            priority: 1,
+            entry_type,
+            cid: "QmA".to_string(),
+            duplicate_status: 0,
+            sliding_window_smallest_match: 0,
+            secs_since_earlier_message: 0,
+            upgrades_earlier_request: false,
+        }
+    }
+
+    fn message(entry_types: &[i32]) -> IngestResult {
+        IngestResult {
+            missing_ledger: false,
+            wantlist_entries: Some(entry_types.iter().map(|t| entry(*t)).collect()),
+            connection_event: None,
+        }
+    }
+
+    fn summary() -> RunSummary {
+        let mut summary = RunSummary::new(EngineSimulationConfig {
+            allow_empty_full_wantlist: false,
+            allow_empty_connection_event: false,
+            insert_full_wantlist_synth_cancels: true,
+            insert_disconnect_synth_cancels: true,
+            reconnect_duplicate_duration_secs: 5,
+            sliding_window_lengths: vec![1],
+        });
+
+        let ts = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        summary.begin_file("a.json.gz".to_string());
+        summary.record_message(
+            "peer1",
+            &message(&[CSV_ENTRY_TYPE_WANT_HAVE, CSV_ENTRY_TYPE_WANT_BLOCK]),
+        );
+        summary.record_message("peer2", &message(&[CSV_ENTRY_TYPE_CANCEL]));
+        summary.finish_file(
+            3,
+            Some((ts("2021-01-01T00:00:00Z"), ts("2021-01-01T00:01:00Z"))),
+            1,
+            2,
+            Duration::from_secs(2),
+        );
+        summary.begin_file("b.json.gz".to_string());
+        summary.record_message(
+            "peer1",
+            &message(&[CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_DISCONNECT]),
+        );
+        summary.finish_file(1, None, 0, 2, Duration::from_secs(1));
+        summary.record_connection(chrono::Duration::milliseconds(1500), false);
+        summary.record_connection(chrono::Duration::milliseconds(500), true);
+        summary.record_session(chrono::Duration::seconds(2));
+        summary.record_end_of_simulation_entries(&[entry(
+            CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_END_OF_SIMULATION,
+        )]);
+
+        summary.finish(Duration::from_secs(4))
+    }
+
+    #[test]
+    fn serializes_totals_and_files() {
+        let mut buf = Vec::new();
+        summary().write_json(&mut buf).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+
+        assert_eq!(json["messages"], 4);
+        assert_eq!(json["messages_per_second"], 1.0);
+        assert_eq!(json["peers_seen"], 2);
+        assert_eq!(json["missing_ledgers"], 1);
+        assert_eq!(json["entries"]["want_have"], 1);
+        assert_eq!(json["entries"]["cancel"], 1);
+        assert_eq!(json["entries"]["synthetic_cancel_disconnect"], 1);
+        assert_eq!(json["entries"]["synthetic_cancel_end_of_simulation"], 1);
+        assert_eq!(json["synthetic_cancels"], 2);
+        assert_eq!(json["connections"], 2);
+        assert_eq!(json["synthetically_closed_connections"], 1);
+        assert_eq!(json["total_connection_seconds"], 2.0);
+        assert_eq!(json["sessions"], 1);
+        assert_eq!(json["simulation_config"]["sliding_window_lengths"][0], 1);
+        // Internal state is not serialized.
+        assert!(json.get("peers").is_none());
+
+        let files = json["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0]["path"], "a.json.gz");
+        assert_eq!(files[0]["messages"], 3);
+        assert_eq!(files[0]["first_timestamp"], "2021-01-01T00:00:00Z");
+        assert_eq!(files[0]["entries"]["want_block"], 1);
+        assert_eq!(files[0]["total_ledgers"], 2);
+        assert_eq!(files[1]["last_timestamp"], serde_json::Value::Null);
+        assert_eq!(files[1]["messages_per_second"], 1.0);
+    }
+
+    #[test]
+    fn writes_to_file_or_stdout() {
+        let dir = std::env::temp_dir().join(format!("summary-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("summary.json");
+
+        let summary = summary();
+        summary.write_to(&path.display().to_string()).unwrap();
+        let mut expected = Vec::new();
+        summary.write_json(&mut expected).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        // `-` means Stdout, not a file of that name.
+        open_output("-").unwrap();
+        assert!(!std::path::Path::new("-").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,512 @@
+use crate::config::{Config, InputFormat};
+use crate::pushed;
+use failure::ResultExt;
+use flate2::read::GzDecoder;
+use ipfs_monitoring_plugin_client::conversion::PushedEventConverter;
+use ipfs_resolver_common::wantlist::JSONMessage;
+use ipfs_resolver_common::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Classes of anomalies found in traces.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AnomalyClass {
+    /// A message or event that could not be decoded.
+    MalformedMessage,
+    /// A message with an earlier timestamp than the message before it.
+    OutOfOrderTimestamp,
+    /// A CONNECTED event which IPFS reports as the first connection to the peer, while we still
+    /// track open connections to it.
+    ConnectWithoutDisconnect,
+    /// A DISCONNECTED event for a peer IPFS knows, while we do not track any open connections to it.
+    DisconnectWithoutConnect,
+    /// A CANCEL for a CID which is not on the wantlist of the peer.
+    UnknownCancel,
+    /// A message from a connected peer with an address different from all of its connections.
+    AddressMismatch,
+    /// Wantlist entries from a peer we were connected to before, but which is now disconnected.
+    WantlistFromDisconnectedPeer,
+}
+
+/// Configuration for the validation mode.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ValidationConfig {
+    /// The maximum number of samples to record per anomaly class.
+    #[serde(default = "default_max_samples")]
+    pub(crate) max_samples: usize,
+
+    /// The maximum number of anomalies per class, over all files.
+    /// If any of these is exceeded, the tool exits with code 2.
+    /// Classes not listed here are unbounded.
+    #[serde(default)]
+    pub(crate) thresholds: BTreeMap<AnomalyClass, u64>,
+}
+
+fn default_max_samples() -> usize {
+    5
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            max_samples: default_max_samples(),
+            thresholds: Default::default(),
+        }
+    }
+}
+
+/// An example of an anomaly.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Sample {
+    file: String,
+    /// The line number for JSON messages, the index of the event for `PushedEvent`s, starting at 1.
+    position: u64,
+    peer: Option<String>,
+    /// The offending line or event.
+    raw: String,
+}
+
+/// The counts of anomalies for one file.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct FileReport {
+    path: String,
+    messages: u64,
+    anomalies: BTreeMap<AnomalyClass, u64>,
+}
+
+/// A violated threshold.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ThresholdViolation {
+    class: AnomalyClass,
+    count: u64,
+    threshold: u64,
+}
+
+/// The result of validating a set of traces.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct ValidationReport {
+    totals: BTreeMap<AnomalyClass, u64>,
+    files: Vec<FileReport>,
+    /// Anomaly counts per peer, only for peers with anomalies.
+    peers: BTreeMap<String, BTreeMap<AnomalyClass, u64>>,
+    samples: BTreeMap<AnomalyClass, Vec<Sample>>,
+    threshold_violations: Vec<ThresholdViolation>,
+}
+
+impl ValidationReport {
+    pub(crate) fn passed(&self) -> bool {
+        self.threshold_violations.is_empty()
+    }
+}
+
+/// Connection and wantlist state of a peer.
+#[derive(Clone, Debug, Default)]
+struct PeerState {
+    /// The addresses of open connections, oldest first.
+    /// Connections can overlap, so there may be more than one.
+    connections: Vec<Option<String>>,
+    was_connected: bool,
+    wanted: HashSet<String>,
+}
+
+impl PeerState {
+    fn connected(&self) -> bool {
+        !self.connections.is_empty()
+    }
+
+    /// Checks whether the address is known and differs from the addresses of all connections.
+    fn address_mismatch(&self, addr: &Option<String>) -> bool {
+        let mut known = self.connections.iter().flatten().peekable();
+        addr.is_some() && known.peek().is_some() && !known.any(|a| Some(a) == addr.as_ref())
+    }
+
+    /// Removes the connection with the given address, or the oldest connection if none matches.
+    fn remove_connection(&mut self, addr: &Option<String>) {
+        let pos = self
+            .connections
+            .iter()
+            .position(|a| addr.is_some() && a == addr)
+            .unwrap_or(0);
+        self.connections.remove(pos);
+    }
+}
+
+/// Tracks connection and wantlist state to find anomalies in a stream of messages.
+/// This mirrors the checks of the engine simulation and connection tracker, but records anomalies
+/// instead of logging them.
+/// Like the simulation, this counts connections per peer and compares them to
+/// `connect_event_peer_found`, so overlapping connections are not reported.
+struct Validator {
+    cfg: ValidationConfig,
+    report: ValidationReport,
+    peers: HashMap<String, PeerState>,
+    last_ts: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Validator {
+    fn new(cfg: ValidationConfig) -> Validator {
+        Validator {
+            cfg,
+            report: Default::default(),
+            peers: Default::default(),
+            last_ts: None,
+        }
+    }
+
+    fn record(
+        &mut self,
+        class: AnomalyClass,
+        file: &str,
+        position: u64,
+        peer: Option<&str>,
+        raw: &dyn Fn() -> String,
+    ) {
+        *self.report.totals.entry(class).or_default() += 1;
+        if let Some(f) = self.report.files.last_mut() {
+            *f.anomalies.entry(class).or_default() += 1;
+        }
+        if let Some(peer) = peer {
+            *self
+                .report
+                .peers
+                .entry(peer.to_string())
+                .or_default()
+                .entry(class)
+                .or_default() += 1;
+        }
+        let samples = self.report.samples.entry(class).or_default();
+        if samples.len() < self.cfg.max_samples {
+            samples.push(Sample {
+                file: file.to_string(),
+                position,
+                peer: peer.map(|p| p.to_string()),
+                raw: raw(),
+            })
+        }
+    }
+
+    fn check_message(
+        &mut self,
+        msg: &JSONMessage,
+        file: &str,
+        position: u64,
+        raw: &dyn Fn() -> String,
+    ) {
+        let mut found = Vec::new();
+
+        if let Some(last) = self.last_ts {
+            if msg.timestamp < last {
+                found.push(AnomalyClass::OutOfOrderTimestamp);
+            }
+        }
+        self.last_ts = Some(
+            self.last_ts
+                .map_or(msg.timestamp, |ts| ts.max(msg.timestamp)),
+        );
+
+        let addr = msg.address.as_ref().map(|a| a.to_string());
+        let state = self.peers.entry(msg.peer.clone()).or_default();
+        // A new connection may come from a new address.
+        if msg.peer_connected != Some(true) && state.address_mismatch(&addr) {
+            found.push(AnomalyClass::AddressMismatch);
+        }
+
+        match (msg.peer_connected, msg.peer_disconnected) {
+            (Some(true), _) => {
+                if msg.connect_event_peer_found == Some(false) && state.connected() {
+                    // Like the simulation, we assume IPFS is right and we missed a disconnect.
+                    found.push(AnomalyClass::ConnectWithoutDisconnect);
+                    state.connections.clear();
+                    state.wanted.clear();
+                }
+                state.connections.push(addr);
+                state.was_connected = true;
+            }
+            (_, Some(true)) => {
+                if state.connected() {
+                    state.remove_connection(&addr);
+                } else if msg.connect_event_peer_found != Some(false) {
+                    found.push(AnomalyClass::DisconnectWithoutConnect);
+                }
+                if !state.connected() {
+                    state.wanted.clear();
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(entries) = msg.received_entries.as_ref() {
+            if !state.connected() && state.was_connected && !entries.is_empty() {
+                found.push(AnomalyClass::WantlistFromDisconnectedPeer);
+            }
+            if msg.full_want_list == Some(true) {
+                state.wanted.clear();
+            }
+            for e in entries {
+                if e.cancel {
+                    if !state.wanted.remove(&e.cid.path) {
+                        found.push(AnomalyClass::UnknownCancel);
+                    }
+                } else {
+                    state.wanted.insert(e.cid.path.clone());
+                }
+            }
+        }
+
+        for class in found {
+            self.record(class, file, position, Some(&msg.peer), raw);
+        }
+    }
+
+    fn validate_json_messages_file(&mut self, infile: BufReader<GzDecoder<File>>, file: &str) {
+        for (i, line) in infile.lines().enumerate() {
+            let position = i as u64 + 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    let err = err.to_string();
+                    self.record(
+                        AnomalyClass::MalformedMessage,
+                        file,
+                        position,
+                        None,
+                        &|| err.clone(),
+                    );
+                    break;
+                }
+            };
+            self.report.files.last_mut().unwrap().messages += 1;
+
+            match serde_json::from_str::<JSONMessage>(&line) {
+                Ok(msg) => self.check_message(&msg, file, position, &|| line.clone()),
+                Err(_) => self.record(
+                    AnomalyClass::MalformedMessage,
+                    file,
+                    position,
+                    None,
+                    &|| line.clone(),
+                ),
+            }
+        }
+    }
+
+    fn validate_pushed_events_file(&mut self, infile: BufReader<GzDecoder<File>>, file: &str) {
+        let mut converter = PushedEventConverter::new();
+        for (i, event) in pushed::read_events(infile).enumerate() {
+            let position = i as u64 + 1;
+            self.report.files.last_mut().unwrap().messages += 1;
+            match event {
+                Ok(event) => {
+                    if let Some(msg) = converter.convert(&event) {
+                        self.check_message(&msg, file, position, &|| {
+                            serde_json::to_string(&event).unwrap_or_default()
+                        })
+                    }
+                }
+                Err(err) => {
+                    let err = err.to_string();
+                    self.record(
+                        AnomalyClass::MalformedMessage,
+                        file,
+                        position,
+                        None,
+                        &|| err.clone(),
+                    );
+                    // The stream cannot be resumed after a decoding error.
+                    break;
+                }
+            }
+        }
+    }
+
+    fn validate_file(&mut self, path: &Path, format: InputFormat) -> Result<()> {
+        let file = path.display().to_string();
+        let infile = BufReader::new(GzDecoder::new(
+            File::open(path).context("unable to open input file for reading")?,
+        ));
+        self.report.files.push(FileReport {
+            path: file.clone(),
+            messages: 0,
+            anomalies: Default::default(),
+        });
+
+        match format {
+            InputFormat::JsonMessages => self.validate_json_messages_file(infile, &file),
+            InputFormat::PushedEvents => self.validate_pushed_events_file(infile, &file),
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> ValidationReport {
+        for (class, threshold) in self.cfg.thresholds.iter() {
+            let count = self.report.totals.get(class).cloned().unwrap_or_default();
+            if count > *threshold {
+                self.report.threshold_violations.push(ThresholdViolation {
+                    class: *class,
+                    count,
+                    threshold: *threshold,
+                })
+            }
+        }
+        self.report
+    }
+}
+
+/// Scans the input files for anomalies, without running the simulation or producing outputs.
+pub(crate) fn validate(cfg: &Config) -> Result<ValidationReport> {
+    let mut validator = Validator::new(cfg.validation.clone());
+
+    let input_files = cfg.glob_results().context("unable to glob")?;
+    for path in input_files {
+        info!("validating {}", path.display());
+        validator
+            .validate_file(&path, cfg.input_format)
+            .context(format!("unable to validate file {}", path.display()))?;
+    }
+
+    Ok(validator.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const TRACE: &str = r#"{"timestamp":"2021-01-01T00:00:01Z","peer":"peer1","address":"/ip4/1.2.3.4/tcp/4001","received_entries":null,"full_want_list":null,"peer_connected":true,"peer_disconnected":false,"connect_event_peer_found":true}
+{"timestamp":"2021-01-01T00:00:02Z","peer":"peer1","address":"/ip4/1.2.3.4/tcp/4001","received_entries":[{"priority":1,"cancel":false,"send_dont_have":false,"cid":{"/":"Qm1"},"want_type":0}],"full_want_list":false,"peer_connected":null,"peer_disconnected":null,"connect_event_peer_found":null}
+{"timestamp":"2021-01-01T00:00:03Z","peer":"peer1","address":"/ip4/5.6.7.8/tcp/4001","received_entries":[{"priority":1,"cancel":true,"send_dont_have":false,"cid":{"/":"Qm2"},"want_type":0}],"full_want_list":false,"peer_connected":null,"peer_disconnected":null,"connect_event_peer_found":null}
+{"timestamp":"2021-01-01T00:00:02.500Z","peer":"peer1","address":"/ip4/1.2.3.4/tcp/4001","received_entries":[{"priority":1,"cancel":true,"send_dont_have":false,"cid":{"/":"Qm1"},"want_type":0}],"full_want_list":false,"peer_connected":null,"peer_disconnected":null,"connect_event_peer_found":null}
+not json
+{"timestamp":"2021-01-01T00:00:04Z","peer":"peer1","address":null,"received_entries":null,"full_want_list":null,"peer_connected":false,"peer_disconnected":true,"connect_event_peer_found":null}
+{"timestamp":"2021-01-01T00:00:05Z","peer":"peer1","address":null,"received_entries":null,"full_want_list":null,"peer_connected":false,"peer_disconnected":true,"connect_event_peer_found":null}
+{"timestamp":"2021-01-01T00:00:06Z","peer":"peer1","address":"/ip4/1.2.3.4/tcp/4001","received_entries":[{"priority":1,"cancel":false,"send_dont_have":false,"cid":{"/":"Qm3"},"want_type":1}],"full_want_list":false,"peer_connected":null,"peer_disconnected":null,"connect_event_peer_found":null}
+{"timestamp":"2021-01-01T00:00:07Z","peer":"peer2","address":"/ip4/9.9.9.9/tcp/4001","received_entries":null,"full_want_list":null,"peer_connected":true,"peer_disconnected":false,"connect_event_peer_found":true}
+{"timestamp":"2021-01-01T00:00:08Z","peer":"peer2","address":"/ip4/9.9.9.9/tcp/4001","received_entries":null,"full_want_list":null,"peer_connected":true,"peer_disconnected":false,"connect_event_peer_found":false}
+"#;
+
+    const OVERLAPPING_TRACE: &str = r#"{"timestamp":"2021-01-01T00:00:01Z","peer":"peer1","address":"/ip4/1.2.3.4/tcp/4001","received_entries":null,"full_want_list":null,"peer_connected":true,"peer_disconnected":false,"connect_event_peer_found":false}
+{"timestamp":"2021-01-01T00:00:02Z","peer":"peer1","address":"/ip4/5.6.7.8/tcp/4001","received_entries":null,"full_want_list":null,"peer_connected":true,"peer_disconnected":false,"connect_event_peer_found":true}
+{"timestamp":"2021-01-01T00:00:03Z","peer":"peer1","address":"/ip4/1.2.3.4/tcp/4001","received_entries":[{"priority":1,"cancel":false,"send_dont_have":false,"cid":{"/":"Qm1"},"want_type":0}],"full_want_list":false,"peer_connected":null,"peer_disconnected":null,"connect_event_peer_found":null}
+{"timestamp":"2021-01-01T00:00:04Z","peer":"peer1","address":"/ip4/1.2.3.4/tcp/4001","received_entries":null,"full_want_list":null,"peer_connected":false,"peer_disconnected":true,"connect_event_peer_found":true}
+{"timestamp":"2021-01-01T00:00:05Z","peer":"peer1","address":"/ip4/5.6.7.8/tcp/4001","received_entries":[{"priority":1,"cancel":true,"send_dont_have":false,"cid":{"/":"Qm1"},"want_type":0}],"full_want_list":false,"peer_connected":null,"peer_disconnected":null,"connect_event_peer_found":null}
+{"timestamp":"2021-01-01T00:00:06Z","peer":"peer1","address":"/ip4/5.6.7.8/tcp/4001","received_entries":null,"full_want_list":null,"peer_connected":false,"peer_disconnected":true,"connect_event_peer_found":true}
+{"timestamp":"2021-01-01T00:00:07Z","peer":"peer1","address":"/ip4/5.6.7.8/tcp/4001","received_entries":null,"full_want_list":null,"peer_connected":false,"peer_disconnected":true,"connect_event_peer_found":true}
+"#;
+
+    fn validate_trace(name: &str, trace: &str, cfg: ValidationConfig) -> ValidationReport {
+        let path =
+            std::env::temp_dir().join(format!("validate-{}-{}.json.gz", name, std::process::id()));
+        let mut enc = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        enc.write_all(trace.as_bytes()).unwrap();
+        enc.finish().unwrap();
+
+        let mut validator = Validator::new(cfg);
+        validator
+            .validate_file(&path, InputFormat::JsonMessages)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        validator.finish()
+    }
+
+    #[test]
+    fn counts_anomalies() {
+        let report = validate_trace("counts", TRACE, ValidationConfig::default());
+
+        let expected: BTreeMap<_, _> = [
+            AnomalyClass::MalformedMessage,
+            AnomalyClass::OutOfOrderTimestamp,
+            AnomalyClass::ConnectWithoutDisconnect,
+            AnomalyClass::DisconnectWithoutConnect,
+            AnomalyClass::UnknownCancel,
+            AnomalyClass::AddressMismatch,
+            AnomalyClass::WantlistFromDisconnectedPeer,
+        ]
+        .into_iter()
+        .map(|class| (class, 1))
+        .collect();
+        assert_eq!(report.totals, expected);
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.files[0].messages, 10);
+        assert_eq!(report.files[0].anomalies, expected);
+
+        // Malformed messages are not attributed to peers.
+        assert_eq!(report.peers["peer1"].values().sum::<u64>(), 5);
+        assert_eq!(
+            report.peers["peer2"].keys().collect::<Vec<_>>(),
+            vec![&AnomalyClass::ConnectWithoutDisconnect]
+        );
+
+        let sample = &report.samples[&AnomalyClass::OutOfOrderTimestamp][0];
+        assert_eq!(sample.position, 4);
+        assert_eq!(sample.peer.as_deref(), Some("peer1"));
+        assert_eq!(
+            report.samples[&AnomalyClass::MalformedMessage][0].raw,
+            "not json"
+        );
+
+        // Without thresholds, any number of anomalies passes.
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn thresholds() {
+        let report = validate_trace(
+            "thresholds",
+            TRACE,
+            ValidationConfig {
+                max_samples: 0,
+                thresholds: [
+                    (AnomalyClass::MalformedMessage, 1),
+                    (AnomalyClass::UnknownCancel, 0),
+                ]
+                .into_iter()
+                .collect(),
+            },
+        );
+        assert!(report.samples.values().all(|s| s.is_empty()));
+        assert!(!report.passed());
+        assert_eq!(report.threshold_violations.len(), 1);
+        let violation = &report.threshold_violations[0];
+        assert_eq!(violation.class, AnomalyClass::UnknownCancel);
+        assert_eq!(violation.count, 1);
+        assert_eq!(violation.threshold, 0);
+
+        let report = validate_trace(
+            "thresholds",
+            TRACE,
+            ValidationConfig {
+                max_samples: 0,
+                thresholds: [(AnomalyClass::MalformedMessage, 1)].into_iter().collect(),
+            },
+        );
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn overlapping_connections() {
+        let report = validate_trace(
+            "overlapping",
+            OVERLAPPING_TRACE,
+            ValidationConfig::default(),
+        );
+
+        // Closing one of two connections keeps the wantlist of the peer, so neither the cancel
+        // nor the second connection are anomalies.
+        // Only the third disconnect is.
+        let expected: BTreeMap<_, _> = [(AnomalyClass::DisconnectWithoutConnect, 1)]
+            .into_iter()
+            .collect();
+        assert_eq!(report.totals, expected);
+        assert_eq!(
+            report.samples[&AnomalyClass::DisconnectWithoutConnect][0].position,
+            7
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use crate::monitoring::{ConnectionEventType, EventType, PushedEvent};
+use ipfs_resolver_common::wantlist;
+use parity_multiaddr::Multiaddr;
+use std::collections::HashMap;
+
+/// Converts `PushedEvent`s to `JSONMessage`s, which can be fed to the engine simulation.
+///
+/// The monitoring plugin does not report whether a ledger was found for a connection event, so we
+/// derive that by tracking the number of open connections per peer.
+#[derive(Clone, Debug, Default)]
+pub struct PushedEventConverter {
+    connections: HashMap<String, usize>,
+}
+
+impl PushedEventConverter {
+    pub fn new() -> PushedEventConverter {
+        Default::default()
+    }
+
+    /// Converts an event to a `JSONMessage`.
+    /// Returns `None` for Bitswap messages which do not affect the wantlist, i.e., messages
+    /// which contain only blocks or block presences.
+    pub fn convert(&mut self, event: &PushedEvent) -> Option<wantlist::JSONMessage> {
+        match &event.inner {
+            EventType::BitswapMessage(msg) => {
+                if msg.wantlist_entries.is_empty() && !msg.full_wantlist {
+                    return None;
+                }
+
+                Some(wantlist::JSONMessage {
+                    timestamp: event.timestamp,
+                    peer: event.peer.clone(),
+                    address: msg
+                        .connected_addresses
+                        .first()
+                        .and_then(|a| Self::parse_address(a)),
+                    received_entries: Some(msg.wantlist_entries.clone()),
+                    full_want_list: Some(msg.full_wantlist),
+                    peer_connected: None,
+                    peer_disconnected: None,
+                    connect_event_peer_found: None,
+                })
+            }
+            EventType::ConnectionEvent(conn_event) => {
+                let connected = match conn_event.connection_event_type {
+                    ConnectionEventType::Connected => true,
+                    ConnectionEventType::Disconnected => false,
+                };
+                let open_connections = self.connections.entry(event.peer.clone()).or_default();
+                let found = *open_connections > 0;
+                if connected {
+                    *open_connections += 1;
+                } else {
+                    *open_connections = open_connections.saturating_sub(1);
+                }
+
+                Some(wantlist::JSONMessage {
+                    timestamp: event.timestamp,
+                    peer: event.peer.clone(),
+                    address: Self::parse_address(&conn_event.remote),
+                    received_entries: None,
+                    full_want_list: None,
+                    peer_connected: Some(connected),
+                    peer_disconnected: Some(!connected),
+                    connect_event_peer_found: Some(found),
+                })
+            }
+        }
+    }
+
+    fn parse_address(addr: &str) -> Option<Multiaddr> {
+        match addr.parse() {
+            Ok(addr) => Some(addr),
+            Err(err) => {
+                debug!("unable to parse address {}: {}", addr, err);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::{BitswapMessage, ConnectionEvent};
+    use ipfs_resolver_common::wantlist::{JSONWantType, JSONWantlistEntry, JsonCID};
+
+    fn event(peer: &str, inner: EventType) -> PushedEvent {
+        PushedEvent {
+            timestamp: chrono::Utc::now(),
+            peer: peer.to_string(),
+            inner,
+        }
+    }
+
+    fn connection_event(peer: &str, connection_event_type: ConnectionEventType) -> PushedEvent {
+        event(
+            peer,
+            EventType::ConnectionEvent(ConnectionEvent {
+                remote: "/ip4/1.2.3.4/tcp/4001".to_string(),
+                connection_event_type,
+            }),
+        )
+    }
+
+    fn bitswap_message(entries: Vec<JSONWantlistEntry>, full_wantlist: bool) -> PushedEvent {
+        event(
+            "peer1",
+            EventType::BitswapMessage(BitswapMessage {
+                wantlist_entries: entries,
+                full_wantlist,
+                blocks: vec![JsonCID {
+                    path: "QmBlock".to_string(),
+                }],
+                block_presences: vec![],
+                connected_addresses: vec!["/ip4/1.2.3.4/tcp/4001".to_string()],
+            }),
+        )
+    }
+
+    /// Converts a connection event and returns whether the peer was found.
+    fn found(
+        converter: &mut PushedEventConverter,
+        peer: &str,
+        connection_event_type: ConnectionEventType,
+    ) -> bool {
+        converter
+            .convert(&connection_event(peer, connection_event_type))
+            .unwrap()
+            .connect_event_peer_found
+            .unwrap()
+    }
+
+    #[test]
+    fn convert_bitswap_message() {
+        let mut converter = PushedEventConverter::new();
+        let entry = JSONWantlistEntry {
+            priority: 1,
+            cancel: false,
+            send_dont_have: true,
+            cid: JsonCID {
+                path: "QmWanted".to_string(),
+            },
+            want_type: JSONWantType::Have,
+        };
+
+        let msg = converter
+            .convert(&bitswap_message(vec![entry.clone()], false))
+            .unwrap();
+        assert_eq!(msg.peer, "peer1");
+        assert_eq!(msg.received_entries, Some(vec![entry]));
+        assert_eq!(msg.full_want_list, Some(false));
+        assert!(msg.address.is_some());
+        assert_eq!(msg.peer_connected, None);
+
+        // An empty full wantlist clears the wantlist, so it is kept.
+        let msg = converter.convert(&bitswap_message(vec![], true)).unwrap();
+        assert_eq!(msg.received_entries, Some(vec![]));
+
+        // Messages with only blocks do not affect the wantlist.
+        assert!(converter.convert(&bitswap_message(vec![], false)).is_none());
+    }
+
+    #[test]
+    fn derive_found_from_open_connections() {
+        let mut converter = PushedEventConverter::new();
+
+        // The first connection to a peer creates a ledger.
+        assert!(!found(
+            &mut converter,
+            "peer1",
+            ConnectionEventType::Connected
+        ));
+        assert!(found(
+            &mut converter,
+            "peer1",
+            ConnectionEventType::Connected
+        ));
+        // Connections are tracked per peer.
+        assert!(!found(
+            &mut converter,
+            "peer2",
+            ConnectionEventType::Connected
+        ));
+
+        assert!(found(
+            &mut converter,
+            "peer1",
+            ConnectionEventType::Disconnected
+        ));
+        assert!(found(
+            &mut converter,
+            "peer1",
+            ConnectionEventType::Disconnected
+        ));
+        // All connections to the peer are closed now.
+        assert!(!found(
+            &mut converter,
+            "peer1",
+            ConnectionEventType::Disconnected
+        ));
+        assert!(!found(
+            &mut converter,
+            "peer1",
+            ConnectionEventType::Connected
+        ));
+
+        let msg = converter
+            .convert(&connection_event(
+                "peer2",
+                ConnectionEventType::Disconnected,
+            ))
+            .unwrap();
+        assert_eq!(msg.peer_connected, Some(false));
+        assert_eq!(msg.peer_disconnected, Some(true));
+        assert_eq!(msg.connect_event_peer_found, Some(true));
+    }
+}
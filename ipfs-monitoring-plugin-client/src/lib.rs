@@ -3,5 +3,6 @@
 #[macro_use]
 extern crate log;
 
+pub mod conversion;
 pub mod http;
 pub mod monitoring;
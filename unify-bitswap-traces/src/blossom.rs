@@ -0,0 +1,606 @@
+//! Maximum weight matching in general graphs.
+//!
+//! This is Edmonds' blossom algorithm with dual variables, in `O(n^3)` for `n` vertices, following
+//! the implementation by Joris van Rantwijk.
+//! Edges are referred to by their index `k`, and their endpoints by `2k` and `2k + 1`.
+//! Dual variables are kept at twice their value, so that integer weights only need integer
+//! computations.
+
+/// Marks the absence of a vertex, edge, endpoint, or blossom.
+const NONE: usize = usize::MAX;
+
+/// Computes a matching of maximum total weight, using only edges with positive weight.
+/// Edges are pairs of vertices, given by their index, with a weight.
+/// Returns the vertex each vertex is matched to, if any.
+pub(crate) fn max_weight_matching(
+    num_vertices: usize,
+    edges: &[(usize, usize, i64)],
+) -> Vec<Option<usize>> {
+    let edges: Vec<_> = edges.iter().copied().filter(|(_, _, w)| *w > 0).collect();
+    if edges.is_empty() {
+        return vec![None; num_vertices];
+    }
+
+    let mut m = Matching::new(num_vertices, &edges);
+    m.solve();
+    m.mate
+        .iter()
+        .map(|p| (*p != NONE).then(|| m.endpoint[*p]))
+        .collect()
+}
+
+/// The state of the algorithm.
+/// Vertices are `0..n`, blossoms `n..2n`, and many fields are indexed by both.
+struct Matching<'a> {
+    n: usize,
+    edges: &'a [(usize, usize, i64)],
+    /// The vertex of each endpoint.
+    endpoint: Vec<usize>,
+    /// The remote endpoints of the edges of each vertex.
+    neighbor_endpoints: Vec<Vec<usize>>,
+    /// The remote endpoint of the matched edge of each vertex.
+    mate: Vec<usize>,
+    /// The label of each top-level blossom and vertex: 0 for free, 1 for S, 2 for T.
+    /// Set to 5 temporarily while scanning for a blossom.
+    label: Vec<u8>,
+    /// The endpoint through which each labeled blossom or vertex got its label.
+    label_end: Vec<usize>,
+    /// The top-level blossom each vertex belongs to.
+    in_blossom: Vec<usize>,
+    blossom_parent: Vec<usize>,
+    /// The sub-blossoms of each blossom, starting with the one containing the base.
+    blossom_children: Vec<Vec<usize>>,
+    blossom_base: Vec<usize>,
+    /// The endpoints connecting the sub-blossoms of each blossom, in order.
+    blossom_endpoints: Vec<Vec<usize>>,
+    /// The least-slack edge to a different S-blossom, for each vertex and S-blossom.
+    best_edge: Vec<usize>,
+    /// The least-slack edges to other S-blossoms, for each non-trivial S-blossom.
+    blossom_best_edges: Vec<Option<Vec<usize>>>,
+    unused_blossoms: Vec<usize>,
+    dual: Vec<i64>,
+    /// Edges with zero slack, that may be used to grow the alternating trees.
+    allow_edge: Vec<bool>,
+    /// S-vertices whose edges have not been scanned yet.
+    queue: Vec<usize>,
+}
+
+impl<'a> Matching<'a> {
+    fn new(n: usize, edges: &'a [(usize, usize, i64)]) -> Self {
+        let max_weight = edges.iter().map(|(_, _, w)| *w).max().unwrap_or(0).max(0);
+        let endpoint = (0..2 * edges.len())
+            .map(|p| {
+                let (i, j, _) = edges[p / 2];
+                if p % 2 == 0 {
+                    i
+                } else {
+                    j
+                }
+            })
+            .collect();
+        let mut neighbor_endpoints = vec![Vec::new(); n];
+        for (k, (i, j, _)) in edges.iter().enumerate() {
+            neighbor_endpoints[*i].push(2 * k + 1);
+            neighbor_endpoints[*j].push(2 * k);
+        }
+
+        Matching {
+            n,
+            edges,
+            endpoint,
+            neighbor_endpoints,
+            mate: vec![NONE; n],
+            label: vec![0; 2 * n],
+            label_end: vec![NONE; 2 * n],
+            in_blossom: (0..n).collect(),
+            blossom_parent: vec![NONE; 2 * n],
+            blossom_children: vec![Vec::new(); 2 * n],
+            blossom_base: (0..2 * n).map(|b| if b < n { b } else { NONE }).collect(),
+            blossom_endpoints: vec![Vec::new(); 2 * n],
+            best_edge: vec![NONE; 2 * n],
+            blossom_best_edges: vec![None; 2 * n],
+            unused_blossoms: (n..2 * n).collect(),
+            dual: (0..2 * n)
+                .map(|b| if b < n { max_weight } else { 0 })
+                .collect(),
+            allow_edge: vec![false; edges.len()],
+            queue: Vec::new(),
+        }
+    }
+
+    fn slack(&self, k: usize) -> i64 {
+        let (i, j, w) = self.edges[k];
+        self.dual[i] + self.dual[j] - 2 * w
+    }
+
+    /// Returns the vertices of the blossom, or the vertex itself.
+    fn blossom_leaves(&self, b: usize) -> Vec<usize> {
+        if b < self.n {
+            return vec![b];
+        }
+        self.blossom_children[b]
+            .iter()
+            .flat_map(|t| self.blossom_leaves(*t))
+            .collect()
+    }
+
+    /// Indexes the children of a blossom cyclically, as the traversals of blossoms go either way.
+    fn child(&self, b: usize, j: isize) -> usize {
+        let children = &self.blossom_children[b];
+        children[j.rem_euclid(children.len() as isize) as usize]
+    }
+
+    fn child_endpoint(&self, b: usize, j: isize) -> usize {
+        let endpoints = &self.blossom_endpoints[b];
+        endpoints[j.rem_euclid(endpoints.len() as isize) as usize]
+    }
+
+    /// Labels the top-level blossom of vertex `w`, reached through endpoint `p`, and the mate of
+    /// its base if the label is T.
+    fn assign_label(&mut self, w: usize, t: u8, p: usize) {
+        let b = self.in_blossom[w];
+        self.label[w] = t;
+        self.label[b] = t;
+        self.label_end[w] = p;
+        self.label_end[b] = p;
+        self.best_edge[w] = NONE;
+        self.best_edge[b] = NONE;
+        if t == 1 {
+            let leaves = self.blossom_leaves(b);
+            self.queue.extend(leaves);
+        } else if t == 2 {
+            let base = self.blossom_base[b];
+            let m = self.mate[base];
+            self.assign_label(self.endpoint[m], 1, m ^ 1);
+        }
+    }
+
+    /// Traces back from S-vertices `v` and `w` to find either a new blossom, returning its base,
+    /// or an augmenting path, returning `NONE`.
+    fn scan_blossom(&mut self, mut v: usize, mut w: usize) -> usize {
+        let mut path = Vec::new();
+        let mut base = NONE;
+        while v != NONE || w != NONE {
+            let mut b = self.in_blossom[v];
+            if self.label[b] & 4 != 0 {
+                base = self.blossom_base[b];
+                break;
+            }
+            path.push(b);
+            self.label[b] = 5;
+            if self.label_end[b] == NONE {
+                // The root of the tree.
+                v = NONE;
+            } else {
+                v = self.endpoint[self.label_end[b]];
+                b = self.in_blossom[v];
+                v = self.endpoint[self.label_end[b]];
+            }
+            if w != NONE {
+                std::mem::swap(&mut v, &mut w);
+            }
+        }
+        for b in path {
+            self.label[b] = 1;
+        }
+        base
+    }
+
+    /// Constructs a new blossom with the given base, through S-vertices connected by edge `k`.
+    fn add_blossom(&mut self, base: usize, k: usize) {
+        let (mut v, mut w, _) = self.edges[k];
+        let bb = self.in_blossom[base];
+        let mut bv = self.in_blossom[v];
+        let mut bw = self.in_blossom[w];
+        let b = self.unused_blossoms.pop().unwrap();
+        self.blossom_base[b] = base;
+        self.blossom_parent[b] = NONE;
+        self.blossom_parent[bb] = b;
+
+        let mut path = Vec::new();
+        let mut endpoints = Vec::new();
+        while bv != bb {
+            self.blossom_parent[bv] = b;
+            path.push(bv);
+            endpoints.push(self.label_end[bv]);
+            v = self.endpoint[self.label_end[bv]];
+            bv = self.in_blossom[v];
+        }
+        path.push(bb);
+        path.reverse();
+        endpoints.reverse();
+        endpoints.push(2 * k);
+        while bw != bb {
+            self.blossom_parent[bw] = b;
+            path.push(bw);
+            endpoints.push(self.label_end[bw] ^ 1);
+            w = self.endpoint[self.label_end[bw]];
+            bw = self.in_blossom[w];
+        }
+        self.blossom_children[b] = path.clone();
+        self.blossom_endpoints[b] = endpoints;
+
+        self.label[b] = 1;
+        self.label_end[b] = self.label_end[bb];
+        self.dual[b] = 0;
+        for v in self.blossom_leaves(b) {
+            if self.label[self.in_blossom[v]] == 2 {
+                // T-vertices become S-vertices, so their edges need to be scanned.
+                self.queue.push(v);
+            }
+            self.in_blossom[v] = b;
+        }
+
+        // Compute the least-slack edges to other S-blossoms.
+        let mut best_edge_to = vec![NONE; 2 * self.n];
+        for bv in path {
+            let edge_lists: Vec<Vec<usize>> = match self.blossom_best_edges[bv].take() {
+                Some(edges) => vec![edges],
+                None => self
+                    .blossom_leaves(bv)
+                    .into_iter()
+                    .map(|v| self.neighbor_endpoints[v].iter().map(|p| p / 2).collect())
+                    .collect(),
+            };
+            for k in edge_lists.into_iter().flatten() {
+                let (mut i, mut j, _) = self.edges[k];
+                if self.in_blossom[j] == b {
+                    std::mem::swap(&mut i, &mut j);
+                }
+                let bj = self.in_blossom[j];
+                if bj != b
+                    && self.label[bj] == 1
+                    && (best_edge_to[bj] == NONE || self.slack(k) < self.slack(best_edge_to[bj]))
+                {
+                    best_edge_to[bj] = k;
+                }
+            }
+            self.best_edge[bv] = NONE;
+        }
+        let best_edges: Vec<usize> = best_edge_to.into_iter().filter(|k| *k != NONE).collect();
+        self.best_edge[b] = NONE;
+        for k in best_edges.iter().copied() {
+            if self.best_edge[b] == NONE || self.slack(k) < self.slack(self.best_edge[b]) {
+                self.best_edge[b] = k;
+            }
+        }
+        self.blossom_best_edges[b] = Some(best_edges);
+    }
+
+    /// Expands the blossom into its sub-blossoms, relabeling them if this happens during a stage.
+    fn expand_blossom(&mut self, b: usize, end_stage: bool) {
+        let children = self.blossom_children[b].clone();
+        for s in children.iter().copied() {
+            self.blossom_parent[s] = NONE;
+            if s < self.n {
+                self.in_blossom[s] = s;
+            } else if end_stage && self.dual[s] == 0 {
+                self.expand_blossom(s, end_stage);
+            } else {
+                for v in self.blossom_leaves(s) {
+                    self.in_blossom[v] = s;
+                }
+            }
+        }
+
+        if !end_stage && self.label[b] == 2 {
+            // Relabel the sub-blossoms on the even-length path from the entry child to the base.
+            let entry_child = self.in_blossom[self.endpoint[self.label_end[b] ^ 1]];
+            let mut j = children.iter().position(|c| *c == entry_child).unwrap() as isize;
+            let (j_step, trick) = if j & 1 != 0 {
+                j -= children.len() as isize;
+                (1, 0)
+            } else {
+                (-1, 1)
+            };
+            let mut p = self.label_end[b];
+            while j != 0 {
+                self.label[self.endpoint[p ^ 1]] = 0;
+                let q = self.child_endpoint(b, j - trick as isize);
+                self.label[self.endpoint[q ^ trick ^ 1]] = 0;
+                self.assign_label(self.endpoint[p ^ 1], 2, p);
+                self.allow_edge[q / 2] = true;
+                j += j_step;
+                p = self.child_endpoint(b, j - trick as isize) ^ trick;
+                self.allow_edge[p / 2] = true;
+                j += j_step;
+            }
+            let bv = self.child(b, j);
+            self.label[self.endpoint[p ^ 1]] = 2;
+            self.label[bv] = 2;
+            self.label_end[self.endpoint[p ^ 1]] = p;
+            self.label_end[bv] = p;
+            self.best_edge[bv] = NONE;
+            j += j_step;
+
+            // The other sub-blossoms are only labeled if they were reached from outside.
+            while self.child(b, j) != entry_child {
+                let bv = self.child(b, j);
+                if self.label[bv] == 1 {
+                    j += j_step;
+                    continue;
+                }
+                let reached = self
+                    .blossom_leaves(bv)
+                    .into_iter()
+                    .find(|v| self.label[*v] != 0);
+                if let Some(v) = reached {
+                    self.label[v] = 0;
+                    let m = self.mate[self.blossom_base[bv]];
+                    self.label[self.endpoint[m]] = 0;
+                    self.assign_label(v, 2, self.label_end[v]);
+                }
+                j += j_step;
+            }
+        }
+
+        self.label[b] = 0;
+        self.label_end[b] = NONE;
+        self.blossom_children[b].clear();
+        self.blossom_endpoints[b].clear();
+        self.blossom_base[b] = NONE;
+        self.blossom_best_edges[b] = None;
+        self.best_edge[b] = NONE;
+        self.unused_blossoms.push(b);
+    }
+
+    /// Swaps matched and unmatched edges on the path within the blossom from vertex `v` to the
+    /// base, making `v` the new base.
+    fn augment_blossom(&mut self, b: usize, v: usize) {
+        let mut t = v;
+        while self.blossom_parent[t] != b {
+            t = self.blossom_parent[t];
+        }
+        if t >= self.n {
+            self.augment_blossom(t, v);
+        }
+
+        let i = self.blossom_children[b]
+            .iter()
+            .position(|c| *c == t)
+            .unwrap();
+        let mut j = i as isize;
+        let (j_step, trick) = if i & 1 != 0 {
+            j -= self.blossom_children[b].len() as isize;
+            (1, 0)
+        } else {
+            (-1, 1)
+        };
+        while j != 0 {
+            j += j_step;
+            let t = self.child(b, j);
+            let p = self.child_endpoint(b, j - trick as isize) ^ trick;
+            if t >= self.n {
+                self.augment_blossom(t, self.endpoint[p]);
+            }
+            j += j_step;
+            let t = self.child(b, j);
+            if t >= self.n {
+                self.augment_blossom(t, self.endpoint[p ^ 1]);
+            }
+            self.mate[self.endpoint[p]] = p ^ 1;
+            self.mate[self.endpoint[p ^ 1]] = p;
+        }
+
+        self.blossom_children[b].rotate_left(i);
+        self.blossom_endpoints[b].rotate_left(i);
+        self.blossom_base[b] = self.blossom_base[self.blossom_children[b][0]];
+    }
+
+    /// Swaps matched and unmatched edges on the augmenting path through edge `k`.
+    fn augment_matching(&mut self, k: usize) {
+        let (v, w, _) = self.edges[k];
+        for (mut s, mut p) in [(v, 2 * k + 1), (w, 2 * k)] {
+            loop {
+                let bs = self.in_blossom[s];
+                if bs >= self.n {
+                    self.augment_blossom(bs, s);
+                }
+                self.mate[s] = p;
+                if self.label_end[bs] == NONE {
+                    // The root of the tree.
+                    break;
+                }
+                let t = self.endpoint[self.label_end[bs]];
+                let bt = self.in_blossom[t];
+                s = self.endpoint[self.label_end[bt]];
+                let j = self.endpoint[self.label_end[bt] ^ 1];
+                if bt >= self.n {
+                    self.augment_blossom(bt, j);
+                }
+                self.mate[j] = self.label_end[bt];
+                p = self.label_end[bt] ^ 1;
+            }
+        }
+    }
+
+    /// Runs stages until no augmenting path improves the matching.
+    fn solve(&mut self) {
+        let n = self.n;
+        for _ in 0..n {
+            self.label.iter_mut().for_each(|l| *l = 0);
+            self.best_edge.iter_mut().for_each(|k| *k = NONE);
+            self.blossom_best_edges[n..]
+                .iter_mut()
+                .for_each(|e| *e = None);
+            self.allow_edge.iter_mut().for_each(|a| *a = false);
+            self.queue.clear();
+
+            for v in 0..n {
+                if self.mate[v] == NONE && self.label[self.in_blossom[v]] == 0 {
+                    self.assign_label(v, 1, NONE);
+                }
+            }
+
+            let mut augmented = false;
+            loop {
+                while !augmented {
+                    let Some(v) = self.queue.pop() else {
+                        break;
+                    };
+                    for p in self.neighbor_endpoints[v].clone() {
+                        let k = p / 2;
+                        let w = self.endpoint[p];
+                        if self.in_blossom[v] == self.in_blossom[w] {
+                            continue;
+                        }
+                        let mut k_slack = 0;
+                        if !self.allow_edge[k] {
+                            k_slack = self.slack(k);
+                            if k_slack <= 0 {
+                                self.allow_edge[k] = true;
+                            }
+                        }
+                        if self.allow_edge[k] {
+                            if self.label[self.in_blossom[w]] == 0 {
+                                self.assign_label(w, 2, p ^ 1);
+                            } else if self.label[self.in_blossom[w]] == 1 {
+                                let base = self.scan_blossom(v, w);
+                                if base != NONE {
+                                    self.add_blossom(base, k);
+                                } else {
+                                    self.augment_matching(k);
+                                    augmented = true;
+                                    break;
+                                }
+                            } else if self.label[w] == 0 {
+                                self.label[w] = 2;
+                                self.label_end[w] = p ^ 1;
+                            }
+                        } else if self.label[self.in_blossom[w]] == 1 {
+                            let b = self.in_blossom[v];
+                            if self.best_edge[b] == NONE || k_slack < self.slack(self.best_edge[b])
+                            {
+                                self.best_edge[b] = k;
+                            }
+                        } else if self.label[w] == 0
+                            && (self.best_edge[w] == NONE
+                                || k_slack < self.slack(self.best_edge[w]))
+                        {
+                            self.best_edge[w] = k;
+                        }
+                    }
+                }
+                if augmented {
+                    break;
+                }
+
+                // No augmenting path yet, update the dual variables by the largest possible delta.
+                let mut delta_type = 1;
+                let mut delta = *self.dual[..n].iter().min().unwrap();
+                let mut delta_edge = NONE;
+                let mut delta_blossom = NONE;
+                for v in 0..n {
+                    if self.label[self.in_blossom[v]] == 0 && self.best_edge[v] != NONE {
+                        let d = self.slack(self.best_edge[v]);
+                        if d < delta {
+                            delta = d;
+                            delta_type = 2;
+                            delta_edge = self.best_edge[v];
+                        }
+                    }
+                }
+                for b in 0..2 * n {
+                    if self.blossom_parent[b] == NONE
+                        && self.label[b] == 1
+                        && self.best_edge[b] != NONE
+                    {
+                        let d = self.slack(self.best_edge[b]) / 2;
+                        if d < delta {
+                            delta = d;
+                            delta_type = 3;
+                            delta_edge = self.best_edge[b];
+                        }
+                    }
+                }
+                for b in n..2 * n {
+                    if self.blossom_base[b] != NONE
+                        && self.blossom_parent[b] == NONE
+                        && self.label[b] == 2
+                        && self.dual[b] < delta
+                    {
+                        delta = self.dual[b];
+                        delta_type = 4;
+                        delta_blossom = b;
+                    }
+                }
+
+                for v in 0..n {
+                    match self.label[self.in_blossom[v]] {
+                        1 => self.dual[v] -= delta,
+                        2 => self.dual[v] += delta,
+                        _ => {}
+                    }
+                }
+                for b in n..2 * n {
+                    if self.blossom_base[b] != NONE && self.blossom_parent[b] == NONE {
+                        match self.label[b] {
+                            1 => self.dual[b] += delta,
+                            2 => self.dual[b] -= delta,
+                            _ => {}
+                        }
+                    }
+                }
+
+                match delta_type {
+                    // Some vertex dual reached zero, the matching is optimal.
+                    1 => break,
+                    2 | 3 => {
+                        self.allow_edge[delta_edge] = true;
+                        let (mut i, mut j, _) = self.edges[delta_edge];
+                        if self.label[self.in_blossom[i]] == 0 {
+                            std::mem::swap(&mut i, &mut j);
+                        }
+                        self.queue.push(i);
+                    }
+                    _ => self.expand_blossom(delta_blossom, false),
+                }
+            }
+
+            if !augmented {
+                break;
+            }
+
+            // Expand S-blossoms whose dual reached zero.
+            for b in n..2 * n {
+                if self.blossom_parent[b] == NONE
+                    && self.blossom_base[b] != NONE
+                    && self.label[b] == 1
+                    && self.dual[b] == 0
+                {
+                    self.expand_blossom(b, true);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_odd_cycles() {
+        // A path prefers the heavier edge, unless both outer edges together weigh more.
+        assert_eq!(
+            max_weight_matching(3, &[(0, 1, 5), (1, 2, 6)]),
+            vec![None, Some(2), Some(1)]
+        );
+        assert_eq!(
+            max_weight_matching(4, &[(0, 1, 5), (1, 2, 6), (2, 3, 5)]),
+            vec![Some(1), Some(0), Some(3), Some(2)]
+        );
+
+        // A triangle with a pendant vertex needs a blossom to find the augmenting path.
+        assert_eq!(
+            max_weight_matching(4, &[(0, 1, 8), (1, 2, 9), (0, 2, 10), (2, 3, 7)]),
+            vec![Some(1), Some(0), Some(3), Some(2)]
+        );
+
+        // Edges without positive weight are never used.
+        assert_eq!(
+            max_weight_matching(3, &[(0, 1, 0), (1, 2, -1)]),
+            vec![None, None, None]
+        );
+    }
+}
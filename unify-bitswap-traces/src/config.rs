@@ -1,5 +1,8 @@
 use crate::Result;
 use failure::ResultExt;
+use ipfs_resolver_common::anonymize::AnonymizationConfig;
+use ipfs_resolver_common::output::OutputFormat;
+use ipfs_resolver_common::rotation::RotationConfig;
 use ipfs_resolver_common::wantlist;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
@@ -12,37 +15,110 @@ pub(crate) struct Config {
     ///
     /// The monitors configured here will be given numeric IDs in the order they are provided in
     /// the config file.
+    /// Ignored in live mode, see `live`.
+    #[serde(default)]
     pub(crate) monitors: Vec<MonitorSourceConfig>,
 
     /// The size of the sliding window (in number of JSON messages) to use for sorting the streams
     /// of per-monitor messages.
     pub(crate) message_sorting_window_size: usize,
 
+    /// If set, the sorting window additionally holds all messages up to this many milliseconds
+    /// newer than the oldest message in the window.
+    /// This is useful if messages are out of order by a bounded time rather than a bounded number
+    /// of messages.
+    #[serde(default)]
+    pub(crate) message_sorting_window_milliseconds: Option<u64>,
+
+    /// What to do with messages that are still out of order after sorting, i.e., that are older
+    /// than a message of the same monitor emitted before them.
+    /// These are always counted and reported per monitor.
+    /// Defaults to warn.
+    #[serde(default)]
+    pub(crate) out_of_order_policy: OutOfOrderPolicy,
+
     /// A pattern for output file paths.
-    /// The pattern must contain "$id$", which will be replaced by the ID (number) of the first
-    /// message in this file, formatted in such a way that the paths are lexicographically ordered.
+    /// The pattern must contain "$id$", which will be replaced by the ID (number) of the last
+    /// message before this file, formatted in such a way that the paths are lexicographically
+    /// ordered.
+    /// The first file is numbered 0, like those of ipfs-json-to-csv.
+    /// Note that earlier versions used the ID of the first message in the file, plus one.
     /// This is used in order not to produce one gigantic output file, but rather a bunch of smaller
     /// ones.
     ///
-    /// The output file will be gzipped CSV.
+    /// The format of the output files is determined by `output_format`.
+    /// In live mode, the ID is prefixed with the start time of the run.
     ///
     /// Example: output/part-$id$.csv.gz
     pub(crate) wantlist_output_file_pattern: String,
 
-    /// Currently unused.
-    ///
-    /// The path for a file to record something about ledgers in.
+    /// When to rotate output files, and where to write a manifest of them.
+    /// If no limit is configured, files are rotated every 100k messages.
+    #[serde(default)]
+    pub(crate) wantlist_output_rotation: RotationConfig,
+
+    /// The format of the output files, either csv (the default, gzipped) or parquet.
+    #[serde(default)]
+    pub(crate) output_format: OutputFormat,
+
+    /// If set, peer IDs and addresses are anonymized before they are written to the output.
+    /// Using the same key as for other tools produces consistent pseudonyms.
+    #[serde(default)]
+    pub(crate) anonymization: Option<AnonymizationConfig>,
+
+    /// The path for a file to record the number of ledgers and missing ledgers in, per monitor
+    /// and in total, once per `ledger_count_interval_secs` of trace time.
     ///
-    /// The file will be gzipped CSV.
+    /// The format of the file is determined by `output_format`.
     pub(crate) ledger_count_output_file: String,
 
+    /// If set, the connection events of all monitors are written to this file, annotated with
+    /// the ID of the monitor.
+    #[serde(default)]
+    pub(crate) connection_events_output_file: Option<String>,
+
+    /// If set, the presence timeline of every peer is written to this file, i.e., the intervals
+    /// during which it was connected to the same set of monitors.
+    #[serde(default)]
+    pub(crate) presence_output_file: Option<String>,
+
+    /// If set, per-peer statistics about how long each peer was connected to all, some, or only
+    /// one of the monitors are written to this file.
+    #[serde(default)]
+    pub(crate) peer_overlap_output_file: Option<String>,
+
+    /// The interval, in seconds of trace time, at which ledger counts are recorded.
+    /// Intervals are aligned to the Unix epoch.
+    /// Defaults to one hour.
+    #[serde(default = "default_ledger_count_interval_secs")]
+    pub(crate) ledger_count_interval_secs: u64,
+
     /// Configuration for the global matching and deduplication algorithm.
     pub(crate) matching_config: MatchingConfig,
 
+    /// If set, clock offsets between monitors are estimated from matched entries, and optionally
+    /// corrected.
+    #[serde(default)]
+    pub(crate) clock_skew: Option<ClockSkewConfig>,
+
+    /// If set, statistics about which peers, CIDs, and requests were seen by which monitors are
+    /// collected and written at the end of the run.
+    #[serde(default)]
+    pub(crate) coverage: Option<CoverageConfig>,
+
+    /// If set, monitors are consumed live via AMQP instead of reading traces from disk.
+    /// Unification runs until interrupted with Ctrl+C or terminated with SIGTERM.
+    #[serde(default)]
+    pub(crate) live: Option<LiveConfig>,
+
     /// Configuration for the single-monitor bitswap simulations.
     pub(crate) simulation_config: wantlist::EngineSimulationConfig,
 }
 
+fn default_ledger_count_interval_secs() -> u64 {
+    3600
+}
+
 impl Config {
     /// Reads a Config from a given path.
     pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Config> {
@@ -54,6 +130,21 @@ impl Config {
     }
 }
 
+/// What to do with messages that are out of order after sorting.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OutOfOrderPolicy {
+    /// Log a warning and process the message anyway, with its timestamp clamped to that of the
+    /// newest message emitted before it.
+    /// The clamped timestamp is only used for the simulation and matching, the output keeps the
+    /// original one.
+    #[default]
+    Warn,
+
+    /// Abort the unification.
+    Fail,
+}
+
 /// Configuration for a single monitor.
 /// This mostly defines which trace files to read (and in what order).
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -81,7 +172,15 @@ pub(crate) struct MatchingConfig {
     /// duplicates.
     pub(crate) global_duplicate_window_seconds: u64,
 
+    /// The strategy used to decide which entry in the window an incoming entry is matched to.
+    /// Defaults to greedy matching.
+    /// Optimal matching decides groups of entries early once they reach 256 entries, which bounds
+    /// the time it takes, but the matching of such groups is not optimal anymore.
+    #[serde(default)]
+    pub(crate) strategy: MatchingStrategyKind,
+
     /// Whether to match an incoming entry with the newest (= most recent) entry in the window.
+    /// This only applies to greedy matching.
     pub(crate) match_newest_first: bool,
 
     /// Whether to allow matching an entry in the window multiple times.
@@ -99,7 +198,160 @@ pub(crate) struct MatchingConfig {
     ///
     /// Synthetic entries are never matched.
     ///
-    /// Matching does not differentiate between full and incremental wantlists.
-    /// TODO maybe we should? Unclear...
+    /// Whether full and incremental wantlists are differentiated is configured via
+    /// `message_type_matching`.
     pub(crate) match_exact_entry_type: bool,
+
+    /// Whether entries from full wantlists are differentiated from entries from incremental
+    /// wantlists.
+    /// Defaults to differentiating them only for matching and clusters.
+    #[serde(default)]
+    pub(crate) message_type_matching: MessageTypeMatching,
+
+    /// How often, in seconds of trace time, to evict entries that have left both windows from the
+    /// queues of all peers.
+    /// Otherwise, the queue of a peer is only pruned when that peer sends another message.
+    /// Defaults to one minute.
+    #[serde(default = "default_peer_queue_sweep_interval_secs")]
+    pub(crate) peer_queue_sweep_interval_secs: u64,
+
+    /// The maximum number of entries held in the queues of all peers together.
+    /// If this is exceeded, the oldest entries are evicted, even if they are still within one of
+    /// the windows, which can cause missed matches and global duplicates.
+    /// Unlimited by default.
+    #[serde(default)]
+    pub(crate) max_queued_entries: Option<usize>,
+}
+
+fn default_peer_queue_sweep_interval_secs() -> u64 {
+    60
+}
+
+/// Strategies to decide which entry in the window an incoming entry is matched to.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MatchingStrategyKind {
+    /// Match each entry as it comes in, to the oldest or newest entry in the window, depending on
+    /// `match_newest_first`.
+    #[default]
+    Greedy,
+
+    /// Match entries such that as many entries as possible are matched, with the minimum total
+    /// time difference.
+    /// Entries are grouped by the entries they could be matched to, and matched once no further
+    /// entry can join the group, or early once the group reaches 256 entries.
+    /// Unless `allow_multiple_match` is set, each entry is part of at most one match, on either
+    /// side.
+    /// If `allow_multiple_match` is set, this matches each entry to the closest entry in the
+    /// window.
+    Optimal,
+}
+
+/// Whether entries from full wantlists are differentiated from entries from incremental ones.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MessageTypeMatching {
+    /// Entries are only matched to, and clustered with, entries with the same message type.
+    /// Global duplicates do not differentiate message types.
+    #[default]
+    MatchesOnly,
+
+    /// Matches, clusters, and global duplicates all require the same message type.
+    All,
+
+    /// Message types are ignored, i.e., an entry of a full wantlist can be matched to the same
+    /// entry of an incremental wantlist.
+    Ignore,
+}
+
+/// Configuration for estimating and correcting clock offsets between monitors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ClockSkewConfig {
+    /// The size, in seconds of trace time, of the buckets over which the median offset is
+    /// computed.
+    /// Defaults to one hour.
+    #[serde(default = "default_clock_skew_bucket_secs")]
+    pub(crate) bucket_secs: u64,
+
+    /// Whether to correct timestamps.
+    /// If set, the traces are read twice: once to estimate the offsets, and once to match entries
+    /// with timestamps converted to the clock of the first monitor.
+    /// The outputs contain the corrected timestamps.
+    #[serde(default)]
+    pub(crate) correct: bool,
+
+    /// The path of a JSON file to write the estimated offsets to.
+    /// If unset, they are only logged.
+    #[serde(default)]
+    pub(crate) report_file: Option<String>,
+}
+
+fn default_clock_skew_bucket_secs() -> u64 {
+    3600
+}
+
+/// Configuration for the monitor coverage and overlap analysis.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CoverageConfig {
+    /// The path of a JSON file to write the full report to.
+    #[serde(default)]
+    pub(crate) report_file: Option<String>,
+
+    /// The path of a CSV file to write the number of peers, CIDs, and match clusters seen by each
+    /// set of monitors to, i.e., the regions of a Venn diagram.
+    #[serde(default)]
+    pub(crate) subsets_file: Option<String>,
+
+    /// The bucket size, in milliseconds, of the per-monitor match time difference histograms.
+    /// Defaults to 100ms.
+    #[serde(default = "default_coverage_match_diff_bucket_ms")]
+    pub(crate) match_diff_bucket_ms: u64,
+}
+
+fn default_coverage_match_diff_bucket_ms() -> u64 {
+    100
+}
+
+/// Configuration for unifying monitors live, via AMQP.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LiveConfig {
+    /// The AMQP servers and monitors to subscribe to.
+    /// Monitors are given numeric IDs in the order they are listed, across all servers.
+    pub(crate) amqp_servers: Vec<AMQPServerConfig>,
+
+    /// How long, in milliseconds of wall clock time, messages are held back for sorting.
+    /// A message is processed once its timestamp is older than this.
+    /// Messages that arrive after a newer message has already been processed are processed with
+    /// their timestamp clamped to that of the newer message, see `OutOfOrderPolicy::Warn`.
+    /// Additionally, at most `message_sorting_window_size` messages per monitor are held back.
+    /// Defaults to 5000.
+    #[serde(default = "default_lateness_bound_milliseconds")]
+    pub(crate) lateness_bound_milliseconds: u64,
+
+    /// Specifies on what address a prometheus endpoint will be created.
+    pub(crate) prometheus_address: String,
+
+    /// The interval, in seconds, over which clock offsets between monitors are estimated for
+    /// prometheus.
+    /// Defaults to 60.
+    #[serde(default = "default_clock_offset_interval_secs")]
+    pub(crate) clock_offset_interval_secs: u64,
+}
+
+fn default_lateness_bound_milliseconds() -> u64 {
+    5000
+}
+
+fn default_clock_offset_interval_secs() -> u64 {
+    60
+}
+
+/// Configuration for a single AMQP server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct AMQPServerConfig {
+    /// The address of the server, including the amqp:// or amqps:// scheme.
+    pub(crate) amqp_server_address: String,
+
+    /// A list of monitor names to subscribe to.
+    pub(crate) monitor_names: Vec<String>,
 }
@@ -0,0 +1,441 @@
+//! Analysis of how monitors complement each other: which peers, CIDs, and requests were seen by
+//! which monitors.
+//!
+//! Sets of monitors are represented as bitmasks, which limits the analysis to 64 monitors.
+
+use crate::config::CoverageConfig;
+use crate::matcher::OutputCSVWantlistEntry;
+use crate::Result;
+use failure::{err_msg, ResultExt};
+use ipfs_resolver_common::wantlist;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+
+/// The maximum number of monitors supported by the coverage analysis.
+const MAX_MONITORS: usize = 64;
+
+/// Per-monitor counters.
+#[derive(Clone, Debug, Default)]
+struct MonitorCounters {
+    entries: u64,
+    single_monitor_entries: u64,
+    matched_entries: u64,
+    match_diff_histogram: Vec<u64>,
+}
+
+/// Collects coverage statistics from the (non-synthetic) entries produced by the matcher.
+pub(crate) struct CoverageTracker {
+    num_monitors: usize,
+    bucket_ms: u64,
+    /// The set of monitors that saw each peer.
+    peers: HashMap<String, u64>,
+    /// The set of monitors that saw each CID.
+    cids: HashMap<String, u64>,
+    /// The number of match clusters, by set of monitors.
+    clusters: HashMap<u64, u64>,
+    monitors: Vec<MonitorCounters>,
+}
+
+/// Coverage statistics for one monitor.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct MonitorCoverage {
+    pub(crate) monitor_id: usize,
+    pub(crate) monitor_name: String,
+    pub(crate) distinct_peers: u64,
+    pub(crate) distinct_cids: u64,
+    /// Peers seen only by this monitor.
+    pub(crate) exclusive_peers: u64,
+    /// CIDs seen only by this monitor.
+    pub(crate) exclusive_cids: u64,
+    pub(crate) entries: u64,
+    /// Entries whose match cluster contains only this monitor.
+    pub(crate) single_monitor_entries: u64,
+    /// Entries matched to an earlier entry of another monitor.
+    pub(crate) matched_entries: u64,
+    /// The number of matched entries, by match time difference.
+    /// Bucket `i` counts differences in `[i * match_diff_bucket_ms, (i + 1) * match_diff_bucket_ms)`.
+    pub(crate) match_diff_histogram: Vec<u64>,
+}
+
+/// Intersections between two monitors.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct PairCoverage {
+    pub(crate) monitor_a: String,
+    pub(crate) monitor_b: String,
+    pub(crate) shared_peers: u64,
+    pub(crate) shared_cids: u64,
+    /// Match clusters containing both monitors.
+    pub(crate) shared_clusters: u64,
+}
+
+/// Counts of things seen by exactly one set of monitors, i.e., one region of a Venn diagram.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct SubsetCoverage {
+    /// The IDs of the monitors in the set, separated by `;`.
+    pub(crate) monitors: String,
+    pub(crate) num_monitors: u32,
+    pub(crate) peers: u64,
+    pub(crate) cids: u64,
+    pub(crate) clusters: u64,
+}
+
+/// The coverage report written at the end of a run.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct CoverageReport {
+    pub(crate) match_diff_bucket_ms: u64,
+    pub(crate) distinct_peers: u64,
+    pub(crate) distinct_cids: u64,
+    pub(crate) monitors: Vec<MonitorCoverage>,
+    pub(crate) pairs: Vec<PairCoverage>,
+    /// Peers, CIDs, and clusters by the number of monitors that saw them, indexed by that number.
+    pub(crate) peers_by_num_monitors: Vec<u64>,
+    pub(crate) cids_by_num_monitors: Vec<u64>,
+    pub(crate) clusters_by_num_monitors: Vec<u64>,
+    /// Counts for each set of monitors that saw anything, ordered by set.
+    pub(crate) subsets: Vec<SubsetCoverage>,
+}
+
+fn monitors_of(mask: u64) -> Vec<usize> {
+    (0..MAX_MONITORS).filter(|i| mask & (1 << i) != 0).collect()
+}
+
+impl CoverageTracker {
+    pub(crate) fn new(
+        num_monitors: usize,
+        matching_window_ms: u64,
+        cfg: &CoverageConfig,
+    ) -> Result<CoverageTracker> {
+        if num_monitors > MAX_MONITORS {
+            return Err(err_msg(format!(
+                "coverage analysis supports at most {} monitors",
+                MAX_MONITORS
+            )));
+        }
+        let bucket_ms = cfg.match_diff_bucket_ms.max(1);
+        let num_buckets = (matching_window_ms / bucket_ms + 1) as usize;
+
+        Ok(CoverageTracker {
+            num_monitors,
+            bucket_ms,
+            peers: HashMap::new(),
+            cids: HashMap::new(),
+            clusters: HashMap::new(),
+            monitors: vec![
+                MonitorCounters {
+                    match_diff_histogram: vec![0; num_buckets],
+                    ..Default::default()
+                };
+                num_monitors
+            ],
+        })
+    }
+
+    /// Records an entry emitted by the matcher.
+    /// Synthetic entries are ignored.
+    pub(crate) fn record(&mut self, e: &OutputCSVWantlistEntry) {
+        if e.entry.message_type == wantlist::CSV_MESSAGE_TYPE_SYNTHETIC {
+            return;
+        }
+        let monitor = e.extension.monitor_id as usize;
+        let bit = 1 << monitor;
+
+        // Avoid allocating for peers and CIDs we already know.
+        match self.peers.get_mut(&e.entry.peer_id) {
+            Some(mask) => *mask |= bit,
+            None => {
+                self.peers.insert(e.entry.peer_id.clone(), bit);
+            }
+        }
+        match self.cids.get_mut(&e.entry.cid) {
+            Some(mask) => *mask |= bit,
+            None => {
+                self.cids.insert(e.entry.cid.clone(), bit);
+            }
+        }
+
+        let counters = &mut self.monitors[monitor];
+        counters.entries += 1;
+        if e.extension.match_cluster_size == Some(1) {
+            counters.single_monitor_entries += 1;
+        }
+        if let Some(diff_ms) = e.extension.match_time_diff_ms {
+            counters.matched_entries += 1;
+            let bucket =
+                ((diff_ms / self.bucket_ms) as usize).min(counters.match_diff_histogram.len() - 1);
+            counters.match_diff_histogram[bucket] += 1;
+        }
+
+        // Count each cluster once, at the entry of its lowest monitor.
+        if let Some(cluster_monitors) = e.extension.match_cluster_monitors.as_ref() {
+            let cluster_monitors: Vec<usize> = cluster_monitors
+                .split(';')
+                .filter_map(|m| m.parse().ok())
+                .collect();
+            if cluster_monitors.first() == Some(&monitor) {
+                let mask = cluster_monitors.iter().fold(0, |mask, m| mask | (1 << m));
+                *self.clusters.entry(mask).or_default() += 1;
+            }
+        }
+    }
+
+    /// Computes the report.
+    pub(crate) fn finish(self, source_names: &[String]) -> CoverageReport {
+        let count_masks = |masks: &mut dyn Iterator<Item = u64>| {
+            let mut counts: HashMap<u64, u64> = HashMap::new();
+            masks.for_each(|mask| *counts.entry(mask).or_default() += 1);
+            counts
+        };
+        let peer_subsets = count_masks(&mut self.peers.values().copied());
+        let cid_subsets = count_masks(&mut self.cids.values().copied());
+
+        let mut all_masks: Vec<u64> = peer_subsets
+            .keys()
+            .chain(cid_subsets.keys())
+            .chain(self.clusters.keys())
+            .copied()
+            .collect();
+        all_masks.sort_unstable();
+        all_masks.dedup();
+
+        let subsets: Vec<_> = all_masks
+            .iter()
+            .map(|mask| SubsetCoverage {
+                monitors: monitors_of(*mask)
+                    .iter()
+                    .map(|m| m.to_string())
+                    .collect::<Vec<_>>()
+                    .join(";"),
+                num_monitors: mask.count_ones(),
+                peers: peer_subsets.get(mask).copied().unwrap_or(0),
+                cids: cid_subsets.get(mask).copied().unwrap_or(0),
+                clusters: self.clusters.get(mask).copied().unwrap_or(0),
+            })
+            .collect();
+
+        // Everything else can be derived from the subsets.
+        let sum_over = |f: &dyn Fn(u64) -> bool, g: &dyn Fn(&SubsetCoverage) -> u64| -> u64 {
+            all_masks
+                .iter()
+                .zip(subsets.iter())
+                .filter(|(mask, _)| f(**mask))
+                .map(|(_, s)| g(s))
+                .sum()
+        };
+        let by_num_monitors = |g: &dyn Fn(&SubsetCoverage) -> u64| -> Vec<u64> {
+            let mut counts = vec![0; self.num_monitors + 1];
+            subsets
+                .iter()
+                .for_each(|s| counts[s.num_monitors as usize] += g(s));
+            counts
+        };
+
+        let monitors = self
+            .monitors
+            .iter()
+            .enumerate()
+            .map(|(i, counters)| {
+                let bit = 1 << i;
+                MonitorCoverage {
+                    monitor_id: i,
+                    monitor_name: source_names[i].clone(),
+                    distinct_peers: sum_over(&|mask| mask & bit != 0, &|s| s.peers),
+                    distinct_cids: sum_over(&|mask| mask & bit != 0, &|s| s.cids),
+                    exclusive_peers: sum_over(&|mask| mask == bit, &|s| s.peers),
+                    exclusive_cids: sum_over(&|mask| mask == bit, &|s| s.cids),
+                    entries: counters.entries,
+                    single_monitor_entries: counters.single_monitor_entries,
+                    matched_entries: counters.matched_entries,
+                    match_diff_histogram: counters.match_diff_histogram.clone(),
+                }
+            })
+            .collect();
+
+        let mut pairs = Vec::new();
+        for a in 0..self.num_monitors {
+            for b in (a + 1)..self.num_monitors {
+                let both = (1 << a) | (1 << b);
+                let contains_both = |mask: u64| mask & both == both;
+                pairs.push(PairCoverage {
+                    monitor_a: source_names[a].clone(),
+                    monitor_b: source_names[b].clone(),
+                    shared_peers: sum_over(&contains_both, &|s| s.peers),
+                    shared_cids: sum_over(&contains_both, &|s| s.cids),
+                    shared_clusters: sum_over(&contains_both, &|s| s.clusters),
+                })
+            }
+        }
+
+        CoverageReport {
+            match_diff_bucket_ms: self.bucket_ms,
+            distinct_peers: self.peers.len() as u64,
+            distinct_cids: self.cids.len() as u64,
+            monitors,
+            pairs,
+            peers_by_num_monitors: by_num_monitors(&|s| s.peers),
+            cids_by_num_monitors: by_num_monitors(&|s| s.cids),
+            clusters_by_num_monitors: by_num_monitors(&|s| s.clusters),
+            subsets,
+        }
+    }
+}
+
+impl CoverageReport {
+    /// Writes the report as JSON and, optionally, the subsets as CSV.
+    pub(crate) fn write(&self, cfg: &CoverageConfig) -> Result<()> {
+        if let Some(path) = cfg.report_file.as_ref() {
+            let f = File::create(path).context("unable to create coverage report")?;
+            serde_json::to_writer_pretty(f, self).context("unable to write coverage report")?;
+        }
+        if let Some(path) = cfg.subsets_file.as_ref() {
+            let mut w = csv::Writer::from_path(path).context("unable to create subsets file")?;
+            self.subsets
+                .iter()
+                .try_for_each(|s| w.serialize(s))
+                .context("unable to write subsets")?;
+            w.flush().context("unable to flush subsets file")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::unmatched_output_entry;
+    use crate::testing::wantlist_entry;
+
+    /// Returns an entry of the given monitor, in a match cluster of the given monitors.
+    fn entry(
+        monitor_id: usize,
+        peer_id: &str,
+        cid: &str,
+        cluster: &[usize],
+        match_diff_ms: Option<u64>,
+    ) -> OutputCSVWantlistEntry {
+        let mut e = unmatched_output_entry(monitor_id, wantlist_entry(1, 0, peer_id, cid));
+        e.extension.match_cluster_size = Some(cluster.len() as u64);
+        e.extension.match_cluster_monitors = Some(
+            cluster
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(";"),
+        );
+        if let Some(diff_ms) = match_diff_ms {
+            e.extension.matched_to_monitor_id = Some(cluster[0] as u64);
+            e.extension.match_time_diff_ms = Some(diff_ms);
+        }
+        e
+    }
+
+    #[test]
+    fn subsets_and_pairs() {
+        let cfg = CoverageConfig {
+            report_file: None,
+            subsets_file: None,
+            match_diff_bucket_ms: 100,
+        };
+        let mut tracker = CoverageTracker::new(3, 1000, &cfg).unwrap();
+
+        let entries = [
+            // A cluster seen by all monitors.
+            entry(0, "p1", "c1", &[0, 1, 2], None),
+            entry(1, "p1", "c1", &[0, 1, 2], Some(50)),
+            entry(2, "p1", "c1", &[0, 1, 2], Some(120)),
+            // A cluster seen by monitors 1 and 2.
+            entry(1, "p2", "c2", &[1, 2], None),
+            entry(2, "p2", "c2", &[1, 2], Some(30)),
+            // Clusters seen by one monitor each.
+            entry(0, "p3", "c3", &[0], None),
+            entry(2, "p4", "c1", &[2], None),
+        ];
+        entries.iter().for_each(|e| tracker.record(e));
+        // Synthetic entries are ignored.
+        let mut synthetic = entry(1, "p5", "c5", &[1], None);
+        synthetic.entry.message_type = wantlist::CSV_MESSAGE_TYPE_SYNTHETIC;
+        tracker.record(&synthetic);
+
+        let names = ["a", "b", "c"].map(String::from);
+        let report = tracker.finish(&names);
+
+        assert_eq!(report.distinct_peers, 4);
+        assert_eq!(report.distinct_cids, 3);
+
+        let subsets: Vec<_> = report
+            .subsets
+            .iter()
+            .map(|s| {
+                (
+                    s.monitors.as_str(),
+                    s.num_monitors,
+                    s.peers,
+                    s.cids,
+                    s.clusters,
+                )
+            })
+            .collect();
+        assert_eq!(
+            subsets,
+            vec![
+                ("0", 1, 1, 1, 1),
+                ("2", 1, 1, 0, 1),
+                ("1;2", 2, 1, 1, 1),
+                ("0;1;2", 3, 1, 1, 1),
+            ]
+        );
+        assert_eq!(report.peers_by_num_monitors, vec![0, 2, 1, 1]);
+        assert_eq!(report.cids_by_num_monitors, vec![0, 1, 1, 1]);
+        // Each cluster is counted once, not once per entry.
+        assert_eq!(report.clusters_by_num_monitors, vec![0, 2, 1, 1]);
+
+        let pairs: Vec<_> = report
+            .pairs
+            .iter()
+            .map(|p| {
+                (
+                    p.monitor_a.as_str(),
+                    p.monitor_b.as_str(),
+                    p.shared_peers,
+                    p.shared_cids,
+                    p.shared_clusters,
+                )
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a", "b", 1, 1, 1),
+                ("a", "c", 1, 1, 1),
+                ("b", "c", 2, 2, 2)
+            ]
+        );
+
+        let monitors: Vec<_> = report
+            .monitors
+            .iter()
+            .map(|m| {
+                (
+                    m.distinct_peers,
+                    m.distinct_cids,
+                    m.exclusive_peers,
+                    m.exclusive_cids,
+                    m.entries,
+                    m.single_monitor_entries,
+                    m.matched_entries,
+                )
+            })
+            .collect();
+        assert_eq!(
+            monitors,
+            vec![
+                (2, 2, 1, 1, 2, 1, 0),
+                (2, 2, 0, 0, 2, 0, 1),
+                (3, 2, 1, 0, 3, 1, 2),
+            ]
+        );
+        assert_eq!(report.monitors[2].match_diff_histogram.len(), 11);
+        assert_eq!(report.monitors[2].match_diff_histogram[..2], [1, 1]);
+        assert_eq!(report.monitors[1].match_diff_histogram[0], 1);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::Result;
+use failure::ResultExt;
+use ipfs_resolver_common::output::{ParquetColumnWriter, ParquetRecord, RecordSink};
+use serde::{Deserialize, Serialize};
+
+/// The number of ledgers at some point in time, to be serialized to CSV.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CSVLedgerCount {
+    /// The timestamp of the last message of the interval, as seconds since the Unix epoch.
+    pub(crate) ts_secs: i64,
+    /// The ID of the monitor, or empty for the sum over all monitors.
+    pub(crate) monitor_id: Option<u64>,
+    /// The number of ledgers that were missing during the interval.
+    pub(crate) missing_ledgers: usize,
+    /// The number of ledgers at the end of the interval.
+    pub(crate) total_ledgers: usize,
+}
+
+impl ParquetRecord for CSVLedgerCount {
+    const PARQUET_SCHEMA: &'static str = "
+        message ledger_count {
+            REQUIRED INT64 ts_secs;
+            OPTIONAL INT64 monitor_id (UINT_64);
+            REQUIRED INT64 missing_ledgers (UINT_64);
+            REQUIRED INT64 total_ledgers (UINT_64);
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_i64(records.iter().map(|r| r.ts_secs))?;
+        w.write_optional_u64(records.iter().map(|r| r.monitor_id))?;
+        w.write_u64(records.iter().map(|r| r.missing_ledgers as u64))?;
+        w.write_u64(records.iter().map(|r| r.total_ledgers as u64))?;
+        Ok(())
+    }
+}
+
+/// Keeps track of missing ledgers and ledger counts per monitor, and writes them out once per
+/// interval of trace time.
+/// Intervals are aligned to the Unix epoch.
+pub(crate) struct LedgerCounter {
+    interval_secs: i64,
+    output: Box<dyn RecordSink<CSVLedgerCount>>,
+
+    current_interval: Option<i64>,
+    last_ts_secs: i64,
+    /// Missing ledgers per monitor in the current interval.
+    missing_ledgers: Vec<usize>,
+    /// Missing ledgers per monitor over the entire trace.
+    total_missing_ledgers: Vec<usize>,
+    /// The number of ledgers per monitor, as of the last message of that monitor.
+    num_ledgers: Vec<usize>,
+}
+
+impl LedgerCounter {
+    pub(crate) fn new(
+        num_monitors: usize,
+        interval_secs: u64,
+        output: Box<dyn RecordSink<CSVLedgerCount>>,
+    ) -> LedgerCounter {
+        LedgerCounter {
+            interval_secs: interval_secs.max(1) as i64,
+            output,
+            current_interval: None,
+            last_ts_secs: 0,
+            missing_ledgers: vec![0; num_monitors],
+            total_missing_ledgers: vec![0; num_monitors],
+            num_ledgers: vec![0; num_monitors],
+        }
+    }
+
+    /// Records the result of ingesting a message, writing out the previous interval if the
+    /// message starts a new one.
+    pub(crate) fn record(
+        &mut self,
+        monitor_id: usize,
+        ts: chrono::DateTime<chrono::Utc>,
+        missing_ledger: bool,
+        num_ledgers: usize,
+    ) -> Result<()> {
+        let ts_secs = ts.timestamp();
+        let interval = ts_secs.div_euclid(self.interval_secs);
+        if self.current_interval.is_some_and(|i| i != interval) {
+            self.write_interval()?;
+        }
+        self.current_interval = Some(interval);
+        self.last_ts_secs = ts_secs;
+
+        if missing_ledger {
+            self.missing_ledgers[monitor_id] += 1;
+            self.total_missing_ledgers[monitor_id] += 1;
+        }
+        self.num_ledgers[monitor_id] = num_ledgers;
+
+        Ok(())
+    }
+
+    /// Writes one row per monitor and one for the sum over all monitors, and resets the missing
+    /// ledger counts.
+    fn write_interval(&mut self) -> Result<()> {
+        for (monitor_id, (missing, total)) in self
+            .missing_ledgers
+            .iter()
+            .zip(self.num_ledgers.iter())
+            .enumerate()
+        {
+            self.output
+                .write(&CSVLedgerCount {
+                    ts_secs: self.last_ts_secs,
+                    monitor_id: Some(monitor_id as u64),
+                    missing_ledgers: *missing,
+                    total_ledgers: *total,
+                })
+                .context("unable to write ledger count")?;
+        }
+        self.output
+            .write(&CSVLedgerCount {
+                ts_secs: self.last_ts_secs,
+                monitor_id: None,
+                missing_ledgers: self.missing_ledgers.iter().sum(),
+                total_ledgers: self.num_ledgers.iter().sum(),
+            })
+            .context("unable to write ledger count")?;
+
+        self.missing_ledgers.iter_mut().for_each(|m| *m = 0);
+        Ok(())
+    }
+
+    /// Writes the last interval, if any, and finishes the output.
+    /// Returns the number of missing ledgers per monitor over the entire trace.
+    pub(crate) fn finish(mut self) -> Result<Vec<usize>> {
+        if self.current_interval.is_some() {
+            self.write_interval()?;
+        }
+        self.output
+            .finish()
+            .context("unable to finish ledger count output")?;
+        Ok(self.total_missing_ledgers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemorySink;
+
+    fn ts(secs: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    /// Returns the rows as (ts_secs, monitor_id, missing_ledgers, total_ledgers).
+    fn rows(records: &[CSVLedgerCount]) -> Vec<(i64, Option<u64>, usize, usize)> {
+        records
+            .iter()
+            .map(|r| (r.ts_secs, r.monitor_id, r.missing_ledgers, r.total_ledgers))
+            .collect()
+    }
+
+    #[test]
+    fn counts_per_interval() {
+        let (sink, records) = MemorySink::new();
+        let mut counter = LedgerCounter::new(2, 60, sink);
+
+        counter.record(0, ts(60), true, 1).unwrap();
+        counter.record(1, ts(70), true, 3).unwrap();
+        counter.record(0, ts(119), false, 2).unwrap();
+        assert!(records.borrow().is_empty());
+
+        // Starts the next interval, monitor 1 has no messages in it.
+        counter.record(0, ts(120), true, 4).unwrap();
+        assert_eq!(
+            rows(&records.borrow()),
+            vec![
+                (119, Some(0), 1, 2),
+                (119, Some(1), 1, 3),
+                (119, None, 2, 5),
+            ]
+        );
+
+        // Skips an interval, which produces no rows.
+        counter.record(1, ts(300), false, 1).unwrap();
+        let missing = counter.finish().unwrap();
+
+        // Missing counts are reset per interval, ledger counts are kept.
+        assert_eq!(
+            rows(&records.borrow()[3..]),
+            vec![
+                (120, Some(0), 1, 4),
+                (120, Some(1), 0, 3),
+                (120, None, 1, 7),
+                (300, Some(0), 0, 4),
+                (300, Some(1), 0, 1),
+                (300, None, 0, 5),
+            ]
+        );
+        assert_eq!(missing, vec![2, 1]);
+    }
+
+    #[test]
+    fn empty_trace() {
+        let (sink, records) = MemorySink::new();
+        let counter = LedgerCounter::new(2, 60, sink);
+        assert_eq!(counter.finish().unwrap(), vec![0, 0]);
+        assert!(records.borrow().is_empty());
+    }
+}
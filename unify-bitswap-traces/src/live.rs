@@ -0,0 +1,610 @@
+//! Live unification of monitors via AMQP.
+//!
+//! Each monitor is consumed by its own task, which converts the pushed events to JSON messages and
+//! forwards them to a single unification loop.
+//! That loop sorts messages of all monitors by timestamp, holding them back for a configurable
+//! lateness bound, and then drives the engine simulations and matching the same way as unifying
+//! traces from disk does.
+
+use crate::config::{Config, LiveConfig};
+use crate::matcher::OutputCSVWantlistEntry;
+use crate::skew::ClockSkewEstimator;
+use crate::source::{clamp_timestamp, simulate, SortedJSONMessage};
+use crate::unifier::Unifier;
+use crate::Result;
+use failure::ResultExt;
+use futures_util::StreamExt;
+use ipfs_monitoring_plugin_client::conversion::PushedEventConverter;
+use ipfs_monitoring_plugin_client::monitoring::{MonitoringClient, RoutingKeyInformation};
+use ipfs_resolver_common::wantlist;
+use ipfs_resolver_common::wantlist::{EngineSimulation, JSONMessage};
+use prometheus::{GaugeVec, IntCounterVec, IntGauge, IntGaugeVec};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+/// How often to check for messages that have become old enough to be processed.
+const PROCESSING_INTERVAL: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    pub static ref MONITOR_CONNECTED: IntGaugeVec = register_int_gauge_vec!(
+        "unify_monitor_connected",
+        "whether we are currently connected to and subscribed to events of a monitor, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref MESSAGES_PROCESSED: IntCounterVec = register_int_counter_vec!(
+        "unify_messages_processed",
+        "number of messages fed into the engine simulations, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref MESSAGES_LATE: IntCounterVec = register_int_counter_vec!(
+        "unify_messages_late",
+        "number of messages that arrived after newer messages were processed, and were processed with their timestamp clamped, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref MESSAGES_BUFFERED: IntGauge = register_int_gauge!(
+        "unify_messages_buffered",
+        "number of messages currently held back for sorting"
+    )
+    .unwrap();
+
+    pub static ref ENTRIES_WRITTEN: IntCounterVec = register_int_counter_vec!(
+        "unify_entries_written",
+        "number of non-synthetic wantlist entries written, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref ENTRIES_MATCHED: IntCounterVec = register_int_counter_vec!(
+        "unify_entries_matched",
+        "number of wantlist entries matched to an entry of another monitor, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref MATCHER_QUEUED_ENTRIES: IntGauge = register_int_gauge!(
+        "unify_matcher_queued_entries",
+        "number of entries currently held in peer queues for matching"
+    )
+    .unwrap();
+
+    pub static ref MATCHER_QUEUED_PEERS: IntGauge = register_int_gauge!(
+        "unify_matcher_queued_peers",
+        "number of peers with entries in their queues, as of the last sweep"
+    )
+    .unwrap();
+
+    pub static ref MATCHER_PEAK_QUEUED_ENTRIES: IntGauge = register_int_gauge!(
+        "unify_matcher_peak_queued_entries",
+        "maximum number of entries held in peer queues at once"
+    )
+    .unwrap();
+
+    pub static ref MATCHER_EVICTED_ENTRIES: IntCounterVec = register_int_counter_vec!(
+        "unify_matcher_evicted_entries",
+        "number of entries evicted from peer queues by periodic sweeps or because max_queued_entries was exceeded, by reason",
+        &["reason"]
+    )
+    .unwrap();
+
+    pub static ref CLOCK_OFFSET: GaugeVec = register_gauge_vec!(
+        "unify_clock_offset_milliseconds",
+        "median offset of the clock of monitor_b relative to monitor_a over the last interval, estimated from matched entries",
+        &["monitor_a","monitor_b"]
+    )
+    .unwrap();
+}
+
+/// A message buffered for sorting.
+/// Messages are ordered by timestamp, then by monitor, then in the order they were received from
+/// their monitor, so that the order does not depend on how messages of different monitors
+/// interleave on arrival.
+struct BufferedMessage {
+    key: (chrono::DateTime<chrono::Utc>, usize, u64),
+    msg: SortedJSONMessage,
+}
+
+impl Ord for BufferedMessage {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl PartialOrd for BufferedMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for BufferedMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for BufferedMessage {}
+
+/// Sorts the messages of all monitors by timestamp.
+/// Messages are held back until they are older than the lateness bound, or until too many
+/// messages are buffered.
+/// Like the sorter for traces on disk, messages arriving after a newer message was popped have
+/// their timestamp clamped, so that the simulation and matching never see time going backwards.
+struct LiveSorter {
+    heap: BinaryHeap<Reverse<BufferedMessage>>,
+    /// The number of messages pushed so far per monitor, used to break ties.
+    seqs: Vec<u64>,
+    lateness_bound: chrono::Duration,
+    max_buffered: usize,
+    /// The timestamp of the last message popped.
+    watermark: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl LiveSorter {
+    fn new(lateness_bound_ms: u64, max_buffered: usize) -> LiveSorter {
+        LiveSorter {
+            heap: BinaryHeap::new(),
+            seqs: Vec::new(),
+            lateness_bound: chrono::Duration::milliseconds(lateness_bound_ms as i64),
+            max_buffered: max_buffered.max(1),
+            watermark: None,
+        }
+    }
+
+    /// Buffers a message.
+    /// Returns false if a newer message was already popped, in which case the timestamp of the
+    /// message is clamped to that of the newest message popped.
+    fn push(&mut self, monitor_id: usize, mut msg: JSONMessage) -> bool {
+        let clamped_from = self
+            .watermark
+            .and_then(|watermark| clamp_timestamp(&mut msg, watermark));
+        if let Some(ts) = clamped_from {
+            debug!(
+                "monitor {}: message at {} arrived late, clamping to {}",
+                monitor_id, ts, msg.timestamp
+            );
+        }
+        if self.seqs.len() <= monitor_id {
+            self.seqs.resize(monitor_id + 1, 0);
+        }
+        self.seqs[monitor_id] += 1;
+        self.heap.push(Reverse(BufferedMessage {
+            key: (msg.timestamp, monitor_id, self.seqs[monitor_id]),
+            msg: SortedJSONMessage { msg, clamped_from },
+        }));
+        clamped_from.is_none()
+    }
+
+    /// Pops the oldest message if it is old enough, or if too many messages are buffered.
+    fn pop_ready(
+        &mut self,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Option<(usize, SortedJSONMessage)> {
+        let ready = match self.heap.peek() {
+            Some(Reverse(m)) => {
+                m.key.0 <= now - self.lateness_bound || self.heap.len() > self.max_buffered
+            }
+            None => false,
+        };
+        if ready {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Pops the oldest message, regardless of its age.
+    fn pop(&mut self) -> Option<(usize, SortedJSONMessage)> {
+        self.heap.pop().map(|Reverse(m)| {
+            self.watermark = Some(m.key.0);
+            (m.key.1, m.msg)
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.heap.len()
+    }
+}
+
+/// Keeps the engine simulations of all monitors and feeds them messages in order.
+struct LiveUnification {
+    source_names: Vec<String>,
+    engine_states: Vec<EngineSimulation>,
+    message_id: i64,
+    unifier: Unifier,
+    offset_estimator: ClockSkewEstimator,
+    offset_interval: Duration,
+    last_offset_update: Instant,
+    /// The numbers of swept and capped entries already exported.
+    exported_evictions: (usize, usize),
+    /// The pairs of monitors for which a clock offset is currently exported.
+    exported_offsets: Vec<(String, String)>,
+}
+
+impl LiveUnification {
+    /// Processes messages popped from the sorter.
+    fn process(&mut self, monitor_id: usize, msg: SortedJSONMessage) -> Result<()> {
+        self.message_id += 1;
+        MESSAGES_PROCESSED
+            .with_label_values(&[&self.source_names[monitor_id]])
+            .inc();
+
+        let res = simulate(
+            &mut self.engine_states,
+            monitor_id,
+            msg.msg,
+            msg.clamped_from,
+            self.message_id,
+        )?;
+        let entries = self.unifier.handle(res)?;
+        self.record_entries(&entries);
+
+        Ok(())
+    }
+
+    fn record_entries(&mut self, entries: &[OutputCSVWantlistEntry]) {
+        for e in entries
+            .iter()
+            .filter(|e| e.entry.message_type != wantlist::CSV_MESSAGE_TYPE_SYNTHETIC)
+        {
+            let monitor = &self.source_names[e.extension.monitor_id as usize];
+            ENTRIES_WRITTEN.with_label_values(&[monitor]).inc();
+            if e.extension.matched_to_monitor_id.is_some() {
+                ENTRIES_MATCHED.with_label_values(&[monitor]).inc();
+            }
+            self.offset_estimator.record(e);
+        }
+    }
+
+    /// Exports the state of the peer queues of the matcher.
+    fn update_queue_metrics(&mut self) {
+        let stats = self.unifier.matcher_stats();
+        MATCHER_QUEUED_ENTRIES.set(stats.queued_entries as i64);
+        MATCHER_QUEUED_PEERS.set(stats.queued_peers as i64);
+        MATCHER_PEAK_QUEUED_ENTRIES.set(stats.peak_queued_entries as i64);
+
+        let (swept, capped) = self.exported_evictions;
+        MATCHER_EVICTED_ENTRIES
+            .with_label_values(&["sweep"])
+            .inc_by((stats.swept_entries - swept) as u64);
+        MATCHER_EVICTED_ENTRIES
+            .with_label_values(&["limit"])
+            .inc_by((stats.capped_entries - capped) as u64);
+        self.exported_evictions = (stats.swept_entries, stats.capped_entries);
+    }
+
+    /// Exports the clock offsets estimated over the last interval, if the interval is over.
+    fn update_clock_offsets(&mut self) {
+        if self.last_offset_update.elapsed() < self.offset_interval {
+            return;
+        }
+        let report = self.offset_estimator.estimate(&self.source_names);
+        for p in report.pairs.iter() {
+            CLOCK_OFFSET
+                .with_label_values(&[&p.monitor_a, &p.monitor_b])
+                .set(p.median_offset_ms);
+        }
+        report.log("live");
+
+        // Remove offsets of pairs without matches in this interval, instead of exporting stale
+        // values.
+        let offsets: Vec<_> = report
+            .pairs
+            .iter()
+            .map(|p| (p.monitor_a.clone(), p.monitor_b.clone()))
+            .collect();
+        for (a, b) in self.exported_offsets.iter() {
+            if !offsets.contains(&(a.clone(), b.clone())) {
+                CLOCK_OFFSET
+                    .remove_label_values(&[a, b])
+                    .expect("offset should have been exported");
+            }
+        }
+        self.exported_offsets = offsets;
+
+        self.offset_estimator = ClockSkewEstimator::new(self.offset_interval.as_secs());
+        self.last_offset_update = Instant::now();
+    }
+}
+
+/// Unifies the configured monitors live, until interrupted with Ctrl+C or terminated with SIGTERM.
+pub(crate) async fn run(mut cfg: Config, live_cfg: LiveConfig) -> Result<()> {
+    let monitors: Vec<_> = live_cfg
+        .amqp_servers
+        .iter()
+        .flat_map(|c| {
+            c.monitor_names
+                .iter()
+                .map(move |name| (c.amqp_server_address.clone(), name.clone()))
+        })
+        .collect();
+    let source_names: Vec<_> = monitors.iter().map(|(_, name)| name.clone()).collect();
+    info!("unifying monitors {:?} live", source_names);
+
+    // Set up prometheus
+    let prometheus_address = live_cfg
+        .prometheus_address
+        .parse::<std::net::SocketAddr>()
+        .context("invalid prometheus_address")?;
+    prometheus_exporter::start(prometheus_address).context("can not start exporter")?;
+    info!("started prometheus server on {}", prometheus_address);
+
+    // Set up simulations and outputs.
+    // Message IDs start at zero for every run, so we prefix them with the start time in order not
+    // to overwrite the output of earlier runs.
+    cfg.wantlist_output_file_pattern = cfg.wantlist_output_file_pattern.replace(
+        "$id$",
+        &format!("{}-$id$", chrono::Utc::now().format("%Y%m%d-%H%M%S")),
+    );
+    let state = EngineSimulation::new(cfg.simulation_config.clone())
+        .context("unable to set up engine simulation")?;
+    let mut unification = LiveUnification {
+        engine_states: vec![state; source_names.len()],
+        message_id: 0,
+        unifier: Unifier::new(&cfg, source_names.clone())
+            .context("unable to set up unification")?,
+        offset_estimator: ClockSkewEstimator::new(live_cfg.clock_offset_interval_secs),
+        offset_interval: Duration::from_secs(live_cfg.clock_offset_interval_secs),
+        last_offset_update: Instant::now(),
+        exported_evictions: (0, 0),
+        exported_offsets: Vec::new(),
+        source_names: source_names.clone(),
+    };
+    let mut sorter = LiveSorter::new(
+        live_cfg.lateness_bound_milliseconds,
+        cfg.message_sorting_window_size * source_names.len(),
+    );
+
+    // Connect to monitors
+    let (msg_tx, mut msg_rx) = mpsc::channel(1024);
+    for (monitor_id, (amqp_server_address, monitor_name)) in monitors.into_iter().enumerate() {
+        tokio::spawn(receive_from_monitor(
+            monitor_id,
+            monitor_name,
+            amqp_server_address,
+            msg_tx.clone(),
+        ));
+    }
+
+    // Shut down cleanly on SIGTERM as well, e.g., when run in a container or by systemd, so that
+    // the outputs are finished.
+    let mut terminate =
+        signal(SignalKind::terminate()).context("failed to set up handler for SIGTERM")?;
+
+    info!("starting unification, try Ctrl+C to exit");
+    let mut ticker = tokio::time::interval(PROCESSING_INTERVAL);
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            msg = msg_rx.recv() => {
+                // We hold a sender, so this never returns None.
+                if let Some((monitor_id, msg)) = msg {
+                    if !sorter.push(monitor_id, msg) {
+                        MESSAGES_LATE
+                            .with_label_values(&[&source_names[monitor_id]])
+                            .inc();
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                let now = chrono::Utc::now();
+                while let Some((monitor_id, msg)) = sorter.pop_ready(now) {
+                    unification.process(monitor_id, msg)?;
+                }
+                MESSAGES_BUFFERED.set(sorter.len() as i64);
+                unification.update_queue_metrics();
+                unification.update_clock_offsets();
+            }
+            res = &mut shutdown => {
+                res.context("unable to listen for Ctrl+C")?;
+                info!("shutting down...");
+                break;
+            }
+            _ = terminate.recv() => {
+                info!("got SIGTERM, shutting down...");
+                break;
+            }
+        }
+    }
+
+    // Process everything still buffered
+    msg_rx.close();
+    while let Ok((monitor_id, msg)) = msg_rx.try_recv() {
+        if !sorter.push(monitor_id, msg) {
+            MESSAGES_LATE
+                .with_label_values(&[&source_names[monitor_id]])
+                .inc();
+        }
+    }
+    while let Some((monitor_id, msg)) = sorter.pop() {
+        unification.process(monitor_id, msg)?;
+    }
+
+    let msg_id = unification.message_id;
+    info!("processed {} messages", msg_id);
+    unification
+        .unifier
+        .finish(&cfg, unification.engine_states, msg_id, None, Vec::new())
+}
+
+/// Receives events from one monitor and forwards them to the unification loop, reconnecting
+/// indefinitely.
+async fn receive_from_monitor(
+    monitor_id: usize,
+    monitor_name: String,
+    amqp_server_address: String,
+    msg_out: mpsc::Sender<(usize, JSONMessage)>,
+) {
+    let routing_keys = vec![
+        RoutingKeyInformation::BitswapMessages {
+            monitor_name: monitor_name.clone(),
+        },
+        RoutingKeyInformation::ConnectionEvents {
+            monitor_name: monitor_name.clone(),
+        },
+    ];
+    // The converter keeps track of connections, so we keep it across reconnects.
+    let mut converter = PushedEventConverter::new();
+
+    loop {
+        let res = connect_and_receive(
+            monitor_id,
+            &monitor_name,
+            &amqp_server_address,
+            &routing_keys,
+            &mut converter,
+            &msg_out,
+        )
+        .await;
+        MONITOR_CONNECTED.with_label_values(&[&monitor_name]).set(0);
+        if msg_out.is_closed() {
+            return;
+        }
+
+        info!(
+            "server {}, monitor {}: result: {:?}",
+            amqp_server_address, monitor_name, res
+        );
+        info!(
+            "server {}, monitor {}: sleeping for one second",
+            amqp_server_address, monitor_name
+        );
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn connect_and_receive(
+    monitor_id: usize,
+    monitor_name: &str,
+    amqp_server_address: &str,
+    routing_keys: &[RoutingKeyInformation],
+    converter: &mut PushedEventConverter,
+    msg_out: &mpsc::Sender<(usize, JSONMessage)>,
+) -> Result<()> {
+    debug!(
+        "connecting to AMQP server at {} and subscribing to events for monitor {}...",
+        amqp_server_address, monitor_name
+    );
+    let mut client = MonitoringClient::new(amqp_server_address, routing_keys).await?;
+    info!(
+        "connected for monitor {} at {}",
+        monitor_name, amqp_server_address
+    );
+    MONITOR_CONNECTED.with_label_values(&[monitor_name]).set(1);
+
+    while let Some(events) = client.next().await {
+        let (_, events) = events.context("unable to receive events")?;
+        for msg in events.iter().filter_map(|e| converter.convert(e)) {
+            if msg_out.send((monitor_id, msg)).await.is_err() {
+                // The unification loop is shutting down.
+                return Ok(());
+            }
+        }
+    }
+
+    warn!("monitor {}: disconnected", monitor_name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::json_message;
+
+    fn ts(millis: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp_millis(millis).unwrap()
+    }
+
+    /// Pops all messages that are ready, as (monitor, peer).
+    fn pop_all_ready(sorter: &mut LiveSorter, now_millis: i64) -> Vec<(usize, String)> {
+        std::iter::from_fn(|| sorter.pop_ready(ts(now_millis)))
+            .map(|(monitor_id, msg)| (monitor_id, msg.msg.peer))
+            .collect()
+    }
+
+    fn popped(expected: &[(usize, &str)]) -> Vec<(usize, String)> {
+        expected
+            .iter()
+            .map(|(monitor_id, peer)| (*monitor_id, peer.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn held_back_for_lateness_bound() {
+        let mut sorter = LiveSorter::new(1000, 100);
+        assert!(sorter.push(0, json_message(10_000, "a")));
+        assert!(sorter.push(1, json_message(9_500, "b")));
+        assert!(sorter.push(0, json_message(10_500, "c")));
+
+        assert!(pop_all_ready(&mut sorter, 10_400).is_empty());
+        assert_eq!(
+            pop_all_ready(&mut sorter, 11_000),
+            popped(&[(1, "b"), (0, "a")])
+        );
+        assert_eq!(sorter.len(), 1);
+        assert_eq!(pop_all_ready(&mut sorter, 11_500), popped(&[(0, "c")]));
+        assert!(sorter.pop().is_none());
+    }
+
+    #[test]
+    fn released_early_if_too_many_buffered() {
+        let mut sorter = LiveSorter::new(1000, 2);
+        assert!(sorter.push(0, json_message(10_000, "a")));
+        assert!(sorter.push(1, json_message(10_200, "b")));
+        assert!(pop_all_ready(&mut sorter, 10_200).is_empty());
+
+        assert!(sorter.push(1, json_message(10_100, "c")));
+        assert_eq!(pop_all_ready(&mut sorter, 10_200), popped(&[(0, "a")]));
+        assert_eq!(sorter.len(), 2);
+    }
+
+    #[test]
+    fn ties_broken_by_monitor() {
+        let mut sorter = LiveSorter::new(1000, 100);
+        assert!(sorter.push(1, json_message(10_000, "a")));
+        assert!(sorter.push(0, json_message(10_000, "b")));
+        assert!(sorter.push(1, json_message(10_000, "c")));
+        assert!(sorter.push(0, json_message(10_000, "d")));
+        assert_eq!(
+            pop_all_ready(&mut sorter, 11_000),
+            popped(&[(0, "b"), (0, "d"), (1, "a"), (1, "c")])
+        );
+    }
+
+    #[test]
+    fn late_messages_are_clamped() {
+        let mut sorter = LiveSorter::new(1000, 100);
+        assert!(sorter.push(0, json_message(10_000, "a")));
+        assert!(sorter.push(1, json_message(10_300, "b")));
+        assert_eq!(pop_all_ready(&mut sorter, 11_000), popped(&[(0, "a")]));
+
+        // Older than what was processed already.
+        assert!(!sorter.push(1, json_message(9_999, "late1")));
+        // Not older, so still sorted in.
+        assert!(sorter.push(0, json_message(10_000, "c")));
+        assert!(sorter.push(0, json_message(10_200, "d")));
+        assert_eq!(
+            pop_all_ready(&mut sorter, 20_000),
+            popped(&[(0, "c"), (1, "late1"), (0, "d"), (1, "b")])
+        );
+        assert!(!sorter.push(0, json_message(10_299, "late2")));
+        assert_eq!(sorter.len(), 1);
+
+        // Late messages are processed with the timestamp of the newest message processed before,
+        // and keep their original timestamp for the output.
+        let (monitor_id, msg) = sorter.pop().unwrap();
+        assert_eq!(monitor_id, 0);
+        assert_eq!(msg.msg.timestamp, ts(10_300));
+        assert_eq!(msg.clamped_from, Some(ts(10_299)));
+        assert_eq!(sorter.len(), 0);
+    }
+}
@@ -1,18 +1,32 @@
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate prometheus;
 
+mod blossom;
 mod config;
+mod coverage;
+mod ledgers;
+mod live;
 mod matcher;
+mod presence;
+mod skew;
 mod source;
+mod strategy;
+#[cfg(test)]
+mod testing;
+mod unifier;
 
-use crate::config::Config;
+use crate::config::{ClockSkewConfig, Config};
 use crate::matcher::InterMonitorMatcher;
-use crate::source::{MultiSourceIngestResult, MultiSourceIngester};
+use crate::skew::{ClockSkewEstimator, ClockSkewReport};
+use crate::source::MultiSourceIngester;
+use crate::unifier::Unifier;
 use clap::{App, Arg};
 use failure::{err_msg, ResultExt};
-use flate2::write::GzEncoder;
 use ipfs_resolver_common::{logging, Result};
-use std::io;
 
 fn main() -> Result<()> {
     logging::set_up_logging()?;
@@ -42,104 +56,107 @@ fn main() -> Result<()> {
     let cfg = Config::open(cfg).context("unable to load config")?;
     debug!("read config {:?}", cfg);
 
+    if let Some(live_cfg) = cfg.live.clone() {
+        if cfg.clock_skew.as_ref().is_some_and(|c| c.correct) {
+            warn!("clock offsets can not be corrected in live mode, only estimated");
+        }
+        let rt = tokio::runtime::Runtime::new().context("unable to set up tokio runtime")?;
+        return rt.block_on(live::run(cfg, live_cfg));
+    }
+
+    // Estimate clock offsets beforehand, if we are to correct them
+    let (uncorrected_skew, clock_corrections) = match cfg.clock_skew.as_ref() {
+        Some(skew_cfg) if skew_cfg.correct => {
+            info!("estimating clock offsets between monitors...");
+            let report =
+                estimate_clock_skew(&cfg, skew_cfg).context("unable to estimate clock skew")?;
+            report.log("estimated");
+            let corrections = report.corrections(&monitor_names(&cfg));
+            (Some(report), corrections)
+        }
+        _ => (None, Vec::new()),
+    };
+
     // Construct merged source
-    let mut multi_source =
-        MultiSourceIngester::from_config(&cfg).context("unable to set up sources")?;
+    let mut multi_source = MultiSourceIngester::from_config(&cfg, &clock_corrections)
+        .context("unable to set up sources")?;
     let source_names = multi_source.source_names();
     info!("unifying sources {:?}", source_names);
 
-    // Construct the thing that keeps track of inter-monitor duplicates and matches entries
-    let mut dup_marker = InterMonitorMatcher::new_from_config(&cfg.matching_config)
-        .context("unable to construct inter-monitor duplicate marker and matcher")?;
-
-    let mut num_messages_in_current_output_file = 0;
-    let messages_per_file = 100_000;
-    let mut current_output_file =
-        create_output_writer_from_pattern(cfg.wantlist_output_file_pattern.clone(), 0)
-            .context("unable to create output file")?;
+    let mut unifier =
+        Unifier::new(&cfg, source_names.clone()).context("unable to set up unification")?;
 
     // Iterate through entries produced by the merged source iterator
     let before = std::time::Instant::now();
-    while let Some(res) = multi_source.next() {
-        match res {
-            Err(e) => {
-                return Err(e.context("unable to advance sources").into());
-            }
-            Ok(MultiSourceIngestResult {
-                monitor_id,
-                timestamp,
-                peer_id,
-                simulation_result,
-            }) => {
-                debug!(
-                    "got entry {:?} from monitor {}",
-                    simulation_result, monitor_id
-                );
-
-                // Feed that into the matching engine
-                let output_entries = dup_marker
-                    .handle_ingest_result(monitor_id, timestamp, peer_id, simulation_result)
-                    .context("unable to handle ingest result")?;
-
-                // Rotate output file if necessary
-                if num_messages_in_current_output_file > messages_per_file {
-                    current_output_file = create_output_writer_from_pattern(
-                        cfg.wantlist_output_file_pattern.clone(),
-                        multi_source.last_message_id() + 1,
-                    )
-                    .context("unable to create output file")?;
-                    num_messages_in_current_output_file = 0;
-                }
-
-                // Write entries to output file
-                output_entries
-                    .into_iter()
-                    .try_for_each(|e| current_output_file.serialize(e))
-                    .context("unable to write output")?;
-                num_messages_in_current_output_file += 1;
-            }
-        }
+    for res in multi_source.by_ref() {
+        let res = res.context("unable to advance sources")?;
+        unifier.handle(res)?;
     }
-
-    // TODO emit end-of-simulation synthetic cancels?
-    // TODO keep track of missing ledgers, maybe per-source?
-    // TODO also keep track of ledger count per-source and in sum?
-
     let time_diff = before.elapsed();
 
     let msg_id = multi_source.last_message_id();
-    let _engine_states = multi_source.into_engine_states();
-    let matching_stats = dup_marker.stats();
-
     info!(
         "processed {} messages in {:.1}s => {:.1}msg/s",
         msg_id,
         time_diff.as_secs_f32(),
         (msg_id as f64) / time_diff.as_secs_f64()
     );
-    info!(
-        "{} entries in total, of which {} were matched between monitors",
-        matching_stats.total_entries, matching_stats.matched_entries
-    );
-    info!(
-        "min/mean/max match diff (ms): {}/{}/{}",
-        matching_stats.min_match_diff,
-        (matching_stats.match_diff_sum / matching_stats.matched_entries as f64) * 1000.0,
-        matching_stats.max_match_diff
-    );
 
-    Ok(())
+    for (name, stats) in source_names
+        .iter()
+        .zip(multi_source.out_of_order_statistics())
+    {
+        if stats.messages > 0 {
+            warn!(
+                "{} messages out of order after sorting for monitor {}, by up to {}ms",
+                stats.messages,
+                name,
+                stats.max_regression.num_milliseconds()
+            );
+        }
+    }
+
+    unifier.finish(
+        &cfg,
+        multi_source.into_engine_states(),
+        msg_id,
+        uncorrected_skew,
+        clock_corrections,
+    )
+}
+
+/// Returns the names of the monitors in the config, in order.
+fn monitor_names(cfg: &Config) -> Vec<String> {
+    cfg.monitors
+        .iter()
+        .map(|m| m.monitor_name.clone())
+        .collect()
 }
 
-fn create_output_writer_from_pattern(
-    pattern: String,
-    current_message_id: i64,
-) -> Result<csv::Writer<GzEncoder<io::BufWriter<std::fs::File>>>> {
-    Ok(csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(pattern.replace("$id$", &format!("{:09}", current_message_id)))
-                .context("unable to open output file for writing")?,
-        ),
-        flate2::Compression::default(),
-    )))
+/// Runs the simulation and matching over all traces without producing outputs, and estimates the
+/// clock offsets between monitors from the matched entries.
+fn estimate_clock_skew(cfg: &Config, skew_cfg: &ClockSkewConfig) -> Result<ClockSkewReport> {
+    let multi_source =
+        MultiSourceIngester::from_config(cfg, &[]).context("unable to set up sources")?;
+    let source_names = multi_source.source_names();
+    let mut matcher = InterMonitorMatcher::new_from_config(&cfg.matching_config)
+        .context("unable to construct inter-monitor matcher")?;
+    let mut estimator = ClockSkewEstimator::new(skew_cfg.bucket_secs);
+
+    for res in multi_source {
+        let res = res.context("unable to advance sources")?;
+        matcher
+            .handle_ingest_result(
+                res.monitor_id,
+                res.timestamp,
+                res.peer_id,
+                res.simulation_result,
+            )
+            .context("unable to handle ingest result")?
+            .iter()
+            .for_each(|e| estimator.record(e));
+    }
+    matcher.finish().iter().for_each(|e| estimator.record(e));
+
+    Ok(estimator.estimate(&source_names))
 }
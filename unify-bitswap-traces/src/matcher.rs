@@ -1,19 +1,26 @@
-use crate::config::MatchingConfig;
+use crate::config::{MatchingConfig, MessageTypeMatching};
+use crate::strategy;
+use crate::strategy::{MatchCandidate, MatchingStrategy};
 use crate::Result;
 use failure::ResultExt;
+use ipfs_resolver_common::csv_wantlist_entry_parquet_columns;
+use ipfs_resolver_common::output::{next_column, ColumnGroup, ParquetColumnWriter};
 use ipfs_resolver_common::wantlist;
-use ipfs_resolver_common::wantlist::{CSVWantlistEntry, IngestResult};
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, VecDeque};
+use ipfs_resolver_common::wantlist::{
+    CSVWantlistEntry, ExtendedCSVWantlistEntry, IngestResult, WantlistEntryExtension,
+};
+use serde::de::SeqAccess;
+use serde::ser::SerializeStruct;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 
 /// An entry in a per-peer queue.
 /// These queues hold entries from multiple monitors.
-/// We need to keep track of where they came from and whether they've been matched with.
+/// We need to keep track of where they came from, and identify them to the matching strategy.
 #[derive(Debug, Clone)]
 struct SourcedCSVWantlistEntry {
     entry: CSVWantlistEntry,
     monitor_id: usize,
-    matched: bool,
+    id: u64,
 }
 
 /// Functions to calculate time differences.
@@ -72,8 +79,11 @@ struct GlobalDuplicateStatus {
 
 /// The struct emitted by the global duplication and matching algorithm.
 /// To be serialized to CSV.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct OutputCSVWantlistEntry {
+pub(crate) type OutputCSVWantlistEntry = ExtendedCSVWantlistEntry<MatchingColumns>;
+
+/// The columns added to wantlist entries by the global duplication and matching algorithm.
+#[derive(Debug, Clone)]
+pub(crate) struct MatchingColumns {
     /// The ID of the monitor this entry originates from.
     pub monitor_id: u64,
 
@@ -82,88 +92,156 @@ pub(crate) struct OutputCSVWantlistEntry {
     /// The time difference between the matches, in milliseconds.
     pub match_time_diff_ms: Option<u64>,
 
+    /// The ID of the cluster of entries this entry belongs to.
+    /// A cluster contains entries for the same peer, CID, and entry type, seen by different
+    /// monitors within the matching window.
+    /// Synthetic entries do not belong to a cluster.
+    pub match_cluster_id: Option<u64>,
+    /// The number of entries, and thus monitors, in the cluster.
+    pub match_cluster_size: Option<u64>,
+    /// The IDs of the monitors that saw the cluster, in ascending order, separated by `;`.
+    pub match_cluster_monitors: Option<String>,
+
     /// Whether this entry was seen at another monitor, within the global duplicate window.
     /// If yes: What's the time difference between the duplicates, in milliseconds?
     pub global_duplicate_time_diff_ms: Option<u64>,
+}
+
+impl ColumnGroup for MatchingColumns {
+    const NUM_COLUMNS: usize = 7;
+
+    fn serialize_columns<S: SerializeStruct>(
+        &self,
+        s: &mut S,
+    ) -> std::result::Result<(), S::Error> {
+        s.serialize_field("monitor_id", &self.monitor_id)?;
+        s.serialize_field("matched_to_monitor_id", &self.matched_to_monitor_id)?;
+        s.serialize_field("match_time_diff_ms", &self.match_time_diff_ms)?;
+        s.serialize_field("match_cluster_id", &self.match_cluster_id)?;
+        s.serialize_field("match_cluster_size", &self.match_cluster_size)?;
+        s.serialize_field("match_cluster_monitors", &self.match_cluster_monitors)?;
+        s.serialize_field(
+            "global_duplicate_time_diff_ms",
+            &self.global_duplicate_time_diff_ms,
+        )
+    }
+
+    fn deserialize_columns<'de, A: SeqAccess<'de>>(
+        seq: &mut A,
+    ) -> std::result::Result<Self, A::Error> {
+        Ok(MatchingColumns {
+            monitor_id: next_column(seq, "monitor_id")?,
+            matched_to_monitor_id: next_column(seq, "matched_to_monitor_id")?,
+            match_time_diff_ms: next_column(seq, "match_time_diff_ms")?,
+            match_cluster_id: next_column(seq, "match_cluster_id")?,
+            match_cluster_size: next_column(seq, "match_cluster_size")?,
+            match_cluster_monitors: next_column(seq, "match_cluster_monitors")?,
+            global_duplicate_time_diff_ms: next_column(seq, "global_duplicate_time_diff_ms")?,
+        })
+    }
 
-    // ================================================
-    // TODO
-    // These are the exact fields of `common::wantlist::CSVWantlistEntry`.
-    // It'd be nice to just [serde(flatten)] that in here, but the `csv` crate does not support
-    // that (yet?).
-    /// A synthetic counter which is incremented for every object in the stream of logged
-    /// `JSONMessage`s.
-    /// This is used to group `want_list` entries from the same message etc.
-    pub message_id: i64,
-
-    /// Message type, see `CSV_MESSAGE_TYPE_`constants.
-    pub message_type: i32,
-
-    /// Timestamp as seconds since the Unix epoch.
-    pub timestamp_seconds: i64,
-    /// Sub-second milliseconds of the timestamp.
-    pub timestamp_subsec_milliseconds: u32,
-
-    /// The ID of the sending peer.
-    pub peer_id: String,
-    /// The underlay multiaddress of the sending peer, if available.
-    pub address: String,
-
-    /// The `priority` field as was sent in the original JSON message.
-    pub priority: i32,
-    /// Entry type, see `CSV_ENTRY_TYPE_` constants.
-    pub entry_type: i32,
-    /// The human-readable CID as was sent in the original JSON message, not normalized.
-    pub cid: String,
-
-    /// Marks _request_ messages as duplicates, see `CSV_DUPLICATE_STATUS` constants.
-    /// This functions as a bitfield, i.e. an entry that is both a `full_wantlist` and a `reconnect`
-    /// duplicate will have `duplicate_status` set to
-    /// `CSV_DUPLICATE_STATUS_DUP_FULL_WANTLIST | CSV_DUPLICATE_STATUS_DUP_RECONNECT`.
-    /// This does not apply to CANCEL entries.
-    pub duplicate_status: u32,
-    /// Indicates the smallest sliding window that matched for a sliding window duplicate request
-    /// entry. This does not apply to CANCEL entries
-    pub sliding_window_smallest_match: u32,
-    /// Lists the time difference in seconds between duplicate requests or request-CANCEL pairs.
-    /// If the entry type is CANCEL, the latter applies.
-    pub secs_since_earlier_message: u32,
-    /// Indicates whether this request upgrades an earlier request.
-    /// This is only valid for requests of type `WANT_BLOCK`, as they can upgrade earlier
-    /// `WANT_HAVE` requests.
-    pub upgrades_earlier_request: bool,
+    fn write_parquet_column_group<'a, I>(records: I, w: &mut ParquetColumnWriter) -> Result<()>
+    where
+        I: Iterator<Item = &'a Self> + Clone,
+    {
+        w.write_u64(records.clone().map(|r| r.monitor_id))?;
+        w.write_optional_u64(records.clone().map(|r| r.matched_to_monitor_id))?;
+        w.write_optional_u64(records.clone().map(|r| r.match_time_diff_ms))?;
+        w.write_optional_u64(records.clone().map(|r| r.match_cluster_id))?;
+        w.write_optional_u64(records.clone().map(|r| r.match_cluster_size))?;
+        w.write_optional_str(records.clone().map(|r| r.match_cluster_monitors.as_deref()))?;
+        w.write_optional_u64(records.map(|r| r.global_duplicate_time_diff_ms))?;
+        Ok(())
+    }
+}
+
+impl WantlistEntryExtension for MatchingColumns {
+    const PARQUET_SCHEMA: &'static str = concat!(
+        "message unified_wantlist_entry {
+            REQUIRED INT64 monitor_id (UINT_64);
+            OPTIONAL INT64 matched_to_monitor_id (UINT_64);
+            OPTIONAL INT64 match_time_diff_ms (UINT_64);
+            OPTIONAL INT64 match_cluster_id (UINT_64);
+            OPTIONAL INT64 match_cluster_size (UINT_64);
+            OPTIONAL BINARY match_cluster_monitors (UTF8);
+            OPTIONAL INT64 global_duplicate_time_diff_ms (UINT_64);",
+        csv_wantlist_entry_parquet_columns!(),
+        "}"
+    );
 }
 
 impl From<GloballyDupedMatchedCSVWantlistEntry> for OutputCSVWantlistEntry {
     fn from(e: GloballyDupedMatchedCSVWantlistEntry) -> Self {
-        OutputCSVWantlistEntry {
-            message_id: e.entry.entry.message_id,
-            message_type: e.entry.entry.message_type,
-            timestamp_seconds: e.entry.entry.timestamp_seconds,
-            timestamp_subsec_milliseconds: e.entry.entry.timestamp_subsec_milliseconds,
-            peer_id: e.entry.entry.peer_id,
-            address: e.entry.entry.address,
-            priority: e.entry.entry.priority,
-            entry_type: e.entry.entry.entry_type,
-            cid: e.entry.entry.cid,
-            duplicate_status: e.entry.entry.duplicate_status,
-            sliding_window_smallest_match: e.entry.entry.sliding_window_smallest_match,
-            secs_since_earlier_message: e.entry.entry.secs_since_earlier_message,
-            monitor_id: e.entry.monitor_id,
-            matched_to_monitor_id: e.entry.inter_source_match.map(|m| m.matched_to_monitor_id),
-            match_time_diff_ms: e.entry.inter_source_match.map(|m| m.match_time_diff_ms),
-            global_duplicate_time_diff_ms: e.global_dup.map(|m| m.time_since_dup_ms),
-            upgrades_earlier_request: false,
+        ExtendedCSVWantlistEntry {
+            extension: MatchingColumns {
+                monitor_id: e.entry.monitor_id,
+                matched_to_monitor_id: e.entry.inter_source_match.map(|m| m.matched_to_monitor_id),
+                match_time_diff_ms: e.entry.inter_source_match.map(|m| m.match_time_diff_ms),
+                match_cluster_id: None,
+                match_cluster_size: None,
+                match_cluster_monitors: None,
+                global_duplicate_time_diff_ms: e.global_dup.map(|m| m.time_since_dup_ms),
+            },
+            entry: e.entry.entry,
         }
     }
 }
 
-/// The algorithm keeping track of global duplicates and matches between monitors.
+/// Constructs an output entry that was not matched and is not a global duplicate.
+/// This is used for entries that do not pass through the matcher, such as end-of-simulation
+/// synthetic cancels.
+pub(crate) fn unmatched_output_entry(
+    monitor_id: usize,
+    entry: CSVWantlistEntry,
+) -> OutputCSVWantlistEntry {
+    GloballyDupedMatchedCSVWantlistEntry {
+        entry: MatchedCSVWantlistEntry {
+            entry,
+            monitor_id: monitor_id as u64,
+            inter_source_match: None,
+        },
+        global_dup: None,
+    }
+    .into()
+}
+
+/// Identifies entries that can be clustered: peer ID, CID, entry type, and message type (or zero,
+/// depending on `message_type_matching`).
+/// This is the same as for matching, so entries of a cluster can be matched to each other.
+type ClusterKey = (String, String, i32, i32);
+
+/// The entries of one message, with the IDs assigned to non-synthetic entries.
+type IdentifiedEntries = Vec<(Option<u64>, GloballyDupedMatchedCSVWantlistEntry)>;
+
+/// A group of entries for the same peer, CID, and type, seen by different monitors within the
+/// matching window, starting at the first entry.
 #[derive(Debug, Clone)]
+struct MatchCluster {
+    key: ClusterKey,
+    first_ts_ms: i64,
+    monitors: BTreeSet<u64>,
+    /// The number of entries of this cluster that have not been emitted yet.
+    pending_entries: usize,
+}
+
+/// The algorithm keeping track of global duplicates and matches between monitors.
+///
+/// Entries are held back until the matching window of their cluster has passed, so that all
+/// entries of a cluster carry its final size, and until the matching strategy has decided their
+/// match.
+/// Entries are emitted in the order they were handled.
+#[derive(Debug)]
 pub(crate) struct InterMonitorMatcher {
     /// Configuration
     cfg: MatchingConfig,
 
+    /// Decides which candidate entries are matched to.
+    strategy: Box<dyn MatchingStrategy>,
+    /// The ID of the next non-synthetic entry.
+    next_entry_id: u64,
+    /// Matches decided by the strategy, for entries that have not been emitted yet.
+    decided_matches: HashMap<u64, Option<InterSourceMatching>>,
+
     /// Statistics
     stats: MatcherStatistics,
 
@@ -171,18 +249,53 @@ pub(crate) struct InterMonitorMatcher {
     /// The queues contain past entries for that peer ID, for both monitors, ordered by timestamp.
     /// The queues do not contain synthetic entries.
     /// The queue is truncated to the appropriate window size whenever a new entry for that peer ID
-    /// is processed.
+    /// is processed, and periodically for all peers, see `sweep_peer_queues`.
+    /// Empty queues are removed by the periodic sweeps.
     peer_queues: HashMap<String, VecDeque<SourcedCSVWantlistEntry>>,
+    /// The newest timestamp seen.
+    watermark: Option<chrono::DateTime<chrono::Utc>>,
+    /// The watermark at the last sweep of all peer queues.
+    last_sweep: Option<chrono::DateTime<chrono::Utc>>,
+    /// Queued entries by peer ID and entry ID, oldest first, if the number of queued entries is
+    /// limited.
+    /// This can contain entries that have already been evicted otherwise.
+    eviction_order: VecDeque<(String, u64)>,
+
+    /// The ID of the next cluster to be created.
+    next_cluster_id: u64,
+    /// The clusters per key which entries can possibly still join, oldest first.
+    open_clusters: HashMap<ClusterKey, VecDeque<u64>>,
+    /// Clusters with entries that have not been emitted yet, by ID.
+    clusters: HashMap<u64, MatchCluster>,
+    /// Entries that have not been emitted yet, with their cluster ID and entry ID, in order.
+    /// Synthetic entries have neither.
+    pending_output: VecDeque<(Option<u64>, Option<u64>, OutputCSVWantlistEntry)>,
 }
 
 /// Some statistics about entry matches between monitors.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub(crate) struct MatcherStatistics {
     pub(crate) total_entries: usize,
     pub(crate) matched_entries: usize,
     pub(crate) min_match_diff: i64,
     pub(crate) max_match_diff: i64,
     pub(crate) match_diff_sum: f64,
+    /// The number of clusters, indexed by cluster size.
+    pub(crate) clusters_by_size: Vec<usize>,
+    /// The number of entries currently held in peer queues.
+    pub(crate) queued_entries: usize,
+    /// The number of peers with entries in their queues, as of the last sweep.
+    pub(crate) queued_peers: usize,
+    /// The maximum number of entries held in peer queues at once.
+    pub(crate) peak_queued_entries: usize,
+    /// The number of entries evicted by periodic sweeps.
+    pub(crate) swept_entries: usize,
+    /// The number of entries evicted because `max_queued_entries` was exceeded.
+    pub(crate) capped_entries: usize,
+    /// The number of queued entries skipped as candidates or duplicates because they are newer
+    /// than the entry they were compared to.
+    /// This only happens if timestamps go backwards, which sorting should prevent.
+    pub(crate) skipped_newer_entries: usize,
 }
 
 impl Default for MatcherStatistics {
@@ -193,6 +306,13 @@ impl Default for MatcherStatistics {
             min_match_diff: std::i64::MAX,
             max_match_diff: std::i64::MIN,
             match_diff_sum: 0_f64,
+            clusters_by_size: Vec::new(),
+            queued_entries: 0,
+            queued_peers: 0,
+            peak_queued_entries: 0,
+            swept_entries: 0,
+            capped_entries: 0,
+            skipped_newer_entries: 0,
         }
     }
 }
@@ -200,9 +320,19 @@ impl Default for MatcherStatistics {
 impl InterMonitorMatcher {
     pub(crate) fn new_from_config(cfg: &MatchingConfig) -> Result<InterMonitorMatcher> {
         Ok(InterMonitorMatcher {
-            cfg: cfg.clone(),
+            cfg: *cfg,
+            strategy: strategy::from_config(cfg),
+            next_entry_id: 0,
+            decided_matches: Default::default(),
             stats: Default::default(),
             peer_queues: Default::default(),
+            watermark: None,
+            last_sweep: None,
+            eviction_order: Default::default(),
+            next_cluster_id: 0,
+            open_clusters: Default::default(),
+            clusters: Default::default(),
+            pending_output: Default::default(),
         })
     }
 
@@ -212,8 +342,9 @@ impl InterMonitorMatcher {
     }
 
     /// Drives the matching and duplicate detection algorithm with the given entries.
-    /// Returns the entries (minus connection events) augmented with matching and duplicate
-    /// detection information.
+    /// Returns entries (minus connection events) augmented with matching and duplicate
+    /// detection information, once the matching windows of their clusters have passed.
+    /// These can be entries of earlier ingest results.
     pub(crate) fn handle_ingest_result(
         &mut self,
         monitor_id: usize,
@@ -223,84 +354,402 @@ impl InterMonitorMatcher {
     ) -> Result<Vec<OutputCSVWantlistEntry>> {
         if let Some(conn_event) = ingest_result.connection_event {
             debug!(
-                "ingest result contained connection event {:?}, ignoring...",
+                "ingest result contained connection event {:?}, not matching...",
                 conn_event
             );
         }
 
-        let output_entries = if let Some(entries) = ingest_result.wantlist_entries {
+        if self.watermark.is_none_or(|w| ts > w) {
+            self.watermark = Some(ts);
+        }
+
+        if let Some(entries) = ingest_result.wantlist_entries {
             self.stats.total_entries += entries.len();
             let queue = self.peer_queues.entry(peer_id).or_default();
-            Self::handle_entries(queue, monitor_id, entries, ts, &self.cfg, &mut self.stats)
-                .context("unable to handle entries")?
-                .into_iter()
-                .map(|entry| OutputCSVWantlistEntry::from(entry))
-                .collect()
+            let queued_before = queue.len();
+            let (output_entries, skipped_newer_entries) = Self::handle_entries(
+                queue,
+                monitor_id,
+                entries,
+                ts,
+                &self.cfg,
+                self.strategy.as_mut(),
+                &mut self.next_entry_id,
+            )
+            .context("unable to handle entries")?;
+            self.stats.skipped_newer_entries += skipped_newer_entries;
+            self.stats.queued_entries = self.stats.queued_entries - queued_before + queue.len();
+
+            for (entry_id, entry) in output_entries {
+                if let (Some(id), Some(_)) = (entry_id, self.cfg.max_queued_entries) {
+                    self.eviction_order
+                        .push_back((entry.entry.entry.peer_id.clone(), id));
+                }
+                let entry = OutputCSVWantlistEntry::from(entry);
+                let cluster_id = self.assign_cluster(&entry);
+                self.pending_output.push_back((cluster_id, entry_id, entry));
+            }
+        }
+
+        self.sweep_peer_queues();
+        self.limit_peer_queues();
+        self.stats.peak_queued_entries = self
+            .stats
+            .peak_queued_entries
+            .max(self.stats.queued_entries);
+
+        Ok(self.emit_entries(Some(ts.timestamp_millis())))
+    }
+
+    /// Evicts entries that have left both windows from the queues of all peers, and removes
+    /// empty queues, if the sweep interval has passed since the last sweep.
+    /// This keeps memory bounded for peers that went silent.
+    fn sweep_peer_queues(&mut self) {
+        let watermark = match self.watermark {
+            Some(w) => w,
+            None => return,
+        };
+        let interval = chrono::Duration::seconds(self.cfg.peer_queue_sweep_interval_secs as i64);
+        match self.last_sweep {
+            Some(last) if watermark - last < interval => return,
+            None => {
+                // Start counting from the first entry.
+                self.last_sweep = Some(watermark);
+                return;
+            }
+            _ => {}
+        }
+        self.last_sweep = Some(watermark);
+
+        let max_window_size = self.max_window_size_ms();
+        let strategy = self.strategy.as_mut();
+        let mut swept = 0;
+        self.peer_queues.retain(|_, queue| {
+            let before = queue.len();
+            queue.retain(|e| {
+                if e.millis_to_timestamp(watermark) > max_window_size {
+                    strategy.forget(e.id);
+                    return false;
+                }
+                true
+            });
+            swept += before - queue.len();
+            !queue.is_empty()
+        });
+        self.stats.swept_entries += swept;
+        self.stats.queued_entries -= swept;
+        self.stats.queued_peers = self.peer_queues.len();
+
+        // Drop evicted entries from the front of the eviction order.
+        while let Some((peer_id, id)) = self.eviction_order.front() {
+            if self.is_queued(peer_id, *id) {
+                break;
+            }
+            self.eviction_order.pop_front();
+        }
+        debug!(
+            "swept {} entries from peer queues, {} entries of {} peers remain",
+            swept, self.stats.queued_entries, self.stats.queued_peers
+        );
+    }
+
+    /// Evicts the oldest entries from the peer queues while more than `max_queued_entries` are
+    /// queued.
+    fn limit_peer_queues(&mut self) {
+        let max_queued_entries = match self.cfg.max_queued_entries {
+            Some(max) => max,
+            None => return,
+        };
+
+        while self.stats.queued_entries > max_queued_entries {
+            let (peer_id, id) = match self.eviction_order.pop_front() {
+                Some(e) => e,
+                None => break,
+            };
+            let queue = match self.peer_queues.get_mut(&peer_id) {
+                Some(queue) => queue,
+                None => continue,
+            };
+            if let Some(pos) = queue.iter().position(|e| e.id == id) {
+                queue.remove(pos);
+                self.strategy.forget(id);
+                self.stats.queued_entries -= 1;
+                self.stats.capped_entries += 1;
+            }
+        }
+    }
+
+    /// Checks whether the given entry is still in the queue of the given peer.
+    fn is_queued(&self, peer_id: &str, id: u64) -> bool {
+        self.peer_queues
+            .get(peer_id)
+            .is_some_and(|queue| queue.iter().any(|e| e.id == id))
+    }
+
+    /// The maximum time, in milliseconds, an entry needs to be kept in a peer queue.
+    fn max_window_size_ms(&self) -> i64 {
+        self.cfg
+            .inter_monitor_matching_window_milliseconds
+            .max(self.cfg.global_duplicate_window_seconds * 1000) as i64
+    }
+
+    /// Emits all remaining entries.
+    pub(crate) fn finish(&mut self) -> Vec<OutputCSVWantlistEntry> {
+        self.emit_entries(None)
+    }
+
+    /// Adds the entry to a cluster, either an open one for the same key, or a new one.
+    /// Returns the ID of the cluster, or None for synthetic entries.
+    fn assign_cluster(&mut self, entry: &OutputCSVWantlistEntry) -> Option<u64> {
+        if entry.entry.message_type == wantlist::CSV_MESSAGE_TYPE_SYNTHETIC {
+            return None;
+        }
+
+        let message_type_key = if self.cfg.message_type_matching == MessageTypeMatching::Ignore {
+            0
         } else {
-            Vec::default()
+            entry.entry.message_type
         };
+        let key = (
+            entry.entry.peer_id.clone(),
+            entry.entry.cid.clone(),
+            entry.entry.entry_type,
+            message_type_key,
+        );
+        let ts_ms =
+            entry.entry.timestamp_seconds * 1000 + entry.entry.timestamp_subsec_milliseconds as i64;
+        let window_ms = self.cfg.inter_monitor_matching_window_milliseconds as i64;
 
-        Ok(output_entries)
+        // Join the oldest open cluster that is still within the window and the monitor did not
+        // already contribute to.
+        // A monitor can see an entry again within the window, which opens another cluster, but
+        // other monitors should still join the first one.
+        let open = self.open_clusters.entry(key.clone()).or_default();
+        while let Some(id) = open.front() {
+            if ts_ms - self.clusters[id].first_ts_ms <= window_ms {
+                break;
+            }
+            open.pop_front();
+        }
+        let joined = open.iter().copied().find(|id| {
+            !self.clusters[id]
+                .monitors
+                .contains(&entry.extension.monitor_id)
+        });
+        if let Some(id) = joined {
+            let cluster = self.clusters.get_mut(&id).unwrap();
+            cluster.monitors.insert(entry.extension.monitor_id);
+            cluster.pending_entries += 1;
+            return Some(id);
+        }
+
+        let id = self.next_cluster_id;
+        self.next_cluster_id += 1;
+        self.clusters.insert(
+            id,
+            MatchCluster {
+                key: key.clone(),
+                first_ts_ms: ts_ms,
+                monitors: BTreeSet::from([entry.extension.monitor_id]),
+                pending_entries: 1,
+            },
+        );
+        self.open_clusters.entry(key).or_default().push_back(id);
+        Some(id)
     }
 
+    /// Collects the matches decided by the strategy at the given time.
+    fn collect_decided_matches(&mut self, now_ms: Option<i64>) {
+        for (entry_id, m) in self.strategy.decide(now_ms) {
+            let m = m.map(|m| {
+                debug!("entry {} matched to {:?}", entry_id, m);
+                self.stats.matched_entries += 1;
+                Self::record_match_time_diff(m.diff_ms, &mut self.stats);
+
+                InterSourceMatching {
+                    matched_to_monitor_id: m.monitor_id as u64,
+                    match_time_diff_ms: m.diff_ms as u64,
+                }
+            });
+            self.decided_matches.insert(entry_id, m);
+        }
+    }
+
+    /// Emits pending entries, in order, as long as their clusters are closed and their matches
+    /// decided at the given time.
+    /// If no time is given, all entries are emitted.
+    fn emit_entries(&mut self, now_ms: Option<i64>) -> Vec<OutputCSVWantlistEntry> {
+        let window_ms = self.cfg.inter_monitor_matching_window_milliseconds as i64;
+        let mut output = Vec::new();
+        self.collect_decided_matches(now_ms);
+
+        while let Some((cluster_id, entry_id, _)) = self.pending_output.front() {
+            if entry_id.is_some_and(|id| !self.decided_matches.contains_key(&id)) {
+                // The strategy could still match this differently.
+                break;
+            }
+            let cluster = match cluster_id {
+                Some(id) => {
+                    let cluster = &self.clusters[id];
+                    if now_ms.is_some_and(|now| now - cluster.first_ts_ms <= window_ms) {
+                        // Other monitors could still see this.
+                        break;
+                    }
+                    Some((*id, cluster))
+                }
+                None => None,
+            };
+
+            let (cluster_id, entry_id, mut entry) = self.pending_output.pop_front().unwrap();
+            if let Some(m) = entry_id.and_then(|id| self.decided_matches.remove(&id).unwrap()) {
+                entry.extension.matched_to_monitor_id = Some(m.matched_to_monitor_id);
+                entry.extension.match_time_diff_ms = Some(m.match_time_diff_ms);
+            }
+            if let Some((id, cluster)) = cluster {
+                entry.extension.match_cluster_id = Some(id);
+                entry.extension.match_cluster_size = Some(cluster.monitors.len() as u64);
+                entry.extension.match_cluster_monitors = Some(
+                    cluster
+                        .monitors
+                        .iter()
+                        .map(|m| m.to_string())
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                );
+            }
+            if let Some(id) = cluster_id {
+                self.entry_of_cluster_emitted(id);
+            }
+            output.push(entry);
+        }
+
+        output
+    }
+
+    /// Removes the cluster once all of its entries have been emitted.
+    fn entry_of_cluster_emitted(&mut self, id: u64) {
+        let cluster = self.clusters.get_mut(&id).unwrap();
+        cluster.pending_entries -= 1;
+        if cluster.pending_entries > 0 {
+            return;
+        }
+
+        let cluster = self.clusters.remove(&id).unwrap();
+        if let Some(open) = self.open_clusters.get_mut(&cluster.key) {
+            open.retain(|open_id| *open_id != id);
+            if open.is_empty() {
+                self.open_clusters.remove(&cluster.key);
+            }
+        }
+        let size = cluster.monitors.len();
+        if self.stats.clusters_by_size.len() <= size {
+            self.stats.clusters_by_size.resize(size + 1, 0);
+        }
+        self.stats.clusters_by_size[size] += 1;
+    }
+
+    /// Finds matching candidates and global duplicates for the entries of one message, and adds
+    /// them to the peer queue.
+    /// Returns the entries, with the IDs assigned to non-synthetic entries, and the number of
+    /// queued entries skipped because they were newer than the entry they were compared to.
     fn handle_entries(
         queue: &mut VecDeque<SourcedCSVWantlistEntry>,
         monitor_id: usize,
         entries: Vec<CSVWantlistEntry>,
         ts: chrono::DateTime<chrono::Utc>,
         cfg: &MatchingConfig,
-        stats: &mut MatcherStatistics,
-    ) -> Result<Vec<GloballyDupedMatchedCSVWantlistEntry>> {
+        strategy: &mut dyn MatchingStrategy,
+        next_entry_id: &mut u64,
+    ) -> Result<(IdentifiedEntries, usize)> {
         // Clear entries in peer queue older than max window size
         let max_window_size = cfg
             .inter_monitor_matching_window_milliseconds
             .max(cfg.global_duplicate_window_seconds * 1000) as i64;
         queue.retain(|e| {
             let diff_ms = e.millis_to_timestamp(ts);
-            diff_ms <= max_window_size
+            if diff_ms > max_window_size {
+                strategy.forget(e.id);
+                return false;
+            }
+            true
         });
         debug!("peer message queue is {:?}", queue);
+        let mut skipped_newer_entries = 0;
 
-        // Try to find and mark matches between monitors.
-        // This augments every entry with a possible global match.
-        // The number of entries does not change.
-        let matched_entries: Vec<_> = entries
-            .into_iter()
-            .map(|entry| Self::match_single_entry(queue, monitor_id, cfg, stats, entry))
+        // Offer non-synthetic entries to the matching strategy, together with the entries they
+        // could be matched to.
+        // The strategy decides on matches, possibly later.
+        let entry_ids: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                if entry.message_type == wantlist::CSV_MESSAGE_TYPE_SYNTHETIC {
+                    return None;
+                }
+                let id = *next_entry_id;
+                *next_entry_id += 1;
+                let candidates = Self::find_candidates(
+                    queue,
+                    monitor_id,
+                    cfg,
+                    entry,
+                    &mut skipped_newer_entries,
+                );
+                debug!(
+                    "offering entry {:?} with candidates {:?}",
+                    entry, candidates
+                );
+                strategy.offer(
+                    id,
+                    entry.timestamp_seconds * 1000 + entry.timestamp_subsec_milliseconds as i64,
+                    candidates,
+                );
+                Some(id)
+            })
             .collect();
-        debug!("generated matched entries {:?}", matched_entries);
 
         // Find and mark global duplicates, i.e., duplicates in some window calculated over all
         // monitors.
         // The number of entries does not change.
-        let duped_entries: Vec<_> = matched_entries
+        let duped_entries: Vec<_> = entries
             .into_iter()
-            .map(|entry| Self::search_dup_single_entry(queue, cfg, entry))
+            .map(|entry| MatchedCSVWantlistEntry {
+                entry,
+                monitor_id: monitor_id as u64,
+                inter_source_match: None,
+            })
+            .map(|entry| {
+                Self::search_dup_single_entry(queue, cfg, entry, &mut skipped_newer_entries)
+            })
             .collect();
         debug!("generated duped entries {:?}", duped_entries);
 
         // Append entries to peer queue
-        // We use the output entries for this so we can mark which ones were matched.
         // We filter synthetic entries, which are not to be included in the per-peer windows.
         queue.extend(
             duped_entries
                 .iter()
-                .filter(|&e| e.entry.entry.entry_type != wantlist::CSV_MESSAGE_TYPE_SYNTHETIC)
-                .map(|e| SourcedCSVWantlistEntry {
-                    entry: e.entry.entry.clone(),
-                    monitor_id,
-                    matched: e.entry.inter_source_match.is_some(),
+                .zip(entry_ids.iter())
+                .filter_map(|(e, id)| {
+                    id.map(|id| SourcedCSVWantlistEntry {
+                        entry: e.entry.entry.clone(),
+                        monitor_id,
+                        id,
+                    })
                 }),
         );
         debug!("peer message queue after update is {:?}", queue);
 
-        Ok(duped_entries)
+        Ok((
+            entry_ids.into_iter().zip(duped_entries).collect(),
+            skipped_newer_entries,
+        ))
     }
 
     fn search_dup_single_entry(
         queue: &mut VecDeque<SourcedCSVWantlistEntry>,
         cfg: &MatchingConfig,
         entry: MatchedCSVWantlistEntry,
+        skipped_newer_entries: &mut usize,
     ) -> GloballyDupedMatchedCSVWantlistEntry {
         debug!("searching for global duplicates for entry {:?}", entry);
         // Oldest entries are first in the queue.
@@ -311,11 +760,15 @@ impl InterMonitorMatcher {
             // Find entries that reference the same CID.
             .filter(|&dup| dup.entry.cid == entry.entry.cid)
             // Find entries with the correct entry type.
+            // Find entries with the same message type, if configured.
+            .filter(|&dup| {
+                cfg.message_type_matching != MessageTypeMatching::All
+                    || dup.entry.message_type == entry.entry.message_type
+            })
             .filter(|&dup| {
                 if cfg.match_exact_entry_type {
-                    // We do not check whether the _message_type_ is the same, as there are no
+                    // We do not check whether the _message_type_ is synthetic, as there are no
                     // synthetic messages in the queue.
-                    // We do not differentiate full wantlists from incremental ones.
                     dup.entry.entry_type == entry.entry.entry_type
                 } else {
                     // If we don't match the exact entry type, just check whether both of them were
@@ -325,10 +778,14 @@ impl InterMonitorMatcher {
                 }
             })
             // Calculate the time difference between entries in the queue and the new entry.
-            .map(|dup| {
+            // Skip entries newer than the new one, which are possible only if time went backwards.
+            .filter_map(|dup| {
                 let diff_ms = dup.millis_to_csv_entry(&entry.entry);
-                assert!(diff_ms >= 0);
-                (dup, diff_ms)
+                if diff_ms < 0 {
+                    *skipped_newer_entries += 1;
+                    return None;
+                }
+                Some((dup, diff_ms))
             })
             // Find the first one that fits into our window.
             // This will be the most recent matching entry because we reversed the iterator at some
@@ -353,70 +810,41 @@ impl InterMonitorMatcher {
         }
     }
 
-    fn match_single_entry(
-        queue: &mut VecDeque<SourcedCSVWantlistEntry>,
+    /// Finds the entries of other monitors in the queue that the entry could be matched to,
+    /// oldest first.
+    fn find_candidates(
+        queue: &VecDeque<SourcedCSVWantlistEntry>,
         monitor_id: usize,
         cfg: &MatchingConfig,
-        stats: &mut MatcherStatistics,
-        entry: CSVWantlistEntry,
-    ) -> MatchedCSVWantlistEntry {
-        debug!("searching for matches for entry {:?}", entry);
-
-        // Search peer queue for matches from another monitor
-        // (oldest are first in queue, queue only contains non-synthetic messages)
-        // We will match with the first candidate while iterating.
-        // If we want to match newest-first, we need to reverse the iterator.
-        let it: Box<dyn Iterator<Item = &mut SourcedCSVWantlistEntry>> = if cfg.match_newest_first {
-            Box::new(queue.iter_mut().rev())
-        } else {
-            Box::new(queue.iter_mut())
-        };
-
-        let mut matched_entry = it
-            .filter(|ref dup| {
-                // Do we allow matching the same entry multiple times?
-                cfg.allow_multiple_match || !dup.matched
-            })
-            .filter(|ref dup| {
+        entry: &CSVWantlistEntry,
+        skipped_newer_entries: &mut usize,
+    ) -> Vec<MatchCandidate> {
+        // The queue only contains non-synthetic entries, oldest first.
+        queue
+            .iter()
+            .filter(|dup| {
                 dup.monitor_id != monitor_id
                     && dup.entry.cid == entry.cid
-                    && dup.entry.message_type == entry.message_type
+                    && (cfg.message_type_matching == MessageTypeMatching::Ignore
+                        || dup.entry.message_type == entry.message_type)
                     && dup.entry.entry_type == entry.entry_type
             })
-            .map(|dup| {
-                let diff_ms = dup.millis_to_csv_entry(&entry);
-                assert!(diff_ms >= 0);
+            .filter_map(|dup| {
+                let diff_ms = dup.millis_to_csv_entry(entry);
+                if diff_ms < 0 {
+                    // Only possible if time went backwards.
+                    *skipped_newer_entries += 1;
+                    return None;
+                }
 
-                (dup, diff_ms)
+                Some(MatchCandidate {
+                    id: dup.id,
+                    monitor_id: dup.monitor_id,
+                    diff_ms,
+                })
             })
-            .find(|&(_, diff_ms)| diff_ms <= cfg.inter_monitor_matching_window_milliseconds as i64);
-
-        // Mark the entry as matched
-        if let Some((entry, _)) = &mut matched_entry {
-            entry.matched = true
-        }
-
-        // Construct output based on our findings...
-        MatchedCSVWantlistEntry {
-            entry,
-            monitor_id: monitor_id as u64,
-            inter_source_match: matched_entry.map_or_else(
-                || {
-                    debug!("found no matching entry");
-                    None
-                },
-                |(matched_entry, diff_ms)| {
-                    debug!("found matching entry {:?}", matched_entry);
-                    stats.matched_entries += 1;
-                    Self::record_match_time_diff(diff_ms, stats);
-
-                    Some(InterSourceMatching {
-                        matched_to_monitor_id: matched_entry.monitor_id as u64,
-                        match_time_diff_ms: diff_ms as u64,
-                    })
-                },
-            ),
-        }
+            .filter(|c| c.diff_ms <= cfg.inter_monitor_matching_window_milliseconds as i64)
+            .collect()
     }
 
     fn record_match_time_diff(diff_ms: i64, stats: &mut MatcherStatistics) {
@@ -429,3 +857,194 @@ impl InterMonitorMatcher {
         stats.match_diff_sum += (diff_ms as f64) / 1000.0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ingest_result, matching_config, wantlist_entry};
+
+    /// Feeds one entry of the given peer to the matcher.
+    fn handle(matcher: &mut InterMonitorMatcher, monitor_id: usize, ts_millis: i64, peer_id: &str) {
+        matcher
+            .handle_ingest_result(
+                monitor_id,
+                chrono::DateTime::from_timestamp_millis(ts_millis).unwrap(),
+                peer_id.to_string(),
+                ingest_result(vec![wantlist_entry(1, ts_millis, peer_id, "cid")]),
+            )
+            .unwrap();
+    }
+
+    /// Returns the timestamps of all queued entries, sorted.
+    fn queued_timestamps(matcher: &InterMonitorMatcher) -> Vec<i64> {
+        let mut ts: Vec<_> = matcher
+            .peer_queues
+            .values()
+            .flatten()
+            .map(|e| {
+                e.entry.timestamp_seconds * 1000 + e.entry.timestamp_subsec_milliseconds as i64
+            })
+            .collect();
+        ts.sort_unstable();
+        ts
+    }
+
+    #[test]
+    fn sweep_frees_queues_of_silent_peers() {
+        // The windows are two seconds, sweeps happen every minute.
+        let mut matcher = InterMonitorMatcher::new_from_config(&matching_config()).unwrap();
+
+        handle(&mut matcher, 0, 0, "silent");
+        handle(&mut matcher, 1, 30_000, "a");
+        handle(&mut matcher, 0, 59_000, "b");
+        // Not a minute since the first entry yet.
+        assert_eq!(matcher.stats().swept_entries, 0);
+        assert_eq!(matcher.stats().queued_entries, 3);
+        assert_eq!(matcher.peer_queues.len(), 3);
+
+        handle(&mut matcher, 1, 60_000, "c");
+        let stats = matcher.stats();
+        assert_eq!(stats.swept_entries, 2);
+        assert_eq!(stats.queued_entries, 2);
+        assert_eq!(stats.queued_peers, 2);
+        // The peak is taken after sweeping.
+        assert_eq!(stats.peak_queued_entries, 3);
+        assert!(!matcher.peer_queues.contains_key("silent"));
+        assert!(!matcher.peer_queues.contains_key("a"));
+        assert_eq!(queued_timestamps(&matcher), vec![59_000, 60_000]);
+
+        // The next sweep is a minute after this one.
+        handle(&mut matcher, 0, 119_000, "d");
+        assert_eq!(matcher.stats().swept_entries, 2);
+        handle(&mut matcher, 0, 120_000, "d");
+        assert_eq!(matcher.stats().swept_entries, 4);
+        assert_eq!(matcher.peer_queues.len(), 1);
+    }
+
+    #[test]
+    fn limit_evicts_oldest_first() {
+        let mut cfg = matching_config();
+        cfg.max_queued_entries = Some(3);
+        let mut matcher = InterMonitorMatcher::new_from_config(&cfg).unwrap();
+
+        handle(&mut matcher, 0, 0, "p0");
+        handle(&mut matcher, 1, 10, "p1");
+        handle(&mut matcher, 0, 20, "p2");
+        // This prunes the first entry of p0, which has left both windows.
+        handle(&mut matcher, 1, 3000, "p0");
+        assert_eq!(matcher.stats().queued_entries, 3);
+        assert_eq!(matcher.stats().capped_entries, 0);
+
+        // The pruned entry is skipped, the entry of p1 is evicted instead.
+        handle(&mut matcher, 0, 3010, "p3");
+        assert_eq!(queued_timestamps(&matcher), vec![20, 3000, 3010]);
+        handle(&mut matcher, 1, 3020, "p4");
+        assert_eq!(queued_timestamps(&matcher), vec![3000, 3010, 3020]);
+
+        let stats = matcher.stats();
+        assert_eq!(stats.capped_entries, 2);
+        assert_eq!(stats.queued_entries, 3);
+        assert_eq!(stats.peak_queued_entries, 3);
+        assert_eq!(stats.swept_entries, 0);
+        assert_eq!(stats.total_entries, 6);
+        assert_eq!(
+            stats.queued_entries,
+            matcher.peer_queues.values().map(|q| q.len()).sum::<usize>()
+        );
+    }
+
+    /// Feeds the entries to the matcher, in order, and returns the cluster size and monitors of
+    /// each output entry, by monitor ID and timestamp.
+    fn clusters(
+        matcher: &mut InterMonitorMatcher,
+        entries: Vec<(usize, CSVWantlistEntry)>,
+    ) -> Vec<(u64, i64, Option<u64>, Option<String>)> {
+        let mut output = Vec::new();
+        for (monitor_id, entry) in entries {
+            let ts = chrono::DateTime::from_timestamp_millis(
+                entry.timestamp_seconds * 1000 + entry.timestamp_subsec_milliseconds as i64,
+            )
+            .unwrap();
+            output.extend(
+                matcher
+                    .handle_ingest_result(
+                        monitor_id,
+                        ts,
+                        entry.peer_id.clone(),
+                        ingest_result(vec![entry]),
+                    )
+                    .unwrap(),
+            );
+        }
+        output.extend(matcher.finish());
+
+        output
+            .into_iter()
+            .map(|e| {
+                (
+                    e.extension.monitor_id,
+                    e.entry.timestamp_seconds * 1000 + e.entry.timestamp_subsec_milliseconds as i64,
+                    e.extension.match_cluster_size,
+                    e.extension.match_cluster_monitors,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repeated_entry_does_not_split_cluster() {
+        let mut matcher = InterMonitorMatcher::new_from_config(&matching_config()).unwrap();
+
+        let output = clusters(
+            &mut matcher,
+            vec![
+                (0, wantlist_entry(1, 0, "peer", "cid")),
+                (1, wantlist_entry(1, 100, "peer", "cid")),
+                // Monitor 0 sees the entry again, which opens another cluster.
+                (0, wantlist_entry(2, 200, "peer", "cid")),
+                // Monitor 2 joins the first cluster.
+                (2, wantlist_entry(1, 300, "peer", "cid")),
+            ],
+        );
+        let all = Some("0;1;2".to_string());
+        assert_eq!(
+            output,
+            vec![
+                (0, 0, Some(3), all.clone()),
+                (1, 100, Some(3), all.clone()),
+                (0, 200, Some(1), Some("0".to_string())),
+                (2, 300, Some(3), all),
+            ]
+        );
+        assert_eq!(matcher.stats().clusters_by_size, vec![0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn clusters_require_exact_entry_type() {
+        let mut cfg = matching_config();
+        cfg.match_exact_entry_type = false;
+        let mut matcher = InterMonitorMatcher::new_from_config(&cfg).unwrap();
+
+        let mut want_block = wantlist_entry(1, 100, "peer", "cid");
+        want_block.entry_type = wantlist::CSV_ENTRY_TYPE_WANT_BLOCK;
+        let output = clusters(
+            &mut matcher,
+            vec![
+                (0, wantlist_entry(1, 0, "peer", "cid")),
+                (1, want_block),
+                (2, wantlist_entry(1, 200, "peer", "cid")),
+            ],
+        );
+        // Like matches, clusters do not mix WANT_HAVE and WANT_BLOCK.
+        assert_eq!(
+            output,
+            vec![
+                (0, 0, Some(2), Some("0;2".to_string())),
+                (1, 100, Some(1), Some("1".to_string())),
+                (2, 200, Some(2), Some("0;2".to_string())),
+            ]
+        );
+        assert_eq!(matcher.stats().matched_entries, 1);
+        assert_eq!(matcher.stats().clusters_by_size, vec![0, 1, 1]);
+    }
+}
@@ -0,0 +1,540 @@
+//! Connection state of peers across monitors.
+//!
+//! Connection events of all monitors are merged, and for every peer we keep track of the set of
+//! monitors it is connected to.
+//! Each change of that set ends one interval of the peer's presence timeline.
+
+use crate::Result;
+use failure::ResultExt;
+use ipfs_resolver_common::anonymize::{anonymized, Anonymize, Anonymizer};
+use ipfs_resolver_common::output::{ParquetColumnWriter, ParquetRecord, RecordSink};
+use ipfs_resolver_common::wantlist::{
+    CSVConnectionEvent, CSV_CONNECTION_EVENT_CONNECTED_FOUND,
+    CSV_CONNECTION_EVENT_CONNECTED_NOT_FOUND, CSV_CONNECTION_EVENT_DISCONNECTED_FOUND,
+    CSV_CONNECTION_EVENT_DISCONNECTED_NOT_FOUND,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+type Sink<T> = Box<dyn RecordSink<T>>;
+
+/// A connection event seen by one of the monitors, to be serialized to CSV.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct OutputCSVConnectionEvent {
+    /// The ID of the monitor this event originates from.
+    pub(crate) monitor_id: u64,
+
+    // These are the fields of `common::wantlist::CSVConnectionEvent`.
+    pub(crate) message_id: i64,
+    pub(crate) timestamp_seconds: i64,
+    pub(crate) timestamp_subsec_millis: u32,
+    pub(crate) peer_id: String,
+    pub(crate) address: String,
+    /// The type of the connection event, see the `CSV_CONNECTION_EVENT_` constants.
+    pub(crate) event_type: i32,
+}
+
+impl OutputCSVConnectionEvent {
+    fn new(monitor_id: usize, e: CSVConnectionEvent) -> OutputCSVConnectionEvent {
+        OutputCSVConnectionEvent {
+            monitor_id: monitor_id as u64,
+            message_id: e.message_id,
+            timestamp_seconds: e.timestamp_seconds,
+            timestamp_subsec_millis: e.timestamp_subsec_millis,
+            peer_id: e.peer_id,
+            address: e.address,
+            event_type: e.event_type,
+        }
+    }
+}
+
+impl ParquetRecord for OutputCSVConnectionEvent {
+    const PARQUET_SCHEMA: &'static str = "
+        message unified_connection_event {
+            REQUIRED INT64 monitor_id (UINT_64);
+            REQUIRED INT64 message_id;
+            REQUIRED INT64 timestamp_seconds;
+            REQUIRED INT32 timestamp_subsec_millis (UINT_32);
+            REQUIRED BINARY peer_id (UTF8);
+            REQUIRED BINARY address (UTF8);
+            REQUIRED INT32 event_type;
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_u64(records.iter().map(|r| r.monitor_id))?;
+        w.write_i64(records.iter().map(|r| r.message_id))?;
+        w.write_i64(records.iter().map(|r| r.timestamp_seconds))?;
+        w.write_u32(records.iter().map(|r| r.timestamp_subsec_millis))?;
+        w.write_str(records.iter().map(|r| r.peer_id.as_str()))?;
+        w.write_str(records.iter().map(|r| r.address.as_str()))?;
+        w.write_i32(records.iter().map(|r| r.event_type))?;
+        Ok(())
+    }
+}
+
+impl Anonymize for OutputCSVConnectionEvent {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.peer_id = anonymizer.pseudonymize_peer(&self.peer_id);
+        self.address = anonymizer.anonymize_address(&self.address);
+    }
+}
+
+/// An interval during which a peer was connected to the same, non-empty, set of monitors.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CSVPresenceInterval {
+    pub(crate) peer_id: String,
+    pub(crate) start_ts_seconds: i64,
+    pub(crate) start_ts_subsec_millis: u32,
+    pub(crate) end_ts_seconds: i64,
+    pub(crate) end_ts_subsec_millis: u32,
+    /// The number of monitors the peer was connected to.
+    pub(crate) num_monitors: u64,
+    /// The IDs of the monitors the peer was connected to, in ascending order, separated by `;`.
+    pub(crate) monitors: String,
+}
+
+impl ParquetRecord for CSVPresenceInterval {
+    const PARQUET_SCHEMA: &'static str = "
+        message presence_interval {
+            REQUIRED BINARY peer_id (UTF8);
+            REQUIRED INT64 start_ts_seconds;
+            REQUIRED INT32 start_ts_subsec_millis (UINT_32);
+            REQUIRED INT64 end_ts_seconds;
+            REQUIRED INT32 end_ts_subsec_millis (UINT_32);
+            REQUIRED INT64 num_monitors (UINT_64);
+            REQUIRED BINARY monitors (UTF8);
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_str(records.iter().map(|r| r.peer_id.as_str()))?;
+        w.write_i64(records.iter().map(|r| r.start_ts_seconds))?;
+        w.write_u32(records.iter().map(|r| r.start_ts_subsec_millis))?;
+        w.write_i64(records.iter().map(|r| r.end_ts_seconds))?;
+        w.write_u32(records.iter().map(|r| r.end_ts_subsec_millis))?;
+        w.write_u64(records.iter().map(|r| r.num_monitors))?;
+        w.write_str(records.iter().map(|r| r.monitors.as_str()))?;
+        Ok(())
+    }
+}
+
+impl Anonymize for CSVPresenceInterval {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.peer_id = anonymizer.pseudonymize_peer(&self.peer_id);
+    }
+}
+
+/// How long a peer was connected to all, some, or only one of the monitors, to be serialized to
+/// CSV.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CSVPeerOverlap {
+    pub(crate) peer_id: String,
+    /// The number of monitors the peer was connected to at any time.
+    pub(crate) monitors_seen: u64,
+    /// The time connected to all monitors, in seconds.
+    pub(crate) seconds_connected_all: f64,
+    /// The time connected to more than one, but not all monitors, in seconds.
+    pub(crate) seconds_connected_some: f64,
+    /// The time connected to exactly one monitor, in seconds.
+    pub(crate) seconds_connected_one: f64,
+}
+
+impl ParquetRecord for CSVPeerOverlap {
+    const PARQUET_SCHEMA: &'static str = "
+        message peer_overlap {
+            REQUIRED BINARY peer_id (UTF8);
+            REQUIRED INT64 monitors_seen (UINT_64);
+            REQUIRED DOUBLE seconds_connected_all;
+            REQUIRED DOUBLE seconds_connected_some;
+            REQUIRED DOUBLE seconds_connected_one;
+        }";
+
+    fn write_parquet_columns(records: &[Self], w: &mut ParquetColumnWriter) -> Result<()> {
+        w.write_str(records.iter().map(|r| r.peer_id.as_str()))?;
+        w.write_u64(records.iter().map(|r| r.monitors_seen))?;
+        w.write_f64(records.iter().map(|r| r.seconds_connected_all))?;
+        w.write_f64(records.iter().map(|r| r.seconds_connected_some))?;
+        w.write_f64(records.iter().map(|r| r.seconds_connected_one))?;
+        Ok(())
+    }
+}
+
+impl Anonymize for CSVPeerOverlap {
+    fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.peer_id = anonymizer.pseudonymize_peer(&self.peer_id);
+    }
+}
+
+/// The connection state of one peer.
+#[derive(Clone, Debug)]
+struct PeerPresence {
+    /// The number of connections per monitor.
+    connections: Vec<u32>,
+    /// The start of the current interval, if connected to any monitor.
+    interval_start: Option<chrono::DateTime<chrono::Utc>>,
+    monitors_seen: Vec<bool>,
+    /// Milliseconds connected to all, some, or one monitor(s).
+    millis_all: i64,
+    millis_some: i64,
+    millis_one: i64,
+}
+
+impl PeerPresence {
+    fn connected_monitors(&self) -> Vec<usize> {
+        self.connections
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c > 0)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+/// Totals of `CSVPeerOverlap` over all peers.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OverlapTotals {
+    pub(crate) peers: usize,
+    pub(crate) peers_seen_by_all: usize,
+    pub(crate) seconds_connected_all: f64,
+    pub(crate) seconds_connected_some: f64,
+    pub(crate) seconds_connected_one: f64,
+}
+
+/// Tracks which monitors each peer is connected to, and writes the unified connection event,
+/// presence, and overlap outputs.
+///
+/// Connection counts per monitor follow the events: `CONNECTED` increments, `DISCONNECTED`
+/// decrements.
+/// A `CONNECTED` event with `found=false` resets the count to one, because it indicates that we
+/// missed a disconnect.
+pub(crate) struct PresenceTracker {
+    num_monitors: usize,
+    peers: HashMap<String, PeerPresence>,
+    event_output: Option<Sink<OutputCSVConnectionEvent>>,
+    presence_output: Option<Sink<CSVPresenceInterval>>,
+    overlap_output: Option<Sink<CSVPeerOverlap>>,
+}
+
+impl PresenceTracker {
+    pub(crate) fn new(
+        num_monitors: usize,
+        event_output: Option<Sink<OutputCSVConnectionEvent>>,
+        presence_output: Option<Sink<CSVPresenceInterval>>,
+        overlap_output: Option<Sink<CSVPeerOverlap>>,
+    ) -> PresenceTracker {
+        PresenceTracker {
+            num_monitors,
+            peers: HashMap::new(),
+            event_output,
+            presence_output,
+            overlap_output,
+        }
+    }
+
+    /// Handles a connection event of a monitor, writing the event and, if the set of monitors the
+    /// peer is connected to changed, the finished presence interval.
+    pub(crate) fn handle_connection_event(
+        &mut self,
+        monitor_id: usize,
+        ts: chrono::DateTime<chrono::Utc>,
+        event: CSVConnectionEvent,
+        anonymizer: Option<&Anonymizer>,
+    ) -> Result<()> {
+        let num_monitors = self.num_monitors;
+        let peer = self
+            .peers
+            .entry(event.peer_id.clone())
+            .or_insert_with(|| PeerPresence {
+                connections: vec![0; num_monitors],
+                interval_start: None,
+                monitors_seen: vec![false; num_monitors],
+                millis_all: 0,
+                millis_some: 0,
+                millis_one: 0,
+            });
+
+        let before = peer.connected_monitors();
+        let count = &mut peer.connections[monitor_id];
+        match event.event_type {
+            CSV_CONNECTION_EVENT_CONNECTED_FOUND => *count += 1,
+            CSV_CONNECTION_EVENT_CONNECTED_NOT_FOUND => *count = 1,
+            CSV_CONNECTION_EVENT_DISCONNECTED_FOUND
+            | CSV_CONNECTION_EVENT_DISCONNECTED_NOT_FOUND => *count = count.saturating_sub(1),
+            t => warn!("unknown connection event type {}", t),
+        }
+        peer.monitors_seen[monitor_id] = true;
+
+        if peer.connected_monitors() != before {
+            Self::end_interval(
+                num_monitors,
+                &event.peer_id,
+                peer,
+                &before,
+                ts,
+                self.presence_output.as_mut(),
+                anonymizer,
+            )?;
+        }
+
+        if let Some(w) = self.event_output.as_mut() {
+            let event = OutputCSVConnectionEvent::new(monitor_id, event);
+            w.write(&anonymized(&event, anonymizer))
+                .context("unable to write connection event")?;
+        }
+
+        Ok(())
+    }
+
+    /// Ends the current interval of a peer, if any, and starts a new one if the peer is still
+    /// connected to any monitor.
+    fn end_interval(
+        num_monitors: usize,
+        peer_id: &str,
+        peer: &mut PeerPresence,
+        monitors: &[usize],
+        ts: chrono::DateTime<chrono::Utc>,
+        output: Option<&mut Sink<CSVPresenceInterval>>,
+        anonymizer: Option<&Anonymizer>,
+    ) -> Result<()> {
+        if let Some(start) = peer.interval_start.take() {
+            let millis = (ts - start).num_milliseconds();
+            match monitors.len() {
+                n if n == num_monitors => peer.millis_all += millis,
+                1 => peer.millis_one += millis,
+                _ => peer.millis_some += millis,
+            }
+
+            if let Some(w) = output {
+                let interval = CSVPresenceInterval {
+                    peer_id: peer_id.to_string(),
+                    start_ts_seconds: start.timestamp(),
+                    start_ts_subsec_millis: start.timestamp_subsec_millis(),
+                    end_ts_seconds: ts.timestamp(),
+                    end_ts_subsec_millis: ts.timestamp_subsec_millis(),
+                    num_monitors: monitors.len() as u64,
+                    monitors: monitors
+                        .iter()
+                        .map(|m| m.to_string())
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                };
+                w.write(&anonymized(&interval, anonymizer))
+                    .context("unable to write presence interval")?;
+            }
+        }
+
+        if peer.connections.iter().any(|c| *c > 0) {
+            peer.interval_start = Some(ts);
+        }
+
+        Ok(())
+    }
+
+    /// Ends all intervals at the given timestamp, writes the per-peer overlap statistics, ordered
+    /// by peer ID, and finishes all outputs.
+    /// Returns the totals over all peers.
+    pub(crate) fn finish(
+        mut self,
+        final_ts: chrono::DateTime<chrono::Utc>,
+        anonymizer: Option<&Anonymizer>,
+    ) -> Result<OverlapTotals> {
+        let mut peers: Vec<_> = self.peers.into_iter().collect();
+        peers.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut totals = OverlapTotals::default();
+        for (peer_id, mut peer) in peers {
+            let monitors = peer.connected_monitors();
+            Self::end_interval(
+                self.num_monitors,
+                &peer_id,
+                &mut peer,
+                &monitors,
+                final_ts,
+                self.presence_output.as_mut(),
+                anonymizer,
+            )?;
+
+            let overlap = CSVPeerOverlap {
+                peer_id,
+                monitors_seen: peer.monitors_seen.iter().filter(|s| **s).count() as u64,
+                seconds_connected_all: peer.millis_all as f64 / 1000.0,
+                seconds_connected_some: peer.millis_some as f64 / 1000.0,
+                seconds_connected_one: peer.millis_one as f64 / 1000.0,
+            };
+            totals.peers += 1;
+            if overlap.monitors_seen as usize == self.num_monitors {
+                totals.peers_seen_by_all += 1;
+            }
+            totals.seconds_connected_all += overlap.seconds_connected_all;
+            totals.seconds_connected_some += overlap.seconds_connected_some;
+            totals.seconds_connected_one += overlap.seconds_connected_one;
+
+            if let Some(w) = self.overlap_output.as_mut() {
+                w.write(&anonymized(&overlap, anonymizer))
+                    .context("unable to write peer overlap")?;
+            }
+        }
+
+        if let Some(w) = self.event_output {
+            w.finish()
+                .context("unable to finish connection event output")?;
+        }
+        if let Some(w) = self.presence_output {
+            w.finish().context("unable to finish presence output")?;
+        }
+        if let Some(w) = self.overlap_output {
+            w.finish().context("unable to finish peer overlap output")?;
+        }
+
+        Ok(totals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemorySink;
+
+    fn ts(secs: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn event(
+        t: &mut PresenceTracker,
+        monitor_id: usize,
+        secs: i64,
+        peer_id: &str,
+        event_type: i32,
+    ) {
+        t.handle_connection_event(
+            monitor_id,
+            ts(secs),
+            CSVConnectionEvent {
+                message_id: secs,
+                timestamp_seconds: secs,
+                timestamp_subsec_millis: 0,
+                peer_id: peer_id.to_string(),
+                address: String::new(),
+                event_type,
+            },
+            None,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn intervals_and_overlap() {
+        let (events, event_records) = MemorySink::new();
+        let (presence, presence_records) = MemorySink::new();
+        let (overlap, overlap_records) = MemorySink::new();
+        let mut t = PresenceTracker::new(3, Some(events), Some(presence), Some(overlap));
+
+        event(&mut t, 0, 0, "p", CSV_CONNECTION_EVENT_CONNECTED_NOT_FOUND);
+        event(&mut t, 1, 5, "q", CSV_CONNECTION_EVENT_CONNECTED_FOUND);
+        event(&mut t, 1, 10, "p", CSV_CONNECTION_EVENT_CONNECTED_FOUND);
+        event(&mut t, 1, 15, "q", CSV_CONNECTION_EVENT_DISCONNECTED_FOUND);
+        // Not connected anymore, this does not change anything.
+        event(
+            &mut t,
+            1,
+            16,
+            "q",
+            CSV_CONNECTION_EVENT_DISCONNECTED_NOT_FOUND,
+        );
+        event(&mut t, 2, 20, "p", CSV_CONNECTION_EVENT_CONNECTED_FOUND);
+        // A second connection to monitor 0.
+        event(&mut t, 0, 25, "p", CSV_CONNECTION_EVENT_CONNECTED_FOUND);
+        // We missed the disconnects, monitor 0 has one connection now.
+        event(&mut t, 0, 35, "p", CSV_CONNECTION_EVENT_CONNECTED_NOT_FOUND);
+        event(&mut t, 1, 40, "p", CSV_CONNECTION_EVENT_DISCONNECTED_FOUND);
+        event(&mut t, 0, 50, "p", CSV_CONNECTION_EVENT_DISCONNECTED_FOUND);
+        // Still connected to monitor 2 at the end.
+
+        let totals = t.finish(ts(70), None).unwrap();
+
+        assert_eq!(event_records.borrow().len(), 10);
+        assert_eq!(event_records.borrow()[1].monitor_id, 1);
+
+        let intervals: Vec<_> = presence_records
+            .borrow()
+            .iter()
+            .map(|i| {
+                (
+                    i.peer_id.clone(),
+                    i.start_ts_seconds,
+                    i.end_ts_seconds,
+                    i.num_monitors,
+                    i.monitors.clone(),
+                )
+            })
+            .collect();
+        let interval = |peer: &str, start, end, monitors: &str| {
+            (
+                peer.to_string(),
+                start,
+                end,
+                monitors.split(';').count() as u64,
+                monitors.to_string(),
+            )
+        };
+        assert_eq!(
+            intervals,
+            vec![
+                interval("p", 0, 10, "0"),
+                interval("q", 5, 15, "1"),
+                interval("p", 10, 20, "0;1"),
+                interval("p", 20, 40, "0;1;2"),
+                interval("p", 40, 50, "0;2"),
+                // Closed at the end.
+                interval("p", 50, 70, "2"),
+            ]
+        );
+
+        let overlaps: Vec<_> = overlap_records
+            .borrow()
+            .iter()
+            .map(|o| {
+                (
+                    o.peer_id.clone(),
+                    o.monitors_seen,
+                    o.seconds_connected_all,
+                    o.seconds_connected_some,
+                    o.seconds_connected_one,
+                )
+            })
+            .collect();
+        assert_eq!(
+            overlaps,
+            vec![
+                ("p".to_string(), 3, 20.0, 20.0, 30.0),
+                ("q".to_string(), 1, 0.0, 0.0, 10.0),
+            ]
+        );
+
+        assert_eq!(totals.peers, 2);
+        assert_eq!(totals.peers_seen_by_all, 1);
+        assert_eq!(totals.seconds_connected_all, 20.0);
+        assert_eq!(totals.seconds_connected_some, 20.0);
+        assert_eq!(totals.seconds_connected_one, 40.0);
+    }
+
+    #[test]
+    fn connected_not_found_resets_count() {
+        let (presence, presence_records) = MemorySink::new();
+        let mut t = PresenceTracker::new(2, None, Some(presence), None);
+
+        event(&mut t, 0, 0, "p", CSV_CONNECTION_EVENT_CONNECTED_FOUND);
+        event(&mut t, 0, 1, "p", CSV_CONNECTION_EVENT_CONNECTED_FOUND);
+        event(&mut t, 0, 2, "p", CSV_CONNECTION_EVENT_CONNECTED_FOUND);
+        event(&mut t, 0, 3, "p", CSV_CONNECTION_EVENT_CONNECTED_NOT_FOUND);
+        // Without the reset, there would be two connections left.
+        event(&mut t, 0, 4, "p", CSV_CONNECTION_EVENT_DISCONNECTED_FOUND);
+        assert_eq!(presence_records.borrow().len(), 1);
+        assert_eq!(presence_records.borrow()[0].end_ts_seconds, 4);
+
+        let totals = t.finish(ts(10), None).unwrap();
+        // Not connected at the end, so nothing is closed.
+        assert_eq!(presence_records.borrow().len(), 1);
+        assert_eq!(totals.seconds_connected_one, 4.0);
+        assert_eq!(totals.peers_seen_by_all, 0);
+    }
+}
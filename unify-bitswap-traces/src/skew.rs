@@ -0,0 +1,386 @@
+//! Estimation and correction of clock offsets between monitors.
+//!
+//! Matched entries are assumed to have been sent to all monitors at roughly the same time, so the
+//! difference of their timestamps is a sample of the clock offset between the two monitors, plus
+//! network latency.
+//! Samples are grouped into buckets of trace time, and the median of each bucket is used as a
+//! robust estimate of the offset at that time.
+//! A linear fit over the bucket medians gives the offset and drift between the two clocks.
+
+use crate::matcher::OutputCSVWantlistEntry;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Collects clock offset samples from matched entries.
+#[derive(Debug, Clone)]
+pub(crate) struct ClockSkewEstimator {
+    bucket_secs: i64,
+    /// Samples in milliseconds, per pair of monitors `(a, b)` with `a < b`, per bucket.
+    /// A sample is the timestamp at `b` minus the timestamp at `a`.
+    samples: HashMap<(usize, usize), BTreeMap<i64, Vec<i64>>>,
+}
+
+/// A linear clock offset of one monitor relative to the reference monitor.
+/// The offset at time `t` is `offset_ms + drift_ms_per_hour * (t - ref_ts_secs) / 3600`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct LinearClockOffset {
+    pub(crate) ref_ts_secs: i64,
+    pub(crate) offset_ms: f64,
+    pub(crate) drift_ms_per_hour: f64,
+}
+
+impl LinearClockOffset {
+    fn offset_ms_at(&self, ts: chrono::DateTime<chrono::Utc>) -> f64 {
+        let hours = (ts.timestamp_millis() as f64 / 1000.0 - self.ref_ts_secs as f64) / 3600.0;
+        self.offset_ms + self.drift_ms_per_hour * hours
+    }
+
+    /// Converts a timestamp of the monitor to the clock of the reference monitor.
+    pub(crate) fn correct(
+        &self,
+        ts: chrono::DateTime<chrono::Utc>,
+    ) -> chrono::DateTime<chrono::Utc> {
+        let offset_us = (self.offset_ms_at(ts) * 1000.0).round() as i64;
+        ts - chrono::Duration::microseconds(offset_us)
+    }
+}
+
+/// The estimated offset in one bucket of trace time.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct BucketEstimate {
+    pub(crate) start_ts_secs: i64,
+    pub(crate) samples: usize,
+    pub(crate) median_offset_ms: f64,
+}
+
+/// The estimated clock offset between two monitors.
+/// Positive offsets mean that the clock of `monitor_b` is ahead of the clock of `monitor_a`.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PairEstimate {
+    pub(crate) monitor_a_id: usize,
+    pub(crate) monitor_a: String,
+    pub(crate) monitor_b_id: usize,
+    pub(crate) monitor_b: String,
+    pub(crate) samples: usize,
+    /// The median over all samples.
+    pub(crate) median_offset_ms: f64,
+    /// The linear fit over the bucket medians.
+    pub(crate) fit: LinearClockOffset,
+    pub(crate) buckets: Vec<BucketEstimate>,
+}
+
+/// The estimated clock offsets between all pairs of monitors that had matched entries.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ClockSkewReport {
+    pub(crate) bucket_secs: i64,
+    pub(crate) pairs: Vec<PairEstimate>,
+}
+
+impl ClockSkewReport {
+    /// Derives per-monitor corrections relative to the first monitor.
+    /// Monitors without matches with the first monitor are not corrected.
+    pub(crate) fn corrections(&self, source_names: &[String]) -> Vec<Option<LinearClockOffset>> {
+        source_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                if i == 0 {
+                    return None;
+                }
+                let pair = self
+                    .pairs
+                    .iter()
+                    .find(|p| p.monitor_a_id == 0 && p.monitor_b_id == i);
+                if pair.is_none() {
+                    warn!(
+                        "no matches between monitors {} and {}, unable to correct clock",
+                        source_names[0], name
+                    );
+                }
+                pair.map(|p| p.fit)
+            })
+            .collect()
+    }
+}
+
+/// The report written at the end of a run.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ClockSkewSummary {
+    /// The offsets estimated from the original timestamps.
+    pub(crate) estimate: ClockSkewReport,
+    /// The corrections applied to each monitor, if correction is enabled.
+    pub(crate) corrections: Option<Vec<Option<LinearClockOffset>>>,
+    /// The offsets remaining after correction, if correction is enabled.
+    pub(crate) residual: Option<ClockSkewReport>,
+}
+
+impl ClockSkewReport {
+    /// Logs the estimated offset of each pair.
+    pub(crate) fn log(&self, what: &str) {
+        for p in self.pairs.iter() {
+            info!(
+                "{} clock offset {} -> {}: median {:.1}ms over {} samples, fit {:.1}ms + {:.3}ms/h",
+                what,
+                p.monitor_a,
+                p.monitor_b,
+                p.median_offset_ms,
+                p.samples,
+                p.fit.offset_ms,
+                p.fit.drift_ms_per_hour
+            );
+        }
+    }
+}
+
+impl ClockSkewEstimator {
+    pub(crate) fn new(bucket_secs: u64) -> ClockSkewEstimator {
+        ClockSkewEstimator {
+            bucket_secs: bucket_secs.max(1) as i64,
+            samples: Default::default(),
+        }
+    }
+
+    /// Records a sample if the entry was matched to an entry of another monitor.
+    pub(crate) fn record(&mut self, e: &OutputCSVWantlistEntry) {
+        let (other, diff_ms) = match (
+            e.extension.matched_to_monitor_id,
+            e.extension.match_time_diff_ms,
+        ) {
+            (Some(other), Some(diff_ms)) => (other as usize, diff_ms as i64),
+            _ => return,
+        };
+        let monitor = e.extension.monitor_id as usize;
+        // The entry is always the later one of the two.
+        let (pair, sample) = if other < monitor {
+            ((other, monitor), diff_ms)
+        } else {
+            ((monitor, other), -diff_ms)
+        };
+        let bucket = e.entry.timestamp_seconds.div_euclid(self.bucket_secs);
+
+        self.samples
+            .entry(pair)
+            .or_default()
+            .entry(bucket)
+            .or_default()
+            .push(sample);
+    }
+
+    /// Computes the estimates for all pairs of monitors, ordered by monitor IDs.
+    pub(crate) fn estimate(&self, source_names: &[String]) -> ClockSkewReport {
+        let mut pairs: Vec<_> = self.samples.iter().collect();
+        pairs.sort_by_key(|(pair, _)| **pair);
+
+        ClockSkewReport {
+            bucket_secs: self.bucket_secs,
+            pairs: pairs
+                .into_iter()
+                .map(|(&(a, b), samples)| self.estimate_pair(a, b, source_names, samples))
+                .collect(),
+        }
+    }
+
+    fn estimate_pair(
+        &self,
+        monitor_a: usize,
+        monitor_b: usize,
+        source_names: &[String],
+        samples: &BTreeMap<i64, Vec<i64>>,
+    ) -> PairEstimate {
+        let buckets: Vec<_> = samples
+            .iter()
+            .map(|(bucket, samples)| BucketEstimate {
+                start_ts_secs: bucket * self.bucket_secs,
+                samples: samples.len(),
+                median_offset_ms: median(samples.clone()),
+            })
+            .collect();
+        let all_samples: Vec<_> = samples.values().flatten().copied().collect();
+
+        PairEstimate {
+            monitor_a_id: monitor_a,
+            monitor_a: source_names[monitor_a].clone(),
+            monitor_b_id: monitor_b,
+            monitor_b: source_names[monitor_b].clone(),
+            samples: all_samples.len(),
+            median_offset_ms: median(all_samples),
+            fit: linear_fit(&buckets, self.bucket_secs),
+            buckets,
+        }
+    }
+}
+
+fn median(mut samples: Vec<i64>) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    samples.sort_unstable();
+    let mid = samples.len() / 2;
+    if samples.len().is_multiple_of(2) {
+        (samples[mid - 1] + samples[mid]) as f64 / 2.0
+    } else {
+        samples[mid] as f64
+    }
+}
+
+/// Fits a line through the bucket medians, by least squares, with each bucket placed at its
+/// center.
+/// With only one bucket, there is no drift.
+fn linear_fit(buckets: &[BucketEstimate], bucket_secs: i64) -> LinearClockOffset {
+    let ref_ts_secs = buckets.first().map(|b| b.start_ts_secs).unwrap_or(0);
+    let points: Vec<_> = buckets
+        .iter()
+        .map(|b| {
+            let hours =
+                (b.start_ts_secs - ref_ts_secs) as f64 / 3600.0 + bucket_secs as f64 / 2.0 / 3600.0;
+            (hours, b.median_offset_ms)
+        })
+        .collect();
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let cov: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let var: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+
+    let drift_ms_per_hour = if var > 0.0 { cov / var } else { 0.0 };
+    LinearClockOffset {
+        ref_ts_secs,
+        offset_ms: mean_y - drift_ms_per_hour * mean_x,
+        drift_ms_per_hour,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::unmatched_output_entry;
+    use crate::testing::wantlist_entry;
+
+    /// The start of the synthetic trace, in seconds since the Unix epoch, aligned to buckets.
+    const START: i64 = 1_600_000_200;
+
+    fn names() -> Vec<String> {
+        vec!["a".to_string(), "b".to_string()]
+    }
+
+    fn ts(millis: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp_millis(millis).unwrap()
+    }
+
+    /// Simulates an entry sent to both monitors at `t_millis` on the clock of monitor 0, with the
+    /// clock of monitor 1 at the given offset, and records the later one of the two, matched to
+    /// the earlier one.
+    /// Returns the timestamps of the entry at both monitors.
+    fn record_match(
+        estimator: &mut ClockSkewEstimator,
+        t_millis: i64,
+        offset_ms: i64,
+    ) -> (i64, i64) {
+        let ts = [t_millis, t_millis + offset_ms];
+        let (later, earlier) = if ts[1] >= ts[0] { (1, 0) } else { (0, 1) };
+        let mut e = unmatched_output_entry(later, wantlist_entry(1, ts[later], "peer", "cid"));
+        e.extension.matched_to_monitor_id = Some(earlier as u64);
+        e.extension.match_time_diff_ms = Some((ts[later] - ts[earlier]) as u64);
+        estimator.record(&e);
+        (ts[0], ts[1])
+    }
+
+    /// Feeds six hours of matches with the given offset and drift of monitor 1, plus some noise,
+    /// and checks that correcting the timestamps of monitor 1 cancels them.
+    fn check_correction(offset_ms: f64, drift_ms_per_hour: f64) {
+        let mut estimator = ClockSkewEstimator::new(600);
+        let mut pairs = Vec::new();
+        for i in 0..6 * 360 {
+            let t_millis = START * 1000 + i * 10_000;
+            let hours = (i * 10) as f64 / 3600.0;
+            let offset = (offset_ms + drift_ms_per_hour * hours).round() as i64;
+            // Latency jitter, which the medians should remove.
+            let noise = [0, 3, -2, 40, -1][i as usize % 5];
+            pairs.push(record_match(&mut estimator, t_millis, offset + noise));
+        }
+
+        let report = estimator.estimate(&names());
+        assert_eq!(report.pairs.len(), 1);
+        let pair = &report.pairs[0];
+        assert_eq!((pair.monitor_a_id, pair.monitor_b_id), (0, 1));
+        assert_eq!(pair.samples, 6 * 360);
+        assert_eq!(pair.buckets.len(), 36);
+        assert!(
+            (pair.fit.offset_ms - offset_ms).abs() < 2.0,
+            "{:?}",
+            pair.fit
+        );
+        assert!(
+            (pair.fit.drift_ms_per_hour - drift_ms_per_hour).abs() < 1.0,
+            "{:?}",
+            pair.fit
+        );
+
+        let corrections = report.corrections(&names());
+        assert!(corrections[0].is_none());
+        let correction = corrections[1].unwrap();
+        for (ts_a, ts_b) in pairs {
+            let corrected = correction.correct(ts(ts_b));
+            let diff = (corrected - ts(ts_a)).num_milliseconds();
+            assert!(diff.abs() <= 42, "remaining offset {}ms", diff);
+        }
+    }
+
+    #[test]
+    fn corrects_clock_ahead() {
+        check_correction(500.0, 60.0);
+    }
+
+    #[test]
+    fn corrects_clock_behind() {
+        // Monitor 0 sees every entry later, so the samples come from the other direction.
+        check_correction(-800.0, -30.0);
+    }
+
+    #[test]
+    fn median_of_samples() {
+        assert_eq!(median(vec![]), 0.0);
+        assert_eq!(median(vec![5, 1, 3]), 3.0);
+        assert_eq!(median(vec![4, 1, 3, 100]), 3.5);
+    }
+
+    #[test]
+    fn linear_fit_of_buckets() {
+        let bucket = |start_ts_secs, median_offset_ms| BucketEstimate {
+            start_ts_secs,
+            samples: 1,
+            median_offset_ms,
+        };
+
+        // A single bucket has no drift.
+        let fit = linear_fit(&[bucket(START, 10.0)], 3600);
+        assert_eq!(fit.ref_ts_secs, START);
+        assert_eq!(fit.offset_ms, 10.0);
+        assert_eq!(fit.drift_ms_per_hour, 0.0);
+
+        // Buckets are placed at their centers, so the offset at the start is extrapolated.
+        let fit = linear_fit(&[bucket(START, 15.0), bucket(START + 3600, 25.0)], 3600);
+        assert!((fit.offset_ms - 10.0).abs() < 1e-9);
+        assert!((fit.drift_ms_per_hour - 10.0).abs() < 1e-9);
+        assert!((fit.offset_ms_at(ts((START + 7200) * 1000)) - 30.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unmatched_entries_are_ignored() {
+        let mut estimator = ClockSkewEstimator::new(600);
+        estimator.record(&unmatched_output_entry(
+            1,
+            wantlist_entry(1, START * 1000, "peer", "cid"),
+        ));
+        assert!(estimator.estimate(&names()).pairs.is_empty());
+        assert!(estimator
+            .estimate(&names())
+            .corrections(&names())
+            .iter()
+            .all(|c| c.is_none()));
+    }
+}
@@ -1,14 +1,17 @@
-use crate::config::{Config, MonitorSourceConfig};
+use crate::config::{Config, MonitorSourceConfig, OutOfOrderPolicy};
+use crate::skew::LinearClockOffset;
 use crate::Result;
-use failure::{Fail, ResultExt};
+use failure::{err_msg, Fail, ResultExt};
 use flate2::read::GzDecoder;
 use ipfs_resolver_common::wantlist;
 use ipfs_resolver_common::wantlist::{EngineSimulation, JSONMessage};
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
+use std::rc::Rc;
 
 /// An Iterator that reads JSON messages from an ordered list of input files.
 /// The input files are GZIP-decoded.
@@ -96,34 +99,71 @@ impl Iterator for MonitorSource {
 }
 
 /// A wrapper around a JSON message that implements ordering by timestamp.
+/// Equal timestamps are ordered by sequence number, i.e., the order in which messages were read.
 #[derive(Clone, Debug)]
-struct TimestampOrderedJSONMessage {
-    msg: JSONMessage,
+pub(crate) struct TimestampOrderedJSONMessage {
+    pub(crate) msg: JSONMessage,
+    pub(crate) seq: u64,
 }
 
 impl Ord for TimestampOrderedJSONMessage {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.msg.timestamp.cmp(&other.msg.timestamp)
+        (self.msg.timestamp, self.seq).cmp(&(other.msg.timestamp, other.seq))
     }
 }
 
 impl PartialOrd for TimestampOrderedJSONMessage {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.msg.timestamp.partial_cmp(&other.msg.timestamp)
+        Some(self.cmp(other))
     }
 }
 
 impl PartialEq for TimestampOrderedJSONMessage {
     fn eq(&self, other: &Self) -> bool {
-        self.msg.timestamp.eq(&other.msg.timestamp)
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 impl Eq for TimestampOrderedJSONMessage {}
 
+/// A message emitted by the sorter.
+#[derive(Clone, Debug)]
+pub(crate) struct SortedJSONMessage {
+    pub(crate) msg: JSONMessage,
+    /// The original timestamp of the message, if it was out of order and its timestamp was
+    /// clamped to that of the newest message emitted before it.
+    pub(crate) clamped_from: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Clamps the timestamp of the message to `newest`, if it is older.
+/// Returns the original timestamp, if it was clamped.
+pub(crate) fn clamp_timestamp(
+    msg: &mut JSONMessage,
+    newest: chrono::DateTime<chrono::Utc>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    if msg.timestamp >= newest {
+        return None;
+    }
+    Some(std::mem::replace(&mut msg.timestamp, newest))
+}
+
+/// Statistics about messages that were still out of order after sorting.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OutOfOrderStatistics {
+    /// The number of messages older than a message emitted before them.
+    pub(crate) messages: usize,
+    /// The largest difference between such a message and the newest message emitted before it.
+    pub(crate) max_regression: chrono::Duration,
+}
+
 /// An iterator adapter that sorts JSON messages by timestamp within a window.
 /// The messages with lowest timestamp (i.e., the oldest messages) are emitted first.
 ///
+/// The window holds at least `window_size` messages and, if configured, at least all messages
+/// up to `window_duration` newer than the oldest one.
+/// Messages that are further out of place than that are emitted out of order.
+/// These are detected and counted, and, depending on the policy, turned into errors.
+///
 /// The iterator adapts from an iterator of results to another iterator of results.
 /// If the underlying iterator returns an error (i.e., reading from disk failed),
 /// this iterator forwards that error.
@@ -134,33 +174,120 @@ struct WindowedJSONMessageSorter {
     input: MonitorSource,
     heap: BinaryHeap<std::cmp::Reverse<TimestampOrderedJSONMessage>>,
     window_size: usize,
+    window_duration: Option<chrono::Duration>,
+    out_of_order_policy: OutOfOrderPolicy,
+    /// The number of messages read so far, used to break ties.
+    seq: u64,
+    /// The newest timestamp read so far.
+    newest_read: Option<chrono::DateTime<chrono::Utc>>,
+    /// The newest timestamp emitted so far.
+    newest_emitted: Option<chrono::DateTime<chrono::Utc>>,
+    out_of_order: Rc<RefCell<OutOfOrderStatistics>>,
 }
 
 impl WindowedJSONMessageSorter {
-    /// Constructs a new windowed sorting iterator adapter for the given source and window size.
-    fn new(src: MonitorSource, window_size: usize) -> WindowedJSONMessageSorter {
+    /// Constructs a new windowed sorting iterator adapter for the given source and window.
+    /// Statistics about messages out of order are recorded to `out_of_order`.
+    fn new(
+        src: MonitorSource,
+        window_size: usize,
+        window_duration_ms: Option<u64>,
+        out_of_order_policy: OutOfOrderPolicy,
+        out_of_order: Rc<RefCell<OutOfOrderStatistics>>,
+    ) -> WindowedJSONMessageSorter {
         WindowedJSONMessageSorter {
             input: src,
             heap: BinaryHeap::new(),
             window_size,
+            window_duration: window_duration_ms.map(|ms| chrono::Duration::milliseconds(ms as i64)),
+            out_of_order_policy,
+            seq: 0,
+            newest_read: None,
+            newest_emitted: None,
+            out_of_order,
+        }
+    }
+
+    /// Whether the heap needs more messages, either by count or by time.
+    fn window_is_filled(&self) -> bool {
+        if self.heap.len() < self.window_size {
+            return false;
+        }
+        match (self.window_duration, self.heap.peek(), self.newest_read) {
+            (Some(duration), Some(oldest), Some(newest)) => {
+                newest - oldest.0.msg.timestamp >= duration
+            }
+            _ => true,
+        }
+    }
+
+    /// Checks whether a message is older than one emitted before it, records that, and applies
+    /// the out-of-order policy.
+    /// If the message is processed anyway, its timestamp is clamped to the newest one emitted,
+    /// so that the simulation and matching never see time going backwards.
+    /// The original timestamp is kept for the output.
+    fn check_order(&mut self, mut msg: JSONMessage) -> Result<SortedJSONMessage> {
+        let newest = match self.newest_emitted {
+            Some(newest) if msg.timestamp < newest => newest,
+            _ => {
+                self.newest_emitted = Some(msg.timestamp);
+                return Ok(SortedJSONMessage {
+                    msg,
+                    clamped_from: None,
+                });
+            }
+        };
+        let regression = newest - msg.timestamp;
+
+        let mut stats = self.out_of_order.borrow_mut();
+        stats.messages += 1;
+        stats.max_regression = stats.max_regression.max(regression);
+
+        match self.out_of_order_policy {
+            OutOfOrderPolicy::Warn => {
+                warn!(
+                    "monitor {}: message at {} is {}ms older than a previous message, outside of the sorting window, clamping to {}",
+                    self.input.monitor_name,
+                    msg.timestamp,
+                    regression.num_milliseconds(),
+                    newest
+                );
+                Ok(SortedJSONMessage {
+                    clamped_from: clamp_timestamp(&mut msg, newest),
+                    msg,
+                })
+            }
+            OutOfOrderPolicy::Fail => Err(err_msg(format!(
+                "monitor {}: message at {} is {}ms older than a previous message, outside of the sorting window",
+                self.input.monitor_name,
+                msg.timestamp,
+                regression.num_milliseconds()
+            ))),
         }
     }
 }
 
 impl Iterator for WindowedJSONMessageSorter {
-    type Item = Result<JSONMessage>;
+    type Item = Result<SortedJSONMessage>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // Do we need to fill up the heap?
-        while self.heap.len() < self.window_size {
+        while !self.window_is_filled() {
             // Are there still messages to be read from disk?
             if let Some(msg) = self.input.next() {
                 // Did we succeed in reading it?
                 match msg {
                     Ok(msg) => {
                         // If yes: Push the next message onto the heap with correct ordering.
+                        if self.newest_read.is_none_or(|ts| msg.timestamp > ts) {
+                            self.newest_read = Some(msg.timestamp);
+                        }
+                        self.seq += 1;
                         self.heap
-                            .push(std::cmp::Reverse(TimestampOrderedJSONMessage { msg }))
+                            .push(std::cmp::Reverse(TimestampOrderedJSONMessage {
+                                msg,
+                                seq: self.seq,
+                            }))
                     }
                     Err(e) => {
                         // If no: Bubble that error through the iterators...
@@ -180,7 +307,8 @@ impl Iterator for WindowedJSONMessageSorter {
 
         // Pop oldest message off the heap.
         // If the heap is empty this will return None.
-        self.heap.pop().map(|m| Ok(m.0.msg))
+        let msg = self.heap.pop()?.0.msg;
+        Some(self.check_order(msg))
     }
 }
 
@@ -189,8 +317,9 @@ impl Iterator for WindowedJSONMessageSorter {
 pub(crate) struct MultiSourceIngester {
     source_names: Vec<String>,
     engine_states: Vec<EngineSimulation>,
-    merged_sources: Box<dyn Iterator<Item = Result<(usize, JSONMessage)>>>,
+    merged_sources: Box<dyn Iterator<Item = Result<(usize, SortedJSONMessage)>>>,
     message_id: i64,
+    out_of_order: Vec<Rc<RefCell<OutOfOrderStatistics>>>,
 }
 
 impl MultiSourceIngester {
@@ -202,7 +331,13 @@ impl MultiSourceIngester {
             .collect::<std::result::Result<Vec<_>, _>>()
     }
 
-    pub(crate) fn from_config(cfg: &Config) -> Result<MultiSourceIngester> {
+    /// Sets up the sources and simulations for the given config.
+    /// If clock corrections are given, they are applied to the timestamps of each monitor's
+    /// messages before they are merged.
+    pub(crate) fn from_config(
+        cfg: &Config,
+        clock_corrections: &[Option<LinearClockOffset>],
+    ) -> Result<MultiSourceIngester> {
         // Construct sources
         info!("constructing sources...");
         let sources = Self::construct_sources(cfg).context("unable to construct sources")?;
@@ -216,20 +351,43 @@ impl MultiSourceIngester {
             .take(sources.len())
             .collect::<Vec<_>>();
 
+        let out_of_order: Vec<Rc<RefCell<OutOfOrderStatistics>>> =
+            sources.iter().map(|_| Default::default()).collect();
+
         // Make it so we can pop messages in order
         // Also sort them in windows, sheesh... (see journal on Nov 29th for an explanation).
         // This turns the sources into iterators that produce (source-index, message) tuples.
         // Pretty sick!
         let sources = sources
             .into_iter()
-            .map(|s| WindowedJSONMessageSorter::new(s, cfg.message_sorting_window_size))
+            .zip(out_of_order.iter())
+            .map(|(s, stats)| {
+                WindowedJSONMessageSorter::new(
+                    s,
+                    cfg.message_sorting_window_size,
+                    cfg.message_sorting_window_milliseconds,
+                    cfg.out_of_order_policy,
+                    stats.clone(),
+                )
+            })
             .enumerate()
-            .map(|(k, s)| s.map(move |msg| msg.map(|msg| (k, msg))))
+            .map(|(k, s)| {
+                let correction = clock_corrections.get(k).copied().flatten();
+                s.map(move |msg| {
+                    msg.map(|mut msg| {
+                        if let Some(c) = correction.as_ref() {
+                            msg.msg.timestamp = c.correct(msg.msg.timestamp);
+                            msg.clamped_from = msg.clamped_from.map(|ts| c.correct(ts));
+                        }
+                        (k, msg)
+                    })
+                })
+            })
             .collect::<Vec<_>>();
         // And now we k-way merge them by timestamp.
         let merged_sources = itertools::kmerge_by(
             sources.into_iter(),
-            |a: &Result<(usize, JSONMessage)>, b: &Result<(usize, JSONMessage)>| {
+            |a: &Result<(usize, SortedJSONMessage)>, b: &Result<(usize, SortedJSONMessage)>| {
                 // This is pretty hacky, but:
                 // We see if any of the two is an error, and if yes sort it towards the front of
                 // the queue.
@@ -237,11 +395,11 @@ impl MultiSourceIngester {
                 // cancel the entire unification if there's an error reading from disk anyway, so
                 // it should be fine.
                 match &a {
-                    Ok((_, msg1)) => {
+                    Ok((k1, msg1)) => {
                         match &b {
-                            Ok((_, msg2)) => {
-                                // TODO what if they are equal?
-                                msg1.timestamp < msg2.timestamp
+                            Ok((k2, msg2)) => {
+                                // Break ties by monitor ID, so the output is reproducible.
+                                (msg1.msg.timestamp, k1) < (msg2.msg.timestamp, k2)
                             }
                             Err(_) => false,
                         }
@@ -256,6 +414,7 @@ impl MultiSourceIngester {
             engine_states,
             merged_sources: Box::new(merged_sources),
             message_id: 0,
+            out_of_order,
         })
     }
 
@@ -267,6 +426,14 @@ impl MultiSourceIngester {
         self.engine_states
     }
 
+    /// Statistics about messages out of order after sorting, per monitor.
+    pub(crate) fn out_of_order_statistics(&self) -> Vec<OutOfOrderStatistics> {
+        self.out_of_order
+            .iter()
+            .map(|s| s.borrow().clone())
+            .collect()
+    }
+
     // The ID of the last message produced by the iterator.
     pub(crate) fn last_message_id(&self) -> i64 {
         self.message_id
@@ -285,25 +452,13 @@ impl Iterator for MultiSourceIngester {
                         self.message_id += 1;
 
                         // Update engine state for that monitor
-                        match self.engine_states[monitor_id].ingest(&msg, self.message_id) {
-                            Ok(ingest_result) => {
-                                debug!("got ingest result {:?}", ingest_result);
-                                Some(Ok(MultiSourceIngestResult {
-                                    monitor_id,
-                                    timestamp: msg.timestamp,
-                                    peer_id: msg.peer,
-                                    simulation_result: ingest_result,
-                                }))
-                            }
-                            Err(e) => {
-                                debug!("unable to ingest: {:?}", e);
-                                Some(Err(e
-                                    .context(
-                                        "unable to update engine simulation state with new message",
-                                    )
-                                    .into()))
-                            }
-                        }
+                        Some(simulate(
+                            &mut self.engine_states,
+                            monitor_id,
+                            msg.msg,
+                            msg.clamped_from,
+                            self.message_id,
+                        ))
                     }
                     Err(e) => {
                         return Some(Err(e.context("unable to get message from source").into()));
@@ -315,10 +470,163 @@ impl Iterator for MultiSourceIngester {
     }
 }
 
+/// Feeds a message into the engine simulation of the monitor it originates from.
+/// If the timestamp of the message was clamped, `clamped_from` is its original timestamp.
+pub(crate) fn simulate(
+    engine_states: &mut [EngineSimulation],
+    monitor_id: usize,
+    msg: JSONMessage,
+    clamped_from: Option<chrono::DateTime<chrono::Utc>>,
+    message_id: i64,
+) -> Result<MultiSourceIngestResult> {
+    match engine_states[monitor_id].ingest(&msg, message_id) {
+        Ok(ingest_result) => {
+            debug!("got ingest result {:?}", ingest_result);
+            Ok(MultiSourceIngestResult {
+                message_id,
+                monitor_id,
+                timestamp: msg.timestamp,
+                clamped_from,
+                peer_id: msg.peer,
+                simulation_result: ingest_result,
+                num_ledgers: engine_states[monitor_id].num_ledgers(),
+            })
+        }
+        Err(e) => {
+            debug!("unable to ingest: {:?}", e);
+            Err(e
+                .context("unable to update engine simulation state with new message")
+                .into())
+        }
+    }
+}
+
 /// The item produced by the `MultiSourceIngester`.
 pub(crate) struct MultiSourceIngestResult {
+    pub(crate) message_id: i64,
     pub(crate) monitor_id: usize,
+    /// The timestamp the simulation and matching work with.
     pub(crate) timestamp: chrono::DateTime<chrono::Utc>,
+    /// The original timestamp of the message, if `timestamp` was clamped.
+    /// This is restored in the output.
+    pub(crate) clamped_from: Option<chrono::DateTime<chrono::Utc>>,
     pub(crate) peer_id: String,
     pub(crate) simulation_result: wantlist::IngestResult,
+    /// The number of ledgers of the monitor's simulation after ingesting the message.
+    pub(crate) num_ledgers: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::{InterMonitorMatcher, MatcherStatistics};
+    use crate::testing::{ingest_result, json_message, matching_config, wantlist_entry, TestDir};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// Writes the messages to a trace file in the directory and returns a source reading it.
+    fn source(dir: &TestDir, name: &str, messages: &[JSONMessage]) -> MonitorSource {
+        let path = dir.path().join(format!("{}.json.gz", name));
+        let mut enc = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        for msg in messages {
+            writeln!(enc, "{}", serde_json::to_string(msg).unwrap()).unwrap();
+        }
+        enc.finish().unwrap();
+
+        MonitorSource {
+            monitor_name: name.to_string(),
+            input_paths: vec![path],
+            current_file: None,
+            input_buffer: String::new(),
+        }
+    }
+
+    /// Feeds entries of monitor 0 at the given timestamps to a matcher, plus one entry of monitor
+    /// 1 at 2500ms, after the first entry.
+    fn match_entries(monitor_0_ts_millis: &[i64]) -> MatcherStatistics {
+        let mut matcher = InterMonitorMatcher::new_from_config(&matching_config()).unwrap();
+        let mut entries: Vec<_> = monitor_0_ts_millis.iter().map(|ts| (0, *ts)).collect();
+        entries.insert(1, (1, 2500));
+        for (monitor_id, ts_millis) in entries {
+            matcher
+                .handle_ingest_result(
+                    monitor_id,
+                    chrono::DateTime::from_timestamp_millis(ts_millis).unwrap(),
+                    "peer".to_string(),
+                    ingest_result(vec![wantlist_entry(1, ts_millis, "peer", "cid")]),
+                )
+                .unwrap();
+        }
+        matcher.finish();
+        matcher.stats()
+    }
+
+    #[test]
+    fn regressed_message_is_clamped_before_matching() {
+        let dir = TestDir::new("regressed-clamped");
+        let src = source(
+            &dir,
+            "m0",
+            &[
+                json_message(2000, "peer"),
+                json_message(3000, "peer"),
+                // Out of place by more than the window of one message.
+                json_message(2400, "peer"),
+            ],
+        );
+        let stats: Rc<RefCell<OutOfOrderStatistics>> = Default::default();
+        let sorter =
+            WindowedJSONMessageSorter::new(src, 1, None, OutOfOrderPolicy::Warn, stats.clone());
+        let (timestamps, clamped_from): (Vec<_>, Vec<_>) = sorter
+            .map(|msg| {
+                let msg = msg.unwrap();
+                (
+                    msg.msg.timestamp.timestamp_millis(),
+                    msg.clamped_from.map(|ts| ts.timestamp_millis()),
+                )
+            })
+            .unzip();
+
+        assert_eq!(timestamps, vec![2000, 3000, 3000]);
+        // The original timestamp is kept for the output.
+        assert_eq!(clamped_from, vec![None, None, Some(2400)]);
+        assert_eq!(stats.borrow().messages, 1);
+        assert_eq!(stats.borrow().max_regression.num_milliseconds(), 600);
+
+        // After clamping, no entry is compared to a newer one.
+        let stats = match_entries(&timestamps);
+        assert_eq!(stats.skipped_newer_entries, 0);
+        assert_eq!(stats.matched_entries, 1);
+
+        // Without clamping, the newer entries are skipped: the entry of monitor 1 as a candidate,
+        // and both newer entries as global duplicates.
+        let stats = match_entries(&[2000, 3000, 2400]);
+        assert_eq!(stats.skipped_newer_entries, 3);
+        assert_eq!(stats.matched_entries, 1);
+    }
+
+    #[test]
+    fn regressed_message_fails_if_configured() {
+        let dir = TestDir::new("regressed-fail");
+        let src = source(
+            &dir,
+            "m0",
+            &[
+                json_message(2000, "peer"),
+                json_message(3000, "peer"),
+                json_message(2400, "peer"),
+            ],
+        );
+        let sorter = WindowedJSONMessageSorter::new(
+            src,
+            1,
+            None,
+            OutOfOrderPolicy::Fail,
+            Default::default(),
+        );
+        let results: Vec<_> = sorter.map(|msg| msg.is_ok()).collect();
+
+        assert_eq!(results, vec![true, true, false]);
+    }
 }
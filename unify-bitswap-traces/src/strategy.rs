@@ -0,0 +1,350 @@
+//! Strategies to decide which earlier entry of another monitor an entry is matched to.
+//!
+//! The matcher finds the candidates for each entry, i.e., earlier entries of other monitors within
+//! the matching window that are equal to it.
+//! Strategies pick among those, either immediately or once more entries have been seen.
+
+use crate::blossom::max_weight_matching;
+use crate::config::{MatchingConfig, MatchingStrategyKind};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Debug;
+
+/// Components of the optimal strategy are matched early once they reach this many entries, which
+/// bounds the cost of computing the matching.
+/// Entries that would have joined the component later are then not taken into account, so the
+/// result is not optimal anymore.
+const MAX_COMPONENT_SIZE: usize = 256;
+
+/// An earlier entry that a new entry can be matched to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct MatchCandidate {
+    /// The ID the matcher assigned to the earlier entry.
+    pub(crate) id: u64,
+    pub(crate) monitor_id: usize,
+    /// The time difference between the entries, in milliseconds, never negative.
+    pub(crate) diff_ms: i64,
+}
+
+/// A strategy deciding which candidate, if any, each entry is matched to.
+pub(crate) trait MatchingStrategy: Debug {
+    /// Offers a new entry, with its timestamp and the candidates it can be matched to, oldest
+    /// first.
+    /// Entries must be offered in timestamp order, more or less.
+    fn offer(&mut self, id: u64, ts_ms: i64, candidates: Vec<MatchCandidate>);
+
+    /// Returns the decisions that are final at the given time, or all remaining ones if no time is
+    /// given.
+    /// Each offered entry is decided exactly once.
+    fn decide(&mut self, now_ms: Option<i64>) -> Vec<(u64, Option<MatchCandidate>)>;
+
+    /// Notifies the strategy that the entry will not be offered as a candidate anymore.
+    fn forget(&mut self, id: u64);
+}
+
+/// Constructs the strategy configured.
+pub(crate) fn from_config(cfg: &MatchingConfig) -> Box<dyn MatchingStrategy> {
+    match cfg.strategy {
+        MatchingStrategyKind::Greedy => Box::new(GreedyStrategy {
+            newest_first: cfg.match_newest_first,
+            allow_multiple_match: cfg.allow_multiple_match,
+            matched: Default::default(),
+            decided: Default::default(),
+        }),
+        MatchingStrategyKind::Optimal => Box::new(OptimalStrategy {
+            window_ms: cfg.inter_monitor_matching_window_milliseconds as i64,
+            allow_multiple_match: cfg.allow_multiple_match,
+            next_component_id: 0,
+            components: Default::default(),
+            component_of: Default::default(),
+            by_last_ts: Default::default(),
+            decided: Default::default(),
+        }),
+    }
+}
+
+/// Matches each entry as it is offered, to the oldest or newest candidate.
+#[derive(Debug)]
+struct GreedyStrategy {
+    newest_first: bool,
+    allow_multiple_match: bool,
+    /// Entries that are part of a match, either side, which can not be matched again unless
+    /// `allow_multiple_match` is set.
+    matched: HashSet<u64>,
+    decided: Vec<(u64, Option<MatchCandidate>)>,
+}
+
+impl MatchingStrategy for GreedyStrategy {
+    fn offer(&mut self, id: u64, _ts_ms: i64, candidates: Vec<MatchCandidate>) {
+        let mut available = candidates
+            .into_iter()
+            .filter(|c| self.allow_multiple_match || !self.matched.contains(&c.id));
+        let m = if self.newest_first {
+            available.next_back()
+        } else {
+            available.next()
+        };
+
+        if let Some(m) = m.as_ref() {
+            self.matched.insert(m.id);
+            self.matched.insert(id);
+        }
+        self.decided.push((id, m));
+    }
+
+    fn decide(&mut self, _now_ms: Option<i64>) -> Vec<(u64, Option<MatchCandidate>)> {
+        std::mem::take(&mut self.decided)
+    }
+
+    fn forget(&mut self, id: u64) {
+        self.matched.remove(&id);
+    }
+}
+
+/// Entries connected by candidate relations.
+/// Matching an entry of a component does not affect entries of other components.
+#[derive(Debug, Default)]
+struct Component {
+    /// The entries of the component, with their candidates.
+    entries: Vec<(u64, Vec<MatchCandidate>)>,
+    last_ts_ms: i64,
+}
+
+/// Matches entries such that as many entries as possible are matched, with the minimum total time
+/// difference.
+///
+/// Entries are grouped into components by their candidates.
+/// A component is matched once the matching window after its latest entry has passed, because no
+/// entry can join it afterwards.
+#[derive(Debug)]
+struct OptimalStrategy {
+    window_ms: i64,
+    allow_multiple_match: bool,
+    next_component_id: u64,
+    components: HashMap<u64, Component>,
+    /// The component of each entry that has not been decided yet.
+    component_of: HashMap<u64, u64>,
+    /// Components by their latest timestamp, to find those that are complete.
+    by_last_ts: BTreeSet<(i64, u64)>,
+    decided: Vec<(u64, Option<MatchCandidate>)>,
+}
+
+impl OptimalStrategy {
+    /// Removes the component and decides all of its entries.
+    fn decide_component(&mut self, component_id: u64) {
+        let component = self.components.remove(&component_id).unwrap();
+        self.by_last_ts
+            .remove(&(component.last_ts_ms, component_id));
+        for (id, _) in component.entries.iter() {
+            self.component_of.remove(id);
+        }
+
+        if self.allow_multiple_match {
+            // Every entry can just take the closest candidate.
+            self.decided
+                .extend(component.entries.into_iter().map(|(id, candidates)| {
+                    (id, candidates.into_iter().rev().min_by_key(|c| c.diff_ms))
+                }));
+            return;
+        }
+
+        // Entries are vertices and candidates are edges, so that each entry is part of at most one
+        // match, either as the later or the earlier entry.
+        // Every match must be worth more than any sum of time differences, so that we maximize the
+        // number of matches first.
+        let ids: Vec<u64> = component.entries.iter().map(|(id, _)| *id).collect();
+        let index_of: HashMap<u64, usize> =
+            ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        let bonus = (component.entries.len() as i64 + 1) * (self.window_ms + 1);
+        let edges: Vec<(usize, usize, i64)> = component
+            .entries
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (_, candidates))| {
+                candidates
+                    .iter()
+                    .map(|c| (i, index_of[&c.id], bonus - c.diff_ms))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let mates = max_weight_matching(ids.len(), &edges);
+
+        // Of each matched pair, the later entry is matched to the earlier one, which is a
+        // candidate of it.
+        self.decided
+            .extend(
+                component
+                    .entries
+                    .into_iter()
+                    .zip(mates)
+                    .map(|((id, candidates), mate)| {
+                        let m = mate.and_then(|j| candidates.into_iter().find(|c| c.id == ids[j]));
+                        (id, m)
+                    }),
+            );
+    }
+}
+
+impl MatchingStrategy for OptimalStrategy {
+    fn offer(&mut self, id: u64, ts_ms: i64, candidates: Vec<MatchCandidate>) {
+        // Candidates of decided components are not available anymore.
+        let candidates: Vec<_> = candidates
+            .into_iter()
+            .filter(|c| self.component_of.contains_key(&c.id))
+            .collect();
+
+        // Merge the components of all candidates into the largest one.
+        let mut merged: Vec<u64> = candidates
+            .iter()
+            .map(|c| self.component_of[&c.id])
+            .collect();
+        merged.sort_unstable();
+        merged.dedup();
+        let target = merged
+            .iter()
+            .copied()
+            .max_by_key(|c| self.components[c].entries.len())
+            .unwrap_or_else(|| {
+                let c = self.next_component_id;
+                self.next_component_id += 1;
+                self.components.insert(
+                    c,
+                    Component {
+                        entries: Vec::new(),
+                        last_ts_ms: ts_ms,
+                    },
+                );
+                self.by_last_ts.insert((ts_ms, c));
+                c
+            });
+        let mut last_ts_ms = ts_ms;
+        let mut entries = Vec::new();
+        for c in merged.into_iter().filter(|c| *c != target) {
+            let component = self.components.remove(&c).unwrap();
+            self.by_last_ts.remove(&(component.last_ts_ms, c));
+            last_ts_ms = last_ts_ms.max(component.last_ts_ms);
+            entries.extend(component.entries);
+        }
+        entries.push((id, candidates));
+
+        let component = self.components.get_mut(&target).unwrap();
+        for (id, _) in entries.iter() {
+            self.component_of.insert(*id, target);
+        }
+        component.entries.extend(entries);
+        self.by_last_ts.remove(&(component.last_ts_ms, target));
+        component.last_ts_ms = component.last_ts_ms.max(last_ts_ms);
+        self.by_last_ts.insert((component.last_ts_ms, target));
+
+        if component.entries.len() >= MAX_COMPONENT_SIZE {
+            debug!(
+                "matching component {} early, with {} entries",
+                target,
+                component.entries.len()
+            );
+            self.decide_component(target);
+        }
+    }
+
+    fn decide(&mut self, now_ms: Option<i64>) -> Vec<(u64, Option<MatchCandidate>)> {
+        while let Some((last_ts_ms, c)) = self.by_last_ts.first().copied() {
+            if now_ms.is_some_and(|now| now - last_ts_ms <= self.window_ms) {
+                break;
+            }
+            self.decide_component(c);
+        }
+
+        std::mem::take(&mut self.decided)
+    }
+
+    fn forget(&mut self, _id: u64) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(id: u64, monitor_id: usize, diff_ms: i64) -> MatchCandidate {
+        MatchCandidate {
+            id,
+            monitor_id,
+            diff_ms,
+        }
+    }
+
+    fn optimal_config() -> MatchingConfig {
+        serde_yaml::from_str(
+            "
+            inter_monitor_matching_window_milliseconds: 100
+            global_duplicate_window_seconds: 1
+            strategy: optimal
+            match_newest_first: true
+            allow_multiple_match: false
+            match_exact_entry_type: true
+            ",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn optimal_strategy_minimizes_total_difference() {
+        let mut cfg = optimal_config();
+
+        // Monitor 0 sees entries 0 and 1, monitor 1 sees entries 2 and 3.
+        // Greedy newest-first matches 2 to 1 and leaves 3 unmatched, optimal matches both.
+        let offers = [
+            (0, 0, vec![]),
+            (1, 50, vec![]),
+            (2, 60, vec![candidate(0, 0, 60), candidate(1, 0, 10)]),
+            (3, 90, vec![candidate(1, 0, 40)]),
+        ];
+        let run = |cfg: &MatchingConfig| {
+            let mut strategy = from_config(cfg);
+            for (id, ts, candidates) in offers.iter().cloned() {
+                strategy.offer(id, ts, candidates);
+            }
+            let mut decisions = strategy.decide(Some(150));
+            assert!(decisions.is_empty() || cfg.strategy == MatchingStrategyKind::Greedy);
+            decisions.extend(strategy.decide(None));
+            decisions.sort_by_key(|(id, _)| *id);
+            decisions
+        };
+
+        assert_eq!(
+            run(&cfg),
+            vec![
+                (0, None),
+                (1, None),
+                (2, Some(candidate(0, 0, 60))),
+                (3, Some(candidate(1, 0, 40))),
+            ]
+        );
+
+        cfg.strategy = MatchingStrategyKind::Greedy;
+        assert_eq!(
+            run(&cfg),
+            vec![
+                (0, None),
+                (1, None),
+                (2, Some(candidate(1, 0, 10))),
+                (3, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn optimal_strategy_matches_each_entry_once() {
+        // A on monitor 0, B on monitor 1, and C on monitor 0 again.
+        // B could be matched to A and C to B, but B can only be part of one match.
+        let mut strategy = from_config(&optimal_config());
+        strategy.offer(0, 0, vec![]);
+        strategy.offer(1, 10, vec![candidate(0, 0, 10)]);
+        strategy.offer(2, 15, vec![candidate(1, 1, 5)]);
+
+        let mut decisions = strategy.decide(None);
+        decisions.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            decisions,
+            vec![(0, None), (1, None), (2, Some(candidate(1, 1, 5)))]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Helpers shared by tests.
+
+use crate::config::MatchingConfig;
+use crate::Result;
+use ipfs_resolver_common::output::RecordSink;
+use ipfs_resolver_common::wantlist::{
+    CSVWantlistEntry, IngestResult, JSONMessage, CSV_ENTRY_TYPE_WANT_HAVE,
+    CSV_MESSAGE_TYPE_INCREMENTAL,
+};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A directory for the files of one test, which is removed when dropped.
+pub(crate) struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// Creates an empty directory, unique to the given test name and the process.
+    pub(crate) fn new(name: &str) -> TestDir {
+        let path = std::env::temp_dir().join(format!("unify-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A sink that keeps records in memory, so that tests can inspect them.
+pub(crate) struct MemorySink<T> {
+    records: Rc<RefCell<Vec<T>>>,
+}
+
+impl<T> MemorySink<T> {
+    /// Returns a sink and a handle to the records written to it.
+    pub(crate) fn new() -> (Box<MemorySink<T>>, Rc<RefCell<Vec<T>>>) {
+        let records = Rc::new(RefCell::new(Vec::new()));
+        (
+            Box::new(MemorySink {
+                records: records.clone(),
+            }),
+            records,
+        )
+    }
+}
+
+impl<T: Clone> RecordSink<T> for MemorySink<T> {
+    fn write(&mut self, record: &T) -> Result<()> {
+        self.records.borrow_mut().push(record.clone());
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        0
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns a WANT_HAVE entry from an incremental message.
+pub(crate) fn wantlist_entry(
+    message_id: i64,
+    ts_millis: i64,
+    peer_id: &str,
+    cid: &str,
+) -> CSVWantlistEntry {
+    CSVWantlistEntry {
+        message_id,
+        message_type: CSV_MESSAGE_TYPE_INCREMENTAL,
+        timestamp_seconds: ts_millis.div_euclid(1000),
+        timestamp_subsec_milliseconds: ts_millis.rem_euclid(1000) as u32,
+        peer_id: peer_id.to_string(),
+        address: String::new(),
+        priority: 1,
+        entry_type: CSV_ENTRY_TYPE_WANT_HAVE,
+        cid: cid.to_string(),
+        duplicate_status: 0,
+        sliding_window_smallest_match: 0,
+        secs_since_earlier_message: 0,
+        upgrades_earlier_request: false,
+    }
+}
+
+/// Returns an incremental message without entries.
+pub(crate) fn json_message(ts_millis: i64, peer: &str) -> JSONMessage {
+    JSONMessage {
+        timestamp: chrono::DateTime::from_timestamp_millis(ts_millis).unwrap(),
+        peer: peer.to_string(),
+        address: None,
+        received_entries: Some(Vec::new()),
+        full_want_list: Some(false),
+        peer_connected: None,
+        peer_disconnected: None,
+        connect_event_peer_found: None,
+    }
+}
+
+/// Returns the result of ingesting a message with the given entries.
+pub(crate) fn ingest_result(entries: Vec<CSVWantlistEntry>) -> IngestResult {
+    IngestResult {
+        missing_ledger: false,
+        wantlist_entries: Some(entries),
+        connection_event: None,
+    }
+}
+
+/// Returns a greedy matching config with a one second matching window.
+pub(crate) fn matching_config() -> MatchingConfig {
+    MatchingConfig {
+        inter_monitor_matching_window_milliseconds: 1000,
+        global_duplicate_window_seconds: 2,
+        strategy: Default::default(),
+        match_newest_first: false,
+        allow_multiple_match: false,
+        match_exact_entry_type: true,
+        message_type_matching: Default::default(),
+        peer_queue_sweep_interval_secs: 60,
+        max_queued_entries: None,
+    }
+}
@@ -0,0 +1,413 @@
+use crate::config::Config;
+use crate::coverage::CoverageTracker;
+use crate::ledgers::LedgerCounter;
+use crate::matcher::{
+    unmatched_output_entry, InterMonitorMatcher, MatcherStatistics, OutputCSVWantlistEntry,
+};
+use crate::presence::PresenceTracker;
+use crate::skew::{ClockSkewEstimator, ClockSkewReport, ClockSkewSummary, LinearClockOffset};
+use crate::source::MultiSourceIngestResult;
+use crate::Result;
+use failure::ResultExt;
+use ipfs_resolver_common::anonymize::{anonymized, Anonymizer};
+use ipfs_resolver_common::output::create_sink;
+use ipfs_resolver_common::rotation::RotatingWriter;
+use ipfs_resolver_common::wantlist::EngineSimulation;
+use std::collections::HashMap;
+
+/// The number of messages per output file, if no rotation is configured.
+const DEFAULT_MESSAGES_PER_OUTPUT_FILE: u64 = 100_000;
+
+/// Everything that happens to the simulation results of the merged monitors: matching, output,
+/// and the statistics derived from them.
+/// This is shared between unifying traces from disk and unifying live from AMQP.
+pub(crate) struct Unifier {
+    source_names: Vec<String>,
+    anonymizer: Option<Anonymizer>,
+    dup_marker: InterMonitorMatcher,
+    output: RotatingWriter<OutputCSVWantlistEntry>,
+    ledger_counter: LedgerCounter,
+    presence_tracker: PresenceTracker,
+    last_timestamps: Vec<Option<chrono::DateTime<chrono::Utc>>>,
+    skew_estimator: Option<ClockSkewEstimator>,
+    coverage_tracker: Option<CoverageTracker>,
+    /// The original timestamps of messages whose timestamps were clamped, by message ID, with the
+    /// number of their entries not written yet.
+    clamped_messages: HashMap<i64, (chrono::DateTime<chrono::Utc>, usize)>,
+}
+
+impl Unifier {
+    /// Sets up matching and all outputs for the given config and monitors.
+    pub(crate) fn new(cfg: &Config, source_names: Vec<String>) -> Result<Unifier> {
+        // Construct the thing that keeps track of inter-monitor duplicates and matches entries
+        let dup_marker = InterMonitorMatcher::new_from_config(&cfg.matching_config)
+            .context("unable to construct inter-monitor duplicate marker and matcher")?;
+
+        // Set up anonymization, if configured
+        let anonymizer = cfg
+            .anonymization
+            .as_ref()
+            .map(Anonymizer::new)
+            .transpose()
+            .context("unable to set up anonymization")?;
+
+        // Set up output, by default rotated every 100k messages
+        let mut rotation = cfg.wantlist_output_rotation.clone();
+        if rotation.is_unbounded() {
+            rotation.max_messages = Some(DEFAULT_MESSAGES_PER_OUTPUT_FILE);
+        }
+        let output: RotatingWriter<OutputCSVWantlistEntry> = RotatingWriter::new(
+            &cfg.wantlist_output_file_pattern,
+            cfg.output_format,
+            rotation,
+        )
+        .context("unable to set up output")?;
+        let ledger_counter = LedgerCounter::new(
+            source_names.len(),
+            cfg.ledger_count_interval_secs,
+            create_sink(&cfg.ledger_count_output_file, cfg.output_format)
+                .context("unable to set up ledger count output")?,
+        );
+        let presence_tracker = PresenceTracker::new(
+            source_names.len(),
+            cfg.connection_events_output_file
+                .as_ref()
+                .map(|path| create_sink(path, cfg.output_format))
+                .transpose()
+                .context("unable to set up connection events output")?,
+            cfg.presence_output_file
+                .as_ref()
+                .map(|path| create_sink(path, cfg.output_format))
+                .transpose()
+                .context("unable to set up presence output")?,
+            cfg.peer_overlap_output_file
+                .as_ref()
+                .map(|path| create_sink(path, cfg.output_format))
+                .transpose()
+                .context("unable to set up peer overlap output")?,
+        );
+        let skew_estimator = cfg
+            .clock_skew
+            .as_ref()
+            .map(|c| ClockSkewEstimator::new(c.bucket_secs));
+        let coverage_tracker = cfg
+            .coverage
+            .as_ref()
+            .map(|c| {
+                CoverageTracker::new(
+                    source_names.len(),
+                    cfg.matching_config
+                        .inter_monitor_matching_window_milliseconds,
+                    c,
+                )
+            })
+            .transpose()
+            .context("unable to set up coverage analysis")?;
+
+        Ok(Unifier {
+            last_timestamps: vec![None; source_names.len()],
+            source_names,
+            anonymizer,
+            dup_marker,
+            output,
+            ledger_counter,
+            presence_tracker,
+            skew_estimator,
+            coverage_tracker,
+            clamped_messages: Default::default(),
+        })
+    }
+
+    /// Handles the simulation result of one message.
+    /// Returns the entries written to the output, which are the entries released by the matcher,
+    /// not necessarily those of this message.
+    pub(crate) fn handle(
+        &mut self,
+        res: MultiSourceIngestResult,
+    ) -> Result<Vec<OutputCSVWantlistEntry>> {
+        let MultiSourceIngestResult {
+            message_id,
+            monitor_id,
+            timestamp,
+            clamped_from,
+            peer_id,
+            simulation_result,
+            num_ledgers,
+        } = res;
+        debug!(
+            "got entry {:?} from monitor {}",
+            simulation_result, monitor_id
+        );
+        self.last_timestamps[monitor_id] = Some(timestamp);
+        self.ledger_counter
+            .record(
+                monitor_id,
+                timestamp,
+                simulation_result.missing_ledger,
+                num_ledgers,
+            )
+            .context("unable to record ledger count")?;
+
+        // Keep the original timestamp for the output, if it was clamped.
+        // Connection events are written right away, wantlist entries once the matcher releases
+        // them.
+        let mut connection_event = simulation_result.connection_event.clone();
+        if let Some(ts) = clamped_from {
+            if let Some(conn_event) = connection_event.as_mut() {
+                conn_event.timestamp_seconds = ts.timestamp();
+                conn_event.timestamp_subsec_millis = ts.timestamp_subsec_millis();
+            }
+            let num_entries = simulation_result
+                .wantlist_entries
+                .as_ref()
+                .map_or(0, |entries| entries.len());
+            if num_entries > 0 {
+                self.clamped_messages.insert(message_id, (ts, num_entries));
+            }
+        }
+
+        // Track which monitors the peer is connected to
+        if let Some(conn_event) = connection_event {
+            self.presence_tracker
+                .handle_connection_event(
+                    monitor_id,
+                    timestamp,
+                    conn_event,
+                    self.anonymizer.as_ref(),
+                )
+                .context("unable to handle connection event")?;
+        }
+
+        // Feed that into the matching engine
+        let mut output_entries = self
+            .dup_marker
+            .handle_ingest_result(monitor_id, timestamp, peer_id, simulation_result)
+            .context("unable to handle ingest result")?;
+
+        self.restore_clamped_timestamps(&mut output_entries);
+        self.write_entries(&output_entries)?;
+        self.output
+            .end_message(message_id)
+            .context("unable to rotate output")?;
+
+        Ok(output_entries)
+    }
+
+    /// Returns the current statistics of the matcher.
+    pub(crate) fn matcher_stats(&self) -> MatcherStatistics {
+        self.dup_marker.stats()
+    }
+
+    /// Restores the original timestamps of entries of messages whose timestamps were clamped.
+    fn restore_clamped_timestamps(&mut self, output_entries: &mut [OutputCSVWantlistEntry]) {
+        if self.clamped_messages.is_empty() {
+            return;
+        }
+        for e in output_entries.iter_mut() {
+            let (ts, remaining) = match self.clamped_messages.get_mut(&e.entry.message_id) {
+                Some(clamped) => clamped,
+                None => continue,
+            };
+            e.entry.timestamp_seconds = ts.timestamp();
+            e.entry.timestamp_subsec_milliseconds = ts.timestamp_subsec_millis();
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.clamped_messages.remove(&e.entry.message_id);
+            }
+        }
+    }
+
+    /// Records matched entries for the statistics and writes them to the output.
+    fn write_entries(&mut self, output_entries: &[OutputCSVWantlistEntry]) -> Result<()> {
+        if let Some(estimator) = self.skew_estimator.as_mut() {
+            output_entries.iter().for_each(|e| estimator.record(e));
+        }
+        if let Some(tracker) = self.coverage_tracker.as_mut() {
+            output_entries.iter().for_each(|e| tracker.record(e));
+        }
+
+        let anonymizer = self.anonymizer.as_ref();
+        output_entries
+            .iter()
+            .try_for_each(|e| self.output.write(&anonymized(e, anonymizer)))
+            .context("unable to write output")?;
+
+        Ok(())
+    }
+
+    /// Writes entries held back by the matcher and end-of-simulation synthetic cancels, finishes
+    /// all outputs, writes the reports, and logs a summary.
+    ///
+    /// If clock offsets were corrected, the estimate made before correction and the corrections
+    /// are included in the clock skew report.
+    pub(crate) fn finish(
+        mut self,
+        cfg: &Config,
+        engine_states: Vec<EngineSimulation>,
+        msg_id: i64,
+        uncorrected_skew: Option<ClockSkewReport>,
+        clock_corrections: Vec<Option<LinearClockOffset>>,
+    ) -> Result<()> {
+        // Write entries held back by the matcher
+        let mut output_entries = self.dup_marker.finish();
+        self.restore_clamped_timestamps(&mut output_entries);
+        self.write_entries(&output_entries)?;
+
+        let source_names = &self.source_names;
+
+        let matching_stats = self.dup_marker.stats();
+
+        let final_ts = self.last_timestamps.iter().flatten().max().copied();
+
+        // Emit end-of-simulation synthetic cancels, at the last timestamp of each monitor.
+        // These are not matched between monitors, like all synthetic entries.
+        info!("finalizing engine simulations...");
+        let mut end_of_simulation_cancels = Vec::new();
+        for (monitor_id, (engine, ts)) in engine_states
+            .into_iter()
+            .zip(self.last_timestamps.iter())
+            .enumerate()
+        {
+            match ts {
+                Some(ts) => end_of_simulation_cancels.extend(
+                    engine
+                        .generate_end_of_simulation_entries(*ts, msg_id + 1)
+                        .into_iter()
+                        .map(|e| unmatched_output_entry(monitor_id, e)),
+                ),
+                None => warn!(
+                    "no messages from monitor {}, unable to finalize",
+                    source_names[monitor_id]
+                ),
+            }
+        }
+        // Sort, so that the output does not depend on hash map order.
+        end_of_simulation_cancels.sort_by(|a, b| {
+            (a.extension.monitor_id, &a.entry.peer_id, &a.entry.cid).cmp(&(
+                b.extension.monitor_id,
+                &b.entry.peer_id,
+                &b.entry.cid,
+            ))
+        });
+        end_of_simulation_cancels
+            .iter()
+            .try_for_each(|e| self.output.write(&anonymized(e, self.anonymizer.as_ref())))
+            .context("unable to write end-of-simulation synthetic cancels")?;
+
+        self.output.finish().context("unable to finish output")?;
+        let missing_ledgers = self
+            .ledger_counter
+            .finish()
+            .context("unable to finish ledger counts")?;
+        let overlap = match final_ts {
+            Some(final_ts) => Some(
+                self.presence_tracker
+                    .finish(final_ts, self.anonymizer.as_ref())
+                    .context("unable to finish presence tracking")?,
+            ),
+            None => None,
+        };
+
+        if let (Some(skew_cfg), Some(estimator)) = (cfg.clock_skew.as_ref(), self.skew_estimator) {
+            let report = estimator.estimate(source_names);
+            let summary = match uncorrected_skew {
+                Some(estimate) => {
+                    report.log("residual");
+                    ClockSkewSummary {
+                        estimate,
+                        corrections: Some(clock_corrections),
+                        residual: Some(report),
+                    }
+                }
+                None => {
+                    report.log("estimated");
+                    ClockSkewSummary {
+                        estimate: report,
+                        corrections: None,
+                        residual: None,
+                    }
+                }
+            };
+            if let Some(path) = skew_cfg.report_file.as_ref() {
+                let f =
+                    std::fs::File::create(path).context("unable to create clock skew report")?;
+                serde_json::to_writer_pretty(f, &summary)
+                    .context("unable to write clock skew report")?;
+            }
+        }
+
+        let coverage = match (cfg.coverage.as_ref(), self.coverage_tracker) {
+            (Some(coverage_cfg), Some(tracker)) => {
+                let report = tracker.finish(source_names);
+                report
+                    .write(coverage_cfg)
+                    .context("unable to write coverage report")?;
+                Some(report)
+            }
+            _ => None,
+        };
+
+        for (name, missing) in source_names.iter().zip(missing_ledgers.iter()) {
+            info!("{} missing ledgers for monitor {}", missing, name);
+        }
+        info!(
+            "{} end-of-simulation synthetic cancels",
+            end_of_simulation_cancels.len()
+        );
+        info!(
+            "{} entries in total, of which {} were matched between monitors",
+            matching_stats.total_entries, matching_stats.matched_entries
+        );
+        info!(
+            "min/mean/max match diff (ms): {}/{}/{}",
+            matching_stats.min_match_diff,
+            (matching_stats.match_diff_sum / matching_stats.matched_entries as f64) * 1000.0,
+            matching_stats.max_match_diff
+        );
+        info!(
+            "peak of {} entries queued for matching, {} entries swept from queues of silent peers, {} evicted early due to max_queued_entries",
+            matching_stats.peak_queued_entries,
+            matching_stats.swept_entries,
+            matching_stats.capped_entries
+        );
+        if matching_stats.skipped_newer_entries > 0 {
+            warn!(
+                "{} queued entries skipped for matching because they were newer than the entry they were compared to",
+                matching_stats.skipped_newer_entries
+            );
+        }
+        if let Some(overlap) = overlap {
+            info!(
+                "{} peers with connection events, {} of which were seen by all monitors",
+                overlap.peers, overlap.peers_seen_by_all
+            );
+            info!(
+                "connected to all monitors for {:.0}s, to some for {:.0}s, to one for {:.0}s in total",
+                overlap.seconds_connected_all,
+                overlap.seconds_connected_some,
+                overlap.seconds_connected_one
+            );
+        }
+        for (size, count) in matching_stats.clusters_by_size.iter().enumerate() {
+            if *count > 0 {
+                info!("{} clusters seen by {} monitor(s)", count, size);
+            }
+        }
+        if let Some(coverage) = coverage {
+            for m in coverage.monitors.iter() {
+                info!(
+                    "monitor {} saw {} peers ({} exclusively) and {} CIDs ({} exclusively), {} of {} entries seen by no other monitor",
+                    m.monitor_name,
+                    m.distinct_peers,
+                    m.exclusive_peers,
+                    m.distinct_cids,
+                    m.exclusive_cids,
+                    m.single_monitor_entries,
+                    m.entries
+                );
+            }
+        }
+
+        Ok(())
+    }
+}